@@ -0,0 +1,110 @@
+use rust_decimal::Decimal;
+use protocol::ws::BookSample;
+use serde::{Deserialize, Serialize};
+
+use super::orderbook::Arena;
+
+// all prices are rescaled to 2 decimals, so one tick is 0.01
+const TICK_SIZE: Decimal = Decimal::from_parts(1, 0, 0, false, 2);
+// every sample walks all levels and adds 11 series entries, shorter client intervals are raised to this
+const MIN_BOOK_STATS_INTERVAL: usize = 10;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct BookStatsConfig {
+  // sample the book after every `interval` orders
+  pub interval: usize,
+  // depth is summed over levels within `depth_ticks` of the best bid/ask
+  pub depth_ticks: u32
+}
+
+impl Default for BookStatsConfig {
+  fn default() -> Self {
+    BookStatsConfig { interval: 100, depth_ticks: 10 }
+  }
+}
+
+// column oriented time series of book samples, ready for charting
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BookStatsSeries {
+  pub order_idx: Vec<usize>,
+  pub spread: Vec<Option<Decimal>>,
+  pub best_bid_size: Vec<u64>,
+  pub best_ask_size: Vec<u64>,
+  pub bid_depth: Vec<u64>,
+  pub ask_depth: Vec<u64>,
+  pub bid_levels: Vec<usize>,
+  pub ask_levels: Vec<usize>,
+  pub resting_orders: Vec<usize>,
+  pub trade_count: Vec<usize>,
+  pub trade_volume: Vec<u64>
+}
+
+impl BookStatsSeries {
+  pub fn push(&mut self, sample: &BookSample) {
+    self.order_idx.push(sample.order_idx);
+    self.spread.push(sample.spread);
+    self.best_bid_size.push(sample.best_bid_size);
+    self.best_ask_size.push(sample.best_ask_size);
+    self.bid_depth.push(sample.bid_depth);
+    self.ask_depth.push(sample.ask_depth);
+    self.bid_levels.push(sample.bid_levels);
+    self.ask_levels.push(sample.ask_levels);
+    self.resting_orders.push(sample.resting_orders);
+    self.trade_count.push(sample.trade_count);
+    self.trade_volume.push(sample.trade_volume);
+  }
+}
+
+pub struct BookStatsSampler {
+  config: BookStatsConfig,
+  executed_orders_offset: usize
+}
+
+impl BookStatsSampler {
+  pub fn new(mut config: BookStatsConfig) -> Self {
+    // 0 keeps sampling off
+    if config.interval != 0 {
+      config.interval = config.interval.max(MIN_BOOK_STATS_INTERVAL);
+    }
+    BookStatsSampler { config, executed_orders_offset: 0 }
+  }
+
+  // samples the book if `idx` (0-based order index) falls on the configured interval
+  pub fn maybe_sample(&mut self, idx: usize, book: &Arena) -> Option<BookSample> {
+    if self.config.interval == 0 || (idx + 1) % self.config.interval != 0 {
+      return None;
+    }
+    Some(self.sample(idx, book))
+  }
+
+  fn sample(&mut self, idx: usize, book: &Arena) -> BookSample {
+    let fresh_trades = &book.executed_orders[self.executed_orders_offset..];
+    self.executed_orders_offset = book.executed_orders.len();
+
+    let depth_range = TICK_SIZE * Decimal::from(self.config.depth_ticks);
+    let best_bid_size = book.highest_buy.and_then(|p| book.buy_limits.get(&p)).map_or(0, |l| l.total_volume);
+    let best_ask_size = book.lowest_sell.and_then(|p| book.sell_limits.get(&p)).map_or(0, |l| l.total_volume);
+
+    let bid_depth = book.highest_buy.map_or(0, |best| {
+      book.buy_limits.values().filter(|l| l.limit_price >= best - depth_range).map(|l| l.total_volume).sum()
+    });
+    let ask_depth = book.lowest_sell.map_or(0, |best| {
+      book.sell_limits.values().filter(|l| l.limit_price <= best + depth_range).map(|l| l.total_volume).sum()
+    });
+
+    BookSample {
+      order_idx: idx,
+      spread: book.lowest_sell.zip(book.highest_buy).map(|(ask, bid)| ask - bid),
+      best_bid_size,
+      best_ask_size,
+      bid_depth,
+      ask_depth,
+      bid_levels: book.buy_limits.len(),
+      ask_levels: book.sell_limits.len(),
+      resting_orders: book.orders.len(),
+      trade_count: fresh_trades.len(),
+      trade_volume: fresh_trades.iter().map(|t| t.volume).sum()
+    }
+  }
+}
@@ -1,2 +1,7 @@
+pub mod book_stats;
 pub mod orderbook;
+pub mod phases;
+pub mod risk;
+pub mod snapshot;
+pub mod stats;
 pub mod tree;
\ No newline at end of file
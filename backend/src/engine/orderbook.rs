@@ -1,8 +1,9 @@
-use std::collections::{HashMap, HashSet};
+use std::{collections::{HashMap, HashSet}, time::{Duration, Instant}};
 use rand::{rngs::StdRng, Rng};
 use rust_decimal::Decimal;
-use serde::{Deserialize, Serialize};
-use super::tree::{delete_limit, insert_recursive};
+use protocol::ws::{ExecutedOrders, PhaseBreakdown};
+use serde::Deserialize;
+use super::{phases::PhaseSpan, stats::{EngineMetrics, OrderOp}, tree::{delete_limit, insert_recursive}};
 
 #[derive(Debug, Clone, Deserialize)]
 pub enum BidOrAsk {
@@ -10,14 +11,6 @@ pub enum BidOrAsk {
   Ask,
 }
 
-#[derive(Debug, Clone, Serialize)]
-pub struct ExecutedOrders {
-  price: Decimal,
-  volume: u64,
-  aggresive_order_id: u64,
-  passive_order_id: u64,
-}
-
 #[derive(Debug)]
 pub struct Order {
   id_number: u64,
@@ -109,11 +102,52 @@ impl Limit {
   }
 }
 
+// Resting order ids with O(1) insert/remove and a deterministic (insertion/removal ordered) layout,
+// so random picks only depend on the rng and not on hasher state
+#[derive(Default)]
+struct OrderIdSet {
+  ids: Vec<u64>,
+  positions: HashMap<u64, usize>
+}
+
+impl OrderIdSet {
+  fn insert(&mut self, order_id: u64) {
+    if !self.positions.contains_key(&order_id) {
+      self.positions.insert(order_id, self.ids.len());
+      self.ids.push(order_id);
+    }
+  }
+
+  fn remove(&mut self, order_id: &u64) {
+    if let Some(pos) = self.positions.remove(order_id) {
+      self.ids.swap_remove(pos);
+      if let Some(moved_id) = self.ids.get(pos) {
+        self.positions.insert(*moved_id, pos);
+      }
+    }
+  }
+
+  fn len(&self) -> usize {
+    self.ids.len()
+  }
+
+  fn get(&self, idx: usize) -> Option<&u64> {
+    self.ids.get(idx)
+  }
+}
+
+// price levels whose resting volume changed, per side
+#[derive(Debug, Default)]
+pub struct ChangedLevels {
+  pub bids: HashSet<Decimal>,
+  pub asks: HashSet<Decimal>
+}
+
 pub struct Arena {
   pub buy_limits: HashMap<Decimal, Limit>,
   pub sell_limits: HashMap<Decimal, Limit>,
   pub orders: HashMap<u64, Order>,
-  limit_orders: HashSet<u64>,
+  limit_orders: OrderIdSet,
   pub executed_orders: Vec<ExecutedOrders>,
 
   pub highest_buy: Option<Decimal>,
@@ -122,12 +156,17 @@ pub struct Arena {
   sell_tree: Option<Decimal>,
   pub executed_orders_count: usize,
   pub avl_rebalances: u64,
-  show_best_price_levels: bool
+  pub phases: PhaseBreakdown,
+  pub last_latency: Duration,
+  pub metrics: EngineMetrics,
+  show_best_price_levels: bool,
+  // levels touched since the last `take_changed_levels`, only recorded once tracking is on
+  changed_levels: Option<ChangedLevels>
 }
 
 impl Arena {
   pub fn new(best_price_lvls: bool) -> Self {
-    Arena {buy_limits: HashMap::new(), sell_limits: HashMap::new(), orders: HashMap::new(), limit_orders: HashSet::new(), executed_orders: Vec::new(), highest_buy: None, lowest_sell: None, buy_tree: None, sell_tree: None, executed_orders_count: 0, avl_rebalances: 0, show_best_price_levels: best_price_lvls} 
+    Arena {buy_limits: HashMap::new(), sell_limits: HashMap::new(), orders: HashMap::new(), limit_orders: OrderIdSet::default(), executed_orders: Vec::new(), highest_buy: None, lowest_sell: None, buy_tree: None, sell_tree: None, executed_orders_count: 0, avl_rebalances: 0, phases: PhaseBreakdown::default(), last_latency: Duration::new(0, 0), metrics: EngineMetrics::new(), show_best_price_levels: best_price_lvls, changed_levels: None} 
   }
 
   pub fn get_executed_orders(&mut self, offset: &mut usize) -> Option<Vec<ExecutedOrders>> {
@@ -221,14 +260,14 @@ impl Arena {
     }
   }
 
-  pub fn get_random_order_id(&self, rng: &mut StdRng) -> Option<&u64> {
-    // NOTE: the 10k pre seed orders
+  // picks a random resting order once more than `min_resting` (e.g. the pre seed orders) rest on the book
+  pub fn get_random_order_id(&self, rng: &mut StdRng, min_resting: usize) -> Option<&u64> {
     let count = self.limit_orders.len();
     // assert_eq!(self.orders.len(), count, "length of order map and limit order should match since we only have limit orders");
 
-    if count > 10_000 {
+    if count > min_resting {
       let skip_count = rng.random_range(0..count);
-      let id = self.limit_orders.iter().skip(skip_count).next();
+      let id = self.limit_orders.get(skip_count);
       //println!("[OB]random id: {:?}", id);
       return id;
     }
@@ -311,7 +350,9 @@ impl Arena {
       }
       // remove book_edge if its size = 0
       if limit_map.get(&book_edge_price).expect("book edge limit should exist if the corresponding price exists while calling market order helper()!!").size == 0 {
-        delete_limit(&mut self.avl_rebalances, &book_edge_price, book_edge, tree, limit_map);
+        let span = PhaseSpan::start();
+        delete_limit(&mut self.avl_rebalances, &mut self.phases, &book_edge_price, book_edge, tree, limit_map);
+        span.finish(&mut self.phases.delete_limit);
       }
 
       self.executed_orders_count += 1;
@@ -334,13 +375,193 @@ impl Arena {
     }
   }
 
-  pub fn add_limit_order(&mut self, order_id: u64, bid_or_ask: BidOrAsk, mut shares: u64, limit_price: Decimal) {
+  // record latency and engine work for the order that was just processed
+  fn record_op(&mut self, op: OrderOp, start: Instant) {
+    self.last_latency = start.elapsed();
+    self.metrics.record(op, self.last_latency, self.avl_rebalances, self.executed_orders_count);
+  }
+
+  pub fn add_limit_order(&mut self, order_id: u64, bid_or_ask: BidOrAsk, shares: u64, limit_price: Decimal) {
+    let fills_from = self.executed_orders.len();
+    let start = Instant::now();
+    self.process_add_limit_order(order_id, bid_or_ask.clone(), shares, limit_price);
+    self.record_op(OrderOp::Add, start);
+    self.mark_changed(&bid_or_ask, limit_price);
+    self.mark_fills(&bid_or_ask, fills_from);
+  }
+
+  pub fn modify_limit_order(&mut self, order_id: u64, new_shares: u64, new_limit_price: Decimal) {
+    let resting = self.resting_level(order_id);
+    let fills_from = self.executed_orders.len();
+    let start = Instant::now();
+    self.process_modify_limit_order(order_id, new_shares, new_limit_price);
+    self.record_op(OrderOp::Modify, start);
+    if let Some((bid_or_ask, price)) = resting {
+      self.mark_changed(&bid_or_ask, price);
+      self.mark_changed(&bid_or_ask, new_limit_price);
+      self.mark_fills(&bid_or_ask, fills_from);
+    }
+  }
+
+  pub fn cancel_limit_order(&mut self, order_id: u64) {
+    let resting = self.resting_level(order_id);
+    let start = Instant::now();
+    self.process_cancel_limit_order(order_id);
+    self.record_op(OrderOp::Cancel, start);
+    if let Some((bid_or_ask, price)) = resting {
+      self.mark_changed(&bid_or_ask, price);
+    }
+  }
+
+  // fills against the opposite side at any price, the unfilled remainder is dropped. returns the unfilled shares
+  pub fn add_market_order(&mut self, order_id: u64, bid_or_ask: BidOrAsk, shares: u64) -> u64 {
+    let fills_from = self.executed_orders.len();
+    let start = Instant::now();
+    let worst_price = match bid_or_ask {
+      BidOrAsk::Bid => Decimal::MAX,
+      BidOrAsk::Ask => Decimal::MIN
+    };
+    let rem_shares = self.process_immediate_order(order_id, bid_or_ask.clone(), shares, worst_price);
+    self.record_op(OrderOp::Market, start);
+    self.mark_fills(&bid_or_ask, fills_from);
+    rem_shares
+  }
+
+  // immediate-or-cancel: fills up to `limit_price` and drops the remainder instead of resting it. returns the unfilled shares
+  pub fn add_ioc_order(&mut self, order_id: u64, bid_or_ask: BidOrAsk, shares: u64, limit_price: Decimal) -> u64 {
+    let fills_from = self.executed_orders.len();
+    let start = Instant::now();
+    let rem_shares = self.process_immediate_order(order_id, bid_or_ask.clone(), shares, limit_price);
+    self.record_op(OrderOp::Ioc, start);
+    self.mark_fills(&bid_or_ask, fills_from);
+    rem_shares
+  }
+
+  // fill-or-kill: fills all `shares` up to `limit_price` or leaves the book untouched. returns whether it filled
+  pub fn add_fok_order(&mut self, order_id: u64, bid_or_ask: BidOrAsk, shares: u64, limit_price: Decimal) -> bool {
+    let fills_from = self.executed_orders.len();
+    let start = Instant::now();
+    let fills = self.fillable_volume(&bid_or_ask, limit_price) >= shares;
+    if fills {
+      self.process_immediate_order(order_id, bid_or_ask.clone(), shares, limit_price);
+    } else {
+      self.avl_rebalances = 0;
+      self.phases.reset();
+      self.executed_orders_count = 0;
+    }
+    self.record_op(OrderOp::Fok, start);
+    self.mark_fills(&bid_or_ask, fills_from);
+    fills
+  }
+
+  // start recording which price levels change, so depth can be published level by level
+  pub fn track_changed_levels(&mut self) {
+    self.changed_levels.get_or_insert_with(ChangedLevels::default);
+  }
+
+  // levels changed since the last call, empty unless tracking is on
+  pub fn take_changed_levels(&mut self) -> ChangedLevels {
+    self.changed_levels.as_mut().map(std::mem::take).unwrap_or_default()
+  }
+
+  // volume resting at `price`, 0 for a level that is gone
+  pub fn level_volume(&self, bid_or_ask: &BidOrAsk, price: Decimal) -> u64 {
+    let limits = match bid_or_ask {
+      BidOrAsk::Bid => &self.buy_limits,
+      BidOrAsk::Ask => &self.sell_limits
+    };
+    limits.get(&price).map_or(0, |limit| limit.total_volume)
+  }
+
+  fn resting_level(&self, order_id: u64) -> Option<(BidOrAsk, Decimal)> {
+    self.changed_levels.as_ref()?;
+    self.orders.get(&order_id).map(|order| (order.bid_or_ask.clone(), order.limit))
+  }
+
+  fn mark_changed(&mut self, bid_or_ask: &BidOrAsk, price: Decimal) {
+    if let Some(changed) = self.changed_levels.as_mut() {
+      match bid_or_ask {
+        BidOrAsk::Bid => changed.bids.insert(price),
+        BidOrAsk::Ask => changed.asks.insert(price)
+      };
+    }
+  }
+
+  // every fill since `fills_from` took volume from a level on the side opposite to `aggressor`
+  fn mark_fills(&mut self, aggressor: &BidOrAsk, fills_from: usize) {
+    if let Some(changed) = self.changed_levels.as_mut() {
+      let passive = match aggressor {
+        BidOrAsk::Bid => &mut changed.asks,
+        BidOrAsk::Ask => &mut changed.bids
+      };
+      passive.extend(self.executed_orders[fills_from..].iter().map(|fill| fill.price));
+    }
+  }
+
+  // resting volume an incoming order could fill against at `limit_price` or better
+  fn fillable_volume(&self, bid_or_ask: &BidOrAsk, limit_price: Decimal) -> u64 {
+    match bid_or_ask {
+      BidOrAsk::Bid => self.sell_limits.values().filter(|limit| limit.limit_price <= limit_price).map(|limit| limit.total_volume).sum(),
+      BidOrAsk::Ask => self.buy_limits.values().filter(|limit| limit.limit_price >= limit_price).map(|limit| limit.total_volume).sum()
+    }
+  }
+
+  // ids of the resting orders, optionally of one side only, in id order
+  pub fn resting_order_ids(&self, side: Option<&BidOrAsk>) -> Vec<u64> {
+    let mut order_ids: Vec<u64> = self.orders.iter()
+      .filter(|(_, order)| side.is_none_or(|side| matches!((&order.bid_or_ask, side), (BidOrAsk::Bid, BidOrAsk::Bid) | (BidOrAsk::Ask, BidOrAsk::Ask))))
+      .map(|(order_id, _)| *order_id)
+      .collect();
+    order_ids.sort_unstable();
+    order_ids
+  }
+
+  // every resting order of one side level by level as (price, [(order id, shares)]),
+  // best price first and in time priority within a level
+  pub fn resting_levels(&self, bid_or_ask: &BidOrAsk) -> Vec<(Decimal, Vec<(u64, u64)>)> {
+    let limits = match bid_or_ask {
+      BidOrAsk::Bid => &self.buy_limits,
+      BidOrAsk::Ask => &self.sell_limits
+    };
+    let mut prices: Vec<Decimal> = limits.keys().copied().collect();
+    prices.sort_unstable();
+    if matches!(bid_or_ask, BidOrAsk::Bid) {
+      prices.reverse();
+    }
+
+    prices.into_iter().filter_map(|price| {
+      let mut orders = Vec::new();
+      let mut next_order = limits[&price].head_order;
+      while let Some(order_id) = next_order {
+        let order = &self.orders[&order_id];
+        orders.push((order_id, order.shares));
+        next_order = order.next_order;
+      }
+      (!orders.is_empty()).then_some((price, orders))
+    }).collect()
+  }
+
+  fn process_immediate_order(&mut self, order_id: u64, bid_or_ask: BidOrAsk, mut shares: u64, limit_price: Decimal) -> u64 {
+    self.avl_rebalances = 0;
+    self.phases.reset();
+    self.executed_orders_count = 0;
+
+    let span = PhaseSpan::start();
+    let rem_shares = self.limit_order_as_market_order(&order_id, &bid_or_ask, &mut shares, &limit_price);
+    span.finish(&mut self.phases.matching);
+    rem_shares
+  }
+
+  fn process_add_limit_order(&mut self, order_id: u64, bid_or_ask: BidOrAsk, mut shares: u64, limit_price: Decimal) {
     
     self.avl_rebalances = 0;
+    self.phases.reset();
     self.executed_orders_count = 0;
 
     // check if order can be immediately executed (market order)
+    let span = PhaseSpan::start();
     let rem_shares = self.limit_order_as_market_order(&order_id, &bid_or_ask, &mut shares, &limit_price);
+    span.finish(&mut self.phases.matching);
 
     if rem_shares != 0 {
       let new_order = Order::new(order_id, bid_or_ask.clone(), rem_shares, limit_price);
@@ -375,9 +596,10 @@ impl Arena {
     }
   }
 
-  pub fn modify_limit_order(&mut self, order_id: u64, mut new_shares: u64, new_limit_price: Decimal) {
+  fn process_modify_limit_order(&mut self, order_id: u64, mut new_shares: u64, new_limit_price: Decimal) {
 
     self.avl_rebalances = 0;
+    self.phases.reset();
     self.executed_orders_count = 0;
   
     let (mut are_rem_shares, mut rem_shares)= (true, None);
@@ -425,12 +647,16 @@ impl Arena {
       parent_limit.size -= 1;
 
       if parent_limit.size == 0 {
-        delete_limit(&mut self.avl_rebalances,&parent_price, book_edge, tree, limit_map)
+        let span = PhaseSpan::start();
+        delete_limit(&mut self.avl_rebalances, &mut self.phases, &parent_price, book_edge, tree, limit_map);
+        span.finish(&mut self.phases.delete_limit);
       }
 
       //CHECK IF IMMEDIATELY EXECUTABLE
       let b_or_a = self.orders.get(&order_id).expect("order should exist!").bid_or_ask.clone();
+      let span = PhaseSpan::start();
       let left_shares = self.limit_order_as_market_order(&order_id, &b_or_a, &mut new_shares, &new_limit_price);
+      span.finish(&mut self.phases.matching);
       
       if left_shares == 0 {
         self.orders.remove(&order_id);
@@ -467,9 +693,10 @@ impl Arena {
     }
   }
 
-  pub fn cancel_limit_order(&mut self, order_id: u64) {
+  fn process_cancel_limit_order(&mut self, order_id: u64) {
     
     self.avl_rebalances = 0;
+    self.phases.reset();
     self.executed_orders_count = 0;
     
     // extract the order and cancel it
@@ -514,7 +741,9 @@ impl Arena {
       parent_limit.size -= 1;
 
       if parent_limit.size == 0 {
-        delete_limit(&mut self.avl_rebalances,&parent_price, book_edge, tree, limit_map)
+        let span = PhaseSpan::start();
+        delete_limit(&mut self.avl_rebalances, &mut self.phases, &parent_price, book_edge, tree, limit_map);
+        span.finish(&mut self.phases.delete_limit);
       }
 
       // delete orderid from ordermap 
@@ -540,7 +769,9 @@ impl Arena {
       },
       Some(_) => {
 
-        let _ = insert_recursive(&mut self.avl_rebalances, limit_map, tree, limit_price, None);
+        let span = PhaseSpan::start();
+        let _ = insert_recursive(&mut self.avl_rebalances, &mut self.phases, limit_map, tree, limit_price, None);
+        span.finish(&mut self.phases.tree_insert);
         // update bookedge i.e highestbuy/lowest sell
         match bid_or_ask {
           BidOrAsk::Bid => {
@@ -557,4 +788,67 @@ impl Arena {
       }
     }
   }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn book_with_asks(asks: &[(u64, u64, i64)]) -> Arena {
+    let mut book = Arena::new(false);
+    for &(order_id, shares, price) in asks {
+      book.add_limit_order(order_id, BidOrAsk::Ask, shares, Decimal::from(price));
+    }
+    book
+  }
+
+  #[test]
+  fn ioc_and_market_remainders_are_dropped_not_rested() {
+    let mut book = book_with_asks(&[(1, 10, 101), (2, 10, 102)]);
+
+    // only the 101 level is within the limit, the other 5 shares are dropped
+    assert_eq!(book.add_ioc_order(10, BidOrAsk::Bid, 15, Decimal::from(101)), 5);
+    assert_eq!(book.executed_orders.len(), 1);
+    assert_eq!((book.executed_orders[0].price, book.executed_orders[0].volume), (Decimal::from(101), 10));
+    assert!(!book.orders.contains_key(&10));
+    assert!(book.get_top_n_bids(1).is_empty());
+    assert_eq!(book.get_top_n_asks(2), vec![(Decimal::from(102), 10)]);
+
+    // a market order takes whatever is left at any price
+    assert_eq!(book.add_market_order(11, BidOrAsk::Bid, 25), 15);
+    assert_eq!(book.executed_orders.len(), 2);
+    assert!(!book.orders.contains_key(&11));
+    assert!(book.get_top_n_bids(1).is_empty());
+    assert!(book.get_top_n_asks(1).is_empty());
+  }
+
+  #[test]
+  fn fok_that_cannot_fully_fill_leaves_the_book_unchanged() {
+    let mut book = book_with_asks(&[(1, 10, 101), (2, 10, 102)]);
+    let before = book.resting_levels(&BidOrAsk::Ask);
+
+    // more than rests on the side, and more than rests within the limit
+    assert!(!book.add_fok_order(10, BidOrAsk::Bid, 25, Decimal::from(102)));
+    assert!(!book.add_fok_order(11, BidOrAsk::Bid, 15, Decimal::from(101)));
+    assert!(book.executed_orders.is_empty());
+    assert_eq!(book.resting_levels(&BidOrAsk::Ask), before);
+    assert!(book.resting_levels(&BidOrAsk::Bid).is_empty());
+
+    assert!(book.add_fok_order(12, BidOrAsk::Bid, 15, Decimal::from(102)));
+    assert_eq!(book.executed_orders.len(), 2);
+    assert_eq!(book.resting_levels(&BidOrAsk::Ask), vec![(Decimal::from(102), vec![(2, 5)])]);
+    assert!(!book.orders.contains_key(&12));
+  }
+
+  #[test]
+  fn market_order_against_an_empty_side_does_nothing() {
+    let mut book = Arena::new(false);
+    book.add_limit_order(1, BidOrAsk::Bid, 10, Decimal::from(99));
+    let bids = book.resting_levels(&BidOrAsk::Bid);
+
+    assert_eq!(book.add_market_order(2, BidOrAsk::Bid, 10), 10);
+    assert!(book.executed_orders.is_empty());
+    assert!(!book.orders.contains_key(&2));
+    assert_eq!(book.resting_levels(&BidOrAsk::Bid), bids);
+    assert!(book.resting_levels(&BidOrAsk::Ask).is_empty());
+  }
+}
@@ -0,0 +1,29 @@
+#[cfg(feature = "phase-timing")]
+use std::time::Instant;
+
+// the per order `PhaseBreakdown` is only measured when built with the `phase-timing` feature
+pub const PHASE_TIMING_ENABLED: bool = cfg!(feature = "phase-timing");
+
+// Measures a single phase, compiles down to nothing without the `phase-timing` feature
+pub struct PhaseSpan {
+  #[cfg(feature = "phase-timing")]
+  start: Instant
+}
+
+impl PhaseSpan {
+  #[inline(always)]
+  pub fn start() -> Self {
+    PhaseSpan {
+      #[cfg(feature = "phase-timing")]
+      start: Instant::now()
+    }
+  }
+
+  #[inline(always)]
+  pub fn finish(self, _slot: &mut u64) {
+    #[cfg(feature = "phase-timing")]
+    {
+      *_slot += self.start.elapsed().as_nanos() as u64;
+    }
+  }
+}
@@ -0,0 +1,345 @@
+use std::{collections::HashMap, fmt, fs, sync::Arc};
+use rust_decimal::{prelude::FromPrimitive, Decimal};
+use serde::{Deserialize, Serialize};
+
+use super::orderbook::{Arena, BidOrAsk};
+
+// NOTE: the engine currently runs a single book, so every order is checked against this instrument
+pub const DEFAULT_INSTRUMENT: &str = "DEFAULT";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RiskLimits {
+  pub max_order_qty: u64,
+  pub max_notional: Decimal,
+  pub max_open_orders: usize,
+  pub max_net_position: u64,
+  // max allowed distance from the reference best bid/ask as a fraction, e.g. 0.5 => 50%
+  pub max_price_deviation: f64
+}
+
+impl Default for RiskLimits {
+  fn default() -> Self {
+    RiskLimits {
+      max_order_qty: 1_000_000,
+      max_notional: Decimal::from(1_000_000_000u64),
+      max_open_orders: 1_000_000,
+      max_net_position: 1_000_000_000,
+      max_price_deviation: 0.5
+    }
+  }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct RiskConfig {
+  pub default_limits: RiskLimits,
+  pub accounts: HashMap<String, RiskLimits>,
+  pub instruments: HashMap<String, RiskLimits>
+}
+
+impl RiskConfig {
+  pub fn from_file(path: &str) -> Result<Self, String> {
+    let raw = fs::read_to_string(path).map_err(|e| format!("failed to read risk config {}: {}", path, e))?;
+    serde_json::from_str::<RiskConfig>(&raw).map_err(|e| format!("failed to parse risk config {}: {}", path, e))
+  }
+
+  fn account_limits(&self, account: &str) -> &RiskLimits {
+    self.accounts.get(account).unwrap_or(&self.default_limits)
+  }
+
+  fn instrument_limits(&self, instrument: &str) -> &RiskLimits {
+    self.instruments.get(instrument).unwrap_or(&self.default_limits)
+  }
+
+  // largest order of `instrument` an account without limits of its own may send
+  pub fn max_order_qty(&self, instrument: &str) -> u64 {
+    self.default_limits.max_order_qty.min(self.instrument_limits(instrument).max_order_qty)
+  }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub enum RiskReject {
+  MaxOrderQty { shares: u64, limit: u64 },
+  MaxNotional { notional: Decimal, limit: Decimal },
+  MaxOpenOrders { open_orders: usize, limit: usize },
+  MaxNetPosition { projected: i128, limit: u64 },
+  PriceDeviation { price: Decimal, reference: Decimal, limit: f64 },
+  UnknownOrder(u64)
+}
+
+impl RiskReject {
+  pub fn kind(&self) -> &'static str {
+    match self {
+      Self::MaxOrderQty { .. } => "MAX_ORDER_QTY",
+      Self::MaxNotional { .. } => "MAX_NOTIONAL",
+      Self::MaxOpenOrders { .. } => "MAX_OPEN_ORDERS",
+      Self::MaxNetPosition { .. } => "MAX_NET_POSITION",
+      Self::PriceDeviation { .. } => "PRICE_DEVIATION",
+      Self::UnknownOrder(_) => "UNKNOWN_ORDER"
+    }
+  }
+}
+
+impl fmt::Display for RiskReject {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::MaxOrderQty { shares, limit } => {
+        write!(f, "order quantity {} exceeds limit of {}", shares, limit)
+      },
+      Self::MaxNotional { notional, limit } => {
+        write!(f, "order notional {} exceeds limit of {}", notional, limit)
+      },
+      Self::MaxOpenOrders { open_orders, limit } => {
+        write!(f, "{} open orders already at limit of {}", open_orders, limit)
+      },
+      Self::MaxNetPosition { projected, limit } => {
+        write!(f, "projected net position {} exceeds limit of {}", projected, limit)
+      },
+      Self::PriceDeviation { price, reference, limit } => {
+        write!(f, "price {} deviates more than {:.2}% from reference {}", price, limit * 100.0, reference)
+      },
+      Self::UnknownOrder(id) => {
+        write!(f, "order {} is not tracked by risk checks", id)
+      }
+    }
+  }
+}
+
+#[derive(Debug, Default)]
+struct AccountExposure {
+  open_orders: usize,
+  // +ve for long and -ve for short
+  net_position: i128
+}
+
+struct TrackedOrder {
+  account: String,
+  bid_or_ask: BidOrAsk
+}
+
+pub struct RiskManager {
+  config: Arc<RiskConfig>,
+  instrument: String,
+  exposures: HashMap<String, AccountExposure>,
+  tracked_orders: HashMap<u64, TrackedOrder>,
+  executed_orders_offset: usize
+}
+
+impl RiskManager {
+  pub fn new(config: Arc<RiskConfig>, instrument: &str) -> Self {
+    RiskManager { config, instrument: instrument.to_string(), exposures: HashMap::new(), tracked_orders: HashMap::new(), executed_orders_offset: 0 }
+  }
+
+  pub fn check_add(&self, account: &str, bid_or_ask: &BidOrAsk, shares: u64, price: Decimal, book: &Arena) -> Result<(), RiskReject> {
+    let exposure = self.exposures.get(account);
+    let open_orders = exposure.map_or(0, |e| e.open_orders);
+    let net_position = exposure.map_or(0, |e| e.net_position);
+
+    for limits in [self.config.account_limits(account), self.config.instrument_limits(&self.instrument)] {
+      if open_orders >= limits.max_open_orders {
+        return Err(RiskReject::MaxOpenOrders { open_orders, limit: limits.max_open_orders });
+      }
+      check_order(limits, bid_or_ask, shares, price, net_position, book)?;
+    }
+    Ok(())
+  }
+
+  pub fn check_modify(&self, order_id: u64, shares: u64, price: Decimal, book: &Arena) -> Result<(), RiskReject> {
+    let tracked = self.tracked_orders.get(&order_id).ok_or(RiskReject::UnknownOrder(order_id))?;
+    let net_position = self.exposures.get(&tracked.account).map_or(0, |e| e.net_position);
+
+    for limits in [self.config.account_limits(&tracked.account), self.config.instrument_limits(&self.instrument)] {
+      check_order(limits, &tracked.bid_or_ask, shares, price, net_position, book)?;
+    }
+    Ok(())
+  }
+
+  // call after the engine processed an ADD (or MARKET/IOC) so resting orders and fills are attributed to `account`
+  pub fn record_add(&mut self, account: &str, order_id: u64, bid_or_ask: BidOrAsk, book: &Arena) {
+    self.tracked_orders.insert(order_id, TrackedOrder { account: account.to_string(), bid_or_ask });
+    self.exposures.entry(account.to_string()).or_default().open_orders += 1;
+    self.apply_fills(book);
+    // market and IOC orders (or fully filled limits) never rest on the book
+    if !book.orders.contains_key(&order_id) {
+      self.untrack(order_id);
+    }
+  }
+
+  // call after the engine processed a MODIFY, which may have crossed the book
+  pub fn record_modify(&mut self, order_id: u64, book: &Arena) {
+    self.apply_fills(book);
+    // a modify that crossed and fully filled (or cut the order to 0 shares) leaves the book
+    if !book.orders.contains_key(&order_id) {
+      self.untrack(order_id);
+    }
+  }
+
+  pub fn record_cancel(&mut self, order_id: u64) {
+    self.untrack(order_id);
+  }
+
+  fn apply_fills(&mut self, book: &Arena) {
+    let fills = &book.executed_orders[self.executed_orders_offset..];
+    self.executed_orders_offset = book.executed_orders.len();
+
+    for fill in fills {
+      let volume = fill.volume as i128;
+      for (order_id, is_passive) in [(fill.aggresive_order_id, false), (fill.passive_order_id, true)] {
+        if let Some(tracked) = self.tracked_orders.get(&order_id) {
+          let exposure = self.exposures.entry(tracked.account.clone()).or_default();
+          match tracked.bid_or_ask {
+            BidOrAsk::Bid => exposure.net_position += volume,
+            BidOrAsk::Ask => exposure.net_position -= volume
+          }
+          // fully filled passive orders leave the book
+          if is_passive && !book.orders.contains_key(&order_id) {
+            self.untrack(order_id);
+          }
+        }
+      }
+    }
+  }
+
+  fn untrack(&mut self, order_id: u64) {
+    if let Some(tracked) = self.tracked_orders.remove(&order_id) {
+      if let Some(exposure) = self.exposures.get_mut(&tracked.account) {
+        exposure.open_orders = exposure.open_orders.saturating_sub(1);
+      }
+    }
+  }
+}
+
+fn check_order(limits: &RiskLimits, bid_or_ask: &BidOrAsk, shares: u64, price: Decimal, net_position: i128, book: &Arena) -> Result<(), RiskReject> {
+  if shares > limits.max_order_qty {
+    return Err(RiskReject::MaxOrderQty { shares, limit: limits.max_order_qty });
+  }
+
+  let notional = Decimal::from(shares).checked_mul(price).unwrap_or(Decimal::MAX);
+  if notional > limits.max_notional {
+    return Err(RiskReject::MaxNotional { notional, limit: limits.max_notional });
+  }
+
+  let projected = match bid_or_ask {
+    BidOrAsk::Bid => net_position + shares as i128,
+    BidOrAsk::Ask => net_position - shares as i128
+  };
+  if projected.unsigned_abs() > limits.max_net_position as u128 {
+    return Err(RiskReject::MaxNetPosition { projected, limit: limits.max_net_position });
+  }
+
+  // compare against the opposite touch first and fall back to our own side for one-sided books
+  let reference = match bid_or_ask {
+    BidOrAsk::Bid => book.lowest_sell.or(book.highest_buy),
+    BidOrAsk::Ask => book.highest_buy.or(book.lowest_sell)
+  };
+  if let (Some(reference), Some(max_deviation)) = (reference, Decimal::from_f64(limits.max_price_deviation)) {
+    if !reference.is_zero() && ((price - reference) / reference).abs() > max_deviation {
+      return Err(RiskReject::PriceDeviation { price, reference, limit: limits.max_price_deviation });
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn manager(limits: RiskLimits) -> RiskManager {
+    RiskManager::new(Arc::new(RiskConfig { default_limits: limits, ..RiskConfig::default() }), DEFAULT_INSTRUMENT)
+  }
+
+  fn open_orders(risk: &RiskManager, account: &str) -> usize {
+    risk.exposures.get(account).map_or(0, |e| e.open_orders)
+  }
+
+  fn net_position(risk: &RiskManager, account: &str) -> i128 {
+    risk.exposures.get(account).map_or(0, |e| e.net_position)
+  }
+
+  fn add(risk: &mut RiskManager, book: &mut Arena, account: &str, id: u64, side: BidOrAsk, shares: u64, price: Decimal) {
+    risk.check_add(account, &side, shares, price, book).expect("add should pass risk checks");
+    book.add_limit_order(id, side.clone(), shares, price);
+    risk.record_add(account, id, side, book);
+  }
+
+  #[test]
+  fn rejects_each_limit_by_kind() {
+    let book = Arena::new(false);
+    let limits = RiskLimits { max_order_qty: 100, max_notional: Decimal::from(5_000), max_net_position: 150, ..RiskLimits::default() };
+    let risk = manager(limits);
+
+    let reject = risk.check_add("a", &BidOrAsk::Bid, 101, Decimal::from(1), &book).unwrap_err();
+    assert_eq!(reject.kind(), "MAX_ORDER_QTY");
+    let reject = risk.check_add("a", &BidOrAsk::Bid, 100, Decimal::from(51), &book).unwrap_err();
+    assert_eq!(reject.kind(), "MAX_NOTIONAL");
+    let reject = risk.check_modify(7, 10, Decimal::from(1), &book).unwrap_err();
+    assert_eq!(reject.kind(), "UNKNOWN_ORDER");
+    assert!(risk.check_add("a", &BidOrAsk::Ask, 100, Decimal::from(50), &book).is_ok());
+  }
+
+  #[test]
+  fn rejects_open_orders_net_position_and_price_deviation() {
+    let mut book = Arena::new(false);
+    let limits = RiskLimits { max_open_orders: 2, max_net_position: 15, max_price_deviation: 0.1, ..RiskLimits::default() };
+    let mut risk = manager(limits);
+
+    add(&mut risk, &mut book, "a", 1, BidOrAsk::Ask, 10, Decimal::from(100));
+    add(&mut risk, &mut book, "a", 2, BidOrAsk::Ask, 10, Decimal::from(101));
+    let reject = risk.check_add("a", &BidOrAsk::Ask, 10, Decimal::from(102), &book).unwrap_err();
+    assert_eq!(reject.kind(), "MAX_OPEN_ORDERS");
+
+    // the best ask of 100 is the reference for bids
+    let reject = risk.check_add("b", &BidOrAsk::Bid, 5, Decimal::from(80), &book).unwrap_err();
+    assert_eq!(reject.kind(), "PRICE_DEVIATION");
+
+    add(&mut risk, &mut book, "b", 3, BidOrAsk::Bid, 10, Decimal::from(100));
+    assert_eq!(net_position(&risk, "b"), 10);
+    let reject = risk.check_add("b", &BidOrAsk::Bid, 6, Decimal::from(100), &book).unwrap_err();
+    assert_eq!(reject.kind(), "MAX_NET_POSITION");
+  }
+
+  #[test]
+  fn fills_move_net_positions_and_free_open_orders() {
+    let mut book = Arena::new(false);
+    let mut risk = manager(RiskLimits::default());
+
+    add(&mut risk, &mut book, "maker", 1, BidOrAsk::Ask, 10, Decimal::from(100));
+    add(&mut risk, &mut book, "maker", 2, BidOrAsk::Ask, 10, Decimal::from(101));
+    assert_eq!(open_orders(&risk, "maker"), 2);
+
+    // takes all of order 1 and half of order 2, then rests nothing
+    add(&mut risk, &mut book, "taker", 3, BidOrAsk::Bid, 15, Decimal::from(101));
+    assert_eq!(net_position(&risk, "taker"), 15);
+    assert_eq!(net_position(&risk, "maker"), -15);
+    assert_eq!(open_orders(&risk, "taker"), 0);
+    assert_eq!(open_orders(&risk, "maker"), 1);
+
+    risk.record_cancel(2);
+    book.cancel_limit_order(2);
+    assert_eq!(open_orders(&risk, "maker"), 0);
+  }
+
+  #[test]
+  fn modify_that_leaves_the_book_frees_its_open_order() {
+    let mut book = Arena::new(false);
+    let mut risk = manager(RiskLimits { max_open_orders: 1, ..RiskLimits::default() });
+
+    add(&mut risk, &mut book, "maker", 1, BidOrAsk::Ask, 10, Decimal::from(100));
+    add(&mut risk, &mut book, "taker", 2, BidOrAsk::Bid, 10, Decimal::from(99));
+
+    // crosses the ask and fills completely
+    risk.check_modify(2, 10, Decimal::from(100), &book).unwrap();
+    book.modify_limit_order(2, 10, Decimal::from(100));
+    risk.record_modify(2, &book);
+    assert_eq!(open_orders(&risk, "taker"), 0);
+    assert_eq!(net_position(&risk, "taker"), 10);
+
+    // a modify down to no shares removes the order without any fill
+    add(&mut risk, &mut book, "taker", 3, BidOrAsk::Bid, 10, Decimal::from(99));
+    book.modify_limit_order(3, 0, Decimal::from(99));
+    risk.record_modify(3, &book);
+    assert_eq!(open_orders(&risk, "taker"), 0);
+    assert!(risk.check_add("taker", &BidOrAsk::Bid, 10, Decimal::from(99), &book).is_ok());
+  }
+}
@@ -0,0 +1,224 @@
+use std::{collections::{BTreeMap, HashMap}, sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc, Mutex}};
+use arc_swap::{ArcSwap, ArcSwapOption};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use tokio::sync::Notify;
+
+use super::orderbook::{Arena, BidOrAsk};
+
+// Immutable top-of-book depth, replaced wholesale whenever a reader catches up with the engine
+#[derive(Debug, Default, Serialize)]
+pub struct DepthSnapshot {
+  pub seq: u64,
+  // simulated time of the last order processed before publishing
+  pub sim_time_ns: u64,
+  pub bids: Vec<(Decimal, u64)>,
+  pub asks: Vec<(Decimal, u64)>
+}
+
+// levels changed since readers last caught up, a volume of 0 removes the level
+#[derive(Clone, Default)]
+struct DepthDelta {
+  seq: u64,
+  sim_time_ns: u64,
+  bids: HashMap<Decimal, u64>,
+  asks: HashMap<Decimal, u64>
+}
+
+// every level of the book, kept on the reader side and folded forward from the deltas
+#[derive(Default)]
+struct DepthLevels {
+  bids: BTreeMap<Decimal, u64>,
+  asks: BTreeMap<Decimal, u64>
+}
+
+impl DepthLevels {
+  fn apply(&mut self, delta: DepthDelta) {
+    for (levels, changed) in [(&mut self.bids, delta.bids), (&mut self.asks, delta.asks)] {
+      for (price, volume) in changed {
+        if volume == 0 {
+          levels.remove(&price);
+        } else {
+          levels.insert(price, volume);
+        }
+      }
+    }
+  }
+
+  fn top(&self, levels: usize, seq: u64, sim_time_ns: u64) -> DepthSnapshot {
+    DepthSnapshot {
+      seq,
+      sim_time_ns,
+      bids: self.bids.iter().rev().take(levels).map(|(price, volume)| (*price, *volume)).collect(),
+      asks: self.asks.iter().take(levels).map(|(price, volume)| (*price, *volume)).collect()
+    }
+  }
+}
+
+struct SharedDepth {
+  levels: usize,
+  // handed over by atomic swaps so the engine never waits on a reader, whoever swaps it out owns it
+  pending: ArcSwapOption<DepthDelta>,
+  published_seq: AtomicU64,
+  // reader side state, the engine never touches it
+  book: Mutex<DepthLevels>,
+  current: ArcSwap<DepthSnapshot>,
+  updated: Notify,
+  closed: AtomicBool
+}
+
+// Owned by the engine task. Publishing only hands over the levels changed since the last publish,
+// readers sort and cut the top levels themselves so the engine never walks the book
+pub struct DepthPublisher {
+  shared: Arc<SharedDepth>,
+  seq: u64
+}
+
+impl DepthPublisher {
+  pub fn new(levels: usize) -> Self {
+    DepthPublisher {
+      shared: Arc::new(SharedDepth {
+        levels,
+        pending: ArcSwapOption::empty(),
+        published_seq: AtomicU64::new(0),
+        book: Mutex::new(DepthLevels::default()),
+        current: ArcSwap::from_pointee(DepthSnapshot::default()),
+        updated: Notify::new(),
+        closed: AtomicBool::new(false)
+      }),
+      seq: 0
+    }
+  }
+
+  // `book` must have level tracking on, see `Arena::track_changed_levels`
+  pub fn publish(&mut self, book: &mut Arena, sim_time_ns: u64) {
+    self.seq += 1;
+    let changed = book.take_changed_levels();
+    // a delta no reader took yet is taken back and extended
+    let mut pending = self.shared.pending.swap(None).map(Arc::unwrap_or_clone).unwrap_or_default();
+    for price in changed.bids {
+      pending.bids.insert(price, book.level_volume(&BidOrAsk::Bid, price));
+    }
+    for price in changed.asks {
+      pending.asks.insert(price, book.level_volume(&BidOrAsk::Ask, price));
+    }
+    pending.seq = self.seq;
+    pending.sim_time_ns = sim_time_ns;
+    self.shared.pending.store(Some(Arc::new(pending)));
+    self.shared.published_seq.store(self.seq, Ordering::Release);
+    self.shared.updated.notify_one();
+  }
+
+  pub fn reader(&self) -> DepthReader {
+    DepthReader { shared: self.shared.clone() }
+  }
+}
+
+impl Drop for DepthPublisher {
+  // wake up any waiting reader so it can drain the last snapshot and exit
+  fn drop(&mut self) {
+    self.shared.closed.store(true, Ordering::Release);
+    self.shared.updated.notify_one();
+  }
+}
+
+// Cheap to clone read handle to the latest published depth
+#[derive(Clone)]
+pub struct DepthReader {
+  shared: Arc<SharedDepth>
+}
+
+impl DepthReader {
+  // folds whatever the engine published since the last read into a fresh snapshot
+  pub fn latest(&self) -> Arc<DepthSnapshot> {
+    let current = self.shared.current.load_full();
+    if current.seq >= self.shared.published_seq.load(Ordering::Acquire) {
+      return current;
+    }
+
+    let mut book = self.shared.book.lock().expect("depth levels lock poisoned!");
+    // another reader folded it in while we waited for the lock, or the engine is extending it
+    let Some(delta) = self.shared.pending.swap(None) else {
+      return self.shared.current.load_full();
+    };
+    let delta = Arc::unwrap_or_clone(delta);
+    let (seq, sim_time_ns) = (delta.seq, delta.sim_time_ns);
+    book.apply(delta);
+    let snapshot = Arc::new(book.top(self.shared.levels, seq, sim_time_ns));
+    self.shared.current.store(snapshot.clone());
+    snapshot
+  }
+
+  // waits for a snapshot newer than `seen_seq`, intermediate snapshots are skipped if the reader falls behind.
+  // returns None once the publisher is gone and nothing newer is left
+  pub async fn changed(&self, seen_seq: u64) -> Option<Arc<DepthSnapshot>> {
+    loop {
+      let snapshot = self.latest();
+      if snapshot.seq > seen_seq {
+        return Some(snapshot);
+      }
+      if self.shared.closed.load(Ordering::Acquire) {
+        return None;
+      }
+      self.shared.updated.notified().await;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn folded_deltas_match_the_book_depth() {
+    // the tree walk from the root lists every level best first
+    let mut book = Arena::new(false);
+    book.track_changed_levels();
+    let mut publisher = DepthPublisher::new(3);
+    let reader = publisher.reader();
+
+    for (id, price) in (1..).zip([99, 98, 97, 96]) {
+      book.add_limit_order(id, BidOrAsk::Bid, 10, Decimal::from(price));
+    }
+    for (id, price) in (10..).zip([101, 102, 103]) {
+      book.add_limit_order(id, BidOrAsk::Ask, 10, Decimal::from(price));
+    }
+    publisher.publish(&mut book, 1);
+    let snapshot = reader.latest();
+    assert_eq!(snapshot.bids, book.get_top_n_bids(3));
+    assert_eq!(snapshot.asks, book.get_top_n_asks(3));
+
+    // crosses the best ask and rests the remainder, cancels and moves some levels
+    book.add_limit_order(20, BidOrAsk::Bid, 15, Decimal::from(101));
+    book.cancel_limit_order(2);
+    book.modify_limit_order(11, 5, Decimal::from(104));
+    publisher.publish(&mut book, 2);
+    let snapshot = reader.latest();
+    assert_eq!(snapshot.seq, 2);
+    assert_eq!(snapshot.sim_time_ns, 2);
+    assert_eq!(snapshot.bids, book.get_top_n_bids(3));
+    assert_eq!(snapshot.asks, book.get_top_n_asks(3));
+    assert!(Arc::ptr_eq(&snapshot, &reader.latest()));
+  }
+
+  #[test]
+  fn unread_deltas_are_merged_into_the_next_publish() {
+    let mut book = Arena::new(false);
+    book.track_changed_levels();
+    let mut publisher = DepthPublisher::new(2);
+    let reader = publisher.reader();
+
+    book.add_limit_order(1, BidOrAsk::Bid, 10, Decimal::from(99));
+    book.add_limit_order(2, BidOrAsk::Ask, 10, Decimal::from(101));
+    publisher.publish(&mut book, 1);
+    // the reader skips the first publish, its levels must still show up
+    book.add_limit_order(3, BidOrAsk::Bid, 5, Decimal::from(98));
+    book.cancel_limit_order(2);
+    publisher.publish(&mut book, 2);
+
+    let snapshot = reader.latest();
+    assert_eq!(snapshot.seq, 2);
+    assert_eq!(snapshot.bids, vec![(Decimal::from(99), 10), (Decimal::from(98), 5)]);
+    assert!(snapshot.asks.is_empty());
+  }
+}
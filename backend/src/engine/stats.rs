@@ -0,0 +1,169 @@
+use std::{collections::HashMap, time::Duration};
+use hdrhistogram::Histogram;
+
+// latencies are tracked between 1ns and 60s with 3 significant digits, which keeps each histogram at a fixed size
+const MAX_TRACKABLE_LATENCY_NS: u64 = 60_000_000_000;
+const SIGNIFICANT_DIGITS: u8 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OrderOp {
+  Add,
+  Modify,
+  Cancel,
+  Market,
+  Ioc,
+  Fok
+}
+
+impl OrderOp {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Self::Add => "ADD",
+      Self::Modify => "MODIFY",
+      Self::Cancel => "CANCEL",
+      Self::Market => "MARKET",
+      Self::Ioc => "IOC",
+      Self::Fok => "FOK"
+    }
+  }
+}
+
+#[derive(Clone)]
+pub struct OpStats {
+  latency: Histogram<u64>,
+  pub total_time: Duration,
+  pub avl_rebalances: i64,
+  pub executed_orders_cnt: i64,
+  pub nos: i64
+}
+
+impl OpStats {
+  fn new() -> Self {
+    OpStats {
+      latency: Histogram::new_with_bounds(1, MAX_TRACKABLE_LATENCY_NS, SIGNIFICANT_DIGITS).expect("latency histogram bounds should be valid!"),
+      total_time: Duration::new(0, 0),
+      avl_rebalances: 0,
+      executed_orders_cnt: 0,
+      nos: 0
+    }
+  }
+
+  fn merge(&mut self, other: &OpStats) {
+    self.latency.add(&other.latency).expect("histograms share the same bounds so merging should not fail!");
+    self.total_time += other.total_time;
+    self.avl_rebalances += other.avl_rebalances;
+    self.executed_orders_cnt += other.executed_orders_cnt;
+    self.nos += other.nos;
+  }
+
+  // latency at the given percentile (0-100)
+  pub fn percentile(&self, pct: f64) -> Duration {
+    Duration::from_nanos(self.latency.value_at_quantile(pct / 100.0))
+  }
+
+  pub fn max(&self) -> Duration {
+    Duration::from_nanos(self.latency.max())
+  }
+}
+
+// constant memory latency stats per operation type, recorded by the engine for every order it processes
+#[derive(Clone, Default)]
+pub struct EngineMetrics {
+  ops: HashMap<OrderOp, OpStats>
+}
+
+impl EngineMetrics {
+  pub fn new() -> Self {
+    EngineMetrics { ops: HashMap::new() }
+  }
+
+  pub fn record(&mut self, op: OrderOp, latency: Duration, avl_rebalances: u64, executed_orders_cnt: usize) {
+    let stats = self.ops.entry(op).or_insert_with(OpStats::new);
+    // saturate instead of failing for outliers beyond the trackable range
+    stats.latency.saturating_record((latency.as_nanos() as u64).max(1));
+    stats.total_time += latency;
+    stats.avl_rebalances += avl_rebalances as i64;
+    stats.executed_orders_cnt += executed_orders_cnt as i64;
+    stats.nos += 1;
+  }
+
+  // adds the stats of another book, e.g. to report a multi-symbol upload as a whole
+  pub fn merge(&mut self, other: &EngineMetrics) {
+    for (op, stats) in &other.ops {
+      self.ops.entry(*op).or_insert_with(OpStats::new).merge(stats);
+    }
+  }
+
+  pub fn snapshot(&self) -> HashMap<OrderOp, OpStats> {
+    self.ops.clone()
+  }
+
+  // stats across all operation types
+  pub fn overall(&self) -> Option<OpStats> {
+    self.ops.values().fold(None, |acc, stats| {
+      let mut merged = acc.unwrap_or_else(OpStats::new);
+      merged.merge(stats);
+      Some(merged)
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // 3 significant digits keep every value within 0.1%
+  fn assert_close(actual: Duration, expected: Duration) {
+    let diff = actual.abs_diff(expected);
+    assert!(diff <= expected / 1_000, "{:?} is not within 0.1% of {:?}", actual, expected);
+  }
+
+  #[test]
+  fn percentiles_stay_within_the_histogram_precision() {
+    let mut metrics = EngineMetrics::new();
+    for micros in 1..=1_000 {
+      metrics.record(OrderOp::Add, Duration::from_micros(micros), 2, 1);
+    }
+    // outliers past the trackable range are capped instead of dropped
+    metrics.record(OrderOp::Cancel, Duration::from_secs(120), 0, 0);
+
+    let snapshot = metrics.snapshot();
+    let add = &snapshot[&OrderOp::Add];
+    assert_eq!(add.nos, 1_000);
+    assert_eq!((add.avl_rebalances, add.executed_orders_cnt), (2_000, 1_000));
+    assert_close(add.percentile(50.0), Duration::from_micros(500));
+    assert_close(add.percentile(99.0), Duration::from_micros(990));
+    assert_close(add.max(), Duration::from_micros(1_000));
+    assert_close(snapshot[&OrderOp::Cancel].max(), Duration::from_secs(60));
+  }
+
+  #[test]
+  fn merged_and_overall_stats_add_up() {
+    let mut first = EngineMetrics::new();
+    let mut second = EngineMetrics::new();
+    for micros in 1..=100 {
+      first.record(OrderOp::Add, Duration::from_micros(micros), 1, 0);
+      second.record(OrderOp::Add, Duration::from_micros(micros + 100), 1, 0);
+      second.record(OrderOp::Cancel, Duration::from_micros(1_000), 0, 0);
+    }
+    assert!(EngineMetrics::new().overall().is_none());
+
+    first.merge(&second);
+    let snapshot = first.snapshot();
+    let add = &snapshot[&OrderOp::Add];
+    assert_eq!(add.nos, 200);
+    assert_eq!(add.avl_rebalances, 200);
+    assert_eq!(add.total_time, Duration::from_micros((1..=200).sum()));
+    assert_close(add.percentile(50.0), Duration::from_micros(100));
+    assert_close(add.max(), Duration::from_micros(200));
+    assert_eq!(snapshot[&OrderOp::Cancel].nos, 100);
+
+    // the cancels make up a third of all operations, all of them slower than any add
+    let overall = first.overall().expect("recorded ops should have overall stats!");
+    assert_eq!(overall.nos, 300);
+    assert_eq!(overall.total_time, add.total_time + Duration::from_micros(100_000));
+    assert_close(overall.percentile(50.0), Duration::from_micros(150));
+    assert_close(overall.percentile(90.0), Duration::from_micros(1_000));
+    assert_close(overall.max(), Duration::from_micros(1_000));
+  }
+}
@@ -1,11 +1,13 @@
 use std::{cmp, collections::HashMap};
 use rust_decimal::Decimal;
-use super::orderbook::{BidOrAsk, Limit};
+use protocol::ws::PhaseBreakdown;
+use super::{orderbook::{BidOrAsk, Limit}, phases::PhaseSpan};
 
 struct BinaryTree<'a> {
   limit_map: &'a mut HashMap<Decimal, Limit>,
   tree: Option<&'a mut Decimal>, // a.k.a the root of tree
-  avl_rebalances: &'a mut u64
+  avl_rebalances: &'a mut u64,
+  phases: &'a mut PhaseBreakdown
 }
 
 impl<'a> BinaryTree <'a> {
@@ -14,12 +16,12 @@ impl<'a> BinaryTree <'a> {
     self.limit_map.get(limit_price).expect("limit should exist when calling to_limit()!!")
   }
 
-  fn new_for_insert(limit_map: &'a mut HashMap<Decimal, Limit>, avl_rebalances: &'a mut u64) -> Self {
-    BinaryTree { limit_map, tree: None, avl_rebalances}
+  fn new_for_insert(limit_map: &'a mut HashMap<Decimal, Limit>, avl_rebalances: &'a mut u64, phases: &'a mut PhaseBreakdown) -> Self {
+    BinaryTree { limit_map, tree: None, avl_rebalances, phases}
   } 
 
-  fn new_for_delete(limit_map: &'a mut HashMap<Decimal, Limit>, tree: Option<&'a mut Decimal>, avl_rebalances: &'a mut u64) -> Self {
-    BinaryTree { limit_map, tree, avl_rebalances}
+  fn new_for_delete(limit_map: &'a mut HashMap<Decimal, Limit>, tree: Option<&'a mut Decimal>, avl_rebalances: &'a mut u64, phases: &'a mut PhaseBreakdown) -> Self {
+    BinaryTree { limit_map, tree, avl_rebalances, phases}
   } 
 
   fn get_balance_factor(&self, limit: &Limit) -> i32 {
@@ -44,11 +46,13 @@ impl<'a> BinaryTree <'a> {
         let left_child_limit = self.to_limit(&left_child);
         let l: Decimal;
 
+        let span = PhaseSpan::start();
         if self.get_balance_factor(left_child_limit) >= 0 {
           l = self.ll_rotate(limit_price);
         } else {
           l = self.lr_rotate(limit_price);
         }
+        span.finish(&mut self.phases.rebalance);
         *self.avl_rebalances += 1;
         l
       },
@@ -57,11 +61,13 @@ impl<'a> BinaryTree <'a> {
         let right_child_limit = self.to_limit(&right_child);
         let l: Decimal;
   
+        let span = PhaseSpan::start();
         if self.get_balance_factor(right_child_limit) > 0 { 
           l = self.rl_rotate(limit_price);
         } else {
           l = self.rr_rotate(limit_price);
         }
+        span.finish(&mut self.phases.rebalance);
 
         *self.avl_rebalances += 1;
         l
@@ -202,7 +208,7 @@ impl<'a> BinaryTree <'a> {
   }
 } 
 
-pub fn insert_recursive(avl_rebal_cnt: &mut u64, limit_map: &mut HashMap<Decimal, Limit>, root: &mut Option<Decimal>, limit_price: Decimal, parent: Option<Decimal>) -> Decimal{
+pub fn insert_recursive(avl_rebal_cnt: &mut u64, phases: &mut PhaseBreakdown, limit_map: &mut HashMap<Decimal, Limit>, root: &mut Option<Decimal>, limit_price: Decimal, parent: Option<Decimal>) -> Decimal{
 
   match root {
     None => {
@@ -213,12 +219,12 @@ pub fn insert_recursive(avl_rebal_cnt: &mut u64, limit_map: &mut HashMap<Decimal
     Some(root_price) => {
       if limit_price < *root_price {
         let mut left_child = limit_map.get_mut(&root_price).expect("root price limit should exist here!!").left_child;
-        let result = insert_recursive(avl_rebal_cnt,limit_map, &mut left_child, limit_price, Some(*root_price));
+        let result = insert_recursive(avl_rebal_cnt, phases, limit_map, &mut left_child, limit_price, Some(*root_price));
         limit_map.get_mut(&root_price).expect("root price limit should exist here!!").left_child = Some(result);
       }
       else if limit_price > *root_price {
         let mut right_child = limit_map.get_mut(&root_price).expect("root price limit should exist here!!").right_child;
-        let result = insert_recursive(avl_rebal_cnt, limit_map, &mut right_child, limit_price, Some(*root_price));
+        let result = insert_recursive(avl_rebal_cnt, phases, limit_map, &mut right_child, limit_price, Some(*root_price));
         limit_map.get_mut(&root_price).expect("root price limit should exist here!!").right_child = Some(result);
       }
       // TODO: check if need to handle limit_price == root_price case
@@ -239,7 +245,7 @@ pub fn insert_recursive(avl_rebal_cnt: &mut u64, limit_map: &mut HashMap<Decimal
         
       //TODO: AVL balancing
       // NOTE: below 'tree' arg is not needed to be passed since we already balance the tree using the 'root_price'
-      let mut bst = BinaryTree::new_for_insert(limit_map, avl_rebal_cnt);
+      let mut bst = BinaryTree::new_for_insert(limit_map, avl_rebal_cnt, phases);
       *root_price = bst.balance_tree(root_price);
 
       // finally return the root price
@@ -248,7 +254,7 @@ pub fn insert_recursive(avl_rebal_cnt: &mut u64, limit_map: &mut HashMap<Decimal
   }
 }
 
-pub fn delete_limit(avl_rebal_cnt: &mut u64, limit_price: &Decimal, book_edge: &mut Option<Decimal>, tree: &mut Option<Decimal>, limit_map: &mut HashMap<Decimal, Limit>) {
+pub fn delete_limit(avl_rebal_cnt: &mut u64, phases: &mut PhaseBreakdown, limit_price: &Decimal, book_edge: &mut Option<Decimal>, tree: &mut Option<Decimal>, limit_map: &mut HashMap<Decimal, Limit>) {
     
   // update bookedge if the removed limit was bookedge
   if Some(*limit_price) == *book_edge {
@@ -301,7 +307,7 @@ pub fn delete_limit(avl_rebal_cnt: &mut u64, limit_price: &Decimal, book_edge: &
 
     let mut parent_limit_price_unwraped = parent_limit_price.expect("parent limit price cannot be None since already checked in the above while exp");
     // balance AVL tree
-    let mut bst = BinaryTree::new_for_delete(limit_map, tree.as_mut(), avl_rebal_cnt);
+    let mut bst = BinaryTree::new_for_delete(limit_map, tree.as_mut(), avl_rebal_cnt, phases);
     parent_limit_price_unwraped = bst.balance_tree(&parent_limit_price_unwraped);
 
     if let Some(parent_limit_) = limit_map.get(&parent_limit_price_unwraped) {
@@ -0,0 +1,432 @@
+use std::{fs, time::{Duration, Instant}};
+use rust_decimal::{prelude::ToPrimitive, Decimal};
+use protocol::grammar::{FileUploadOrderType, OrderLine, ParseError, ParseReport, Side};
+
+use super::parser::UploadParser;
+
+// Binary order files, for uploads too large or too slow to parse as text.
+//
+// Header (8 bytes): the magic `OBCMD`, the format version (1), flags (bit 0: records carry a timestamp)
+// and the price scale, the number of decimal places of a price tick (2 means ticks of 0.01).
+//
+// Records (22 bytes, 30 with timestamps), little-endian and back to back:
+//   0       u8   message type: 1 ADD, 2 MODIFY, 3 CANCEL, 4 MARKET, 5 IOC, 6 FOK, 7 STOP, 8 MASSCANCEL
+//   1       u8   side: 0 BID, 1 ASK, 2 both sides (MASSCANCEL only)
+//   2..10   u64  order id
+//   10..14  u32  shares
+//   14..18  i32  price in ticks, the stop price for STOP
+//   18..22  i32  limit price of a STOP in ticks, `NO_LIMIT_PRICE` for a stop market order
+//   22..30  u64  timestamp, only when the header flag is set
+// Fields a message type does not use are written as zero and ignored when read.
+// Binary files have no symbol column, every order trades the default instrument
+pub const MAGIC: &[u8; 5] = b"OBCMD";
+pub const BINARY_VERSION: u8 = 1;
+pub const HEADER_SIZE: usize = 8;
+pub const RECORD_SIZE: usize = 22;
+pub const FLAG_TIMESTAMPS: u8 = 1;
+// matches the two decimal places prices are rounded to by the text grammar
+pub const DEFAULT_PRICE_SCALE: u8 = 2;
+pub const NO_LIMIT_PRICE: i32 = i32::MIN;
+const TIMESTAMP_SIZE: usize = 8;
+const SIDE_BOTH: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BinaryHeader {
+  pub version: u8,
+  pub timestamps: bool,
+  pub price_scale: u8
+}
+
+impl BinaryHeader {
+  pub fn new(timestamps: bool) -> Self {
+    BinaryHeader { version: BINARY_VERSION, timestamps, price_scale: DEFAULT_PRICE_SCALE }
+  }
+
+  pub fn encode(&self) -> [u8; HEADER_SIZE] {
+    let mut header = [0u8; HEADER_SIZE];
+    header[..MAGIC.len()].copy_from_slice(MAGIC);
+    header[5] = self.version;
+    header[6] = if self.timestamps { FLAG_TIMESTAMPS } else { 0 };
+    header[7] = self.price_scale;
+    header
+  }
+
+  pub fn decode(bytes: &[u8; HEADER_SIZE]) -> Result<Self, ParseError> {
+    if &bytes[..MAGIC.len()] != MAGIC {
+      return Err(ParseError::InvalidHeader("missing binary magic".to_string()));
+    }
+    if bytes[5] != BINARY_VERSION {
+      return Err(ParseError::InvalidHeader(format!("unsupported binary version {}", bytes[5])));
+    }
+    if bytes[7] > 9 {
+      return Err(ParseError::InvalidHeader(format!("unsupported price scale {}", bytes[7])));
+    }
+    Ok(BinaryHeader { version: bytes[5], timestamps: bytes[6] & FLAG_TIMESTAMPS != 0, price_scale: bytes[7] })
+  }
+
+  pub fn record_size(&self) -> usize {
+    RECORD_SIZE + if self.timestamps { TIMESTAMP_SIZE } else { 0 }
+  }
+
+  fn price_to_ticks(&self, id: u64, price: Decimal) -> Result<i32, String> {
+    let ticks = price * Decimal::from(10i64.pow(self.price_scale as u32));
+    if !ticks.fract().is_zero() {
+      return Err(format!("order {}: price {} is not a whole number of ticks", id, price));
+    }
+    ticks.to_i32().filter(|ticks| *ticks != NO_LIMIT_PRICE).ok_or_else(|| format!("order {}: price {} does not fit a binary record", id, price))
+  }
+
+  fn ticks_to_price(&self, ticks: i32) -> Decimal {
+    let mut price = Decimal::new(ticks as i64, self.price_scale as u32);
+    price.rescale(DEFAULT_PRICE_SCALE as u32);
+    price
+  }
+
+  // appends the record of one order, symbols are dropped
+  pub fn encode_record(&self, order_line: &OrderLine, out: &mut Vec<u8>) -> Result<(), String> {
+    let side_byte = |side: &Side| match side { Side::Bid => 0u8, Side::Ask => 1u8 };
+    let (message_type, side, id, shares, price, limit_price) = match &order_line.order {
+      FileUploadOrderType::Add { id, side, shares, price } => (1, side_byte(side), *id, *shares, Some(*price), None),
+      FileUploadOrderType::Modify { id, shares, price } => (2, 0, *id, *shares, Some(*price), None),
+      FileUploadOrderType::Cancel { id } => (3, 0, *id, 0, None, None),
+      FileUploadOrderType::Market { id, side, shares } => (4, side_byte(side), *id, *shares, None, None),
+      FileUploadOrderType::Ioc { id, side, shares, price } => (5, side_byte(side), *id, *shares, Some(*price), None),
+      FileUploadOrderType::Fok { id, side, shares, price } => (6, side_byte(side), *id, *shares, Some(*price), None),
+      FileUploadOrderType::Stop { id, side, shares, stop_price, limit_price } => (7, side_byte(side), *id, *shares, Some(*stop_price), Some(*limit_price)),
+      FileUploadOrderType::MassCancel { side } => (8, side.as_ref().map_or(SIDE_BOTH, side_byte), 0, 0, None, None)
+    };
+    let shares = u32::try_from(shares).map_err(|_| format!("order {}: {} shares do not fit a binary record", id, shares))?;
+    let price = price.map_or(Ok(0), |price| self.price_to_ticks(id, price))?;
+    let limit_price = match limit_price {
+      Some(Some(limit_price)) => self.price_to_ticks(id, limit_price)?,
+      Some(None) => NO_LIMIT_PRICE,
+      None => 0
+    };
+
+    out.push(message_type);
+    out.push(side);
+    out.extend_from_slice(&id.to_le_bytes());
+    out.extend_from_slice(&shares.to_le_bytes());
+    out.extend_from_slice(&price.to_le_bytes());
+    out.extend_from_slice(&limit_price.to_le_bytes());
+    if self.timestamps {
+      out.extend_from_slice(&order_line.timestamp.unwrap_or_default().to_le_bytes());
+    }
+    Ok(())
+  }
+
+  // `record` is exactly `record_size()` bytes long
+  pub fn decode_record(&self, record: &[u8], line: usize) -> Result<OrderLine, ParseError> {
+    let u64_at = |at: usize| u64::from_le_bytes(record[at..at + 8].try_into().expect("slice is 8 bytes long!"));
+    let u32_at = |at: usize| u32::from_le_bytes(record[at..at + 4].try_into().expect("slice is 4 bytes long!"));
+    let i32_at = |at: usize| i32::from_le_bytes(record[at..at + 4].try_into().expect("slice is 4 bytes long!"));
+
+    let side = || match record[1] {
+      0 => Ok(Side::Bid),
+      1 => Ok(Side::Ask),
+      other => Err(ParseError::InvalidBidorAsk(other.to_string()))
+    };
+    let id = u64_at(2);
+    let shares = u32_at(10) as u64;
+    let price = self.ticks_to_price(i32_at(14));
+    let limit_ticks = i32_at(18);
+
+    let order = match record[0] {
+      1 => FileUploadOrderType::Add { id, side: side()?, shares, price },
+      2 => FileUploadOrderType::Modify { id, shares, price },
+      3 => FileUploadOrderType::Cancel { id },
+      4 => FileUploadOrderType::Market { id, side: side()?, shares },
+      5 => FileUploadOrderType::Ioc { id, side: side()?, shares, price },
+      6 => FileUploadOrderType::Fok { id, side: side()?, shares, price },
+      7 => FileUploadOrderType::Stop {
+        id,
+        side: side()?,
+        shares,
+        stop_price: price,
+        limit_price: (limit_ticks != NO_LIMIT_PRICE).then(|| self.ticks_to_price(limit_ticks))
+      },
+      8 if record[1] == SIDE_BOTH => FileUploadOrderType::MassCancel { side: None },
+      8 => FileUploadOrderType::MassCancel { side: Some(side()?) },
+      other => return Err(ParseError::InvalidOrderType(other.to_string()))
+    };
+    let timestamp = self.timestamps.then(|| u64_at(RECORD_SIZE));
+    Ok(OrderLine { line, timestamp, symbol: None, order })
+  }
+}
+
+pub fn is_binary(data: &[u8]) -> bool {
+  data.starts_with(MAGIC)
+}
+
+// Parses a binary order file as it arrives in chunks, the counterpart of `StreamingOrderParser`.
+// Records are numbered from 1 in place of lines
+pub struct BinaryOrderParser {
+  header: Option<Result<BinaryHeader, String>>,
+  pending: Vec<u8>,
+  records_read: usize,
+  // a bad header counts as one invalid order, like a bad text header line
+  total_raw_orders: i32,
+  invalid_orders: i32,
+  report: ParseReport,
+  parse_time: Duration
+}
+
+impl BinaryOrderParser {
+  pub fn new() -> Self {
+    BinaryOrderParser { header: None, pending: vec![], records_read: 0, total_raw_orders: 0, invalid_orders: 0, report: ParseReport::default(), parse_time: Duration::ZERO }
+  }
+
+  pub fn feed(&mut self, chunk: &[u8]) -> Vec<OrderLine> {
+    let start = Instant::now();
+    let mut parsed_orders = vec![];
+    self.pending.extend_from_slice(chunk);
+
+    if self.header.is_none() {
+      let Some(header_bytes) = self.pending.first_chunk::<HEADER_SIZE>() else {
+        return parsed_orders;
+      };
+      let header = BinaryHeader::decode(header_bytes);
+      if let Err(e) = &header {
+        self.total_raw_orders += 1;
+        self.invalid_orders += 1;
+        self.report.record(0, e, &hex(header_bytes));
+      }
+      self.header = Some(header.map_err(|e| e.to_string()));
+      self.pending.drain(..HEADER_SIZE);
+    }
+
+    match self.header {
+      Some(Ok(header)) => {
+        let record_size = header.record_size();
+        let complete = self.pending.len() - self.pending.len() % record_size;
+        for record in self.pending[..complete].chunks_exact(record_size) {
+          self.records_read += 1;
+          self.total_raw_orders += 1;
+          match header.decode_record(record, self.records_read) {
+            Ok(order) => parsed_orders.push(order),
+            Err(e) => {
+              self.invalid_orders += 1;
+              self.report.record(self.records_read, &e, &hex(record));
+            }
+          }
+        }
+        self.pending.drain(..complete);
+      },
+      // nothing after a bad header can be read
+      _ => self.pending.clear()
+    }
+    self.parse_time += start.elapsed();
+    parsed_orders
+  }
+
+  pub fn finish(mut self) -> (Vec<OrderLine>, Duration, i32, i32, ParseReport) {
+    if !self.pending.is_empty() {
+      let e = match self.header {
+        None => ParseError::InvalidHeader("file is shorter than the binary header".to_string()),
+        Some(_) => ParseError::InvalidOrderFormat("truncated".to_string())
+      };
+      self.records_read += 1;
+      self.total_raw_orders += 1;
+      self.invalid_orders += 1;
+      self.report.record(self.records_read, &e, &hex(&self.pending));
+    }
+    (vec![], self.parse_time, self.total_raw_orders, self.invalid_orders, self.report)
+  }
+}
+
+fn hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// offline converter: `backend convert <in_file> <out_file>` writes the orders of a text (or binary) order file
+// as a binary one. Timestamps are kept if any order has one, unparsable lines are reported and left out
+pub fn run_cli(args: &[String]) -> Result<(), String> {
+  let [in_path, out_path] = args else {
+    return Err("usage: backend convert <in_file> <out_file>".to_string());
+  };
+
+  let data = fs::read(in_path).map_err(|e| format!("failed to read {}: {}", in_path, e))?;
+  let mut parser = UploadParser::new();
+  let mut orders = parser.feed(&data);
+  let (last_orders, parse_duration, total_raw_orders, invalid_orders, report) = parser.finish();
+  orders.extend(last_orders);
+  for error in &report.errors {
+    println!("[WARN] line {}: {} ({})", error.line, error.message, error.text);
+  }
+  if report.total_errors > report.errors.len() {
+    println!("[WARN] {} more invalid lines", report.total_errors - report.errors.len());
+  }
+
+  let header = BinaryHeader::new(orders.iter().any(|order| order.timestamp.is_some()));
+  let mut out = Vec::with_capacity(HEADER_SIZE + orders.len() * header.record_size());
+  out.extend_from_slice(&header.encode());
+  for order in &orders {
+    header.encode_record(order, &mut out).map_err(|e| format!("line {}: {}", order.line, e))?;
+  }
+  let dropped_symbols = orders.iter().filter(|order| order.symbol.is_some()).count();
+  if dropped_symbols > 0 {
+    println!("[WARN] binary files have no symbols, {} orders will trade the default instrument", dropped_symbols);
+  }
+  fs::write(out_path, &out).map_err(|e| format!("failed to write {}: {}", out_path, e))?;
+
+  println!("[INFO] converted {} of {} orders to {} ({} bytes, parsed in {:?})", orders.len(), total_raw_orders, out_path, out.len(), parse_duration);
+  if invalid_orders > 0 {
+    println!("[WARN] {} invalid orders were left out", invalid_orders);
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::str::FromStr;
+
+  use super::*;
+
+  fn price(p: &str) -> Decimal {
+    Decimal::from_str(p).expect("test prices should parse!")
+  }
+
+  fn orders(timestamps: bool) -> Vec<OrderLine> {
+    let orders = vec![
+      FileUploadOrderType::Add { id: 1, side: Side::Bid, shares: 100, price: price("150.25") },
+      FileUploadOrderType::Modify { id: 1, shares: 80, price: price("150.20") },
+      FileUploadOrderType::Cancel { id: 1 },
+      FileUploadOrderType::Market { id: 2, side: Side::Ask, shares: u32::MAX as u64 },
+      FileUploadOrderType::Ioc { id: 3, side: Side::Ask, shares: 10, price: price("0.01") },
+      FileUploadOrderType::Fok { id: u64::MAX, side: Side::Bid, shares: 10, price: price("-5.00") },
+      FileUploadOrderType::Stop { id: 5, side: Side::Ask, shares: 10, stop_price: price("149.00"), limit_price: Some(price("148.50")) },
+      FileUploadOrderType::Stop { id: 6, side: Side::Bid, shares: 10, stop_price: price("152.00"), limit_price: None },
+      FileUploadOrderType::MassCancel { side: Some(Side::Bid) },
+      FileUploadOrderType::MassCancel { side: Some(Side::Ask) },
+      FileUploadOrderType::MassCancel { side: None }
+    ];
+    orders.into_iter().enumerate().map(|(idx, order)| OrderLine { line: idx + 1, timestamp: timestamps.then_some(1_000 + idx as u64), symbol: None, order }).collect()
+  }
+
+  fn encode_file(header: &BinaryHeader, orders: &[OrderLine]) -> Vec<u8> {
+    let mut file = header.encode().to_vec();
+    for order in orders {
+      header.encode_record(order, &mut file).expect("test orders should encode!");
+    }
+    file
+  }
+
+  fn parse_in_chunks(file: &[u8], chunk_size: usize) -> (Vec<OrderLine>, i32, i32, ParseReport) {
+    let mut parser = BinaryOrderParser::new();
+    let mut parsed = vec![];
+    for chunk in file.chunks(chunk_size) {
+      parsed.extend(parser.feed(chunk));
+    }
+    let (last_orders, _, total_raw_orders, invalid_orders, report) = parser.finish();
+    parsed.extend(last_orders);
+    (parsed, total_raw_orders, invalid_orders, report)
+  }
+
+  fn error_kinds(report: &ParseReport) -> Vec<(usize, &str)> {
+    report.errors.iter().map(|error| (error.line, error.kind.as_str())).collect()
+  }
+
+  #[test]
+  fn records_round_trip_with_and_without_timestamps() {
+    for timestamps in [false, true] {
+      let header = BinaryHeader::new(timestamps);
+      let orders = orders(timestamps);
+      let mut out = vec![];
+      for order in &orders {
+        header.encode_record(order, &mut out).expect("test orders should encode!");
+      }
+      assert_eq!(out.len(), orders.len() * header.record_size());
+      let decoded: Vec<OrderLine> = out.chunks_exact(header.record_size()).enumerate()
+        .map(|(idx, record)| header.decode_record(record, idx + 1).expect("encoded records should decode!"))
+        .collect();
+      assert_eq!(decoded, orders);
+    }
+  }
+
+  #[test]
+  fn records_follow_the_documented_layout() {
+    let header = BinaryHeader::new(true);
+    let mut out = vec![];
+    let stop = OrderLine { line: 1, timestamp: Some(7), symbol: Some("AAPL".to_string()), order: FileUploadOrderType::Stop { id: 9, side: Side::Ask, shares: 3, stop_price: price("1.50"), limit_price: None } };
+    header.encode_record(&stop, &mut out).expect("stop should encode!");
+    assert_eq!(header.encode(), *b"OBCMD\x01\x01\x02");
+    assert_eq!(out[..2], [7, 1]);
+    assert_eq!(out[2..10], 9u64.to_le_bytes());
+    assert_eq!(out[10..14], 3u32.to_le_bytes());
+    assert_eq!(out[14..18], 150i32.to_le_bytes());
+    assert_eq!(out[18..22], NO_LIMIT_PRICE.to_le_bytes());
+    assert_eq!(out[22..30], 7u64.to_le_bytes());
+
+    // prices are read in the header's scale and rounded to the text grammar's two decimals
+    let scaled = BinaryHeader { price_scale: 4, ..BinaryHeader::new(false) };
+    let mut record = vec![1, 0];
+    record.extend_from_slice(&1u64.to_le_bytes());
+    record.extend_from_slice(&10u32.to_le_bytes());
+    record.extend_from_slice(&1_502_500i32.to_le_bytes());
+    record.extend_from_slice(&0i32.to_le_bytes());
+    let decoded = scaled.decode_record(&record, 1).expect("scaled record should decode!");
+    assert_eq!(decoded.order, FileUploadOrderType::Add { id: 1, side: Side::Bid, shares: 10, price: price("150.25") });
+  }
+
+  #[test]
+  fn orders_that_do_not_fit_a_record_are_refused() {
+    let header = BinaryHeader::new(false);
+    let unfit = [
+      FileUploadOrderType::Add { id: 1, side: Side::Bid, shares: 10, price: price("1.005") },
+      FileUploadOrderType::Add { id: 1, side: Side::Bid, shares: u32::MAX as u64 + 1, price: price("1.00") },
+      FileUploadOrderType::Ioc { id: 1, side: Side::Bid, shares: 10, price: price("30000000.00") },
+      FileUploadOrderType::Stop { id: 1, side: Side::Bid, shares: 10, stop_price: price("1.00"), limit_price: Some(price("-21474836.48")) }
+    ];
+    for order in unfit {
+      let mut out = vec![];
+      assert!(header.encode_record(&OrderLine { line: 1, timestamp: None, symbol: None, order: order.clone() }, &mut out).is_err(), "{:?} should not encode", order);
+    }
+  }
+
+  #[test]
+  fn bad_headers_and_records_are_reported() {
+    let valid = BinaryHeader::new(false).encode();
+    for (at, byte) in [(0, b'X'), (5, 2), (7, 10)] {
+      let mut header = valid;
+      header[at] = byte;
+      assert!(matches!(BinaryHeader::decode(&header), Err(ParseError::InvalidHeader(_))));
+
+      // nothing after a bad header is read
+      let mut file = header.to_vec();
+      BinaryHeader::new(false).encode_record(&orders(false)[0], &mut file).expect("test orders should encode!");
+      let (parsed, total_raw_orders, invalid_orders, report) = parse_in_chunks(&file, file.len());
+      assert!(parsed.is_empty());
+      assert_eq!((total_raw_orders, invalid_orders), (1, 1));
+      assert_eq!(error_kinds(&report), vec![(0, "invalid_header")]);
+    }
+
+    let (_, _, _, report) = parse_in_chunks(b"OBCMD", 5);
+    assert_eq!(error_kinds(&report), vec![(1, "invalid_header")]);
+
+    let header = BinaryHeader::new(false);
+    let mut file = encode_file(&header, &orders(false)[..4]);
+    file.drain(HEADER_SIZE + 2 * RECORD_SIZE..HEADER_SIZE + 3 * RECORD_SIZE);
+    file[HEADER_SIZE] = 9;
+    // MODIFY has no side, so its side byte is ignored
+    file[HEADER_SIZE + RECORD_SIZE + 1] = 5;
+    file[HEADER_SIZE + 2 * RECORD_SIZE + 1] = 5;
+    file.push(1);
+    let (parsed, total_raw_orders, invalid_orders, report) = parse_in_chunks(&file, file.len());
+    assert_eq!(parsed, vec![orders(false)[1].clone()]);
+    assert_eq!((total_raw_orders, invalid_orders), (4, 3));
+    assert_eq!(error_kinds(&report), vec![(1, "invalid_order_type"), (3, "invalid_side"), (4, "invalid_order_format")]);
+  }
+
+  #[test]
+  fn records_split_across_chunks_parse_like_the_whole_file() {
+    for timestamps in [false, true] {
+      let file = encode_file(&BinaryHeader::new(timestamps), &orders(timestamps));
+      let whole = parse_in_chunks(&file, file.len());
+      assert_eq!(whole.0, orders(timestamps));
+      assert_eq!((whole.1, whole.2), (11, 0));
+      for chunk_size in [1, 3, 7, RECORD_SIZE + 1, 64] {
+        assert_eq!(parse_in_chunks(&file, chunk_size), whole, "chunks of {} bytes", chunk_size);
+      }
+    }
+  }
+}
@@ -0,0 +1,332 @@
+use std::{collections::HashMap, sync::Arc};
+use rust_decimal::Decimal;
+use protocol::{upload::DownloadSummary, ws::ExecutedOrders};
+use serde::{Deserialize, Serialize};
+
+use crate::engine::orderbook::{Arena, BidOrAsk};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DownloadFormat {
+  #[default]
+  Csv,
+  // one json object per line
+  Jsonl
+}
+
+impl DownloadFormat {
+  pub fn content_type(&self) -> &'static str {
+    match self {
+      Self::Csv => "text/csv",
+      Self::Jsonl => "application/x-ndjson"
+    }
+  }
+
+  pub fn extension(&self) -> &'static str {
+    match self {
+      Self::Csv => "csv",
+      Self::Jsonl => "jsonl"
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BookDepth {
+  // volume and order count per price level
+  L2,
+  // every resting order in time priority
+  #[default]
+  L3
+}
+
+impl BookDepth {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Self::L2 => "l2",
+      Self::L3 => "l3"
+    }
+  }
+}
+
+// rows kept per download, the trade tape and the book each stop growing past it
+pub const MAX_DOWNLOAD_ROWS: usize = 200_000;
+// rows rendered into each piece of a streamed download
+const RENDER_BATCH_ROWS: usize = 1_000;
+
+// a row of a download, symbols cannot hold commas so csv fields need no quoting
+trait DownloadRow: Serialize {
+  const CSV_HEADER: &'static str;
+
+  fn csv_line(&self) -> String;
+}
+
+#[derive(Debug, Serialize)]
+pub struct TradeRecord<'a> {
+  // 1-based position of the trade across every symbol of the upload
+  pub seq: u64,
+  // line of the order that caused the trade
+  pub line: usize,
+  pub symbol: &'a str,
+  pub aggressive_order_id: u64,
+  pub passive_order_id: u64,
+  pub price: Decimal,
+  pub volume: u64
+}
+
+impl DownloadRow for TradeRecord<'_> {
+  const CSV_HEADER: &'static str = "seq,line,symbol,aggressive_order_id,passive_order_id,price,volume";
+
+  fn csv_line(&self) -> String {
+    format!("{},{},{},{},{},{},{}", self.seq, self.line, self.symbol, self.aggressive_order_id, self.passive_order_id, self.price, self.volume)
+  }
+}
+
+#[derive(Debug, Serialize)]
+pub struct RestingOrder<'a> {
+  pub symbol: &'a str,
+  pub side: &'static str,
+  pub price: Decimal,
+  // 1-based place in the time priority queue of the level
+  pub queue_position: usize,
+  pub order_id: u64,
+  pub shares: u64
+}
+
+impl DownloadRow for RestingOrder<'_> {
+  const CSV_HEADER: &'static str = "symbol,side,price,queue_position,order_id,shares";
+
+  fn csv_line(&self) -> String {
+    format!("{},{},{},{},{},{}", self.symbol, self.side, self.price, self.queue_position, self.order_id, self.shares)
+  }
+}
+
+#[derive(Debug, Serialize)]
+pub struct BookLevel<'a> {
+  pub symbol: &'a str,
+  pub side: &'static str,
+  pub price: Decimal,
+  pub volume: u64,
+  pub orders: usize
+}
+
+impl DownloadRow for BookLevel<'_> {
+  const CSV_HEADER: &'static str = "symbol,side,price,volume,orders";
+
+  fn csv_line(&self) -> String {
+    format!("{},{},{},{},{}", self.symbol, self.side, self.price, self.volume, self.orders)
+  }
+}
+
+// rows are kept without their symbol name, `symbol` indexes the interned names of the upload
+struct StoredTrade {
+  line: usize,
+  symbol: u32,
+  aggressive_order_id: u64,
+  passive_order_id: u64,
+  price: Decimal,
+  volume: u64
+}
+
+struct StoredOrder {
+  symbol: u32,
+  side: &'static str,
+  price: Decimal,
+  queue_position: usize,
+  order_id: u64,
+  shares: u64
+}
+
+struct StoredLevel {
+  symbol: u32,
+  side: &'static str,
+  price: Decimal,
+  volume: u64,
+  orders: usize
+}
+
+fn side_name(side: &BidOrAsk) -> &'static str {
+  match side {
+    BidOrAsk::Bid => "BID",
+    BidOrAsk::Ask => "ASK"
+  }
+}
+
+// The trade tape and final book of an upload, recorded only when the upload asked for them.
+// Each keeps at most `MAX_DOWNLOAD_ROWS` rows, the summary tells the client when some were left out
+#[derive(Default)]
+pub struct UploadDownloads {
+  symbols: Vec<String>,
+  symbol_ids: HashMap<String, u32>,
+  trades: Vec<StoredTrade>,
+  // final L3 book, symbols in name order with bids before asks
+  book: Vec<StoredOrder>,
+  truncated: bool
+}
+
+impl UploadDownloads {
+  fn symbol_id(&mut self, symbol: &str) -> u32 {
+    if let Some(id) = self.symbol_ids.get(symbol) {
+      return *id;
+    }
+    let id = self.symbols.len() as u32;
+    self.symbols.push(symbol.to_string());
+    self.symbol_ids.insert(symbol.to_string(), id);
+    id
+  }
+
+  pub fn record_trades(&mut self, line: usize, symbol: &str, trades: &[ExecutedOrders]) {
+    if trades.is_empty() {
+      return;
+    }
+    let symbol = self.symbol_id(symbol);
+    for trade in trades {
+      if self.trades.len() >= MAX_DOWNLOAD_ROWS {
+        self.truncated = true;
+        return;
+      }
+      self.trades.push(StoredTrade {
+        line,
+        symbol,
+        aggressive_order_id: trade.aggresive_order_id,
+        passive_order_id: trade.passive_order_id,
+        price: trade.price,
+        volume: trade.volume
+      });
+    }
+  }
+
+  pub fn record_book(&mut self, symbol: &str, book: &Arena) {
+    let symbol = self.symbol_id(symbol);
+    for side in [BidOrAsk::Bid, BidOrAsk::Ask] {
+      for (price, orders) in book.resting_levels(&side) {
+        for (idx, (order_id, shares)) in orders.into_iter().enumerate() {
+          if self.book.len() >= MAX_DOWNLOAD_ROWS {
+            self.truncated = true;
+            return;
+          }
+          self.book.push(StoredOrder { symbol, side: side_name(&side), price, queue_position: idx + 1, order_id, shares });
+        }
+      }
+    }
+  }
+
+  pub fn summary(&self) -> DownloadSummary {
+    DownloadSummary { trades: self.trades.len(), resting_orders: self.book.len(), truncated: self.truncated }
+  }
+
+  // the trade tape, rendered a batch of rows at a time as the body is sent
+  pub fn trades(self: Arc<Self>, format: DownloadFormat) -> impl Iterator<Item = String> + Send + 'static {
+    render(self.trades.len(), format, TradeRecord::CSV_HEADER, move |idx| {
+      let trade = &self.trades[idx];
+      line(&TradeRecord {
+        seq: idx as u64 + 1,
+        line: trade.line,
+        symbol: &self.symbols[trade.symbol as usize],
+        aggressive_order_id: trade.aggressive_order_id,
+        passive_order_id: trade.passive_order_id,
+        price: trade.price,
+        volume: trade.volume
+      }, format)
+    })
+  }
+
+  pub fn book(self: Arc<Self>, depth: BookDepth, format: DownloadFormat) -> Box<dyn Iterator<Item = String> + Send> {
+    match depth {
+      BookDepth::L3 => Box::new(render(self.book.len(), format, RestingOrder::CSV_HEADER, move |idx| {
+        let order = &self.book[idx];
+        line(&RestingOrder {
+          symbol: &self.symbols[order.symbol as usize],
+          side: order.side,
+          price: order.price,
+          queue_position: order.queue_position,
+          order_id: order.order_id,
+          shares: order.shares
+        }, format)
+      })),
+      BookDepth::L2 => {
+        let levels = self.book_levels();
+        Box::new(render(levels.len(), format, BookLevel::CSV_HEADER, move |idx| {
+          let level = &levels[idx];
+          line(&BookLevel { symbol: &self.symbols[level.symbol as usize], side: level.side, price: level.price, volume: level.volume, orders: level.orders }, format)
+        }))
+      }
+    }
+  }
+
+  // the L3 book folded into its price levels, orders of a level are next to each other
+  fn book_levels(&self) -> Vec<StoredLevel> {
+    let mut levels: Vec<StoredLevel> = Vec::new();
+    for order in &self.book {
+      match levels.last_mut() {
+        Some(level) if level.symbol == order.symbol && level.side == order.side && level.price == order.price => {
+          level.volume += order.shares;
+          level.orders += 1;
+        },
+        _ => levels.push(StoredLevel { symbol: order.symbol, side: order.side, price: order.price, volume: order.shares, orders: 1 })
+      }
+    }
+    levels
+  }
+}
+
+fn line<T: DownloadRow>(row: &T, format: DownloadFormat) -> String {
+  match format {
+    DownloadFormat::Csv => row.csv_line(),
+    DownloadFormat::Jsonl => serde_json::to_string(row).expect("download rows should serialize!")
+  }
+}
+
+// the csv header then `rows` rows made by `row`, each batch of them joined into one piece
+fn render<F>(rows: usize, format: DownloadFormat, csv_header: &'static str, row: F) -> impl Iterator<Item = String> + Send + 'static
+where
+  F: Fn(usize) -> String + Send + 'static
+{
+  let header = (format == DownloadFormat::Csv).then(|| format!("{}\n", csv_header));
+  let batches = (0..rows).step_by(RENDER_BATCH_ROWS).map(move |start| {
+    (start..(start + RENDER_BATCH_ROWS).min(rows)).fold(String::new(), |mut out, idx| {
+      out.push_str(&row(idx));
+      out.push('\n');
+      out
+    })
+  });
+  header.into_iter().chain(batches)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn trade(id: u64) -> ExecutedOrders {
+    ExecutedOrders { price: Decimal::new(10_050, 2), volume: 5, aggresive_order_id: id, passive_order_id: id + 1 }
+  }
+
+  #[test]
+  fn trades_are_streamed_in_batches_and_capped() {
+    let mut downloads = UploadDownloads::default();
+    let trades: Vec<_> = (0..RENDER_BATCH_ROWS as u64 + 1).map(trade).collect();
+    downloads.record_trades(3, "AAPL", &trades);
+    downloads.record_trades(4, "MSFT", &[trade(7)]);
+    let downloads = Arc::new(downloads);
+    assert_eq!(downloads.summary(), DownloadSummary { trades: RENDER_BATCH_ROWS + 2, resting_orders: 0, truncated: false });
+
+    let pieces: Vec<String> = downloads.clone().trades(DownloadFormat::Csv).collect();
+    // the header then two batches
+    assert_eq!(pieces.len(), 3);
+    assert_eq!(pieces[0], format!("{}\n", TradeRecord::CSV_HEADER));
+    let csv = pieces.concat();
+    assert_eq!(csv.lines().count(), RENDER_BATCH_ROWS + 3);
+    assert_eq!(csv.lines().nth(1), Some("1,3,AAPL,0,1,100.50,5"));
+    assert_eq!(csv.lines().last(), Some(format!("{},4,MSFT,7,8,100.50,5", RENDER_BATCH_ROWS + 2).as_str()));
+
+    let jsonl: String = downloads.trades(DownloadFormat::Jsonl).collect();
+    let first: serde_json::Value = serde_json::from_str(jsonl.lines().next().expect("jsonl should have rows!")).expect("rows should be json!");
+    assert_eq!(first["symbol"], "AAPL");
+    assert_eq!(jsonl.lines().count(), RENDER_BATCH_ROWS + 2);
+
+    let mut capped = UploadDownloads::default();
+    let many: Vec<_> = (0..MAX_DOWNLOAD_ROWS as u64 + 10).map(trade).collect();
+    capped.record_trades(1, "AAPL", &many);
+    assert_eq!(capped.summary(), DownloadSummary { trades: MAX_DOWNLOAD_ROWS, resting_orders: 0, truncated: true });
+  }
+}
@@ -0,0 +1,210 @@
+use std::{collections::HashMap, sync::{Arc, Mutex}, time::{Duration, Instant}};
+use protocol::upload::{JobState, JobStatus};
+use serde::Serialize;
+use tokio::sync::Semaphore;
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
+
+use super::{downloads::UploadDownloads, processor::{LargeUploadResponse, SmallUploadResponse}};
+
+// orders processed between two progress updates and cancellation checks
+pub const PROGRESS_INTERVAL: usize = 10_000;
+// finished jobs are kept this long for the client to fetch their results
+const FINISHED_JOB_RETENTION: Duration = Duration::from_secs(60 * 10);
+// jobs kept per ip, the oldest finished one makes room for a new job
+const MAX_JOBS_PER_OWNER: usize = 8;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum UploadJobResult {
+  Small(SmallUploadResponse),
+  Large(LargeUploadResponse)
+}
+
+struct JobProgress {
+  state: JobState,
+  result: Option<UploadJobResult>,
+  downloads: Option<Arc<UploadDownloads>>,
+  finished_at: Option<Instant>
+}
+
+// the processing of a completed upload, running after its last chunk was answered
+pub struct UploadJob {
+  id: String,
+  // ip of the uploader, the only one allowed to see or cancel the job
+  owner: String,
+  progress: Mutex<JobProgress>,
+  cancel: CancellationToken
+}
+
+impl UploadJob {
+  fn new(owner: &str) -> Self {
+    UploadJob {
+      id: Uuid::new_v4().to_string(),
+      owner: owner.to_string(),
+      progress: Mutex::new(JobProgress { state: JobState::Queued, result: None, downloads: None, finished_at: None }),
+      cancel: CancellationToken::new()
+    }
+  }
+
+  pub fn id(&self) -> &str {
+    &self.id
+  }
+
+  pub fn status(&self) -> JobStatus<UploadJobResult> {
+    let progress = self.progress.lock().expect("upload job lock poisoned!");
+    JobStatus { job_id: self.id.clone(), state: progress.state.clone(), result: progress.result.clone() }
+  }
+
+  // the trade tape and final book of a completed upload that asked for them
+  pub fn downloads(&self) -> Option<Arc<UploadDownloads>> {
+    self.progress.lock().expect("upload job lock poisoned!").downloads.clone()
+  }
+
+  pub fn is_cancelled(&self) -> bool {
+    self.cancel.is_cancelled()
+  }
+
+  // a finished job keeps its final state
+  fn update(&self, state: JobState, result: Option<UploadJobResult>, downloads: Option<UploadDownloads>) {
+    let mut progress = self.progress.lock().expect("upload job lock poisoned!");
+    if progress.state.is_finished() {
+      return;
+    }
+    if state.is_finished() {
+      progress.finished_at = Some(Instant::now());
+    }
+    progress.state = state;
+    progress.result = result;
+    progress.downloads = downloads.map(Arc::new);
+  }
+
+  fn finished_at(&self) -> Option<Instant> {
+    self.progress.lock().expect("upload job lock poisoned!").finished_at
+  }
+
+  pub fn parsing(&self) {
+    self.update(JobState::Parsing, None, None);
+  }
+
+  pub fn processing(&self, done: usize, total: usize) {
+    let percent_done = if total == 0 { 100.0 } else { done as f64 * 100.0 / total as f64 };
+    self.update(JobState::Processing { percent_done }, None, None);
+  }
+
+  pub fn complete(&self, result: UploadJobResult, downloads: Option<UploadDownloads>) {
+    self.update(JobState::Completed, Some(result), downloads);
+  }
+
+  pub fn fail(&self, error: &str) {
+    self.update(JobState::Failed { error: error.to_string() }, None, None);
+  }
+
+  pub fn cancel(&self) {
+    self.update(JobState::Cancelled, None, None);
+    self.cancel.cancel();
+  }
+}
+
+fn prune_finished(jobs: &mut HashMap<String, Arc<UploadJob>>) {
+  if let Some(cutoff) = Instant::now().checked_sub(FINISHED_JOB_RETENTION) {
+    jobs.retain(|_, job| job.finished_at().is_none_or(|finished_at| finished_at >= cutoff));
+  }
+}
+
+// Upload jobs by id. Their processing runs on the blocking thread pool, at most `max_workers` at once,
+// so long uploads neither stall the async executor nor hold the final chunk request open
+#[derive(Clone)]
+pub struct UploadJobs {
+  jobs: Arc<Mutex<HashMap<String, Arc<UploadJob>>>>,
+  workers: Arc<Semaphore>
+}
+
+impl UploadJobs {
+  pub fn new(max_workers: usize) -> Self {
+    Self { jobs: Arc::new(Mutex::new(HashMap::new())), workers: Arc::new(Semaphore::new(max_workers.max(1))) }
+  }
+
+  // A queued job for `owner`, jobs finished long enough ago are dropped on the way.
+  // An owner at `MAX_JOBS_PER_OWNER` loses its oldest finished job, or gets an error when none has finished
+  pub fn create(&self, owner: &str) -> Result<Arc<UploadJob>, String> {
+    let mut jobs = self.jobs.lock().expect("upload jobs lock poisoned!");
+    prune_finished(&mut jobs);
+    let owned = jobs.values().filter(|job| job.owner == owner).count();
+    if owned >= MAX_JOBS_PER_OWNER {
+      let oldest_finished = jobs.values()
+        .filter(|job| job.owner == owner)
+        .filter_map(|job| job.finished_at().map(|finished_at| (finished_at, job.id.clone())))
+        .min()
+        .ok_or_else(|| format!("{} uploads are still being processed, wait for one to finish", owned))?;
+      jobs.remove(&oldest_finished.1);
+    }
+    let job = Arc::new(UploadJob::new(owner));
+    jobs.insert(job.id.clone(), job.clone());
+    Ok(job)
+  }
+
+  // drops the jobs finished long enough ago, run by the session sweep
+  pub fn expire(&self) {
+    prune_finished(&mut self.jobs.lock().expect("upload jobs lock poisoned!"));
+  }
+
+  // the job with `job_id` if it belongs to `owner`
+  pub fn get(&self, job_id: &str, owner: &str) -> Option<Arc<UploadJob>> {
+    let jobs = self.jobs.lock().expect("upload jobs lock poisoned!");
+    jobs.get(job_id).filter(|job| job.owner == owner).cloned()
+  }
+
+  // Runs `work` on a worker once one is free. None if the job got cancelled before it finished or the work panicked,
+  // `work` itself is expected to check `is_cancelled` every now and then and give up with None
+  pub async fn run<T, F>(&self, job: Arc<UploadJob>, work: F) -> Option<T>
+  where
+    T: Send + 'static,
+    F: FnOnce(&UploadJob) -> Option<T> + Send + 'static
+  {
+    let permit = tokio::select! {
+      permit = self.workers.clone().acquire_owned() => permit.expect("upload worker pool should never close!"),
+      _ = job.cancel.cancelled() => return None
+    };
+    let worker_job = job.clone();
+    let outcome = tokio::task::spawn_blocking(move || {
+      let _permit = permit;
+      work(&worker_job)
+    }).await;
+
+    match outcome {
+      Ok(result) => result.filter(|_| !job.is_cancelled()),
+      Err(e) => {
+        println!("[ERROR] upload job {} failed: {:?}", job.id, e);
+        job.fail("upload processing failed");
+        None
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn an_owner_keeps_a_bounded_number_of_jobs() {
+    let jobs = UploadJobs::new(1);
+    let first = jobs.create("1.1.1.1").expect("a first job should be created!");
+    let others: Vec<_> = (1..MAX_JOBS_PER_OWNER).map(|_| jobs.create("1.1.1.1").expect("jobs under the limit should be created!")).collect();
+
+    // every job is still running
+    assert!(jobs.create("1.1.1.1").is_err());
+    // other owners are not affected
+    assert!(jobs.create("2.2.2.2").is_ok());
+
+    // the oldest finished job makes room
+    others[2].fail("failed");
+    std::thread::sleep(Duration::from_millis(5));
+    others[0].cancel();
+    jobs.create("1.1.1.1").expect("a finished job should make room!");
+    assert!(jobs.get(others[2].id(), "1.1.1.1").is_none());
+    assert!(jobs.get(others[0].id(), "1.1.1.1").is_some());
+    assert!(jobs.get(first.id(), "1.1.1.1").is_some());
+  }
+}
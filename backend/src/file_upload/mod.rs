@@ -1,2 +1,5 @@
+pub mod binary;
+pub mod downloads;
+pub mod jobs;
 pub mod processor;
 pub mod parser;
\ No newline at end of file
@@ -1,47 +1,9 @@
-use std::{fmt, str::FromStr, time::{Duration, Instant}};
-use rust_decimal::Decimal;
+use std::{fmt, time::{Duration, Instant}};
 
 use crate::engine::orderbook::BidOrAsk;
-use super::processor::FileUploadOrderType;
-
-#[derive(Debug)]
-pub enum ParseError {
-  InvalidBidorAsk(String),
-  InvalidOrderType(String),
-  InvalidOrderFormat(String),
-  InvalidOrderId(std::num::ParseIntError),
-  InvalidShares(std::num::ParseIntError),
-  InvalidPrice(rust_decimal::Error),
-  Empty
-}
+use protocol::grammar::{OrderFileParser, OrderLine, ParseError, ParseReport, Side};
 
-impl fmt::Display for ParseError {
-  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-    match self {
-      Self::InvalidBidorAsk(bid_or_ask) => {
-        write!(f, "Invalid bid/ask string: {}", bid_or_ask)
-      },
-      Self::InvalidOrderType(order_type) => {
-        write!(f, "Invalid order type string: {}", order_type)
-      },
-      Self::InvalidOrderFormat(order) => {
-        write!(f, "Invalid {} order format", order)
-      },
-      Self::InvalidOrderId(err) => {
-        write!(f, "Faled to parse Order ID: {:?}", err)
-      },
-      Self::InvalidShares(err) => {
-        write!(f, "Faled to parse shares: {:?}", err)
-      },
-      Self::InvalidPrice(err) => {
-        write!(f, "Faled to parse price: {:?}", err)
-      }
-      Self::Empty => {
-        write!(f, "Empty order line in file")
-      }
-    }
-  }
-}
+use super::binary::{is_binary, BinaryOrderParser, MAGIC};
 
 impl fmt::Display for BidOrAsk {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -52,142 +14,229 @@ impl fmt::Display for BidOrAsk {
   }
 }
 
-impl FromStr for BidOrAsk {
-  type Err = ParseError;
-  fn from_str(s: &str) -> Result<Self, Self::Err> {
-    match s.to_lowercase().as_str() {
-      "bid" => Ok(BidOrAsk::Bid),
-      "ask" => Ok(BidOrAsk::Ask),
-      _ => Err(ParseError::InvalidBidorAsk(s.to_string())) 
+impl From<&BidOrAsk> for Side {
+  fn from(side: &BidOrAsk) -> Self {
+    match side {
+      BidOrAsk::Bid => Side::Bid,
+      BidOrAsk::Ask => Side::Ask
     }
   }
 }
 
-impl From<std::num::ParseIntError> for ParseError {
-  fn from(value: std::num::ParseIntError) -> Self {
-    ParseError::InvalidOrderId(value)
-  }
-}
-
-impl From<rust_decimal::Error> for ParseError {
-  fn from(value: rust_decimal::Error) -> Self {
-    ParseError::InvalidPrice(value)
+impl From<Side> for BidOrAsk {
+  fn from(side: Side) -> Self {
+    match side {
+      Side::Bid => BidOrAsk::Bid,
+      Side::Ask => BidOrAsk::Ask
+    }
   }
 }
 
-pub struct FileUploadOrder {
-  order: FileUploadOrderType
+// longest order line accepted, longer ones are reported as invalid without buffering them
+const MAX_LINE_BYTES: usize = 4 * 1024;
+
+// Parses an order file as it arrives in chunks of any size. The bytes after the last newline of a chunk
+// are held back until the next chunk completes the line, at most `MAX_LINE_BYTES + 1` of them.
+// Blank lines, comments and the header are not counted as raw orders, every failing line ends up in the report
+pub struct StreamingOrderParser {
+  parser: OrderFileParser,
+  partial_line: Vec<u8>,
+  total_raw_orders: i32,
+  invalid_orders: i32,
+  report: ParseReport,
+  parse_time: Duration
 }
 
-impl FileUploadOrder {
-  fn parse(line: &str) -> Result<Self, ParseError> {
-    let parts: Vec<&str> = line.split(|c| c == ',').map(|s| s.trim()).collect();
+impl StreamingOrderParser {
+  pub fn new() -> Self {
+    StreamingOrderParser {
+      parser: OrderFileParser::new(),
+      partial_line: vec![],
+      total_raw_orders: 0,
+      invalid_orders: 0,
+      report: ParseReport::default(),
+      parse_time: Duration::ZERO
+    }
+  }
 
-    let order_type = match parts.get(0).map(|s| s.to_uppercase()) {
-      Some(s) => s,
-      None => return Err(ParseError::Empty)
+  // orders of the lines completed by this chunk
+  pub fn feed(&mut self, chunk: &[u8]) -> Vec<OrderLine> {
+    let start = Instant::now();
+    let mut parsed_orders = vec![];
+    let Some(last_newline) = chunk.iter().rposition(|&byte| byte == b'\n') else {
+      self.hold(chunk);
+      return parsed_orders;
     };
 
-    let order = match order_type.as_str() {
-      "ADD" => {
-        if parts.len() != 5 {
-          return Err(ParseError::InvalidOrderFormat("ADD".to_string()));
-        }
-        let id = parts[1].parse().map_err(|err| ParseError::InvalidOrderId(err))?;
-        let side = BidOrAsk::from_str(parts[2])?;
-        let shares = parts[3].parse().map_err(|err| ParseError::InvalidShares(err))?;
-        let mut price =  Decimal::from_str(parts[4])?; 
-        price.rescale(2);
-
-        FileUploadOrderType::Add { 
-          id,
-          side,
-          shares,
-          price
-        }
-      },
-      "MODIFY" => {
-        if parts.len() != 4 {
-          return Err(ParseError::InvalidOrderFormat("MODIFY".to_string()));
-        }
-        let id = parts[1].parse().map_err(|err| ParseError::InvalidOrderId(err))?;
-        let shares = parts[2].parse().map_err(|err| ParseError::InvalidShares(err))?;
-        let mut price =  Decimal::from_str(parts[3])?;
-        price.rescale(2); 
-
-        FileUploadOrderType::Modify { 
-          id,
-          shares,
-          price
-        }
-      },
-      "CANCEL" => {
-        if parts.len() != 2 {
-          return Err(ParseError::InvalidOrderFormat("CANCEL".to_string()));
-        }
-        let id = parts[1].parse().map_err(|err| ParseError::InvalidOrderId(err))?;
-        FileUploadOrderType::Cancel { id }
-      },
-      _ => return Err(ParseError::InvalidOrderType(order_type)),
-    };
-    Ok(FileUploadOrder {order})
+    let (complete, rest) = chunk.split_at(last_newline + 1);
+    let mut lines = complete[..complete.len() - 1].split(|&byte| byte == b'\n');
+    if !self.partial_line.is_empty() {
+      self.hold(lines.next().unwrap_or_default());
+      let first_line = std::mem::take(&mut self.partial_line);
+      self.parse_raw_line(&first_line, &mut parsed_orders);
+    }
+    for raw_line in lines {
+      self.parse_raw_line(raw_line, &mut parsed_orders);
+    }
+    self.hold(rest);
+    self.parse_time += start.elapsed();
+    parsed_orders
   }
-}
 
-pub fn parse_file_orders (data: &[u8]) -> (Vec<FileUploadOrderType>, Duration, i32, i32) {
-  let mut total_raw_orders = 0;
-  let mut invalid_orders = 0;
-  let mut parsed_orders: Vec<FileUploadOrderType> = vec![];
-
-  let start = Instant::now();
-  
-  let mut start_pos = 0;
-  for (i, &byte) in data.iter().enumerate() {
-    if byte == b'\n' {
-      if i > start_pos {
-        total_raw_orders += 1;
-
-        match std::str::from_utf8(&data[start_pos..i]) {
-          Ok(line) => {
-            match FileUploadOrder::parse(line) {
-              Ok(parsed_order) => {
-                parsed_orders.push(parsed_order.order);
-              },
-              Err(_e) => {
-                invalid_orders += 1;
-              }
-            }
-          },
-          Err(_e) => {
-            invalid_orders += 1;
-          }
-        }
-      }
-      start_pos = i + 1;
-    }
+  // parses the last line, which has no newline after it
+  pub fn finish(mut self) -> (Vec<OrderLine>, Duration, i32, i32, ParseReport) {
+    let start = Instant::now();
+    let mut parsed_orders = vec![];
+    let last_line = std::mem::take(&mut self.partial_line);
+    self.parse_raw_line(&last_line, &mut parsed_orders);
+    self.parse_time += start.elapsed();
+    (parsed_orders, self.parse_time, self.total_raw_orders, self.invalid_orders, self.report)
   }
 
-  // handle last line if there's no trailing newline
-  if start_pos < data.len() {
-    total_raw_orders += 1;
-    match std::str::from_utf8(&data[start_pos..]) {
+  // keeps one byte past the limit, enough to tell an oversized line from one right at it
+  fn hold(&mut self, bytes: &[u8]) {
+    let room = (MAX_LINE_BYTES + 1).saturating_sub(self.partial_line.len());
+    self.partial_line.extend_from_slice(&bytes[..bytes.len().min(room)]);
+  }
+
+  fn parse_raw_line(&mut self, raw_line: &[u8], parsed_orders: &mut Vec<OrderLine>) {
+    if raw_line.len() > MAX_LINE_BYTES {
+      self.parser.skip_line();
+      self.total_raw_orders += 1;
+      self.invalid_orders += 1;
+      self.report.record(self.parser.lines_read(), &ParseError::InvalidOrderFormat("oversized".to_string()), &String::from_utf8_lossy(&raw_line[..MAX_LINE_BYTES]));
+      return;
+    }
+    match std::str::from_utf8(raw_line) {
       Ok(line) => {
-        match FileUploadOrder::parse(line) {
-          Ok(parsed_order) => {
-            parsed_orders.push(parsed_order.order);
+        match self.parser.parse_line(line) {
+          Ok(Some(parsed_order)) => {
+            self.total_raw_orders += 1;
+            parsed_orders.push(parsed_order);
           },
-          Err(_e) => {
-            invalid_orders += 1;
+          Ok(None) => {},
+          Err(e) => {
+            self.total_raw_orders += 1;
+            self.invalid_orders += 1;
+            self.report.record(self.parser.lines_read(), &e, line.trim_end());
           }
         }
       },
       Err(_e) => {
-        invalid_orders += 1;
+        // the grammar parser never sees this line, so it is numbered here
+        self.parser.skip_line();
+        self.total_raw_orders += 1;
+        self.invalid_orders += 1;
+        self.report.record(self.parser.lines_read(), &ParseError::InvalidEncoding, &String::from_utf8_lossy(raw_line));
+      }
+    }
+  }
+}
+
+// picks the text or the binary parser from the first bytes of an upload
+pub enum UploadParser {
+  // fewer bytes than the binary magic so far, all of them matching it
+  Detecting(Vec<u8>),
+  Text(StreamingOrderParser),
+  Binary(BinaryOrderParser)
+}
+
+impl UploadParser {
+  pub fn new() -> Self {
+    UploadParser::Detecting(vec![])
+  }
+
+  pub fn feed(&mut self, chunk: &[u8]) -> Vec<OrderLine> {
+    match self {
+      UploadParser::Text(parser) => parser.feed(chunk),
+      UploadParser::Binary(parser) => parser.feed(chunk),
+      UploadParser::Detecting(head) => {
+        head.extend_from_slice(chunk);
+        let seen = head.len().min(MAGIC.len());
+        if head[..seen] == MAGIC[..seen] && seen < MAGIC.len() {
+          return vec![];
+        }
+        let head = std::mem::take(head);
+        *self = if is_binary(&head) { UploadParser::Binary(BinaryOrderParser::new()) } else { UploadParser::Text(StreamingOrderParser::new()) };
+        self.feed(&head)
+      }
+    }
+  }
+
+  pub fn finish(self) -> (Vec<OrderLine>, Duration, i32, i32, ParseReport) {
+    match self {
+      UploadParser::Text(parser) => parser.finish(),
+      UploadParser::Binary(parser) => parser.finish(),
+      // too short to be binary
+      UploadParser::Detecting(head) => {
+        let mut parser = StreamingOrderParser::new();
+        let mut parsed_orders = parser.feed(&head);
+        let (last_orders, parse_duration, total_raw_orders, invalid_orders, report) = parser.finish();
+        parsed_orders.extend(last_orders);
+        (parsed_orders, parse_duration, total_raw_orders, invalid_orders, report)
       }
     }
   }
+}
 
-  let parse_duration = start.elapsed();
-  (parsed_orders, parse_duration, total_raw_orders, invalid_orders)
+#[cfg(test)]
+mod tests {
+  use rand::{rngs::StdRng, Rng, SeedableRng};
 
-}
\ No newline at end of file
+  use super::*;
+
+  // header, comments, CRLF endings, blank lines, multi-byte characters, invalid UTF-8, an oversized line
+  // and a last line without a newline
+  fn order_file() -> Vec<u8> {
+    let mut file = b"VERSION,2,TIMESTAMP,SYMBOL\r\n# caf\xc3\xa9 orders \xe2\x82\xac\n1000,AAPL,ADD,1,BID,100,150.25\r\n\r\n".to_vec();
+    file.extend_from_slice(b"1001,AAPL,ADD,2,ASK,50,150.30 # \xc3\xa9\n1002,AAPL,BOGUS,3\n1003,\xff\xfe,CANCEL,1\n1004,AAPL,CANCEL,");
+    file.extend(std::iter::repeat_n(b'9', 2 * MAX_LINE_BYTES));
+    file.extend_from_slice(b"\n\n1005,AAPL,MODIFY,2,40,150.35\r\n1006,AAPL,MASSCANCEL");
+    file
+  }
+
+  fn parse_in_chunks(file: &[u8], chunk_sizes: impl Iterator<Item = usize>) -> (Vec<OrderLine>, i32, i32, ParseReport) {
+    let mut parser = StreamingOrderParser::new();
+    let mut orders = vec![];
+    let mut rest = file;
+    for size in chunk_sizes {
+      if rest.is_empty() {
+        break;
+      }
+      let (chunk, tail) = rest.split_at(size.clamp(1, rest.len()));
+      orders.extend(parser.feed(chunk));
+      assert!(parser.partial_line.len() <= MAX_LINE_BYTES + 1);
+      rest = tail;
+    }
+    let (last_orders, _, total_raw_orders, invalid_orders, report) = parser.finish();
+    orders.extend(last_orders);
+    (orders, total_raw_orders, invalid_orders, report)
+  }
+
+  #[test]
+  fn chunking_does_not_change_the_result() {
+    let file = order_file();
+    let whole = parse_in_chunks(&file, std::iter::once(file.len()));
+    let (orders, total_raw_orders, invalid_orders, report) = &whole;
+    assert_eq!(orders.iter().map(|order| order.line).collect::<Vec<_>>(), vec![3, 5, 10, 11]);
+    assert_eq!((*total_raw_orders, *invalid_orders), (7, 3));
+    assert_eq!(report.errors.iter().map(|error| (error.line, error.kind.as_str())).collect::<Vec<_>>(),
+      vec![(6, "invalid_order_type"), (7, "invalid_encoding"), (8, "invalid_order_format")]);
+
+    assert_eq!(parse_in_chunks(&file, std::iter::repeat(1)), whole);
+    let mut rng = StdRng::seed_from_u64(44);
+    for _ in 0..20 {
+      let sizes: Vec<usize> = (0..file.len()).map(|_| rng.random_range(1..=3 * MAX_LINE_BYTES)).collect();
+      assert_eq!(parse_in_chunks(&file, sizes.into_iter()), whole);
+    }
+  }
+
+  #[test]
+  fn a_file_without_newlines_stays_bounded() {
+    let file = vec![b'x'; 64 * MAX_LINE_BYTES];
+    let (orders, total_raw_orders, invalid_orders, report) = parse_in_chunks(&file, std::iter::repeat(1000));
+    assert!(orders.is_empty());
+    assert_eq!((total_raw_orders, invalid_orders), (1, 1));
+    assert_eq!(report.error_counts.get("invalid_order_format"), Some(&1));
+  }
+}
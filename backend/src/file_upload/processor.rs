@@ -1,74 +1,248 @@
 use std::{collections::HashMap, io::Read, sync::Arc, time::{Duration, Instant}};
 use axum::body::Bytes;
-use flate2::read::DeflateDecoder;
+use flate2::read::{DeflateDecoder, MultiGzDecoder};
 use futures::lock::Mutex;
 use rust_decimal::Decimal;
-use serde::{Deserialize, Serialize};
-use crate::{engine::orderbook::{Arena, BidOrAsk}, midwares::app_state::AppError};
-
-#[derive(Debug, Clone, Deserialize)]
-pub enum FileUploadOrderType {
-  Add {
-    id: u64,
-    side: BidOrAsk,
-    shares: u64,
-    price: Decimal
-  },
-  Modify {
-    id: u64,
-    shares: u64,
-    price: Decimal
-  },
-  Cancel {
-    id: u64,
-  },
-}
-
-struct OrderStats {
-  latency: Duration,
-  avl_rebalances: i64,
-  executed_orders_cnt: usize
-}
+use protocol::{grammar::{FileUploadOrderType, OrderLine, ParseReport, Side, MAX_REPORTED_ERRORS}, upload::{ChunkStatus, DownloadSummary}, validator::{OrderValidator, ValidationPolicy, ValidationReport}};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use crate::{engine::{book_stats::{BookStatsConfig, BookStatsSampler, BookStatsSeries}, orderbook::{Arena, BidOrAsk}, stats::{EngineMetrics, OpStats}, risk::{RiskConfig, RiskManager, DEFAULT_INSTRUMENT}}, midwares::app_state::AppError};
+use super::{downloads::UploadDownloads, jobs::{UploadJob, PROGRESS_INTERVAL}, parser::UploadParser};
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct FinalStats {
   total_time: Duration,
   avl_rebalances: i64,
   executed_orders_cnt: i64,
-  nos: i64
+  nos: i64,
+  p50: Duration,
+  p90: Duration,
+  p99: Duration,
+  p999: Duration,
+  max: Duration
 }
 
-#[derive(Debug, Serialize)]
+impl From<&OpStats> for FinalStats {
+  fn from(stats: &OpStats) -> Self {
+    FinalStats {
+      total_time: stats.total_time,
+      avl_rebalances: stats.avl_rebalances,
+      executed_orders_cnt: stats.executed_orders_cnt,
+      nos: stats.nos,
+      p50: stats.percentile(50.0),
+      p90: stats.percentile(90.0),
+      p99: stats.percentile(99.0),
+      p999: stats.percentile(99.9),
+      max: stats.max()
+    }
+  }
+}
+
+// everything the engine reports back after processing an upload
+pub struct UploadResults {
+  pub orderbook_results: HashMap<String, FinalStats>,
+  pub overall_results: Option<FinalStats>,
+  pub risk_rejects: HashMap<String, usize>,
+  pub book_stats: BookStatsSeries,
+  // trade tape and final book, when the upload asked for them
+  pub downloads: Option<UploadDownloads>
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct SmallUploadResponse {
   pub orderbook_results: Option<HashMap<String, FinalStats>>,
-  pub processed: bool
+  pub overall_results: Option<FinalStats>,
+  pub risk_rejects: Option<HashMap<String, usize>>,
+  pub book_stats: Option<BookStatsSeries>,
+  // the report of the client-side parse, echoed back with the final response
+  pub parse_report: Option<ParseReport>,
+  pub validation_report: Option<ValidationReport>,
+  // what can be downloaded from `/jobs/{job_id}/trades` and `/jobs/{job_id}/book`
+  pub downloads: Option<DownloadSummary>,
+  pub processed: bool,
+  // set on the response to the last chunk, results are then fetched from `/jobs/{job_id}`
+  pub job_id: Option<String>
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LargeUploadResponse {
+  pub orderbook_results: Option<HashMap<String, FinalStats>>,
+  pub overall_results: Option<FinalStats>,
+  pub risk_rejects: Option<HashMap<String, usize>>,
+  pub book_stats: Option<BookStatsSeries>,
+  pub parse_results: Option<(Duration, i32, i32)>,
+  pub parse_report: Option<ParseReport>,
+  pub validation_report: Option<ValidationReport>,
+  // what can be downloaded from `/jobs/{job_id}/trades` and `/jobs/{job_id}/book`
+  pub downloads: Option<DownloadSummary>,
+  pub processed: bool,
+  // set on the response to the last chunk, results are then fetched from `/jobs/{job_id}`
+  pub job_id: Option<String>
 }
 
-#[derive(Debug, Deserialize)]
-pub struct SmallUploadRequest {
+// a session expires once no chunk arrived for this long
+const SESSION_TTL: Duration = Duration::from_secs(60 * 5);
+// how often expired sessions are dropped
+pub const SESSION_SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+// most chunks one upload may be split into
+const MAX_SESSION_CHUNKS: usize = 1_000;
+// most decoded bytes a small upload may buffer across all its chunks
+const MAX_SESSION_BYTES: usize = 1024 * 1024 * 15;
+// most bytes a large upload may buffer at once, in chunks ahead of their turn and orders held back by the reject policy
+const MAX_STREAMING_SESSION_BYTES: usize = 1024 * 1024 * 64;
+// most bytes buffered by all the uploads of one kind in transit together
+const MAX_TOTAL_SESSION_BYTES: usize = 1024 * 1024 * 128;
+// most uploads of one kind a client may have in transit
+const MAX_SESSIONS_PER_IP: usize = 4;
+
+// what a request says about the chunk it carries
+pub struct ChunkMeta {
   pub session_id: String,
   pub total_chunks: usize,
-  pub total_orders: usize,
   pub chunk_number: usize,
-  pub orders: Vec<FileUploadOrderType>
+  // decoded size of the chunk
+  pub bytes: usize,
+  // verified sha256 of the decoded chunk, when the client sent one
+  pub checksum: Option<String>
 }
 
-#[derive(Debug, Serialize)]
-pub struct LargeUploadResponse {
-  pub orderbook_results: Option<HashMap<String, FinalStats>>,
-  pub parse_results: Option<(Duration, i32, i32)>,
-  pub processed: bool
+// what an upload session knows about the chunks it got so far
+struct ChunkLedger {
+  // ip of the uploader, the only one allowed to add to or look at the session
+  owner: String,
+  total_chunks: usize,
+  checksums: HashMap<usize, Option<String>>,
+  // decoded bytes received so far
+  bytes: usize,
+  // the part of them the session still holds, counted against the byte limits
+  buffered: usize,
+  last_activity: Instant
 }
 
-// Session manager
-struct UploadSessionData<T> {
-  chunks: HashMap<usize, T>,
-  total_chunks: usize
+impl ChunkLedger {
+  fn new(owner: &str, total_chunks: usize) -> Result<Self, AppError> {
+    if total_chunks == 0 || total_chunks > MAX_SESSION_CHUNKS {
+      return Err(AppError::BadRequest(format!("total_chunks must be between 1 and {}", MAX_SESSION_CHUNKS)));
+    }
+    Ok(ChunkLedger { owner: owner.to_string(), total_chunks, checksums: HashMap::new(), bytes: 0, buffered: 0, last_activity: Instant::now() })
+  }
+
+  // true for a new chunk, false for one received before, which a client resuming an upload may send again.
+  // A new chunk is buffered until the session says otherwise, so it must fit in `limit` and in the `room` left by the other sessions
+  fn record(&mut self, chunk: &ChunkMeta, limit: usize, room: usize) -> Result<bool, AppError> {
+    if chunk.total_chunks != self.total_chunks {
+      return Err(AppError::BadRequest(format!("Session {} has {} chunks, not {}", chunk.session_id, self.total_chunks, chunk.total_chunks)));
+    }
+    if chunk.chunk_number >= self.total_chunks {
+      return Err(AppError::BadRequest(format!("Chunk {} out of range, the upload has {} chunks", chunk.chunk_number, self.total_chunks)));
+    }
+    self.last_activity = Instant::now();
+    if let Some(checksum) = self.checksums.get(&chunk.chunk_number) {
+      return match (checksum, &chunk.checksum) {
+        (Some(received), Some(resent)) if received != resent => Err(AppError::BadRequest(format!("Chunk {} was already received with a different checksum", chunk.chunk_number))),
+        _ => Ok(false)
+      };
+    }
+    if self.buffered + chunk.bytes > limit {
+      return Err(AppError::PayloadTooLarge(format!("Uploads may not hold more than {} bytes on the server", limit)));
+    }
+    if chunk.bytes > room {
+      return Err(AppError::RateLimitExceeded("The server is holding too many uploads in transit, try again later".to_string()));
+    }
+    self.bytes += chunk.bytes;
+    self.buffered += chunk.bytes;
+    self.checksums.insert(chunk.chunk_number, chunk.checksum.clone());
+    Ok(true)
+  }
+
+  fn is_complete(&self) -> bool {
+    self.checksums.len() == self.total_chunks
+  }
+
+  fn is_expired(&self, now: Instant) -> bool {
+    now.duration_since(self.last_activity) > SESSION_TTL
+  }
+
+  fn status(&self, session_id: &str) -> ChunkStatus {
+    ChunkStatus {
+      session_id: session_id.to_string(),
+      total_chunks: self.total_chunks,
+      received: self.checksums.len(),
+      missing: (0..self.total_chunks).filter(|chunk_number| !self.checksums.contains_key(chunk_number)).collect(),
+      bytes: self.bytes,
+      expires_in_secs: SESSION_TTL.saturating_sub(self.last_activity.elapsed()).as_secs()
+    }
+  }
+}
+
+struct UploadSession<S> {
+  ledger: ChunkLedger,
+  data: S
 }
 
+// upload sessions by id with their expiry and limits, `S` is whatever an upload keeps between chunks
+struct SessionTable<S> {
+  sessions: HashMap<String, UploadSession<S>>,
+  // most bytes one session may buffer
+  max_session_bytes: usize
+}
+
+impl<S> SessionTable<S> {
+  fn new(max_session_bytes: usize) -> Self {
+    SessionTable { sessions: HashMap::new(), max_session_bytes }
+  }
+
+  fn expire(&mut self) {
+    let now = Instant::now();
+    self.sessions.retain(|session_id, session| {
+      let expired = session.ledger.is_expired(now);
+      if expired {
+        println!("[INFO] upload session {} expired with {}/{} chunks", session_id, session.ledger.checksums.len(), session.ledger.total_chunks);
+      }
+      !expired
+    });
+  }
+
+  // the session of `chunk`, started with `start` if this is the first chunk to arrive
+  fn open(&mut self, owner: &str, chunk: &ChunkMeta, start: impl FnOnce() -> S) -> Result<&mut UploadSession<S>, AppError> {
+    if !self.sessions.contains_key(&chunk.session_id) {
+      if self.sessions.values().filter(|session| session.ledger.owner == owner).count() >= MAX_SESSIONS_PER_IP {
+        return Err(AppError::RateLimitExceeded(format!("At most {} uploads may be in progress at once", MAX_SESSIONS_PER_IP)));
+      }
+      let ledger = ChunkLedger::new(owner, chunk.total_chunks)?;
+      self.sessions.insert(chunk.session_id.clone(), UploadSession { ledger, data: start() });
+    }
+    self.sessions.get_mut(&chunk.session_id)
+      .filter(|session| session.ledger.owner == owner)
+      .ok_or_else(|| AppError::NotFound(format!("Session {} not found", chunk.session_id)))
+  }
+
+  // Records `chunk` in its session and tells whether it is new. Sessions that outgrow their byte limit are dropped,
+  // as are sessions whose first chunk is refused
+  fn record(&mut self, owner: &str, chunk: &ChunkMeta, start: impl FnOnce() -> S) -> Result<(&mut UploadSession<S>, bool), AppError> {
+    self.expire();
+    let room = MAX_TOTAL_SESSION_BYTES.saturating_sub(self.sessions.values().map(|session| session.ledger.buffered).sum());
+    let limit = self.max_session_bytes;
+    let session = self.open(owner, chunk, start)?;
+    let recorded = session.ledger.record(chunk, limit, room);
+    if recorded.as_ref().is_err_and(|e| matches!(e, AppError::PayloadTooLarge(_)) || session.ledger.checksums.is_empty()) {
+      self.sessions.remove(&chunk.session_id);
+    }
+    let is_new = recorded?;
+    Ok((self.sessions.get_mut(&chunk.session_id).expect("recorded session should exist!"), is_new))
+  }
+
+  fn status(&self, session_id: &str, owner: &str) -> Option<ChunkStatus> {
+    self.sessions.get(session_id)
+      .filter(|session| session.ledger.owner == owner)
+      .map(|session| session.ledger.status(session_id))
+  }
+}
+
+// Session manager
 #[derive(Clone)]
 pub struct UploadSessionManager<T> {
-  sessions: Arc<Mutex<HashMap<String, UploadSessionData<T>>>>
+  sessions: Arc<Mutex<SessionTable<HashMap<usize, T>>>>
 }
 
 impl<T, Item> UploadSessionManager<T>
@@ -77,25 +251,24 @@ where
   Vec<Item>: FromIterator<Item>
 {
   pub fn new() -> Self {
-    Self { sessions: Arc::new(Mutex::new(HashMap::new())) }
+    Self { sessions: Arc::new(Mutex::new(SessionTable::new(MAX_SESSION_BYTES))) }
   }
 
-  pub async fn store_chunk(&self, session_id: &str, chunk_number: usize, chunk_data: T, total_chunks: usize) {
-
+  pub async fn store_chunk(&self, owner: &str, chunk: &ChunkMeta, chunk_data: T) -> Result<(), AppError> {
     let mut sessions = self.sessions.lock().await;
-    let session = sessions.entry(session_id.to_string()).or_insert_with(|| UploadSessionData {
-      chunks: HashMap::new(),
-      total_chunks
-    });
+    let (session, is_new) = sessions.record(owner, chunk, HashMap::new)?;
     // update session
-    session.chunks.insert(chunk_number, chunk_data);
+    if is_new {
+      session.data.insert(chunk.chunk_number, chunk_data);
+    }
+    Ok(())
   }
 
   pub async fn is_upload_complete(&self, session_id: &str) -> bool {
     let sessions = self.sessions.lock().await;
 
-    if let Some(session) = sessions.get(session_id) {
-      session.chunks.len() == session.total_chunks
+    if let Some(session) = sessions.sessions.get(session_id) {
+      session.ledger.is_complete()
     } else {
       false
     }
@@ -104,20 +277,20 @@ where
   pub async fn get_all_chunks(&self, session_id: &str) -> Result<Vec<Item>, String> {
     let mut sessions = self.sessions.lock().await;
 
-    let session = sessions.get_mut(session_id).ok_or_else(|| format!("Session {} not found", session_id))?;
+    let session = sessions.sessions.get_mut(session_id).ok_or_else(|| format!("Session {} not found", session_id))?;
 
-    if session.chunks.len() != session.total_chunks {
+    if !session.ledger.is_complete() {
       return Err(format!(
         "Incomplete Upload: got {}/{} chunks",
-        session.chunks.len(),
-        session.total_chunks
+        session.data.len(),
+        session.ledger.total_chunks
       ));
     }
 
     // collect chunks in order
     let mut result = Vec::new();
-    for chunk_num in 0..session.total_chunks {
-      if let Some(chunk) = session.chunks.get(&chunk_num) {
+    for chunk_num in 0..session.ledger.total_chunks {
+      if let Some(chunk) = session.data.get(&chunk_num) {
         result.extend(chunk.clone());
       } else {
         return Err(format!("Missing chunk {} in session {}", chunk_num, session_id));
@@ -129,78 +302,606 @@ where
 
   pub async fn clear_chunks(&self, session_id: &str) -> Result<(), String> {
     let mut sessions = self.sessions.lock().await;
-    if sessions.remove(session_id).is_none() {
+    if sessions.sessions.remove(session_id).is_none() {
       return Err(format!("Session {} not found for deletion", session_id));
     }
     Ok(())
   }
+
+  pub async fn status(&self, session_id: &str, owner: &str) -> Option<ChunkStatus> {
+    self.sessions.lock().await.status(session_id, owner)
+  }
+
+  pub async fn expire(&self) {
+    self.sessions.lock().await.expire();
+  }
 }
 
 // Type aliases for convenience
-pub type LargeUploadSessionManager = UploadSessionManager<Bytes>;
-pub type SmallUploadSessionManager = UploadSessionManager<Vec<FileUploadOrderType>>;
+pub type SmallUploadSessionManager = UploadSessionManager<Vec<OrderLine>>;
+
+// what is left to report once every chunk of a large upload went through the engine
+pub struct FinishedUpload {
+  // None when no order reached the engine
+  pub results: Option<UploadResults>,
+  pub orders: usize,
+  pub parse_results: (Duration, i32, i32),
+  pub parse_report: ParseReport,
+  pub validation_report: ValidationReport
+}
+
+// A large upload in the text or binary format parsed, validated and processed chunk by chunk while the rest of it is still in transit,
+// so only the books and a partial line are kept around instead of the whole file.
+// Chunks arriving ahead of their turn wait until the ones before them are in
+pub struct StreamingUpload {
+  total_chunks: usize,
+  next_chunk: usize,
+  early_chunks: HashMap<usize, Bytes>,
+  parser: UploadParser,
+  validator: OrderValidator,
+  processor: UploadProcessor,
+  // the reject policy cannot let anything through before the whole file is validated
+  held_orders: Vec<OrderLine>,
+  // rough memory taken by `held_orders`
+  held_bytes: usize
+}
+
+impl StreamingUpload {
+  pub fn new(total_chunks: usize, validation: ValidationPolicy, downloads: bool, book_stats: BookStatsConfig, risk_config: Arc<RiskConfig>, account: &str) -> Self {
+    StreamingUpload {
+      total_chunks,
+      next_chunk: 0,
+      early_chunks: HashMap::new(),
+      parser: UploadParser::new(),
+      validator: OrderValidator::new(validation),
+      processor: UploadProcessor::new(risk_config, account, downloads, book_stats),
+      held_orders: Vec::new(),
+      held_bytes: 0
+    }
+  }
+
+  pub fn accept(&mut self, chunk_number: usize, chunk: Bytes) -> Result<(), String> {
+    if chunk_number >= self.total_chunks {
+      return Err(format!("Chunk {} out of range, the upload has {} chunks", chunk_number, self.total_chunks));
+    }
+    if chunk_number < self.next_chunk || self.early_chunks.contains_key(&chunk_number) {
+      return Err(format!("Chunk {} was already received", chunk_number));
+    }
+    self.early_chunks.insert(chunk_number, chunk);
+    while let Some(chunk) = self.early_chunks.remove(&self.next_chunk) {
+      let orders = self.parser.feed(&chunk);
+      self.handle_orders(orders);
+      self.next_chunk += 1;
+    }
+    Ok(())
+  }
+
+  pub fn is_complete(&self) -> bool {
+    self.next_chunk == self.total_chunks
+  }
+
+  // bytes the upload holds until its other chunks arrive: chunks ahead of their turn and held back orders
+  pub fn buffered_bytes(&self) -> usize {
+    self.early_chunks.values().map(Bytes::len).sum::<usize>() + self.held_bytes
+  }
+
+  // valid orders so far, processed or held back
+  pub fn orders_seen(&self) -> usize {
+    self.processor.processed() + self.held_orders.len()
+  }
+
+  // runs on an upload worker, None if `job` got cancelled on the way
+  pub fn finish(mut self, job: &UploadJob) -> Option<FinishedUpload> {
+    job.parsing();
+    let parser = std::mem::replace(&mut self.parser, UploadParser::new());
+    let (last_orders, parse_duration, total_raw_orders, invalid_orders, parse_report) = parser.finish();
+    self.handle_orders(last_orders);
+
+    let validation_report = self.validator.finish();
+    if !validation_report.rejected {
+      let held_orders = std::mem::take(&mut self.held_orders);
+      self.held_bytes = 0;
+      process_with_progress(&mut self.processor, held_orders, job)?;
+    }
+    let orders = if validation_report.rejected { 0 } else { self.processor.processed() };
+    Some(FinishedUpload {
+      results: (orders > 0).then(|| self.processor.finish()),
+      orders,
+      parse_results: (parse_duration, total_raw_orders, invalid_orders),
+      parse_report,
+      validation_report
+    })
+  }
+
+  fn handle_orders(&mut self, orders: Vec<OrderLine>) {
+    for order in orders {
+      if let Some(order) = self.validator.check(order) {
+        match self.validator.policy() {
+          ValidationPolicy::Reject => {
+            self.held_bytes += std::mem::size_of::<OrderLine>() + order.symbol.as_ref().map_or(0, String::capacity);
+            self.held_orders.push(order);
+          },
+          _ => self.processor.process(order)
+        }
+      }
+    }
+  }
+}
+
+// a large upload in transit, taken out by the request that completes it
+pub type StreamingSession = Arc<std::sync::Mutex<Option<StreamingUpload>>>;
+
+// Large uploads in transit, each behind its own lock so sessions do not wait on each other.
+// Chunks are fed to their upload off the async executor, hence the blocking lock
+#[derive(Clone)]
+pub struct LargeUploadSessionManager {
+  sessions: Arc<Mutex<SessionTable<StreamingSession>>>
+}
+
+impl LargeUploadSessionManager {
+  pub fn new() -> Self {
+    Self { sessions: Arc::new(Mutex::new(SessionTable::new(MAX_STREAMING_SESSION_BYTES))) }
+  }
+
+  // the upload of `chunk`, started by `start` if this is its first chunk, and whether the chunk is new to it
+  pub async fn accept_chunk(&self, owner: &str, chunk: &ChunkMeta, start: impl FnOnce() -> StreamingUpload) -> Result<(StreamingSession, bool), AppError> {
+    let mut sessions = self.sessions.lock().await;
+    let (session, is_new) = sessions.record(owner, chunk, || Arc::new(std::sync::Mutex::new(Some(start()))))?;
+    Ok((session.data.clone(), is_new))
+  }
+
+  // what the upload still buffers once a chunk went through it, a chunk fed in its turn is not kept
+  pub async fn set_buffered(&self, session_id: &str, bytes: usize) {
+    if let Some(session) = self.sessions.lock().await.sessions.get_mut(session_id) {
+      session.ledger.buffered = bytes;
+    }
+  }
 
-// method that feeds uploaded orders into the ob engine (used by both: /largeupload and /smallupload routes)
-pub fn process_uploaded_orders(orders: Vec<FileUploadOrderType>) -> HashMap<String, FinalStats>  {
+  pub async fn remove(&self, session_id: &str) -> Option<StreamingSession> {
+    self.sessions.lock().await.sessions.remove(session_id).map(|session| session.data)
+  }
 
-  println!("[INFO] processing total {:?} orders", orders.len());
-  let mut book = Arena::new(true);
-  let mut order_stats = HashMap::new();
+  pub async fn status(&self, session_id: &str, owner: &str) -> Option<ChunkStatus> {
+    self.sessions.lock().await.status(session_id, owner)
+  }
 
-  for order in orders {
-    
+  pub async fn expire(&self) {
+    self.sessions.lock().await.expire();
+  }
+}
+
+// stop orders waiting for a trade at or through their stop price
+struct PendingStop {
+  id: u64,
+  side: BidOrAsk,
+  shares: u64,
+  stop_price: Decimal,
+  limit_price: Option<Decimal>
+}
+
+// an order book per symbol, lines without a symbol trade the default instrument
+struct SymbolBook {
+  book: Arena,
+  risk: RiskManager,
+  stops: Vec<PendingStop>,
+  trades_seen: usize
+}
+
+impl SymbolBook {
+  fn new(risk_config: Arc<RiskConfig>, instrument: &str) -> Self {
+    SymbolBook { book: Arena::new(true), risk: RiskManager::new(risk_config, instrument), stops: Vec::new(), trades_seen: 0 }
+  }
+
+  fn process(&mut self, order: FileUploadOrderType, account: &str, risk_rejects: &mut HashMap<String, usize>) {
     match order {
-      FileUploadOrderType::Add { id, side, shares, price } => {
-        let start = Instant::now();
-        book.add_limit_order(id, side, shares, price);
-        let duration = start.elapsed();
-        order_stats.entry("ADD")
-        .or_insert(vec![])
-        .push(OrderStats { latency: duration, avl_rebalances: book.avl_rebalances as i64, executed_orders_cnt: book.executed_orders_count });
-      },
+      FileUploadOrderType::Add { id, side, shares, price } => self.add_limit(account, id, side.into(), shares, price, risk_rejects),
       FileUploadOrderType::Modify { id, shares, price } => {
-        let start = Instant::now();
-        book.modify_limit_order(id, shares, price);
-        let duration = start.elapsed();
-        order_stats.entry("MODIFY")
-        .or_insert(vec![])
-        .push(OrderStats { latency: duration, avl_rebalances: book.avl_rebalances as i64, executed_orders_cnt: book.executed_orders_count });
+        if let Err(reject) = self.risk.check_modify(id, shares, price, &self.book) {
+          *risk_rejects.entry(reject.kind().to_string()).or_insert(0) += 1;
+        } else {
+          self.book.modify_limit_order(id, shares, price);
+          self.risk.record_modify(id, &self.book);
+        }
       },
       FileUploadOrderType::Cancel { id } => {
-        let start = Instant::now();
-        book.cancel_limit_order(id);
-        let duration = start.elapsed();
-        order_stats.entry("CANCEL")
-        .or_insert(vec![])
-        .push(OrderStats { latency: duration, avl_rebalances: book.avl_rebalances as i64, executed_orders_cnt: book.executed_orders_count });
+        // a stop that has not triggered yet is only withdrawn
+        self.stops.retain(|stop| stop.id != id);
+        self.book.cancel_limit_order(id);
+        self.risk.record_cancel(id);
+      },
+      FileUploadOrderType::Market { id, side, shares } => self.add_immediate(account, id, side.into(), shares, None, risk_rejects),
+      FileUploadOrderType::Ioc { id, side, shares, price } => self.add_immediate(account, id, side.into(), shares, Some(price), risk_rejects),
+      FileUploadOrderType::Fok { id, side, shares, price } => {
+        let side: BidOrAsk = side.into();
+        if let Err(reject) = self.risk.check_add(account, &side, shares, price, &self.book) {
+          *risk_rejects.entry(reject.kind().to_string()).or_insert(0) += 1;
+        } else if self.book.add_fok_order(id, side.clone(), shares, price) {
+          self.risk.record_add(account, id, side, &self.book);
+        }
+      },
+      FileUploadOrderType::Stop { id, side, shares, stop_price, limit_price } => {
+        self.stops.push(PendingStop { id, side: side.into(), shares, stop_price, limit_price });
+      },
+      FileUploadOrderType::MassCancel { side } => {
+        self.stops.retain(|stop| side.is_some_and(|side| side != Side::from(&stop.side)));
+        let side = side.map(BidOrAsk::from);
+        for id in self.book.resting_order_ids(side.as_ref()) {
+          self.book.cancel_limit_order(id);
+          self.risk.record_cancel(id);
+        }
       }
     }
+    self.trigger_stops(account, risk_rejects);
+  }
+
+  fn add_limit(&mut self, account: &str, id: u64, side: BidOrAsk, shares: u64, price: Decimal, risk_rejects: &mut HashMap<String, usize>) {
+    if let Err(reject) = self.risk.check_add(account, &side, shares, price, &self.book) {
+      *risk_rejects.entry(reject.kind().to_string()).or_insert(0) += 1;
+    } else {
+      self.book.add_limit_order(id, side.clone(), shares, price);
+      self.risk.record_add(account, id, side, &self.book);
+    }
   }
-  
-  let mut final_stats: HashMap<String, FinalStats> = HashMap::new();
-  
-  for (k, v) in order_stats {
-    let z = v.iter().fold(FinalStats { total_time: Duration::new(0, 0), avl_rebalances: 0, executed_orders_cnt: 0, nos: 0 }, |mut state, e| {
-      state.total_time += e.latency;
-      state.avl_rebalances += e.avl_rebalances;
-      state.executed_orders_cnt += e.executed_orders_cnt as i64;
-      state.nos += 1;
-      state
+
+  // market orders are checked at the opposite touch they will start filling at, and dropped against an empty side
+  fn add_immediate(&mut self, account: &str, id: u64, side: BidOrAsk, shares: u64, limit_price: Option<Decimal>, risk_rejects: &mut HashMap<String, usize>) {
+    let check_price = limit_price.or(match side {
+      BidOrAsk::Bid => self.book.lowest_sell,
+      BidOrAsk::Ask => self.book.highest_buy
     });
-    final_stats.insert(k.to_string(), z);
+    let Some(check_price) = check_price else {
+      return;
+    };
+    if let Err(reject) = self.risk.check_add(account, &side, shares, check_price, &self.book) {
+      *risk_rejects.entry(reject.kind().to_string()).or_insert(0) += 1;
+      return;
+    }
+    match limit_price {
+      None => self.book.add_market_order(id, side.clone(), shares),
+      Some(limit_price) => self.book.add_ioc_order(id, side.clone(), shares, limit_price)
+    };
+    self.risk.record_add(account, id, side, &self.book);
+  }
+
+  // fires the stops crossed by the trades since the last check, which may trade and fire further stops
+  fn trigger_stops(&mut self, account: &str, risk_rejects: &mut HashMap<String, usize>) {
+    while self.book.executed_orders.len() > self.trades_seen {
+      let last_price = self.book.executed_orders[self.book.executed_orders.len() - 1].price;
+      self.trades_seen = self.book.executed_orders.len();
+
+      let (triggered, waiting): (Vec<PendingStop>, Vec<PendingStop>) = self.stops.drain(..).partition(|stop| match stop.side {
+        BidOrAsk::Bid => last_price >= stop.stop_price,
+        BidOrAsk::Ask => last_price <= stop.stop_price
+      });
+      self.stops = waiting;
+      for stop in triggered {
+        match stop.limit_price {
+          Some(limit_price) => self.add_limit(account, stop.id, stop.side, stop.shares, limit_price, risk_rejects),
+          None => self.add_immediate(account, stop.id, stop.side, stop.shares, None, risk_rejects)
+        }
+      }
+    }
+  }
+}
+
+// feeds uploaded orders into the ob engine as they come (used by both: /largeupload and /smallupload routes)
+// orders breaching the pre-trade risk limits of `account` never reach the engine and are counted by reject kind.
+// Every symbol trades on its own book, book stats follow the book of the first order
+pub struct UploadProcessor {
+  risk_config: Arc<RiskConfig>,
+  account: String,
+  books: HashMap<String, SymbolBook>,
+  risk_rejects: HashMap<String, usize>,
+  sampler: BookStatsSampler,
+  book_stats: BookStatsSeries,
+  sampled_symbol: Option<String>,
+  processed: usize,
+  downloads: Option<UploadDownloads>
+}
+
+impl UploadProcessor {
+  pub fn new(risk_config: Arc<RiskConfig>, account: &str, downloads: bool, book_stats: BookStatsConfig) -> Self {
+    UploadProcessor {
+      risk_config,
+      account: account.to_string(),
+      books: HashMap::new(),
+      risk_rejects: HashMap::new(),
+      sampler: BookStatsSampler::new(book_stats),
+      book_stats: BookStatsSeries::default(),
+      sampled_symbol: None,
+      processed: 0,
+      downloads: downloads.then(UploadDownloads::default)
+    }
+  }
+
+  pub fn process(&mut self, order: OrderLine) {
+    let symbol = order.symbol.unwrap_or_else(|| DEFAULT_INSTRUMENT.to_string());
+    let risk_config = &self.risk_config;
+    let symbol_book = self.books.entry(symbol.clone()).or_insert_with(|| SymbolBook::new(risk_config.clone(), &symbol));
+    let trades_before = symbol_book.book.executed_orders.len();
+    symbol_book.process(order.order, &self.account, &mut self.risk_rejects);
+    if let Some(downloads) = self.downloads.as_mut() {
+      downloads.record_trades(order.line, &symbol, &symbol_book.book.executed_orders[trades_before..]);
+    }
+
+    let sampled_symbol = self.sampled_symbol.get_or_insert(symbol);
+    if let Some(sample) = self.books.get(sampled_symbol).and_then(|sampled| self.sampler.maybe_sample(self.processed, &sampled.book)) {
+      self.book_stats.push(&sample);
+    }
+    self.processed += 1;
+  }
+
+  // orders fed to the engine so far
+  pub fn processed(&self) -> usize {
+    self.processed
   }
-  
-  final_stats
+
+  pub fn finish(mut self) -> UploadResults {
+    println!("[INFO] processed total {:?} orders", self.processed);
+    if let Some(downloads) = self.downloads.as_mut() {
+      let mut symbols: Vec<&String> = self.books.keys().collect();
+      symbols.sort();
+      symbols.into_iter().for_each(|symbol| downloads.record_book(symbol, &self.books[symbol].book));
+    }
+    let mut metrics = EngineMetrics::new();
+    self.books.values().for_each(|symbol_book| metrics.merge(&symbol_book.book.metrics));
+    let orderbook_results = metrics.snapshot().iter()
+      .map(|(op, stats)| (op.as_str().to_string(), FinalStats::from(stats)))
+      .collect();
+
+    UploadResults {
+      orderbook_results,
+      overall_results: metrics.overall().as_ref().map(FinalStats::from),
+      risk_rejects: self.risk_rejects,
+      book_stats: self.book_stats,
+      downloads: self.downloads
+    }
+  }
+}
+
+// feeds `orders` to the engine while keeping `job` posted on the progress, None once it got cancelled
+fn process_with_progress(processor: &mut UploadProcessor, orders: Vec<OrderLine>, job: &UploadJob) -> Option<()> {
+  let total = orders.len();
+  for (done, order) in orders.into_iter().enumerate() {
+    if done % PROGRESS_INTERVAL == 0 {
+      if job.is_cancelled() {
+        return None;
+      }
+      job.processing(done, total);
+    }
+    processor.process(order);
+  }
+  job.processing(total, total);
+  Some(())
+}
+
+// runs on an upload worker, None if `job` got cancelled on the way
+pub fn process_uploaded_orders(orders: Vec<OrderLine>, risk_config: Arc<RiskConfig>, account: &str, downloads: bool, book_stats: BookStatsConfig, job: &UploadJob) -> Option<UploadResults>  {
+  let mut processor = UploadProcessor::new(risk_config, account, downloads, book_stats);
+  process_with_progress(&mut processor, orders, job)?;
+  Some(processor.finish())
 }
 
+// client reports are not trusted to respect the cap
+pub fn capped_parse_report(mut report: ParseReport) -> ParseReport {
+  report.errors.truncate(MAX_REPORTED_ERRORS);
+  report
+}
+
+// the checksum a client sent for a chunk, lowercased, if it matches the sha256 of the decoded chunk
+pub fn verify_checksum(data: &[u8], checksum: Option<&str>) -> Result<Option<String>, AppError> {
+  let Some(checksum) = checksum.map(str::to_lowercase) else {
+    return Ok(None);
+  };
+  let actual = hex::encode(Sha256::digest(data));
+  if actual != checksum {
+    return Err(AppError::BadRequest(format!("Chunk checksum mismatch: expected {}, got {}", checksum, actual)));
+  }
+  Ok(Some(checksum))
+}
+
+// decompression bomb limits: no request body may expand past this many bytes,
+const MAX_DECOMPRESSED_SIZE: u64 = 1024 * 1024 * 64;
+// nor to more than this many times its compressed size
+const MAX_EXPANSION_RATIO: u64 = 100;
+
+// reads at most `limit` bytes out of `decoder`, anything past that is treated as a decompression bomb
+fn read_bounded(decoder: impl Read, limit: u64, encoding: &str) -> Result<Vec<u8>, AppError> {
+  let mut decompressed_data = Vec::new();
+  decoder.take(limit + 1).read_to_end(&mut decompressed_data).map_err(|e| AppError::BadRequest(format!("invalid {} data: {}", encoding, e)))?;
+  if decompressed_data.len() as u64 > limit {
+    return Err(AppError::PayloadTooLarge(format!("{} data expands past the {} bytes allowed", encoding, limit)));
+  }
+  Ok(decompressed_data)
+}
+
+// decompresses a request body and verifies its checksum on the blocking pool, inflating and hashing up to the decompression limit takes a while
+pub async fn decode_body(body: Bytes, content_encoding: Option<String>, checksum: Option<String>) -> Result<(Vec<u8>, Option<String>), AppError> {
+  tokio::task::spawn_blocking(move || {
+    let data = decompress_if_needed(&body, content_encoding.as_deref())?;
+    let checksum = verify_checksum(&data, checksum.as_deref())?;
+    Ok((data, checksum))
+  }).await.map_err(|e| AppError::InternalError(e.to_string()))?
+}
+
+// decodes the body as described by its `content-encoding`, codings listed as `gzip, br` are undone last to first
 pub fn decompress_if_needed(data: &[u8], content_encoding: Option<&str>) -> Result<Vec<u8>, AppError> {
-  match content_encoding {
-    Some("deflate") => {
-      let mut decoder = DeflateDecoder::new(data);
-      let mut decompressed_data = Vec::new();
-      decoder.read_to_end(&mut decompressed_data).map_err(|e| AppError::BadRequest(e.to_string()))?;
-      Ok(decompressed_data)
-    },
-    _ => Ok(data.to_vec())
-  }
-}
\ No newline at end of file
+  let limit = MAX_DECOMPRESSED_SIZE.min(data.len() as u64 * MAX_EXPANSION_RATIO);
+  let mut data = data.to_vec();
+
+  for encoding in content_encoding.unwrap_or_default().split(',').rev() {
+    let encoding = encoding.trim().to_lowercase();
+    data = match encoding.as_str() {
+      "" | "identity" => data,
+      "deflate" => read_bounded(DeflateDecoder::new(&data[..]), limit, &encoding)?,
+      "gzip" | "x-gzip" => read_bounded(MultiGzDecoder::new(&data[..]), limit, &encoding)?,
+      "zstd" => {
+        let decoder = zstd::stream::read::Decoder::new(&data[..]).map_err(|e| AppError::InternalError(e.to_string()))?;
+        read_bounded(decoder, limit, &encoding)?
+      },
+      "br" => read_bounded(brotli::Decompressor::new(&data[..], 4096), limit, &encoding)?,
+      _ => return Err(AppError::UnsupportedMediaType(format!("unsupported content-encoding {}, use deflate, gzip, zstd or br", encoding)))
+    };
+  }
+  Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+  use std::io::Write;
+  use super::*;
+
+  // a chunk of the text format padded with comments to `bytes`, the first one carries the header
+  fn order_chunk(chunk_number: usize, bytes: usize) -> Bytes {
+    let mut chunk = if chunk_number == 0 { b"VERSION,2,TIMESTAMP,SYMBOL\n".to_vec() } else { Vec::new() };
+    for idx in 0..100 {
+      let id = chunk_number * 100 + idx + 1;
+      chunk.extend_from_slice(format!("{},AAPL,ADD,{},{},10,{}\n", id, id, if id % 2 == 0 { "BID" } else { "ASK" }, 100 + id % 7).as_bytes());
+    }
+    let padding = format!("#{}\n", "x".repeat(1_000));
+    while chunk.len() + padding.len() <= bytes {
+      chunk.extend_from_slice(padding.as_bytes());
+    }
+    chunk.into()
+  }
+
+  fn chunk(session_id: &str, chunk_number: usize, bytes: usize) -> ChunkMeta {
+    ChunkMeta { session_id: session_id.to_string(), total_chunks: 4, chunk_number, bytes, checksum: None }
+  }
+
+  fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+    encoder.write_all(data).expect("compressing into memory should not fail!");
+    encoder.finish().expect("compressing into memory should not fail!")
+  }
+
+  fn br(data: &[u8]) -> Vec<u8> {
+    let mut encoder = brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+    encoder.write_all(data).expect("compressing into memory should not fail!");
+    encoder.into_inner()
+  }
+
+  #[test]
+  fn bodies_are_decoded_as_their_content_encoding_lists() {
+    let body = order_chunk(0, 0);
+    let zstd = zstd::encode_all(&body[..], 1).expect("compressing into memory should not fail!");
+
+    assert_eq!(decompress_if_needed(&body, None).expect("an unencoded body should pass through!"), body);
+    assert_eq!(decompress_if_needed(&gzip(&body), Some("gzip")).expect("gzip should decode!"), body);
+    assert_eq!(decompress_if_needed(&zstd, Some("zstd")).expect("zstd should decode!"), body);
+    assert_eq!(decompress_if_needed(&br(&body), Some("BR")).expect("br should decode!"), body);
+    // gzip was applied first, so it is undone last
+    assert_eq!(decompress_if_needed(&br(&gzip(&body)), Some("gzip, br")).expect("stacked codings should decode!"), body);
+
+    assert!(matches!(decompress_if_needed(&body, Some("compress")), Err(AppError::UnsupportedMediaType(_))));
+    assert!(matches!(decompress_if_needed(&br(&body), Some("br, compress")), Err(AppError::UnsupportedMediaType(_))));
+  }
+
+  #[test]
+  fn decompression_bombs_are_refused() {
+    // zeros expand far past the allowed ratio
+    let zeros = vec![0; 1024 * 1024];
+    assert!(matches!(decompress_if_needed(&gzip(&zeros), Some("gzip")), Err(AppError::PayloadTooLarge(_))));
+
+    // one noisy byte in every 32 keeps the ratio in bounds, but the body outgrows the size limit
+    let mut noisy = vec![0u8; MAX_DECOMPRESSED_SIZE as usize + 1024 * 1024];
+    let mut state: u32 = 1;
+    for byte in noisy.iter_mut().step_by(32) {
+      state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+      *byte = (state >> 24) as u8;
+    }
+    let compressed = zstd::encode_all(&noisy[..], 1).expect("compressing into memory should not fail!");
+    assert!((compressed.len() as u64) * MAX_EXPANSION_RATIO > MAX_DECOMPRESSED_SIZE);
+    assert!(matches!(decompress_if_needed(&compressed, Some("zstd")), Err(AppError::PayloadTooLarge(_))));
+  }
+
+  #[test]
+  fn sessions_stay_within_their_own_and_the_shared_byte_limits() {
+    let mut table = SessionTable::new(MAX_SESSION_BYTES);
+    let half = MAX_SESSION_BYTES / 2;
+
+    table.record("1.1.1.1", &chunk("big", 0, half), || ()).expect("a chunk under the limit should be recorded!");
+    table.record("1.1.1.1", &chunk("big", 1, half), || ()).expect("a chunk under the limit should be recorded!");
+    // a resent chunk adds nothing
+    assert!(!table.record("1.1.1.1", &chunk("big", 1, half), || ()).expect("a resent chunk should be acknowledged!").1);
+    // the session outgrows its limit and is dropped
+    assert!(matches!(table.record("1.1.1.1", &chunk("big", 2, half), || ()), Err(AppError::PayloadTooLarge(_))));
+    assert!(table.status("big", "1.1.1.1").is_none());
+
+    // sessions of many clients fill the shared budget
+    let sessions = MAX_TOTAL_SESSION_BYTES / MAX_SESSION_BYTES;
+    for idx in 0..sessions {
+      table.record(&format!("10.0.0.{}", idx), &chunk(&format!("s{}", idx), 0, MAX_SESSION_BYTES), || ()).expect("chunks within the budget should be recorded!");
+    }
+    let refused = table.record("2.2.2.2", &chunk("late", 0, MAX_TOTAL_SESSION_BYTES % MAX_SESSION_BYTES + 1), || ());
+    assert!(matches!(refused, Err(AppError::RateLimitExceeded(_))));
+    // the refused first chunk leaves no session behind
+    assert!(table.status("late", "2.2.2.2").is_none());
+
+    // a finished upload makes room again
+    table.sessions.remove("s0");
+    table.record("2.2.2.2", &chunk("late", 0, MAX_SESSION_BYTES), || ()).expect("freed room should be usable!");
+  }
+
+  #[test]
+  fn pending_stops_can_be_cancelled_and_triggered_stop_limits_rest() {
+    let price = |p: i64| Decimal::new(p, 0);
+    let stop = |id: u64, side: Side, limit_price: Option<Decimal>| FileUploadOrderType::Stop { id, side, shares: 5, stop_price: price(100), limit_price };
+    let mut processor = UploadProcessor::new(Arc::new(RiskConfig::default()), "1.1.1.1", false, BookStatsConfig::default());
+    let process = |processor: &mut UploadProcessor, orders: Vec<FileUploadOrderType>| {
+      orders.into_iter().for_each(|order| processor.process(OrderLine { line: 0, timestamp: None, symbol: None, order }));
+    };
+
+    process(&mut processor, vec![
+      stop(4, Side::Ask, None),
+      FileUploadOrderType::MassCancel { side: Some(Side::Ask) },
+      FileUploadOrderType::Add { id: 1, side: Side::Ask, shares: 20, price: price(100) },
+      // would buy 5 more at 100 once triggered
+      stop(2, Side::Bid, None),
+      stop(3, Side::Bid, Some(price(99))),
+      FileUploadOrderType::Cancel { id: 2 },
+      // trades at 100 and fires the stops still pending
+      FileUploadOrderType::Add { id: 5, side: Side::Bid, shares: 10, price: price(100) }
+    ]);
+
+    let book = &processor.books[DEFAULT_INSTRUMENT];
+    assert!(book.stops.is_empty());
+    // only the bid of order 5 traded, the withdrawn stops never fired
+    assert_eq!(book.book.executed_orders.len(), 1);
+    assert_eq!(book.book.resting_order_ids(Some(&BidOrAsk::Bid)), vec![3]);
+
+    // the triggered stop-limit rests and can be cancelled like any limit order
+    process(&mut processor, vec![FileUploadOrderType::Cancel { id: 3 }]);
+    assert!(processor.books[DEFAULT_INSTRUMENT].book.resting_order_ids(Some(&BidOrAsk::Bid)).is_empty());
+  }
+
+  #[tokio::test]
+  async fn large_uploads_are_only_charged_for_what_they_buffer() {
+    let sessions = LargeUploadSessionManager::new();
+    let chunks: Vec<Bytes> = (0..3).map(|chunk_number| order_chunk(chunk_number, 8 * 1024 * 1024)).collect();
+    let start = || StreamingUpload::new(3, ValidationPolicy::default(), false, BookStatsConfig::default(), Arc::new(RiskConfig::default()), "1.1.1.1");
+
+    // the last chunk arrives first and waits for the others
+    for chunk_number in [2, 0, 1] {
+      let meta = ChunkMeta { session_id: "big".to_string(), total_chunks: 3, chunk_number, bytes: chunks[chunk_number].len(), checksum: None };
+      let (session, is_new) = sessions.accept_chunk("1.1.1.1", &meta, start).await.expect("every 8MB chunk should be accepted!");
+      assert!(is_new);
+      let buffered = {
+        let mut session = session.lock().expect("upload session lock poisoned!");
+        let upload = session.as_mut().expect("the upload is not complete yet!");
+        upload.accept(chunk_number, chunks[chunk_number].clone()).expect("the chunk should be fed!");
+        upload.buffered_bytes()
+      };
+      // only the early chunk is held, until the one before it arrives
+      let expected = if chunk_number == 1 { 0 } else { chunks[2].len() };
+      assert_eq!(buffered, expected);
+      sessions.set_buffered("big", buffered).await;
+    }
+
+    let status = sessions.status("big", "1.1.1.1").await.expect("the session should still be open!");
+    assert_eq!(status.bytes, chunks.iter().map(Bytes::len).sum::<usize>());
+    assert!(status.missing.is_empty());
+    let session = sessions.remove("big").await.expect("the session should still be open!");
+    assert!(session.lock().expect("upload session lock poisoned!").as_ref().is_some_and(StreamingUpload::is_complete));
+  }
+}
@@ -4,7 +4,7 @@ mod file_upload;
 mod midwares;
 mod route_handlers;
 
-use std::net::SocketAddr;
+use std::{net::SocketAddr, sync::Arc};
 use axum::{
   extract::DefaultBodyLimit,
   http::{header::{CONTENT_ENCODING, CONTENT_TYPE}, HeaderName, HeaderValue, Method},
@@ -18,12 +18,17 @@ use serde_json::json;
 use tokio::{net::TcpListener, sync::OnceCell};
 use tower_http::cors::CorsLayer;
 
-use file_upload::processor::{LargeUploadSessionManager, SmallUploadSessionManager};
-use midwares::{app_state::{PostgresDBPool, RateLimiter}, auth::ip_tracker_with_auth};
-use route_handlers::{sockets::ws_handler, uploads::{large_upload_handler, small_upload_handler}};
+use engine::{book_stats::BookStatsConfig, risk::RiskConfig};
+use file_upload::{jobs::UploadJobs, processor::{LargeUploadSessionManager, SmallUploadSessionManager, SESSION_SWEEP_INTERVAL}};
+use midwares::{app_state::{CommandExportDir, DepthRegistry, PostgresDBPool, RateLimiter, SimulationSlots}, auth::ip_tracker_with_auth};
+use route_handlers::{depth::depth_handler, jobs::{book_download_handler, cancel_job_handler, get_job_handler, trades_download_handler}, sockets::{ws_handler, SimulationServices}, uploads::{chunk_status_handler, large_upload_handler, small_upload_handler, UploadServices}};
 
 // allow max file uploads of 15MB for the /largeupload route
 const MAX_FILE_SIZE: usize = 1024 * 1024 * 15;
+// simulations running at once when `MAX_CONCURRENT_SIMS` is not set
+const DEFAULT_MAX_CONCURRENT_SIMS: usize = 8;
+// uploads processed at once when `MAX_UPLOAD_WORKERS` is not set
+const DEFAULT_MAX_UPLOAD_WORKERS: usize = 4;
 pub static EXPECTED_ORIGIN: OnceCell<String> = OnceCell::const_new();
 static REDIS_URL: OnceCell<String> = OnceCell::const_new();
 static DB_URL: OnceCell<String> = OnceCell::const_new();
@@ -53,11 +58,64 @@ async fn get_global_limit() -> usize {
 async fn get_global_window() -> i64 {
   std::env::var("GLOBAL_WINDOW").expect("GLOBAL_WINDOW should be available!").parse::<i64>().expect("ip limit parse should not fail!")
 }
+// optional path to a json file with per account/instrument risk limits, falls back to defaults
+fn get_risk_config() -> RiskConfig {
+  match std::env::var("RISK_CONFIG") {
+    Ok(path) => RiskConfig::from_file(&path).expect("risk config should be valid!"),
+    Err(_) => RiskConfig::default()
+  }
+}
+
+// optional directory simulations can export their command stream to
+fn get_export_dir() -> CommandExportDir {
+  CommandExportDir(std::env::var("SIM_EXPORT_DIR").ok().map(|dir| Arc::new(dir.into())))
+}
+
+// optional cap on simulations running at once, later ones are queued
+fn get_max_concurrent_sims() -> usize {
+  match std::env::var("MAX_CONCURRENT_SIMS") {
+    Ok(max) => max.parse::<usize>().expect("max concurrent sims parse should not fail!"),
+    Err(_) => DEFAULT_MAX_CONCURRENT_SIMS
+  }
+}
 
+// optional cap on uploads processed at once, later ones stay queued
+fn get_max_upload_workers() -> usize {
+  match std::env::var("MAX_UPLOAD_WORKERS") {
+    Ok(max) => max.parse::<usize>().expect("max upload workers parse should not fail!"),
+    Err(_) => DEFAULT_MAX_UPLOAD_WORKERS
+  }
+}
+
+// optional book stats sampling interval for uploads that do not ask for one
+fn get_upload_book_stats() -> BookStatsConfig {
+  match std::env::var("UPLOAD_BOOK_STATS_INTERVAL") {
+    Ok(interval) => BookStatsConfig { interval: interval.parse::<usize>().expect("upload book stats interval parse should not fail!"), ..BookStatsConfig::default() },
+    Err(_) => BookStatsConfig::default()
+  }
+}
 
 #[tokio::main]
 async fn main() {
 
+  // offline generator, writes an upload file without starting the server
+  let args: Vec<String> = std::env::args().collect();
+  if args.get(1).map(String::as_str) == Some("generate") {
+    if let Err(e) = order_generator::export::run_cli(&args[2..], Arc::new(get_risk_config())) {
+      println!("{}", e);
+      std::process::exit(1);
+    }
+    return;
+  }
+  // offline converter, writes a text order file in the binary format
+  if args.get(1).map(String::as_str) == Some("convert") {
+    if let Err(e) = file_upload::binary::run_cli(&args[2..]) {
+      println!("{}", e);
+      std::process::exit(1);
+    }
+    return;
+  }
+
   let expected_origin = EXPECTED_ORIGIN.get_or_init(get_origin).await;
   let redis_url = REDIS_URL.get_or_init(get_redis).await;
   let db_url = DB_URL.get_or_init(get_postgres).await;
@@ -69,28 +127,64 @@ async fn main() {
   let rate_limiter = RateLimiter::new(redis_url, *ip_limit, *ip_window, *global_limit, *global_window).expect("failed to create ratelimiterl!");
   let db_pool = PostgresDBPool::new(db_url).await.expect("failed to create postgres connection pool!");
 
+  let risk_config = Arc::new(get_risk_config());
+  let depth_registry = DepthRegistry::new();
+  let simulation_services = SimulationServices {
+    risk_config: risk_config.clone(),
+    depth_registry: depth_registry.clone(),
+    export_dir: get_export_dir(),
+    slots: SimulationSlots::new(get_max_concurrent_sims())
+  };
+
   let small_upload_session_manager = SmallUploadSessionManager::new();
   let large_upload_session_manager = LargeUploadSessionManager::new();
+  let upload_jobs = UploadJobs::new(get_max_upload_workers());
+  // drops the sessions of abandoned uploads and the results nobody fetched
+  let (small_sessions, large_sessions, expired_jobs) = (small_upload_session_manager.clone(), large_upload_session_manager.clone(), upload_jobs.clone());
+  tokio::spawn(async move {
+    let mut sweep = tokio::time::interval(SESSION_SWEEP_INTERVAL);
+    loop {
+      sweep.tick().await;
+      small_sessions.expire().await;
+      large_sessions.expire().await;
+      expired_jobs.expire();
+    }
+  });
+  let upload_services = UploadServices {
+    risk_config: risk_config.clone(),
+    jobs: upload_jobs,
+    book_stats: get_upload_book_stats()
+  };
 
   let cors = CorsLayer::new()
-  .allow_methods([Method::GET, Method::POST])
+  .allow_methods([Method::GET, Method::POST, Method::DELETE])
   .allow_origin(expected_origin.parse::<HeaderValue>().unwrap())
   .allow_headers([
     CONTENT_TYPE,
     CONTENT_ENCODING,
     HeaderName::from_static("x-timestamp"),
     HeaderName::from_static("x-signature"),
+    HeaderName::from_static("x-chunk-sha256"),
     ]);
     
   let with_middleware = Router::new()
     .route("/wslob", any(ws_handler))
+    .route("/depth/{sim_id}", get(depth_handler))
+    .route("/uploads/{session_id}/chunks", get(chunk_status_handler)
+            .with_state((small_upload_session_manager.clone(), large_upload_session_manager.clone())))
     .route("/smallupload", post(small_upload_handler)
               .with_state(small_upload_session_manager))
     .route("/largeupload", post(large_upload_handler)
             .layer(DefaultBodyLimit::max(MAX_FILE_SIZE))
             .with_state(large_upload_session_manager))
+    .route("/jobs/{job_id}", get(get_job_handler).delete(cancel_job_handler))
+    .route("/jobs/{job_id}/trades", get(trades_download_handler))
+    .route("/jobs/{job_id}/book", get(book_download_handler))
     .layer(Extension(rate_limiter))
     .layer(Extension(db_pool))
+    .layer(Extension(upload_services))
+    .layer(Extension(depth_registry))
+    .layer(Extension(simulation_services))
     .layer(middleware::from_fn(ip_tracker_with_auth));
 
   let health_check = Router::new()
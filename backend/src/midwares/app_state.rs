@@ -1,11 +1,15 @@
-use std::sync::Arc;
+use std::{collections::{HashMap, VecDeque}, path::PathBuf, sync::Arc};
 use axum::{
   body::Bytes, http::StatusCode, response::IntoResponse, Json 
 };
+use futures::lock::Mutex;
 use serde::Serialize;
 use serde_json::json;
 use redis::{AsyncCommands, Client as RedisClient};
 use sqlx::{postgres::PgPoolOptions, PgPool};
+use tokio::sync::watch;
+
+use crate::{engine::snapshot::DepthReader, file_upload::binary::{is_binary, HEADER_SIZE, RECORD_SIZE}};
 
 // simple heurestic based on size to estimate orders 
 static ESTIMATED_ORDERS_PER_MB: i32 = 30_000;
@@ -26,7 +30,25 @@ pub enum AppError {
   DeserializeError(String),
   BadRequest(String),
   InternalError(String),
-  Unauthorized(String)
+  Unauthorized(String),
+  NotFound(String),
+  UnsupportedMediaType(String),
+  PayloadTooLarge(String)
+}
+
+impl AppError {
+  pub fn message(&self) -> &str {
+    match self {
+      Self::RateLimitExceeded(msg)
+      | Self::DeserializeError(msg)
+      | Self::BadRequest(msg)
+      | Self::InternalError(msg)
+      | Self::Unauthorized(msg)
+      | Self::NotFound(msg)
+      | Self::UnsupportedMediaType(msg)
+      | Self::PayloadTooLarge(msg) => msg
+    }
+  }
 }
 
 impl IntoResponse for AppError {
@@ -36,7 +58,10 @@ impl IntoResponse for AppError {
       Self::DeserializeError(msg) => (StatusCode::BAD_REQUEST, msg),
       Self::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg),
       Self::InternalError(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg),
-      Self::Unauthorized(msg) => (StatusCode::UNAUTHORIZED, msg)
+      Self::Unauthorized(msg) => (StatusCode::UNAUTHORIZED, msg),
+      Self::NotFound(msg) => (StatusCode::NOT_FOUND, msg),
+      Self::UnsupportedMediaType(msg) => (StatusCode::UNSUPPORTED_MEDIA_TYPE, msg),
+      Self::PayloadTooLarge(msg) => (StatusCode::PAYLOAD_TOO_LARGE, msg)
     };
 
     let body = Json(json!({"error": message, "code": status.as_u16()}));
@@ -224,10 +249,140 @@ impl PostgresDBPool {
   }
 }
 
+// depth readers of running simulations, keyed by simulation id
+#[derive(Clone)]
+pub struct DepthRegistry {
+  readers: Arc<Mutex<HashMap<String, DepthReader>>>
+}
+
+impl DepthRegistry {
+  pub fn new() -> Self {
+    Self { readers: Arc::new(Mutex::new(HashMap::new())) }
+  }
+
+  pub async fn register(&self, sim_id: &str, reader: DepthReader) {
+    self.readers.lock().await.insert(sim_id.to_string(), reader);
+  }
+
+  pub async fn get(&self, sim_id: &str) -> Option<DepthReader> {
+    self.readers.lock().await.get(sim_id).cloned()
+  }
+
+  pub async fn remove(&self, sim_id: &str) {
+    self.readers.lock().await.remove(sim_id);
+  }
+}
+
+// binary files have fixed size records, so their count is known up to the timestamps
 pub fn estimate_orders_from_1stchunk(chunk_data: &Bytes, total_chunks: &usize) -> usize {
 
+  if is_binary(chunk_data) {
+    return (chunk_data.len().saturating_sub(HEADER_SIZE) / RECORD_SIZE) * total_chunks;
+  }
+
   let chunk_size_mb = (chunk_data.len() as f64) / (1024.0 * 1024.0);
   let estimated_orders_in_chunk = (chunk_size_mb * ESTIMATED_ORDERS_PER_MB as f64) as usize;
 
  estimated_orders_in_chunk * total_chunks
+}
+
+// directory simulations export their command stream to, set with `SIM_EXPORT_DIR`
+#[derive(Clone)]
+pub struct CommandExportDir(pub Option<Arc<PathBuf>>);
+
+impl CommandExportDir {
+  pub fn file_for(&self, sim_id: &str) -> Option<PathBuf> {
+    self.0.as_ref().map(|dir| dir.join(format!("{}.txt", sim_id)))
+  }
+}
+
+
+// caps how many simulations run at once, the rest wait for a slot in arrival order
+#[derive(Clone)]
+pub struct SimulationSlots {
+  max_running: usize,
+  queue: Arc<std::sync::Mutex<SlotQueue>>,
+  // bumped whenever a slot frees up or someone leaves the queue
+  changed: Arc<watch::Sender<u64>>
+}
+
+#[derive(Default)]
+struct SlotQueue {
+  running: usize,
+  waiting: VecDeque<u64>,
+  next_ticket: u64
+}
+
+// place in the simulation queue, leaves the queue when dropped
+pub struct QueueTicket {
+  slots: SimulationSlots,
+  ticket: u64,
+  changed: watch::Receiver<u64>
+}
+
+// a running simulation, frees its slot when dropped
+pub struct SlotPermit {
+  slots: SimulationSlots
+}
+
+impl SimulationSlots {
+  pub fn new(max_running: usize) -> Self {
+    let (changed, _) = watch::channel(0);
+    Self { max_running: max_running.max(1), queue: Arc::new(std::sync::Mutex::new(SlotQueue::default())), changed: Arc::new(changed) }
+  }
+
+  pub fn enqueue(&self) -> QueueTicket {
+    let changed = self.changed.subscribe();
+    let mut queue = self.queue.lock().expect("simulation queue lock poisoned!");
+    let ticket = queue.next_ticket;
+    queue.next_ticket += 1;
+    queue.waiting.push_back(ticket);
+    QueueTicket { slots: self.clone(), ticket, changed }
+  }
+
+  fn notify(&self) {
+    self.changed.send_modify(|version| *version = version.wrapping_add(1));
+  }
+}
+
+impl QueueTicket {
+  // takes a slot if this ticket is first in line and one is free, otherwise returns the 1-based queue position
+  pub fn try_start(&mut self) -> Result<SlotPermit, usize> {
+    let mut queue = self.slots.queue.lock().expect("simulation queue lock poisoned!");
+    let position = queue.waiting.iter().position(|ticket| *ticket == self.ticket).expect("queued ticket should be waiting!");
+    if position == 0 && queue.running < self.slots.max_running {
+      queue.waiting.pop_front();
+      queue.running += 1;
+      drop(queue);
+      // the next ticket in line moved up
+      self.slots.notify();
+      return Ok(SlotPermit { slots: self.slots.clone() });
+    }
+    Err(position + 1)
+  }
+
+  // resolves once the queue changed and `try_start` is worth retrying
+  pub async fn changed(&mut self) {
+    let _ = self.changed.changed().await;
+  }
+}
+
+impl Drop for QueueTicket {
+  fn drop(&mut self) {
+    let mut queue = self.slots.queue.lock().expect("simulation queue lock poisoned!");
+    let before = queue.waiting.len();
+    queue.waiting.retain(|ticket| *ticket != self.ticket);
+    let left_queue = queue.waiting.len() != before;
+    drop(queue);
+    if left_queue {
+      self.slots.notify();
+    }
+  }
+}
+
+impl Drop for SlotPermit {
+  fn drop(&mut self) {
+    self.slots.queue.lock().expect("simulation queue lock poisoned!").running -= 1;
+    self.slots.notify();
+  }
 }
\ No newline at end of file
@@ -0,0 +1,440 @@
+use std::{collections::{HashMap, VecDeque}, sync::Arc};
+use rand::{distr::weighted::WeightedIndex, rngs::StdRng, Rng};
+use rand_distr::{Distribution, Normal};
+use rust_decimal::{prelude::{FromPrimitive, ToPrimitive}, Decimal};
+use protocol::ws::AgentStats;
+use serde::Deserialize;
+
+use crate::engine::orderbook::{Arena, BidOrAsk};
+
+use super::models::{best_price, book_mid, TICK};
+
+// trade prices kept for agents that react to recent prints
+const PRICE_HISTORY: usize = 1_000;
+// agents across all groups of one simulation, every one of them is reported on each agent step
+const MAX_AGENTS: usize = 1_000;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AgentGroup {
+  #[serde(flatten)]
+  pub agent: AgentConfig,
+  // number of identical agents in the group
+  #[serde(default = "default_count")]
+  pub count: usize,
+  // relative chance of an agent in this group acting on each simulation step
+  #[serde(default = "default_weight")]
+  pub weight: f64
+}
+
+fn default_count() -> usize { 1 }
+fn default_weight() -> f64 { 1.0 }
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "kind")]
+pub enum AgentConfig {
+  MarketMaker(MarketMakerParams),
+  Momentum(MomentumParams),
+  Noise(NoiseParams)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MarketMakerParams {
+  pub half_spread_ticks: u32,
+  pub quote_size: u64,
+  // stops quoting the side that would grow the inventory beyond this
+  pub max_inventory: i64,
+  // quotes are shifted by this many ticks against every `quote_size` of inventory
+  pub skew_ticks: f64
+}
+
+impl Default for MarketMakerParams {
+  fn default() -> Self {
+    MarketMakerParams { half_spread_ticks: 5, quote_size: 200, max_inventory: 2_000, skew_ticks: 1.0 }
+  }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct MomentumParams {
+  // number of trades to look back over
+  pub lookback: usize,
+  // minimum price move over the lookback that triggers an order
+  pub threshold: f64,
+  pub order_size: u64,
+  pub max_position: i64
+}
+
+impl Default for MomentumParams {
+  fn default() -> Self {
+    MomentumParams { lookback: 20, threshold: 0.5, order_size: 100, max_position: 1_000 }
+  }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct NoiseParams {
+  pub max_size: u64,
+  // sd of the limit price around the mid, large values make noise orders cross the spread
+  pub price_sd: f64,
+  pub cancel_prob: f64
+}
+
+impl Default for NoiseParams {
+  fn default() -> Self {
+    NoiseParams { max_size: 500, price_sd: 1.0, cancel_prob: 0.3 }
+  }
+}
+
+#[derive(Debug, Clone)]
+pub enum AgentCommand {
+  Add { side: BidOrAsk, shares: u64, price: f64 },
+  Modify { order_id: u64, shares: u64, price: f64 },
+  Cancel { order_id: u64 },
+  Idle
+}
+
+// result of a command as reported back by the simulator
+pub enum CommandOutcome {
+  Placed(u64, BidOrAsk),
+  Cancelled(u64),
+  Accepted,
+  Rejected,
+  Skipped
+}
+
+// what an agent sees when it is scheduled
+pub struct MarketView<'a> {
+  pub book: &'a Arena,
+  pub recent_prices: &'a VecDeque<f64>,
+  pub position: i64,
+  pub reference_price: f64
+}
+
+impl MarketView<'_> {
+  fn mid(&self) -> f64 {
+    book_mid(self.book).unwrap_or(self.reference_price)
+  }
+
+  fn is_live(&self, order_id: u64) -> bool {
+    self.book.orders.contains_key(&order_id)
+  }
+}
+
+pub trait Agent: Send {
+  fn act(&mut self, view: &MarketView, rng: &mut StdRng) -> AgentCommand;
+
+  // called once an ADD from this agent reached the book
+  fn on_placed(&mut self, order_id: u64, side: BidOrAsk);
+}
+
+// quotes both sides around the mid and leans its quotes against its inventory
+struct MarketMaker {
+  params: MarketMakerParams,
+  bid: Option<u64>,
+  ask: Option<u64>,
+  requote_bid: bool
+}
+
+impl Agent for MarketMaker {
+  fn act(&mut self, view: &MarketView, _rng: &mut StdRng) -> AgentCommand {
+    self.bid = self.bid.filter(|id| view.is_live(*id));
+    self.ask = self.ask.filter(|id| view.is_live(*id));
+
+    let MarketMakerParams { half_spread_ticks, quote_size, max_inventory, skew_ticks } = self.params;
+    let skew = skew_ticks * TICK * view.position as f64 / quote_size.max(1) as f64;
+    let bid_price = view.mid() - half_spread_ticks as f64 * TICK - skew;
+    let ask_price = view.mid() + half_spread_ticks as f64 * TICK - skew;
+
+    // pull the quote that would grow an inventory already at its limit
+    if view.position >= max_inventory {
+      if let Some(order_id) = self.bid.take() {
+        return AgentCommand::Cancel { order_id };
+      }
+    }
+    if view.position <= -max_inventory {
+      if let Some(order_id) = self.ask.take() {
+        return AgentCommand::Cancel { order_id };
+      }
+    }
+
+    if self.bid.is_none() && view.position < max_inventory {
+      return AgentCommand::Add { side: BidOrAsk::Bid, shares: quote_size, price: bid_price };
+    }
+    if self.ask.is_none() && view.position > -max_inventory {
+      return AgentCommand::Add { side: BidOrAsk::Ask, shares: quote_size, price: ask_price };
+    }
+
+    // both quotes are resting, move one of them to the new mid
+    self.requote_bid = !self.requote_bid;
+    let quote = if self.requote_bid { self.bid.map(|id| (id, bid_price)) } else { self.ask.map(|id| (id, ask_price)) };
+    match quote {
+      Some((order_id, price)) => AgentCommand::Modify { order_id, shares: quote_size, price },
+      None => AgentCommand::Idle
+    }
+  }
+
+  fn on_placed(&mut self, order_id: u64, side: BidOrAsk) {
+    match side {
+      BidOrAsk::Bid => self.bid = Some(order_id),
+      BidOrAsk::Ask => self.ask = Some(order_id)
+    }
+  }
+}
+
+// takes liquidity in the direction of the recent price move, any unfilled remainder is cancelled on its next turn
+struct Momentum {
+  params: MomentumParams,
+  resting: Vec<u64>
+}
+
+impl Agent for Momentum {
+  fn act(&mut self, view: &MarketView, _rng: &mut StdRng) -> AgentCommand {
+    self.resting.retain(|id| view.is_live(*id));
+    if let Some(order_id) = self.resting.pop() {
+      return AgentCommand::Cancel { order_id };
+    }
+
+    let prices = view.recent_prices;
+    if prices.len() <= self.params.lookback {
+      return AgentCommand::Idle;
+    }
+    let price_move = prices[prices.len() - 1] - prices[prices.len() - 1 - self.params.lookback];
+
+    if price_move > self.params.threshold && view.position < self.params.max_position {
+      if let Some(price) = best_price(view.book.lowest_sell) {
+        return AgentCommand::Add { side: BidOrAsk::Bid, shares: self.params.order_size, price };
+      }
+    } else if price_move < -self.params.threshold && view.position > -self.params.max_position {
+      if let Some(price) = best_price(view.book.highest_buy) {
+        return AgentCommand::Add { side: BidOrAsk::Ask, shares: self.params.order_size, price };
+      }
+    }
+    AgentCommand::Idle
+  }
+
+  fn on_placed(&mut self, order_id: u64, _side: BidOrAsk) {
+    self.resting.push(order_id);
+  }
+}
+
+// random orders around the mid and random cancels of its own resting orders
+struct Noise {
+  params: NoiseParams,
+  price_offset: Normal<f64>,
+  open_orders: Vec<u64>
+}
+
+impl Agent for Noise {
+  fn act(&mut self, view: &MarketView, rng: &mut StdRng) -> AgentCommand {
+    self.open_orders.retain(|id| view.is_live(*id));
+
+    if !self.open_orders.is_empty() && rng.random_bool(self.params.cancel_prob.clamp(0.0, 1.0)) {
+      let idx = rng.random_range(0..self.open_orders.len());
+      return AgentCommand::Cancel { order_id: self.open_orders.swap_remove(idx) };
+    }
+
+    let side = if rng.random_bool(0.5) { BidOrAsk::Bid } else { BidOrAsk::Ask };
+    let shares = rng.random_range(1..=self.params.max_size.max(1));
+    let price = view.mid() + self.price_offset.sample(rng);
+    AgentCommand::Add { side, shares, price }
+  }
+
+  fn on_placed(&mut self, order_id: u64, _side: BidOrAsk) {
+    self.open_orders.push(order_id);
+  }
+}
+
+impl AgentConfig {
+  fn name(&self) -> &'static str {
+    match self {
+      Self::MarketMaker(_) => "market_maker",
+      Self::Momentum(_) => "momentum",
+      Self::Noise(_) => "noise"
+    }
+  }
+
+  fn build(&self) -> Result<Box<dyn Agent>, String> {
+    Ok(match self {
+      Self::MarketMaker(params) => Box::new(MarketMaker { params: params.clone(), bid: None, ask: None, requote_bid: false }),
+      Self::Momentum(params) => Box::new(Momentum { params: params.clone(), resting: Vec::new() }),
+      Self::Noise(params) if !(params.price_sd >= 0.0 && params.price_sd.is_finite()) => {
+        return Err(format!("noise price_sd should be a finite number >= 0, got {}", params.price_sd));
+      },
+      Self::Noise(params) => Box::new(Noise {
+        params: params.clone(),
+        price_offset: Normal::new(0.0, params.price_sd).expect("error creating a normal dist for noise trader prices"),
+        open_orders: Vec::new()
+      })
+    })
+  }
+}
+
+// Schedules the agents, routes fills back to the agent owning the order and keeps per-agent stats
+pub struct AgentPool {
+  agents: Vec<Box<dyn Agent>>,
+  accounts: Vec<Arc<str>>,
+  stats: Vec<AgentStats>,
+  scheduler: WeightedIndex<f64>,
+  // agent and side of every agent order that may still fill
+  owners: HashMap<u64, (usize, BidOrAsk)>,
+  recent_prices: VecDeque<f64>,
+  reference_price: f64,
+  executed_orders_offset: usize
+}
+
+impl AgentPool {
+  // the groups come from the client, checked before any agent is allocated
+  pub fn new(groups: &[AgentGroup], reference_price: f64) -> Result<Self, String> {
+    let total_agents = groups.iter().fold(0usize, |total, group| total.saturating_add(group.count));
+    if total_agents > MAX_AGENTS {
+      return Err(format!("at most {} agents are allowed, got {}", MAX_AGENTS, total_agents));
+    }
+    if let Some(group) = groups.iter().find(|group| !(group.weight.is_finite() && group.weight >= 0.0)) {
+      return Err(format!("agent weights should be finite numbers >= 0, got {} for a {} group", group.weight, group.agent.name()));
+    }
+    if !groups.iter().any(|group| group.count > 0 && group.weight > 0.0) {
+      return Err("at least one agent group needs a count and a weight above 0".to_string());
+    }
+
+    let (mut agents, mut accounts, mut stats, mut weights) = (Vec::new(), Vec::new(), Vec::new(), Vec::new());
+
+    for group in groups {
+      let kind = group.agent.name();
+      for _ in 0..group.count {
+        // each agent trades under its own risk account, e.g. "market_maker_0"
+        let ordinal = stats.iter().filter(|s: &&AgentStats| s.kind == kind).count();
+        let account = format!("{}_{}", kind, ordinal);
+        agents.push(group.agent.build()?);
+        accounts.push(Arc::from(account.as_str()));
+        stats.push(AgentStats { account, kind: kind.to_string(), orders_sent: 0, risk_rejects: 0, fills: 0, bought: 0, sold: 0, position: 0, cash: Decimal::ZERO, pnl: Decimal::ZERO });
+        weights.push(group.weight);
+      }
+    }
+
+    Ok(AgentPool {
+      agents,
+      accounts,
+      stats,
+      scheduler: WeightedIndex::new(weights).expect("agent groups should have positive weights!"),
+      owners: HashMap::new(),
+      recent_prices: VecDeque::with_capacity(PRICE_HISTORY),
+      reference_price,
+      executed_orders_offset: 0
+    })
+  }
+
+  // picks the agent acting on this step and asks it for a command
+  pub fn next_command(&mut self, book: &Arena, rng: &mut StdRng) -> (usize, AgentCommand) {
+    let agent = self.scheduler.sample(rng);
+    let view = MarketView { book, recent_prices: &self.recent_prices, position: self.stats[agent].position, reference_price: self.reference_price };
+    (agent, self.agents[agent].act(&view, rng))
+  }
+
+  pub fn account(&self, agent: usize) -> Arc<str> {
+    self.accounts[agent].clone()
+  }
+
+  pub fn on_outcome(&mut self, agent: usize, outcome: CommandOutcome) {
+    let stats = &mut self.stats[agent];
+    match outcome {
+      CommandOutcome::Placed(order_id, side) => {
+        stats.orders_sent += 1;
+        self.owners.insert(order_id, (agent, side.clone()));
+        self.agents[agent].on_placed(order_id, side);
+      },
+      CommandOutcome::Cancelled(order_id) => {
+        stats.orders_sent += 1;
+        self.owners.remove(&order_id);
+      },
+      CommandOutcome::Accepted => stats.orders_sent += 1,
+      CommandOutcome::Rejected => {
+        stats.orders_sent += 1;
+        stats.risk_rejects += 1;
+      },
+      CommandOutcome::Skipped => {}
+    }
+  }
+
+  // books the trades since the last call against the agents on either side
+  pub fn settle_fills(&mut self, book: &Arena) {
+    let mut filled = Vec::new();
+    for trade in &book.executed_orders[self.executed_orders_offset..] {
+      if self.recent_prices.len() == PRICE_HISTORY {
+        self.recent_prices.pop_front();
+      }
+      self.recent_prices.push_back(trade.price.to_f64().unwrap_or(self.reference_price));
+
+      for order_id in [trade.aggresive_order_id, trade.passive_order_id] {
+        let Some((agent, side)) = self.owners.get(&order_id) else { continue };
+        let stats = &mut self.stats[*agent];
+        filled.push(order_id);
+        let notional = trade.price * Decimal::from(trade.volume);
+        stats.fills += 1;
+        match side {
+          BidOrAsk::Bid => {
+            stats.bought += trade.volume;
+            stats.position += trade.volume as i64;
+            stats.cash -= notional;
+          },
+          BidOrAsk::Ask => {
+            stats.sold += trade.volume;
+            stats.position -= trade.volume as i64;
+            stats.cash += notional;
+          }
+        }
+      }
+    }
+    self.executed_orders_offset = book.executed_orders.len();
+    // orders no longer resting were fully filled (or never rested) and cannot fill again
+    for order_id in filled {
+      if !book.orders.contains_key(&order_id) {
+        self.owners.remove(&order_id);
+      }
+    }
+  }
+
+  pub fn report(&self, book: &Arena) -> Vec<AgentStats> {
+    let mark = book.executed_orders.last().map(|t| t.price)
+      .or_else(|| book_mid(book).and_then(Decimal::from_f64))
+      .unwrap_or_default();
+
+    self.stats.iter().map(|stats| {
+      let mut stats = stats.clone();
+      stats.pnl = stats.cash + mark * Decimal::from(stats.position);
+      stats
+    }).collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn owners_are_dropped_once_their_orders_are_cancelled_or_filled() {
+    let groups = [AgentGroup { agent: AgentConfig::Noise(NoiseParams::default()), count: 1, weight: 1.0 }];
+    let mut pool = AgentPool::new(&groups, 100.0).expect("a single noise agent should be valid!");
+    let mut book = Arena::new(false);
+
+    book.add_limit_order(1, BidOrAsk::Bid, 10, Decimal::from(99));
+    pool.on_outcome(0, CommandOutcome::Placed(1, BidOrAsk::Bid));
+    book.add_limit_order(2, BidOrAsk::Bid, 10, Decimal::from(98));
+    pool.on_outcome(0, CommandOutcome::Placed(2, BidOrAsk::Bid));
+
+    // a partial fill keeps the owner, the rest of the order still rests
+    book.add_market_order(100, BidOrAsk::Ask, 4);
+    pool.settle_fills(&book);
+    assert!(pool.owners.contains_key(&1));
+    // two more fills in one settlement are all booked before the owner goes
+    book.add_market_order(101, BidOrAsk::Ask, 3);
+    book.add_market_order(102, BidOrAsk::Ask, 3);
+    pool.settle_fills(&book);
+    assert!(!pool.owners.contains_key(&1));
+    assert_eq!((pool.stats[0].fills, pool.stats[0].bought, pool.stats[0].position), (3, 10, 10));
+
+    book.cancel_limit_order(2);
+    pool.on_outcome(0, CommandOutcome::Cancelled(2));
+    assert!(pool.owners.is_empty());
+  }
+}
@@ -0,0 +1,83 @@
+use std::time::Instant;
+use rand::rngs::StdRng;
+use rand_distr::{Distribution, Exp};
+use serde::{Deserialize, Deserializer};
+
+pub fn default_arrival_rate() -> f64 { 1_000.0 }
+// one order every ~17 minutes, slower rates would push the clock past u64 nanoseconds within a run
+const MIN_ARRIVAL_RATE: f64 = 0.001;
+
+// `arrival_rate` of the START message, anything the clock cannot run with fails the parse
+pub fn deserialize_arrival_rate<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+  let arrival_rate = f64::deserialize(deserializer)?;
+  if !(arrival_rate.is_finite() && arrival_rate >= MIN_ARRIVAL_RATE) {
+    return Err(serde::de::Error::custom(format!("arrival_rate should be a finite number >= {}, got {}", MIN_ARRIVAL_RATE, arrival_rate)));
+  }
+  Ok(arrival_rate)
+}
+
+// Simulated exchange time. Orders arrive as a Poisson process with `arrival_rate` orders per second
+pub struct SimClock {
+  now_ns: u64,
+  mean_gap_ns: f64,
+  inter_arrival: Exp<f64>
+}
+
+impl SimClock {
+  pub fn new(arrival_rate: f64) -> Self {
+    SimClock { now_ns: 0, mean_gap_ns: 1e9 / arrival_rate, inter_arrival: Exp::new(arrival_rate / 1e9).expect("arrival rate should be positive!") }
+  }
+
+  // advance to the arrival time of the next order
+  pub fn tick(&mut self, rng: &mut StdRng) {
+    self.now_ns = self.now_ns.saturating_add(self.inter_arrival.sample(rng).round() as u64);
+  }
+
+  // advance by a gap from a model with its own arrival process, measured in mean inter-arrival times
+  pub fn advance(&mut self, gap: f64) {
+    self.now_ns = self.now_ns.saturating_add((gap * self.mean_gap_ns).round() as u64);
+  }
+
+  pub fn now_ns(&self) -> u64 {
+    self.now_ns
+  }
+}
+
+// ends a run before `total_objects` orders once this much time has passed
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunDuration {
+  // simulated time of the arrival process
+  SimulatedMs(u64),
+  // real time since seeding finished, pauses included
+  WallClockMs(u64)
+}
+
+impl RunDuration {
+  pub fn reached(&self, clock: &SimClock, started: Instant) -> bool {
+    match self {
+      Self::SimulatedMs(ms) => clock.now_ns() >= ms.saturating_mul(1_000_000),
+      Self::WallClockMs(ms) => started.elapsed().as_millis() >= *ms as u128
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[derive(Debug, Deserialize)]
+  struct Rate {
+    #[serde(deserialize_with = "deserialize_arrival_rate")]
+    arrival_rate: f64
+  }
+
+  #[test]
+  fn arrival_rates_the_clock_cannot_run_with_fail_to_parse() {
+    for rate in ["0", "-5", "0.0000001", "1e400"] {
+      assert!(serde_json::from_str::<Rate>(&format!("{{\"arrival_rate\": {}}}", rate)).is_err(), "{} should be rejected", rate);
+    }
+    let rate = serde_json::from_str::<Rate>("{\"arrival_rate\": 250.5}").expect("a positive rate should parse!");
+    assert_eq!(rate.arrival_rate, 250.5);
+  }
+}
@@ -0,0 +1,252 @@
+use std::str::FromStr;
+use rand::{rngs::StdRng, Rng};
+use rand_distr::{Bernoulli, Distribution, Exp1, LogNormal, Pareto, Uniform};
+use rust_decimal::Decimal;
+use serde::Deserialize;
+
+// highest price level a simulation may center on, far from the range of `Decimal`
+pub const MAX_LIMIT_PRICE: f64 = 1_000_000_000.0;
+
+// Rounds to a 2 decimal limit price. Prices drifting below zero (or NaN) are floored at one tick
+// and runaway ones capped at `MAX_LIMIT_PRICE`, so the result always fits a `Decimal`
+pub fn to_limit_price(price: f64) -> Decimal {
+  let price = if price.is_nan() { 0.01 } else { price.clamp(0.01, MAX_LIMIT_PRICE) };
+  let price_string = format!("{:.2}", price);
+  Decimal::from_str(&price_string).expect("parsing price string to decimal failed")
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct UniformQtyParams {
+  pub min: u64,
+  pub max: u64
+}
+
+impl Default for UniformQtyParams {
+  fn default() -> Self {
+    UniformQtyParams { min: 1, max: 999 }
+  }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LogNormalQtyParams {
+  pub median: f64,
+  pub sigma: f64
+}
+
+impl Default for LogNormalQtyParams {
+  fn default() -> Self {
+    LogNormalQtyParams { median: 200.0, sigma: 1.0 }
+  }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ParetoQtyParams {
+  // smallest order size
+  pub scale: f64,
+  // tail index, smaller values give heavier tails
+  pub shape: f64
+}
+
+impl Default for ParetoQtyParams {
+  fn default() -> Self {
+    ParetoQtyParams { scale: 50.0, shape: 1.5 }
+  }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RoundLotQtyParams {
+  pub lot_size: u64,
+  // sizes are drawn lognormal and snapped to the nearest lot with `round_lot_prob`
+  pub median: f64,
+  pub sigma: f64,
+  pub round_lot_prob: f64
+}
+
+impl Default for RoundLotQtyParams {
+  fn default() -> Self {
+    RoundLotQtyParams { lot_size: 100, median: 300.0, sigma: 1.0, round_lot_prob: 0.8 }
+  }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "dist")]
+pub enum QuantityDistConfig {
+  Uniform(UniformQtyParams),
+  LogNormal(LogNormalQtyParams),
+  Pareto(ParetoQtyParams),
+  RoundLot(RoundLotQtyParams)
+}
+
+impl Default for QuantityDistConfig {
+  fn default() -> Self {
+    QuantityDistConfig::Uniform(UniformQtyParams::default())
+  }
+}
+
+// order sizes, always at least one share
+#[derive(Debug, Clone)]
+pub enum QuantitySampler {
+  Uniform(Uniform<u64>),
+  LogNormal(LogNormal<f64>),
+  Pareto(Pareto<f64>),
+  RoundLot { lot_size: u64, sizes: LogNormal<f64>, round_lot: Bernoulli }
+}
+
+impl QuantitySampler {
+  // the parameters come from the client, invalid ones are reported instead of panicking the simulation
+  pub fn new(config: &QuantityDistConfig) -> Result<Self, String> {
+    Ok(match config {
+      QuantityDistConfig::Uniform(p) => Self::Uniform(Uniform::new_inclusive(p.min.max(1), p.max.max(1))
+        .map_err(|_| format!("uniform quantity min should not exceed max, got min {} and max {}", p.min, p.max))?),
+      QuantityDistConfig::LogNormal(p) => Self::LogNormal(lognormal_sizes(p.median, p.sigma)?),
+      QuantityDistConfig::Pareto(p) => Self::Pareto(Pareto::new(p.scale, p.shape)
+        .map_err(|_| format!("pareto quantity scale and shape should be finite numbers > 0, got scale {} and shape {}", p.scale, p.shape))?),
+      QuantityDistConfig::RoundLot(p) => Self::RoundLot {
+        lot_size: p.lot_size.max(1),
+        sizes: lognormal_sizes(p.median, p.sigma)?,
+        round_lot: Bernoulli::new(p.round_lot_prob).map_err(|_| format!("round_lot_prob should be within [0, 1], got {}", p.round_lot_prob))?
+      }
+    })
+  }
+}
+
+fn lognormal_sizes(median: f64, sigma: f64) -> Result<LogNormal<f64>, String> {
+  if !(median > 0.0 && median.is_finite()) {
+    return Err(format!("quantity median should be a finite number > 0, got {}", median));
+  }
+  if !(sigma >= 0.0 && sigma.is_finite()) {
+    return Err(format!("quantity sigma should be a finite number >= 0, got {}", sigma));
+  }
+  Ok(LogNormal::new(median.ln(), sigma).expect("error creating lognormal dist for shares/qty"))
+}
+
+impl Distribution<u64> for QuantitySampler {
+  fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> u64 {
+    let shares = match self {
+      Self::Uniform(dist) => return dist.sample(rng),
+      Self::LogNormal(dist) => dist.sample(rng).round() as u64,
+      Self::Pareto(dist) => dist.sample(rng).round() as u64,
+      Self::RoundLot { lot_size, sizes, round_lot } => {
+        let size = sizes.sample(rng).round() as u64;
+        if round_lot.sample(rng) { (size + lot_size / 2) / lot_size * lot_size } else { size }
+      }
+    };
+    shares.max(1)
+  }
+}
+
+// Normal distribution sampled by inverting its CDF over the allowed region, so the cost stays
+// constant however far into the tail the bound is (e.g. a very narrow `sd_price`)
+#[derive(Debug, Clone, Copy)]
+pub struct TruncatedNormal {
+  mean: f64,
+  sd: f64
+}
+
+impl TruncatedNormal {
+  pub fn new(mean: f64, sd: f64) -> Self {
+    TruncatedNormal { mean, sd }
+  }
+
+  // draw conditioned on being above `lower`
+  pub fn sample_above(&self, lower: f64, rng: &mut StdRng) -> f64 {
+    if self.sd.is_nan() || self.sd <= 0.0 {
+      return self.mean.max(lower);
+    }
+    let z = standard_normal_above((lower - self.mean) / self.sd, rng);
+    self.mean + self.sd * z
+  }
+
+  // draw conditioned on being below `upper`
+  pub fn sample_below(&self, upper: f64, rng: &mut StdRng) -> f64 {
+    if self.sd.is_nan() || self.sd <= 0.0 {
+      return self.mean.min(upper);
+    }
+    let z = -standard_normal_above((self.mean - upper) / self.sd, rng);
+    self.mean + self.sd * z
+  }
+}
+
+fn standard_normal_above(a: f64, rng: &mut StdRng) -> f64 {
+  let u: f64 = rng.random();
+  if a <= 0.0 {
+    // most of the mass is allowed, invert the lower CDF
+    let p_lo = normal_upper_tail(-a);
+    return inverse_normal_cdf(p_lo + u * (1.0 - p_lo));
+  }
+  // invert the upper tail directly to keep precision far out in the tail
+  let tail = normal_upper_tail(a);
+  if tail > 0.0 {
+    return -inverse_normal_cdf((u * tail).max(f64::MIN_POSITIVE));
+  }
+  // beyond double precision the tail is close to an exponential with rate `a`
+  let e: f64 = rng.sample(Exp1);
+  a + e / a
+}
+
+// P(Z > x) for a standard normal Z, accurate to ~1e-7 relative error (Numerical Recipes erfc)
+fn normal_upper_tail(x: f64) -> f64 {
+  let z = (x / std::f64::consts::SQRT_2).abs();
+  let t = 1.0 / (1.0 + 0.5 * z);
+  let erfc = t * (-z * z - 1.26551223 + t * (1.00002368 + t * (0.37409196 + t * (0.09678418 + t * (-0.18628806
+    + t * (0.27886807 + t * (-1.13520398 + t * (1.48851587 + t * (-0.82215223 + t * 0.17087277))))))))).exp();
+  if x >= 0.0 { 0.5 * erfc } else { 1.0 - 0.5 * erfc }
+}
+
+// inverse of the standard normal CDF (Acklam's rational approximation)
+fn inverse_normal_cdf(p: f64) -> f64 {
+  const A: [f64; 6] = [-3.969683028665376e+01, 2.209460984245205e+02, -2.759285104469687e+02, 1.38357751867269e+02, -3.066479806614716e+01, 2.506628277459239e+00];
+  const B: [f64; 5] = [-5.447609879822406e+01, 1.615858368580409e+02, -1.556989798598866e+02, 6.680131188771972e+01, -1.328068155288572e+01];
+  const C: [f64; 6] = [-7.784894002430293e-03, -3.223964580411365e-01, -2.400758277161838e+00, -2.549732539343734e+00, 4.374664141464968e+00, 2.938163982698783e+00];
+  const D: [f64; 4] = [7.784695709041462e-03, 3.224671290700398e-01, 2.445134137142996e+00, 3.754408661907416e+00];
+  const P_LOW: f64 = 0.02425;
+
+  let p = p.clamp(f64::MIN_POSITIVE, 1.0 - f64::EPSILON);
+  if p < P_LOW {
+    let q = (-2.0 * p.ln()).sqrt();
+    (((((C[0] * q + C[1]) * q + C[2]) * q + C[3]) * q + C[4]) * q + C[5]) / ((((D[0] * q + D[1]) * q + D[2]) * q + D[3]) * q + 1.0)
+  } else if p <= 1.0 - P_LOW {
+    let q = p - 0.5;
+    let r = q * q;
+    (((((A[0] * r + A[1]) * r + A[2]) * r + A[3]) * r + A[4]) * r + A[5]) * q / (((((B[0] * r + B[1]) * r + B[2]) * r + B[3]) * r + B[4]) * r + 1.0)
+  } else {
+    let q = (-2.0 * (1.0 - p).ln()).sqrt();
+    -(((((C[0] * q + C[1]) * q + C[2]) * q + C[3]) * q + C[4]) * q + C[5]) / ((((D[0] * q + D[1]) * q + D[2]) * q + D[3]) * q + 1.0)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn invalid_quantity_params_are_rejected() {
+    let invalid = [
+      QuantityDistConfig::Uniform(UniformQtyParams { min: 10, max: 5 }),
+      QuantityDistConfig::LogNormal(LogNormalQtyParams { median: 0.0, sigma: 1.0 }),
+      QuantityDistConfig::LogNormal(LogNormalQtyParams { median: 200.0, sigma: -1.0 }),
+      QuantityDistConfig::Pareto(ParetoQtyParams { scale: -50.0, shape: 1.5 }),
+      QuantityDistConfig::Pareto(ParetoQtyParams { scale: 50.0, shape: 0.0 }),
+      QuantityDistConfig::RoundLot(RoundLotQtyParams { round_lot_prob: 1.5, ..RoundLotQtyParams::default() }),
+      QuantityDistConfig::RoundLot(RoundLotQtyParams { median: -300.0, ..RoundLotQtyParams::default() })
+    ];
+    for config in &invalid {
+      assert!(QuantitySampler::new(config).is_err(), "{:?} should be rejected", config);
+    }
+    assert!(QuantitySampler::new(&QuantityDistConfig::RoundLot(RoundLotQtyParams::default())).is_ok());
+  }
+
+  #[test]
+  fn limit_prices_stay_within_a_tick_and_the_price_cap() {
+    assert_eq!(to_limit_price(100.456), Decimal::new(10_046, 2));
+    assert_eq!(to_limit_price(-5.0), Decimal::new(1, 2));
+    assert_eq!(to_limit_price(f64::NAN), Decimal::new(1, 2));
+    let cap = Decimal::from_str(&format!("{:.2}", MAX_LIMIT_PRICE)).expect("the cap should be a decimal!");
+    assert_eq!(to_limit_price(1e300), cap);
+    assert_eq!(to_limit_price(f64::INFINITY), cap);
+  }
+}
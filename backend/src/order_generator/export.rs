@@ -0,0 +1,119 @@
+use std::{fs::File, io::{self, BufWriter, Write}, path::Path, sync::Arc, time::Instant};
+use rust_decimal::Decimal;
+use protocol::grammar::{FileUploadOrderType, OrderLine, LATEST_VERSION};
+
+use crate::{engine::{orderbook::BidOrAsk, risk::RiskConfig}, file_upload::binary::BinaryHeader};
+
+use super::gen::{Simulator, SimulatorConfig};
+
+// Writes the commands the engine processed as an order file accepted by the upload routes,
+// so a simulation can be replayed through them. Text by default, binary records when `binary` is set
+pub struct CommandWriter {
+  out: BufWriter<Box<dyn Write + Send>>,
+  binary: Option<BinaryHeader>,
+  lines: u64
+}
+
+impl CommandWriter {
+  // starts the file with its format header, which is not counted as a line
+  pub fn new(out: Box<dyn Write + Send>) -> io::Result<Self> {
+    let mut out = BufWriter::new(out);
+    writeln!(out, "VERSION,{}", LATEST_VERSION)?;
+    Ok(CommandWriter { out, binary: None, lines: 0 })
+  }
+
+  pub fn new_binary(out: Box<dyn Write + Send>) -> io::Result<Self> {
+    let mut out = BufWriter::new(out);
+    let header = BinaryHeader::new(false);
+    out.write_all(&header.encode())?;
+    Ok(CommandWriter { out, binary: Some(header), lines: 0 })
+  }
+
+  // files ending in `.bin` are written in the binary format
+  pub fn create(path: &Path) -> io::Result<Self> {
+    let file = Box::new(File::create(path)?);
+    match path.extension() {
+      Some(extension) if extension == "bin" => Self::new_binary(file),
+      _ => Self::new(file)
+    }
+  }
+
+  pub fn add(&mut self, order_id: u64, bid_or_ask: &BidOrAsk, shares: u64, price: Decimal) -> io::Result<()> {
+    self.lines += 1;
+    match self.binary {
+      None => writeln!(self.out, "ADD,{},{},{},{:.2}", order_id, bid_or_ask, shares, price),
+      Some(header) => self.write_record(header, FileUploadOrderType::Add { id: order_id, side: bid_or_ask.into(), shares, price })
+    }
+  }
+
+  pub fn modify(&mut self, order_id: u64, shares: u64, price: Decimal) -> io::Result<()> {
+    self.lines += 1;
+    match self.binary {
+      None => writeln!(self.out, "MODIFY,{},{},{:.2}", order_id, shares, price),
+      Some(header) => self.write_record(header, FileUploadOrderType::Modify { id: order_id, shares, price })
+    }
+  }
+
+  pub fn cancel(&mut self, order_id: u64) -> io::Result<()> {
+    self.lines += 1;
+    match self.binary {
+      None => writeln!(self.out, "CANCEL,{}", order_id),
+      Some(header) => self.write_record(header, FileUploadOrderType::Cancel { id: order_id })
+    }
+  }
+
+  // market orders without a limit, IOC orders with one
+  pub fn immediate(&mut self, order_id: u64, bid_or_ask: &BidOrAsk, shares: u64, limit_price: Option<Decimal>) -> io::Result<()> {
+    self.lines += 1;
+    match (self.binary, limit_price) {
+      (None, None) => writeln!(self.out, "MARKET,{},{},{}", order_id, bid_or_ask, shares),
+      (None, Some(price)) => writeln!(self.out, "IOC,{},{},{},{:.2}", order_id, bid_or_ask, shares, price),
+      (Some(header), None) => self.write_record(header, FileUploadOrderType::Market { id: order_id, side: bid_or_ask.into(), shares }),
+      (Some(header), Some(price)) => self.write_record(header, FileUploadOrderType::Ioc { id: order_id, side: bid_or_ask.into(), shares, price })
+    }
+  }
+
+  fn write_record(&mut self, header: BinaryHeader, order: FileUploadOrderType) -> io::Result<()> {
+    let mut record = Vec::with_capacity(header.record_size());
+    header.encode_record(&OrderLine { line: 0, timestamp: None, symbol: None, order }, &mut record).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    self.out.write_all(&record)
+  }
+
+  pub fn lines(&self) -> u64 {
+    self.lines
+  }
+
+  pub fn flush(&mut self) -> io::Result<()> {
+    self.out.flush()
+  }
+}
+
+// offline generator: `backend generate <config.json> <total_orders> <out_file>`.
+// The config uses the same fields as the websocket START message, a `duration` may end the run early.
+// An `out_file` ending in `.bin` is written in the binary format
+pub fn run_cli(args: &[String], risk_config: Arc<RiskConfig>) -> Result<(), String> {
+  let [config_path, total_orders, out_path] = args else {
+    return Err("usage: backend generate <config.json> <total_orders> <out_file>".to_string());
+  };
+
+  let config_file = std::fs::read_to_string(config_path).map_err(|e| format!("failed to read {}: {}", config_path, e))?;
+  let config: SimulatorConfig = serde_json::from_str(&config_file).map_err(|e| format!("invalid simulator config: {}", e))?;
+  let total_orders: usize = total_orders.parse().map_err(|e| format!("invalid total orders {:?}: {}", total_orders, e))?;
+  let mut simulator = Simulator::new(config, risk_config).map_err(|e| format!("invalid simulator config: {}", e))?;
+  let writer = CommandWriter::create(Path::new(out_path)).map_err(|e| format!("failed to create {}: {}", out_path, e))?;
+
+  let start = Instant::now();
+  simulator.export_commands(writer);
+  simulator.seed_orderbook();
+  for idx in 0..total_orders {
+    if simulator.duration_reached() {
+      break;
+    }
+    simulator.run_scenario(idx);
+    simulator.generate_orders();
+  }
+
+  let lines = simulator.finish_export().map_err(|e| format!("failed to write {}: {}", out_path, e))?;
+  println!("[INFO] wrote {} commands to {} in {:?} (seed: {})", lines, out_path, start.elapsed(), simulator.seed());
+  Ok(())
+}
@@ -1,216 +1,498 @@
-use std::{str::FromStr, time::Instant};
+use std::{io, sync::Arc, time::Instant};
 use rand::{rngs::StdRng, SeedableRng};
-use rand_distr::{Bernoulli, Distribution, Normal, Uniform};
-use rust_decimal::{prelude::ToPrimitive, Decimal};
-use serde::Serialize;
-
-use crate::engine::orderbook::{Arena, BidOrAsk, ExecutedOrders};
-
-#[derive(Debug, Serialize)]
-// #[serde(tag = "type")]
-pub enum WsResponse {
-  PriceLevels { snapshot: bool, bids: Vec<(Decimal, u64)>, asks: Vec<(Decimal, u64)> },
-  Trades (Vec<ExecutedOrders>),
-  ExecutionStats (EngineStats),
-  BestLevels {best_buy: Option<Decimal>, best_sell: Option<Decimal>},
-  Completed,
-  RateLimitExceeded
+use rust_decimal::Decimal;
+use protocol::ws::{AgentStats, EngineStats, PhaseChange, SimulationParams, WsResponse};
+use serde::Deserialize;
+
+use crate::engine::{book_stats::{BookStatsConfig, BookStatsSampler}, orderbook::{Arena, BidOrAsk}, phases::PHASE_TIMING_ENABLED, snapshot::{DepthPublisher, DepthReader}, stats::OrderOp, risk::{RiskConfig, RiskManager, RiskReject, DEFAULT_INSTRUMENT}};
+
+use super::{agents::{AgentCommand, AgentGroup, AgentPool, CommandOutcome}, clock::{default_arrival_rate, deserialize_arrival_rate, RunDuration, SimClock}, export::CommandWriter, distributions::{to_limit_price, QuantityDistConfig, QuantitySampler}, models::{AggressiveFlow, AggressiveFlowConfig, FlowModelConfig, OrderAction, OrderFlowModel, RegimeChange}, scenario::{Scenario, ScenarioAction, ScenarioRunner}, seeding::{default_seed_orders, SeedShape}};
+
+// all simulated flow is attributed to a single account for risk checks
+const SIMULATOR_ACCOUNT: &str = "simulator";
+// sweeps injected by scenario scripts are booked separately
+const SCENARIO_ACCOUNT: &str = "scenario";
+// price levels per side in the published depth snapshots
+const DEPTH_LEVELS: usize = 1_000;
+
+// simulation parameters sent by the client with the START message
+#[derive(Debug, Deserialize)]
+pub struct SimulatorConfig {
+  #[serde(flatten)]
+  pub params: SimulationParams, // mean and sd price, order probs, best price levels and seed
+  #[serde(default)]
+  pub book_stats: BookStatsConfig, // sampling interval and depth range for book stats, defaults to every 100 orders within 10 ticks
+  #[serde(default)]
+  pub flow_model: FlowModelConfig, // order-flow model driving the simulated orders, defaults to `Normal`
+  #[serde(default)]
+  pub aggressive_flow: AggressiveFlowConfig, // size and price distributions of the liquidity taking order types
+  #[serde(default)]
+  pub agents: Vec<AgentGroup>, // interacting market participants, replaces the order-flow model when not empty
+  #[serde(default)]
+  pub quantity: QuantityDistConfig, // order size distribution for seeding and the order-flow models, defaults to uniform in [1, 999]
+  #[serde(default = "default_seed_orders")]
+  pub seed_orders: u64, // resting orders pre-seeded into the book, defaults to 10_000
+  #[serde(default)]
+  pub seed_shape: SeedShape, // how seed orders are laid out around `mean_price`, defaults to `Normal`
+  #[serde(default = "default_arrival_rate", deserialize_with = "deserialize_arrival_rate")]
+  pub arrival_rate: f64, // simulated orders per second of simulated time, defaults to 1000
+  #[serde(default)]
+  pub duration: Option<RunDuration>, // stop after this much simulated or wall-clock time, `total_objects` still caps the order count
+  #[serde(default)]
+  pub scenario: Option<Scenario>, // scripted regime changes applied during the run
+  #[serde(default)]
+  pub export_commands: bool // write the processed commands as an upload file, needs `SIM_EXPORT_DIR` on the server
 }
 
-#[derive(Debug, Serialize, Clone)]
-pub struct EngineStats {
-  pub order_type: String,
-  pub latency: i64,
-  pub avl_rebalances: i64,
-  pub executed_orders_cnt: usize
+impl SimulatorConfig {
+  // orders placed before the simulated ones, they count towards the client's rate limit too
+  pub fn seed_order_count(&self) -> usize {
+    self.seed_shape.order_count(self.seed_orders) as usize
+  }
 }
 
 pub struct Simulator {
   pub book : Arena,
-  engine_stats: Vec<EngineStats>,
+  risk: RiskManager,
+  last_reject: Option<(u64, RiskReject)>,
+  book_stats_sampler: BookStatsSampler,
+  depth_publisher: DepthPublisher,
+  last_engine_stats: Option<EngineStats>,
   rng: StdRng,
+  seed: u64,
   order_id: u64,
   mean_limit_price: f64,
   sd_limit_price: f64,
-  order_type_dist: Uniform<f32>,
-  order_type_cuml_probs: Vec<f32>,
-  price_dist: Normal<f64>,
-  qty_dist: Uniform<u64>,
-  side_dist: Bernoulli,
+  flow_model: Box<dyn OrderFlowModel>,
+  aggressive_flow: AggressiveFlow,
+  agents: Option<AgentPool>,
+  qty_dist: QuantitySampler,
+  seed_orders: u64,
+  seed_shape: SeedShape,
+  // random cancels/modifies only start once the book holds more orders than it was seeded with
+  seeded_orders: usize,
+  clock: SimClock,
+  duration: Option<RunDuration>,
+  // wall-clock start of the run, reset once seeding is done
+  run_started: Instant,
+  scenario: Option<ScenarioRunner>,
+  phase_changes: Vec<PhaseChange>,
+  command_log: Option<CommandWriter>,
   executed_orders_offset: usize,
 }
 
 impl Simulator {
-  pub fn new(mean_price: f64, sd_price: f64, order_probs: Vec<f32>, best_price_lvls: bool) -> Self {
+  // fails on client parameters the models cannot run with
+  pub fn new(config: SimulatorConfig, risk_config: Arc<RiskConfig>) -> Result<Self, String> {
+    let SimulatorConfig { params: SimulationParams { mean_price, sd_price, order_probs, best_price_levels: best_price_lvls, seed }, book_stats: book_stats_config, flow_model, aggressive_flow, agents, quantity, seed_orders, seed_shape, arrival_rate, duration, scenario, export_commands: _ } = config;
+    let qty_dist = QuantitySampler::new(&quantity)?;
+    seed_shape.validate(seed_orders, sd_price)?;
+    let seed = seed.unwrap_or_else(rand::random);
     //let order_probs = vec![0.0, 0.4, 0.6]; // ADD, CANCEL, MODIFY
-    Simulator {
-      book: Arena::new(best_price_lvls),
-      engine_stats: Vec::new(),
-      rng: StdRng::from_os_rng(),
+    // the depth publisher only ships the levels the engine touched
+    let mut book = Arena::new(best_price_lvls);
+    book.track_changed_levels();
+    if let Some(scenario) = &scenario {
+      scenario.validate(mean_price, risk_config.max_order_qty(DEFAULT_INSTRUMENT))?;
+    }
+    Ok(Simulator {
+      book,
+      risk: RiskManager::new(risk_config, DEFAULT_INSTRUMENT),
+      last_reject: None,
+      book_stats_sampler: BookStatsSampler::new(book_stats_config),
+      depth_publisher: DepthPublisher::new(DEPTH_LEVELS),
+      last_engine_stats: None,
+      rng: StdRng::seed_from_u64(seed),
+      seed,
       order_id: 1,
       mean_limit_price: mean_price,
       sd_limit_price: sd_price,
-      order_type_dist: Uniform::new(0.0, 1.0).expect("error creating uniform dist for order type"),
-      order_type_cuml_probs: order_probs.into_iter().scan(0.0, |acc, x| { 
-        *acc += x;
-        Some(*acc)
-      }).collect(),
-      price_dist: Normal::new(mean_price, sd_price).expect("error creating a normal distribution"),
-      qty_dist: Uniform::new(1, 1000).expect("error creating uniform dist for shares/qty"),
-      side_dist: Bernoulli::new(0.5).expect("error creating bernoulii distr"),
+      flow_model: flow_model.build(mean_price, sd_price, &order_probs, qty_dist.clone())?,
+      aggressive_flow: AggressiveFlow::new(&aggressive_flow)?,
+      agents: (!agents.is_empty()).then(|| AgentPool::new(&agents, mean_price)).transpose()?,
+      qty_dist,
+      seed_orders,
+      seed_shape,
+      seeded_orders: 0,
+      clock: SimClock::new(arrival_rate),
+      duration,
+      run_started: Instant::now(),
+      scenario: scenario.map(ScenarioRunner::new),
+      phase_changes: Vec::new(),
+      command_log: None,
       executed_orders_offset: 0,
-    }
+    })
+  }
+
+  // keep only the stats of the latest order, the engine aggregates the rest in its latency histograms
+  fn record_engine_stats(&mut self, op: OrderOp) {
+    self.last_engine_stats = Some(EngineStats { order_type: op.as_str().to_string(), latency: self.book.last_latency.as_nanos() as i64, avl_rebalances: self.book.avl_rebalances as i64, executed_orders_cnt: self.book.executed_orders_count, sim_time_ns: self.clock.now_ns(), phases: PHASE_TIMING_ENABLED.then_some(self.book.phases) });
   }
 
   fn create_add_limit(&mut self) {
     // println!("**ADD");
-    let shares = self.qty_dist.sample(&mut self.rng);
-    let side = self.side_dist.sample(&mut self.rng);
-
-    let mut price;
-    let bid_or_ask;
-
-    if side {
-      bid_or_ask = BidOrAsk::Bid;
-      let lowest_sell = self.book.lowest_sell.unwrap().to_f64().unwrap();
-      loop {
-        price = self.price_dist.sample(&mut self.rng);
-        if !(price >= lowest_sell) { 
-          break;
-        }
-      };
-    } else {
-      bid_or_ask = BidOrAsk::Ask;
-      let highest_buy = self.book.highest_buy.unwrap().to_f64().unwrap();
-      loop {
-        price = self.price_dist.sample(&mut self.rng);
-        if !(price <= highest_buy) {
-          break;
-        }
-      }
-    }
+    let shares = self.flow_model.quantity(&mut self.rng);
+    let bid_or_ask = self.flow_model.side(&mut self.rng);
+    let price = self.flow_model.add_price(&mut self.rng, &bid_or_ask, &self.book);
+    let limit_price = to_limit_price(price);
 
-    let price_string = format!("{:.2}", price);
-    let start = Instant::now();
-    self.book.add_limit_order(self.order_id, bid_or_ask, shares, Decimal::from_str(&price_string).expect("parsing price string to decimal failed"));
-    let duration = start.elapsed().as_nanos();
-    self.engine_stats.push(EngineStats { order_type: String::from("ADD"), latency: duration as i64, avl_rebalances: self.book.avl_rebalances as i64, executed_orders_cnt: self.book.executed_orders_count });
-    
-    self.order_id += 1;
+    self.submit_add(SIMULATOR_ACCOUNT, bid_or_ask, shares, limit_price);
   }
 
   fn create_cancel_limit(&mut self) {
-    match self.book.get_random_order_id(&mut self.rng) {
+    match self.book.get_random_order_id(&mut self.rng, self.seeded_orders) {
       None => self.create_add_limit(),
-      Some(order_id) => {
-        let start = Instant::now();
-        self.book.cancel_limit_order(*order_id);
-        let duration = start.elapsed().as_nanos();
-        self.engine_stats.push(EngineStats { order_type: String::from("CANCEL"), latency: duration as i64, avl_rebalances: self.book.avl_rebalances as i64, executed_orders_cnt: self.book.executed_orders_count });
-      }
+      Some(&order_id) => self.submit_cancel(order_id)
     }
   }
 
   fn create_modify_limit(&mut self) {
-    //TODO: highest buy checks req or not as we pre-seed
-    let highest_buy = self.book.highest_buy.unwrap().to_f64().unwrap();
-    let price_distr = Normal::new(highest_buy, self.sd_limit_price).expect("error creating a normal dist for modify limit!");
+    match self.book.get_random_order_id(&mut self.rng, self.seeded_orders) {
+      None => self.create_add_limit(),
+      Some(&order_id) => {
+        let bid_or_ask = self.book.orders.get(&order_id).expect("order should exist after the checks!").bid_or_ask.clone();
+        let shares = self.flow_model.quantity(&mut self.rng);
+        let price = self.flow_model.modify_price(&mut self.rng, &bid_or_ask, &self.book);
+        let limit_price = to_limit_price(price);
+
+        self.submit_modify(order_id, shares, limit_price);
+      }
+    }
+  }
+
+  // crosses the spread through the opposite best, any unfilled remainder rests on the book
+  fn create_marketable_limit(&mut self) {
+    let bid_or_ask = self.flow_model.side(&mut self.rng);
+    let sizing = self.aggressive_flow.quantity(&mut self.rng, &bid_or_ask, &self.book);
+    let pricing = self.aggressive_flow.limit_price(&mut self.rng, &bid_or_ask, &self.book);
 
-    match self.book.get_random_order_id(&mut self.rng) {
+    match sizing.zip(pricing) {
       None => self.create_add_limit(),
-      Some(order_id) => {
-        let order = self.book.orders.get(order_id).expect("order should exist after the checks!");
-        let shares = self.qty_dist.sample(&mut self.rng);
-        let mut price;
-
-        match order.bid_or_ask {
-          BidOrAsk::Bid => {
-            let lowest_sell = self.book.lowest_sell.unwrap().to_f64().unwrap();
-            loop {
-              price = price_distr.sample(&mut self.rng);
-              if !(price >= lowest_sell) { 
-                break;
-              }
-            };
-          },
-          BidOrAsk::Ask => {
-            loop { 
-              price = price_distr.sample(&mut self.rng);
-              if !(price <= highest_buy) {
-                break;
-              }
-            }
-          }
-        }
-        let price_string = format!("{:.2}", price);
-        let start = Instant::now();
-        self.book.modify_limit_order(*order_id, shares, Decimal::from_str(&price_string).expect("parsing price string to decimal failed"));
-        let duration = start.elapsed().as_nanos();
-        self.engine_stats.push(EngineStats { order_type: String::from("MODIFY"), latency: duration as i64, avl_rebalances: self.book.avl_rebalances as i64, executed_orders_cnt: self.book.executed_orders_count });
+      Some((shares, price)) => {
+        self.submit_add(SIMULATOR_ACCOUNT, bid_or_ask, shares, to_limit_price(price));
       }
     }
   }
 
-  pub fn seed_orderbook(&mut self, n: u64) {
-    
-    // seed the orderbook with `n` ADD Limit orders
-    for i in 1..=n {
-      let shares = self.qty_dist.sample(&mut self.rng);
-      let limit_price = self.price_dist.sample(&mut self.rng);
-      let price_string = format!("{:.2}", limit_price);
-      // Initially all bids < mean price and asks >= mean price
-      let bid_or_ask = if limit_price < self.mean_limit_price {BidOrAsk::Bid} else {BidOrAsk::Ask}; 
+  fn create_market_order(&mut self) {
+    let bid_or_ask = self.flow_model.side(&mut self.rng);
+    match self.aggressive_flow.quantity(&mut self.rng, &bid_or_ask, &self.book) {
+      None => self.create_add_limit(),
+      Some(shares) => self.submit_immediate(SIMULATOR_ACCOUNT, bid_or_ask, shares, None)
+    }
+  }
 
-      self.book.add_limit_order(i, bid_or_ask, shares, Decimal::from_str(&price_string).expect("parsing price string to decimal failed"));
+  fn create_ioc_order(&mut self) {
+    let bid_or_ask = self.flow_model.side(&mut self.rng);
+    let sizing = self.aggressive_flow.quantity(&mut self.rng, &bid_or_ask, &self.book);
+    let pricing = self.aggressive_flow.limit_price(&mut self.rng, &bid_or_ask, &self.book);
+
+    match sizing.zip(pricing) {
+      None => self.create_add_limit(),
+      Some((shares, price)) => self.submit_immediate(SIMULATOR_ACCOUNT, bid_or_ask, shares, Some(to_limit_price(price)))
     }
-    self.order_id = n+1;
   }
 
-  pub fn generate_orders(&mut self) {
-    
-    let rand_num = self.order_type_dist.sample(&mut self.rng);
+  // runs the pre-trade checks and sends a new limit order to the engine, returns its id if it was not rejected
+  fn submit_add(&mut self, account: &str, bid_or_ask: BidOrAsk, shares: u64, limit_price: Decimal) -> Option<u64> {
+    let order_id = self.order_id;
+    self.order_id += 1;
+
+    if let Err(reject) = self.risk.check_add(account, &bid_or_ask, shares, limit_price, &self.book) {
+      self.last_reject = Some((order_id, reject));
+      return None;
+    }
+    self.book.add_limit_order(order_id, bid_or_ask.clone(), shares, limit_price);
+    self.log_command(|log| log.add(order_id, &bid_or_ask, shares, limit_price));
+    self.risk.record_add(account, order_id, bid_or_ask, &self.book);
+    self.record_engine_stats(OrderOp::Add);
+    Some(order_id)
+  }
+
+  // MARKET (no limit price) or IOC order, neither leaves anything on the book
+  fn submit_immediate(&mut self, account: &str, bid_or_ask: BidOrAsk, shares: u64, limit_price: Option<Decimal>) {
+    let order_id = self.order_id;
+    self.order_id += 1;
+
+    // market orders are checked at the opposite touch they will start filling at
+    let check_price = limit_price.or(match bid_or_ask {
+      BidOrAsk::Bid => self.book.lowest_sell,
+      BidOrAsk::Ask => self.book.highest_buy
+    }).expect("aggressive orders are only sent against a non-empty opposite side!");
+
+    if let Err(reject) = self.risk.check_add(account, &bid_or_ask, shares, check_price, &self.book) {
+      self.last_reject = Some((order_id, reject));
+      return;
+    }
+    let op = match limit_price {
+      None => {
+        self.book.add_market_order(order_id, bid_or_ask.clone(), shares);
+        OrderOp::Market
+      },
+      Some(limit_price) => {
+        self.book.add_ioc_order(order_id, bid_or_ask.clone(), shares, limit_price);
+        OrderOp::Ioc
+      }
+    };
+    self.log_command(|log| log.immediate(order_id, &bid_or_ask, shares, limit_price));
+    self.risk.record_add(account, order_id, bid_or_ask, &self.book);
+    self.record_engine_stats(op);
+  }
+
+  fn submit_modify(&mut self, order_id: u64, shares: u64, limit_price: Decimal) -> bool {
+    if let Err(reject) = self.risk.check_modify(order_id, shares, limit_price, &self.book) {
+      self.last_reject = Some((order_id, reject));
+      return false;
+    }
+    self.book.modify_limit_order(order_id, shares, limit_price);
+    self.log_command(|log| log.modify(order_id, shares, limit_price));
+    self.risk.record_modify(order_id, &self.book);
+    self.record_engine_stats(OrderOp::Modify);
+    true
+  }
+
+  fn submit_cancel(&mut self, order_id: u64) {
+    self.book.cancel_limit_order(order_id);
+    self.log_command(|log| log.cancel(order_id));
+    self.risk.record_cancel(order_id);
+    self.record_engine_stats(OrderOp::Cancel);
+  }
+
+  // lets one scheduled agent act and hands it back the outcome and any fills
+  fn run_agent_step(&mut self) {
+    let pool = self.agents.as_mut().expect("agent pool should exist for agent steps!");
+    let (agent, command) = pool.next_command(&self.book, &mut self.rng);
+    let account = pool.account(agent);
 
-    match self.order_type_cuml_probs.iter().position(|cumprob| rand_num <= *cumprob).expect("error getting order type idx!") {
-      0 => {
-        //println!("ADD trigg with order id: {:?}", self.order_id);
-        self.create_add_limit()
+    let outcome = match command {
+      AgentCommand::Add { side, shares, price } => match self.submit_add(&account, side.clone(), shares, to_limit_price(price)) {
+        Some(order_id) => CommandOutcome::Placed(order_id, side),
+        None => CommandOutcome::Rejected
       },
-      1 => {
-        // println!("CANCEL trigg. current order id: {:?}", self.order_id);
-        self.create_cancel_limit()},
-      2 => {
-        //println!("MODIFY trigg. curr order id: {:?}", self.order_id);
-        self.create_modify_limit()
+      AgentCommand::Modify { order_id, shares, price } => {
+        if self.submit_modify(order_id, shares, to_limit_price(price)) { CommandOutcome::Accepted } else { CommandOutcome::Rejected }
       },
-      _ => panic!("error choosing a order type in generate_orders()!")
+      AgentCommand::Cancel { order_id } => {
+        self.submit_cancel(order_id);
+        CommandOutcome::Cancelled(order_id)
+      },
+      AgentCommand::Idle => CommandOutcome::Skipped
+    };
+
+    let pool = self.agents.as_mut().expect("agent pool should exist for agent steps!");
+    pool.on_outcome(agent, outcome);
+    pool.settle_fills(&self.book);
+  }
+
+  // per-agent fills and PnL, only for multi-agent runs
+  pub fn agent_report(&self) -> Option<Vec<AgentStats>> {
+    self.agents.as_ref().map(|pool| pool.report(&self.book))
+  }
+
+  pub fn seed_orderbook(&mut self) {
+    // seed the orderbook with ADD Limit orders laid out by the configured shape
+    let seed_orders = self.seed_shape.orders(self.seed_orders, self.mean_limit_price, self.sd_limit_price, &self.qty_dist, &mut self.rng);
+    for (i, order) in (1..).zip(seed_orders) {
+      self.book.add_limit_order(i, order.bid_or_ask.clone(), order.shares, order.limit_price);
+      self.log_command(|log| log.add(i, &order.bid_or_ask, order.shares, order.limit_price));
+      // seed orders skip the pre-trade checks but still count towards the simulator's exposure
+      self.risk.record_add(SIMULATOR_ACCOUNT, i, order.bid_or_ask, &self.book);
+      self.order_id = i + 1;
+    }
+    self.seeded_orders = self.book.orders.len();
+    self.depth_publisher.publish(&mut self.book, self.clock.now_ns());
+    self.run_started = Instant::now();
+  }
+
+  // whether a duration-bounded run is over, checked before every order
+  pub fn duration_reached(&self) -> bool {
+    self.duration.is_some_and(|duration| duration.reached(&self.clock, self.run_started))
+  }
+
+  pub fn export_commands(&mut self, writer: CommandWriter) {
+    self.command_log = Some(writer);
+  }
+
+  // flushes the command log and returns the number of lines written
+  pub fn finish_export(&mut self) -> io::Result<u64> {
+    match self.command_log.take() {
+      Some(mut log) => log.flush().map(|_| log.lines()),
+      None => Ok(0)
+    }
+  }
+
+  // a failing export is dropped instead of stopping the simulation
+  fn log_command(&mut self, write: impl FnOnce(&mut CommandWriter) -> io::Result<()>) {
+    if let Some(log) = self.command_log.as_mut() {
+      if let Err(e) = write(log) {
+        println!("writing simulated commands failed with: {:?}, stopping the export", e);
+        self.command_log = None;
+      }
+    }
+  }
+
+  pub fn seed(&self) -> u64 {
+    self.seed
+  }
+
+  pub fn depth_reader(&self) -> DepthReader {
+    self.depth_publisher.reader()
+  }
+
+  // applies the scenario phases that are due before the order at `idx` is generated
+  pub fn run_scenario(&mut self, idx: usize) {
+    let Some(runner) = self.scenario.as_mut() else { return };
+    let sim_time_ns = self.clock.now_ns();
+    let due = runner.due_phases(idx, sim_time_ns);
+    let scenario = runner.name().to_string();
+
+    for phase in due {
+      println!("[INFO] scenario {:?} entering phase {:?} at order {}", scenario, phase.name, idx);
+      for action in phase.actions {
+        self.apply_scenario_action(action);
+      }
+      self.phase_changes.push(PhaseChange { scenario: scenario.clone(), phase: phase.name, order_idx: idx, sim_time_ns });
+    }
+  }
+
+  fn apply_scenario_action(&mut self, action: ScenarioAction) {
+    match action {
+      ScenarioAction::ShiftMean { delta } => self.flow_model.apply_regime(&RegimeChange::ShiftMean(delta)),
+      ScenarioAction::ScaleSd { factor } => self.flow_model.apply_regime(&RegimeChange::ScaleSd(factor)),
+      ScenarioAction::SetOrderProbs { order_probs } => self.flow_model.apply_regime(&RegimeChange::OrderProbs(order_probs)),
+      ScenarioAction::Sweep { side, shares } => {
+        // exogenous shocks bypass the pre-trade checks, like the seed orders
+        let order_id = self.order_id;
+        self.order_id += 1;
+        self.book.add_market_order(order_id, side.clone(), shares);
+        self.log_command(|log| log.immediate(order_id, &side, shares, None));
+        self.risk.record_add(SCENARIO_ACCOUNT, order_id, side, &self.book);
+      },
+      ScenarioAction::PullLiquidity { side } => {
+        // cancel in id order so seeded runs stay reproducible
+        for order_id in self.book.resting_order_ids(Some(&side)) {
+          self.book.cancel_limit_order(order_id);
+          self.log_command(|log| log.cancel(order_id));
+          self.risk.record_cancel(order_id);
+        }
+      }
+    }
+  }
+
+  pub fn generate_orders(&mut self) {
+    if self.agents.is_some() {
+      self.clock.tick(&mut self.rng);
+      return self.run_agent_step();
+    }
+    let action = if self.flow_model.arrival_gap().is_some() {
+      // the model runs its own arrival process, the clock follows its gaps between events
+      let action = self.flow_model.next_action(&mut self.rng);
+      self.clock.advance(self.flow_model.arrival_gap().unwrap_or_default());
+      action
+    } else {
+      self.clock.tick(&mut self.rng);
+      self.flow_model.next_action(&mut self.rng)
+    };
+    match action {
+      OrderAction::Add => self.create_add_limit(),
+      OrderAction::Cancel => self.create_cancel_limit(),
+      OrderAction::Modify => self.create_modify_limit(),
+      OrderAction::MarketableLimit => self.create_marketable_limit(),
+      OrderAction::Market => self.create_market_order(),
+      OrderAction::Ioc => self.create_ioc_order()
     };
   } 
 
-  pub fn get_snapshot(&self) -> Vec<WsResponse> {
-    vec![WsResponse::PriceLevels { snapshot: true, bids: self.book.get_top_n_bids(20), asks: (self.book.get_top_n_asks(20)) }]
+  pub fn get_snapshot(&self, sim_id: &str) -> Vec<WsResponse> {
+    vec![WsResponse::SimulationInfo { sim_id: sim_id.to_string(), seed: self.seed }, WsResponse::PriceLevels { snapshot: true, sim_time_ns: self.clock.now_ns(), bids: self.book.get_top_n_bids(20), asks: (self.book.get_top_n_asks(20)) }]
   }
   
   pub fn generate_updates(&mut self, idx: usize) -> Vec<WsResponse>{
     
-    let mut messages = Vec::new();
-    // always send the engine stats
-    let engine_stat = self.engine_stats.get(idx).expect("each order should have a execution stat!").clone();
-    messages.push(WsResponse::ExecutionStats(engine_stat));
+    let mut messages: Vec<WsResponse> = self.phase_changes.drain(..).map(WsResponse::ScenarioPhase).collect();
+    // send the engine stats, or the risk reject if the order never reached the engine (idle agents send neither)
+    if let Some((order_id, reject)) = self.last_reject.take() {
+      messages.push(WsResponse::RiskRejected { order_id, reason: reject.to_string() });
+    } else if let Some(engine_stat) = self.last_engine_stats.take() {
+      messages.push(WsResponse::ExecutionStats(engine_stat));
+    }
     
-    // sending top `n=1000` price levels 
-    // NOTE: bids or asks may be empty vectors
+    // publish the levels changed since the last publish, the depth fan-out task streams the top `n=1000` to the client
     if (idx+1) % 100 == 0 {
-      let price_levels = WsResponse::PriceLevels { snapshot: false, bids: self.book.get_top_n_bids(1_000), asks: (self.book.get_top_n_asks(1_000)) }; 
-      messages.push(price_levels);
+      self.depth_publisher.publish(&mut self.book, self.clock.now_ns());
     }
 
     if idx % 100 == 0 {
       messages.push(WsResponse::BestLevels { best_buy: self.book.highest_buy, best_sell: self.book.lowest_sell });
     }
 
+    if let Some(sample) = self.book_stats_sampler.maybe_sample(idx, &self.book) {
+      messages.push(WsResponse::BookStats(sample));
+    }
+
     if let Some(trades) = self.book.get_executed_orders(&mut self.executed_orders_offset) {
-      messages.push(WsResponse::Trades(trades));
+      messages.push(WsResponse::Trades { sim_time_ns: self.clock.now_ns(), trades });
     }  
     messages
   }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{io::Write, sync::Mutex};
+  use protocol::ws::ExecutedOrders;
+  use super::*;
+
+  // collects the exported commands in memory
+  #[derive(Clone, Default)]
+  struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+  impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      self.0.lock().expect("buffer lock poisoned!").extend_from_slice(buf);
+      Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+
+  fn run(seed: u64) -> (String, Vec<ExecutedOrders>) {
+    let config: SimulatorConfig = serde_json::from_value(serde_json::json!({
+      "mean_price": 300.0,
+      "sd_price": 50.0,
+      "order_probs": [0.4, 0.2, 0.2, 0.1, 0.05, 0.05],
+      "best_price_levels": false,
+      "seed": seed,
+      "seed_orders": 500
+    })).expect("simulator config should deserialize!");
+    let mut simulator = Simulator::new(config, Arc::new(RiskConfig::default())).expect("simulator config should be valid!");
+    let buffer = SharedBuffer::default();
+    simulator.export_commands(CommandWriter::new(Box::new(buffer.clone())).expect("writing to memory should not fail!"));
+
+    simulator.seed_orderbook();
+    for idx in 0..2_000 {
+      simulator.run_scenario(idx);
+      simulator.generate_orders();
+      simulator.generate_updates(idx);
+    }
+    simulator.finish_export().expect("flushing to memory should not fail!");
+    let commands = String::from_utf8(buffer.0.lock().expect("buffer lock poisoned!").clone()).expect("exported commands should be utf-8!");
+    (commands, simulator.book.executed_orders)
+  }
+
+  #[test]
+  fn runs_with_the_same_seed_are_identical() {
+    let (commands, trades) = run(7);
+    assert!(commands.lines().count() > 2_000);
+    assert!(!trades.is_empty());
+
+    let (replayed_commands, replayed_trades) = run(7);
+    assert_eq!(commands, replayed_commands);
+    assert_eq!(trades, replayed_trades);
+
+    let (other_commands, _) = run(8);
+    assert_ne!(commands, other_commands);
+  }
+}
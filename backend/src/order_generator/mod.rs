@@ -1 +1,8 @@
-pub mod gen;
\ No newline at end of file
+pub mod agents;
+pub mod clock;
+pub mod distributions;
+pub mod export;
+pub mod gen;
+pub mod models;
+pub mod scenario;
+pub mod seeding;
\ No newline at end of file
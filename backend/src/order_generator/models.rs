@@ -0,0 +1,656 @@
+use rand::{rngs::StdRng, Rng};
+use rand_distr::{Bernoulli, Distribution, Exp, LogNormal, Normal, Uniform};
+use rust_decimal::prelude::ToPrimitive;
+use serde::Deserialize;
+
+use crate::engine::orderbook::{Arena, BidOrAsk};
+
+use super::distributions::{QuantitySampler, TruncatedNormal};
+
+// smallest price increment, prices are rounded to 2 decimals before reaching the engine
+pub const TICK: f64 = 0.01;
+
+#[derive(Debug, Clone, Copy)]
+pub enum OrderAction {
+  Add,
+  Cancel,
+  Modify,
+  MarketableLimit,
+  Market,
+  Ioc
+}
+
+// An order-flow model decides what the next simulated order looks like.
+// The simulator owns order ids, risk checks and the engine calls.
+pub trait OrderFlowModel: Send {
+  // advance the model by one event and pick its type
+  fn next_action(&mut self, rng: &mut StdRng) -> OrderAction;
+
+  fn side(&mut self, rng: &mut StdRng) -> BidOrAsk {
+    if rng.random_bool(0.5) { BidOrAsk::Bid } else { BidOrAsk::Ask }
+  }
+
+  fn quantity(&mut self, rng: &mut StdRng) -> u64;
+
+  fn add_price(&mut self, rng: &mut StdRng, side: &BidOrAsk, book: &Arena) -> f64;
+
+  fn modify_price(&mut self, rng: &mut StdRng, side: &BidOrAsk, book: &Arena) -> f64 {
+    self.add_price(rng, side, book)
+  }
+
+  // regime changes a model has no notion of are ignored
+  fn apply_regime(&mut self, _change: &RegimeChange) {}
+
+  // time between the last two events for models with their own arrival process, in mean
+  // inter-arrival times of `arrival_rate`. Orders of the other models arrive as a Poisson process
+  fn arrival_gap(&self) -> Option<f64> { None }
+}
+
+// mid-run parameter changes driven by scenario scripts
+#[derive(Debug, Clone)]
+pub enum RegimeChange {
+  ShiftMean(f64),
+  ScaleSd(f64),
+  OrderProbs(Vec<f32>)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ZeroIntelligenceParams {
+  // limit prices are drawn uniformly within this many ticks of the opposite best quote
+  pub max_offset_ticks: u32,
+  pub order_size: u64
+}
+
+impl Default for ZeroIntelligenceParams {
+  fn default() -> Self {
+    ZeroIntelligenceParams { max_offset_ticks: 100, order_size: 100 }
+  }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct HawkesParams {
+  // background intensity for [ADD, CANCEL, MODIFY], optionally followed by [MARKETABLE_LIMIT, MARKET, IOC]
+  pub baseline: Vec<f64>,
+  // jump in intensity of an event type right after it occurs
+  pub excitation: f64,
+  // exponential decay rate of the excitation, `excitation < decay` keeps the process stationary
+  pub decay: f64
+}
+
+impl Default for HawkesParams {
+  fn default() -> Self {
+    HawkesParams { baseline: vec![0.4, 0.2, 0.4], excitation: 0.8, decay: 1.0 }
+  }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct OrnsteinUhlenbeckParams {
+  // speed at which the mid reverts to `mean_price` per event, in (0, 2) or the mid runs away
+  pub reversion: f64,
+  pub volatility: f64
+}
+
+impl Default for OrnsteinUhlenbeckParams {
+  fn default() -> Self {
+    OrnsteinUhlenbeckParams { reversion: 0.01, volatility: 0.5 }
+  }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct RandomWalkParams {
+  pub volatility: f64,
+  // mean distance from the mid in multiples of the half spread beyond the touch
+  pub spread_factor: f64
+}
+
+impl Default for RandomWalkParams {
+  fn default() -> Self {
+    RandomWalkParams { volatility: 0.05, spread_factor: 2.0 }
+  }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(tag = "model")]
+pub enum FlowModelConfig {
+  // Normal prices around `mean_price`, uniform quantities and coin-flip sides
+  #[default]
+  Normal,
+  ZeroIntelligence(ZeroIntelligenceParams),
+  Hawkes(HawkesParams),
+  OrnsteinUhlenbeck(OrnsteinUhlenbeckParams),
+  RandomWalk(RandomWalkParams)
+}
+
+impl FlowModelConfig {
+  // `qty_dist` sizes the orders of every model except zero-intelligence, which uses a fixed size.
+  // Parameters come from the client, so invalid ones are reported instead of panicking the simulation
+  pub fn build(&self, mean_price: f64, sd_price: f64, order_probs: &[f32], qty_dist: QuantitySampler) -> Result<Box<dyn OrderFlowModel>, String> {
+    validate_order_probs(order_probs)?;
+    Ok(match self {
+      Self::Normal => Box::new(NormalModel::new(mean_price, sd_price, order_probs, qty_dist)),
+      Self::ZeroIntelligence(params) => Box::new(ZeroIntelligenceModel::new(params.clone(), mean_price, order_probs)),
+      Self::Hawkes(params) => Box::new(HawkesModel::new(params.clone(), mean_price, sd_price, order_probs, qty_dist)?),
+      Self::OrnsteinUhlenbeck(params) => Box::new(OrnsteinUhlenbeckModel::new(params.clone(), mean_price, sd_price, order_probs, qty_dist)?),
+      Self::RandomWalk(params) => Box::new(RandomWalkModel::new(params.clone(), mean_price, order_probs, qty_dist)?)
+    })
+  }
+}
+
+// zero mean normal noise, `name` is the client parameter the error refers to
+fn noise_dist(sd: f64, name: &str) -> Result<Normal<f64>, String> {
+  if !(sd >= 0.0 && sd.is_finite()) {
+    return Err(format!("{} should be a finite number >= 0, got {}", name, sd));
+  }
+  Ok(Normal::new(0.0, sd).expect("error creating a normal dist"))
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct AggressiveFlowConfig {
+  // aggressor sizes are a lognormal fraction of the resting volume in the top `depth_levels` opposite levels
+  pub depth_levels: usize,
+  pub median_depth_fraction: f64,
+  pub depth_fraction_sigma: f64,
+  // marketable limits and IOCs are priced up to this many ticks through the opposite best
+  pub max_through_ticks: u32
+}
+
+impl Default for AggressiveFlowConfig {
+  fn default() -> Self {
+    AggressiveFlowConfig { depth_levels: 1, median_depth_fraction: 0.5, depth_fraction_sigma: 0.75, max_through_ticks: 5 }
+  }
+}
+
+// sizes and prices orders that take liquidity, shared by every order-flow model
+pub struct AggressiveFlow {
+  depth_levels: usize,
+  fraction_dist: LogNormal<f64>,
+  through_dist: Uniform<u32>
+}
+
+impl AggressiveFlow {
+  pub fn new(config: &AggressiveFlowConfig) -> Result<Self, String> {
+    if !(config.median_depth_fraction > 0.0 && config.median_depth_fraction.is_finite()) {
+      return Err(format!("median_depth_fraction should be a finite number > 0, got {}", config.median_depth_fraction));
+    }
+    if !(config.depth_fraction_sigma >= 0.0 && config.depth_fraction_sigma.is_finite()) {
+      return Err(format!("depth_fraction_sigma should be a finite number >= 0, got {}", config.depth_fraction_sigma));
+    }
+    Ok(AggressiveFlow {
+      depth_levels: config.depth_levels.max(1),
+      fraction_dist: LogNormal::new(config.median_depth_fraction.ln(), config.depth_fraction_sigma).expect("error creating lognormal dist for aggressor sizes"),
+      through_dist: Uniform::new_inclusive(0, config.max_through_ticks).expect("error creating uniform dist for aggressor prices")
+    })
+  }
+
+  // None when there is nothing to take on the opposite side
+  pub fn quantity(&self, rng: &mut StdRng, side: &BidOrAsk, book: &Arena) -> Option<u64> {
+    let opposite_levels = match side {
+      BidOrAsk::Bid => book.get_top_n_asks(self.depth_levels),
+      BidOrAsk::Ask => book.get_top_n_bids(self.depth_levels)
+    };
+    let depth: u64 = opposite_levels.iter().map(|(_, volume)| volume).sum();
+    if depth == 0 {
+      return None;
+    }
+    Some(((depth as f64 * self.fraction_dist.sample(rng)).round() as u64).max(1))
+  }
+
+  pub fn limit_price(&self, rng: &mut StdRng, side: &BidOrAsk, book: &Arena) -> Option<f64> {
+    let through = self.through_dist.sample(rng) as f64 * TICK;
+    match side {
+      BidOrAsk::Bid => best_price(book.lowest_sell).map(|ask| ask + through),
+      BidOrAsk::Ask => best_price(book.highest_buy).map(|bid| bid - through)
+    }
+  }
+}
+
+// order types `order_probs` can pick from
+const ORDER_ACTIONS: usize = 6;
+// how far the sum of `order_probs` may be from 1, rounding of client side floats
+const ORDER_PROBS_TOLERANCE: f32 = 1e-3;
+
+// client supplied `order_probs` are checked before a sampler is made out of them
+pub fn validate_order_probs(order_probs: &[f32]) -> Result<(), String> {
+  if order_probs.is_empty() || order_probs.len() > ORDER_ACTIONS {
+    return Err(format!("order_probs needs between 1 and {} probabilities, got {}", ORDER_ACTIONS, order_probs.len()));
+  }
+  if order_probs.iter().any(|prob| !prob.is_finite() || *prob < 0.0) {
+    return Err("order_probs must be finite and non-negative".to_string());
+  }
+  let total: f32 = order_probs.iter().sum();
+  if (total - 1.0).abs() > ORDER_PROBS_TOLERANCE {
+    return Err(format!("order_probs must add up to 1, they add up to {}", total));
+  }
+  Ok(())
+}
+
+// picks the order type from the client supplied `order_probs`,
+// [ADD, CANCEL, MODIFY] optionally followed by [MARKETABLE_LIMIT, MARKET, IOC]
+struct ActionSampler {
+  dist: Uniform<f32>,
+  cuml_probs: Vec<f32>
+}
+
+impl ActionSampler {
+  // `order_probs` passed `validate_order_probs`, the last cumulative probability is set to 1 so rounding never leaves a gap
+  fn new(order_probs: &[f32]) -> Self {
+    let mut cuml_probs: Vec<f32> = order_probs.iter().scan(0.0, |acc, x| {
+      *acc += x;
+      Some(*acc)
+    }).collect();
+    if let Some(last) = cuml_probs.last_mut() {
+      *last = 1.0;
+    }
+    ActionSampler {
+      dist: Uniform::new(0.0, 1.0).expect("error creating uniform dist for order type"),
+      cuml_probs
+    }
+  }
+
+  fn sample(&self, rng: &mut StdRng) -> OrderAction {
+    let rand_num = self.dist.sample(rng);
+
+    match self.cuml_probs.iter().position(|cumprob| rand_num <= *cumprob).expect("error getting order type idx!") {
+      0 => OrderAction::Add,
+      1 => OrderAction::Cancel,
+      2 => OrderAction::Modify,
+      3 => OrderAction::MarketableLimit,
+      4 => OrderAction::Market,
+      5 => OrderAction::Ioc,
+      _ => panic!("error choosing a order type in generate_orders()!")
+    }
+  }
+}
+
+pub fn best_price(edge: Option<rust_decimal::Decimal>) -> Option<f64> {
+  edge.and_then(|p| p.to_f64())
+}
+
+pub fn book_mid(book: &Arena) -> Option<f64> {
+  match (best_price(book.highest_buy), best_price(book.lowest_sell)) {
+    (Some(bid), Some(ask)) => Some((bid + ask) / 2.0),
+    (bid, ask) => bid.or(ask)
+  }
+}
+
+// samples `dist` restricted to prices that rest on the book without crossing
+fn sample_passive(dist: &TruncatedNormal, rng: &mut StdRng, side: &BidOrAsk, book: &Arena) -> f64 {
+  match side {
+    BidOrAsk::Bid => dist.sample_below(best_price(book.lowest_sell).unwrap_or(f64::MAX), rng),
+    BidOrAsk::Ask => dist.sample_above(best_price(book.highest_buy).unwrap_or(f64::MIN), rng)
+  }
+}
+
+pub struct NormalModel {
+  actions: ActionSampler,
+  mean_price: f64,
+  sd_price: f64,
+  qty_dist: QuantitySampler
+}
+
+impl NormalModel {
+  fn new(mean_price: f64, sd_price: f64, order_probs: &[f32], qty_dist: QuantitySampler) -> Self {
+    NormalModel {
+      actions: ActionSampler::new(order_probs),
+      mean_price,
+      sd_price,
+      qty_dist
+    }
+  }
+}
+
+impl OrderFlowModel for NormalModel {
+  fn next_action(&mut self, rng: &mut StdRng) -> OrderAction {
+    self.actions.sample(rng)
+  }
+
+  fn quantity(&mut self, rng: &mut StdRng) -> u64 {
+    self.qty_dist.sample(rng)
+  }
+
+  fn add_price(&mut self, rng: &mut StdRng, side: &BidOrAsk, book: &Arena) -> f64 {
+    sample_passive(&TruncatedNormal::new(self.mean_price, self.sd_price), rng, side, book)
+  }
+
+  // modifies reprice around the best bid, or the mean once scenarios pulled all bids
+  fn modify_price(&mut self, rng: &mut StdRng, side: &BidOrAsk, book: &Arena) -> f64 {
+    let highest_buy = best_price(book.highest_buy).unwrap_or(self.mean_price);
+    sample_passive(&TruncatedNormal::new(highest_buy, self.sd_price), rng, side, book)
+  }
+
+  fn apply_regime(&mut self, change: &RegimeChange) {
+    match change {
+      RegimeChange::ShiftMean(delta) => self.mean_price += delta,
+      RegimeChange::ScaleSd(factor) => self.sd_price *= factor,
+      RegimeChange::OrderProbs(probs) => self.actions = ActionSampler::new(probs)
+    }
+  }
+}
+
+// Santa Fe zero-intelligence model: orders of a fixed size placed uniformly at random away from the opposite quote
+pub struct ZeroIntelligenceModel {
+  actions: ActionSampler,
+  params: ZeroIntelligenceParams,
+  offset_dist: Uniform<u32>,
+  mean_price: f64
+}
+
+impl ZeroIntelligenceModel {
+  fn new(params: ZeroIntelligenceParams, mean_price: f64, order_probs: &[f32]) -> Self {
+    ZeroIntelligenceModel {
+      actions: ActionSampler::new(order_probs),
+      offset_dist: Uniform::new_inclusive(1, params.max_offset_ticks.max(1)).expect("error creating uniform dist for price offsets"),
+      params,
+      mean_price
+    }
+  }
+}
+
+impl OrderFlowModel for ZeroIntelligenceModel {
+  fn next_action(&mut self, rng: &mut StdRng) -> OrderAction {
+    self.actions.sample(rng)
+  }
+
+  fn quantity(&mut self, _rng: &mut StdRng) -> u64 {
+    self.params.order_size
+  }
+
+  fn add_price(&mut self, rng: &mut StdRng, side: &BidOrAsk, book: &Arena) -> f64 {
+    let offset = self.offset_dist.sample(rng) as f64 * TICK;
+    let fallback = book_mid(book).unwrap_or(self.mean_price);
+    match side {
+      BidOrAsk::Bid => best_price(book.lowest_sell).unwrap_or(fallback) - offset,
+      BidOrAsk::Ask => best_price(book.highest_buy).unwrap_or(fallback) + offset
+    }
+  }
+
+  // prices follow the book, a mean shift only moves the fallback for an empty book
+  fn apply_regime(&mut self, change: &RegimeChange) {
+    match change {
+      RegimeChange::ShiftMean(delta) => self.mean_price += delta,
+      RegimeChange::ScaleSd(_) => {},
+      RegimeChange::OrderProbs(probs) => self.actions = ActionSampler::new(probs)
+    }
+  }
+}
+
+const HAWKES_ACTIONS: [OrderAction; 6] = [OrderAction::Add, OrderAction::Cancel, OrderAction::Modify, OrderAction::MarketableLimit, OrderAction::Market, OrderAction::Ioc];
+
+// self-exciting arrivals: each event raises the intensity of its own type, which then decays exponentially
+pub struct HawkesModel {
+  params: HawkesParams,
+  // one dimension per baseline intensity, in `HAWKES_ACTIONS` order
+  excitation: Vec<f64>,
+  // waits of all proposals since the previous event, accepted or not
+  last_gap: f64,
+  price_sd: f64,
+  mean_price: f64,
+  qty_dist: QuantitySampler
+}
+
+impl HawkesModel {
+  fn new(params: HawkesParams, mean_price: f64, sd_price: f64, order_probs: &[f32], qty_dist: QuantitySampler) -> Result<Self, String> {
+    if params.baseline.len() != 3 && params.baseline.len() != HAWKES_ACTIONS.len() {
+      return Err(format!("hawkes baseline should have 3 or 6 intensities, got {}", params.baseline.len()));
+    }
+    // order types come from the intensities, aggressive flow needs its own dimensions
+    if order_probs.len() > params.baseline.len() {
+      return Err("the hawkes model ignores order_probs, give 6 baseline intensities to simulate marketable limit, market and IOC orders".to_string());
+    }
+    // intensities only ever grow from the baseline, so a positive baseline keeps every proposal rate positive
+    if params.baseline.iter().any(|b| !(b.is_finite() && *b >= 0.0)) || params.baseline.iter().sum::<f64>() <= 0.0 {
+      return Err(format!("hawkes baseline intensities should be finite, >= 0 and not all 0, got {:?}", params.baseline));
+    }
+    if !(params.excitation.is_finite() && params.excitation >= 0.0) {
+      return Err(format!("hawkes excitation should be a finite number >= 0, got {}", params.excitation));
+    }
+    if !(params.decay.is_finite() && params.decay >= 0.0) {
+      return Err(format!("hawkes decay should be a finite number >= 0, got {}", params.decay));
+    }
+    Ok(HawkesModel { excitation: vec![0.0; params.baseline.len()], params, last_gap: 0.0, price_sd: sd_price, mean_price, qty_dist })
+  }
+
+  fn intensities(&self) -> impl Iterator<Item = f64> + '_ {
+    self.params.baseline.iter().zip(&self.excitation).map(|(baseline, excitation)| baseline + excitation)
+  }
+}
+
+impl OrderFlowModel for HawkesModel {
+  // Ogata thinning: propose with the current (upper bound) intensity and accept with the decayed one
+  fn next_action(&mut self, rng: &mut StdRng) -> OrderAction {
+    let mut gap = 0.0;
+    loop {
+      let upper_bound: f64 = self.intensities().sum();
+      let wait = Exp::new(upper_bound).expect("hawkes intensity should be positive!").sample(rng);
+      gap += wait;
+      let decay = (-self.params.decay * wait).exp();
+      self.excitation.iter_mut().for_each(|e| *e *= decay);
+
+      let total: f64 = self.intensities().sum();
+      if rng.random::<f64>() * upper_bound > total {
+        continue;
+      }
+
+      let mut pick = rng.random::<f64>() * total;
+      let mut event_type = self.excitation.len() - 1;
+      for (k, intensity) in self.intensities().enumerate() {
+        if pick < intensity {
+          event_type = k;
+          break;
+        }
+        pick -= intensity;
+      }
+      self.excitation[event_type] += self.params.excitation;
+      self.last_gap = gap;
+
+      return HAWKES_ACTIONS[event_type];
+    }
+  }
+
+  fn quantity(&mut self, rng: &mut StdRng) -> u64 {
+    self.qty_dist.sample(rng)
+  }
+
+  fn add_price(&mut self, rng: &mut StdRng, side: &BidOrAsk, book: &Arena) -> f64 {
+    let mid = book_mid(book).unwrap_or(self.mean_price);
+    sample_passive(&TruncatedNormal::new(mid, self.price_sd), rng, side, book)
+  }
+
+  // event types come from the intensities, so order probabilities do not apply
+  fn apply_regime(&mut self, change: &RegimeChange) {
+    match change {
+      RegimeChange::ShiftMean(delta) => self.mean_price += delta,
+      RegimeChange::ScaleSd(factor) => self.price_sd *= factor,
+      RegimeChange::OrderProbs(_) => {}
+    }
+  }
+
+  // waits are in units of the baseline intensities, a baseline summing to one runs at `arrival_rate`
+  fn arrival_gap(&self) -> Option<f64> {
+    Some(self.last_gap)
+  }
+}
+
+// mean reverting mid price, orders are placed on their side of the mid and may cross a stale book
+pub struct OrnsteinUhlenbeckModel {
+  actions: ActionSampler,
+  params: OrnsteinUhlenbeckParams,
+  mean_price: f64,
+  mid: f64,
+  noise: Normal<f64>,
+  sd_price: f64,
+  offset_dist: Normal<f64>,
+  qty_dist: QuantitySampler
+}
+
+impl OrnsteinUhlenbeckModel {
+  fn new(params: OrnsteinUhlenbeckParams, mean_price: f64, sd_price: f64, order_probs: &[f32], qty_dist: QuantitySampler) -> Result<Self, String> {
+    if !(params.reversion > 0.0 && params.reversion < 2.0) {
+      return Err(format!("reversion should be between 0 and 2 (exclusive), got {}", params.reversion));
+    }
+    Ok(OrnsteinUhlenbeckModel {
+      actions: ActionSampler::new(order_probs),
+      noise: noise_dist(params.volatility, "volatility")?,
+      params,
+      mean_price,
+      mid: mean_price,
+      sd_price,
+      offset_dist: noise_dist(sd_price, "sd_price")?,
+      qty_dist
+    })
+  }
+}
+
+impl OrderFlowModel for OrnsteinUhlenbeckModel {
+  fn next_action(&mut self, rng: &mut StdRng) -> OrderAction {
+    self.mid += self.params.reversion * (self.mean_price - self.mid) + self.noise.sample(rng);
+    self.actions.sample(rng)
+  }
+
+  fn quantity(&mut self, rng: &mut StdRng) -> u64 {
+    self.qty_dist.sample(rng)
+  }
+
+  fn add_price(&mut self, rng: &mut StdRng, side: &BidOrAsk, _book: &Arena) -> f64 {
+    let offset = self.offset_dist.sample(rng).abs();
+    match side {
+      BidOrAsk::Bid => self.mid - offset,
+      BidOrAsk::Ask => self.mid + offset
+    }
+  }
+
+  // a mean shift moves the level the mid reverts to, not the mid itself
+  fn apply_regime(&mut self, change: &RegimeChange) {
+    match change {
+      RegimeChange::ShiftMean(delta) => self.mean_price += delta,
+      RegimeChange::ScaleSd(factor) => {
+        self.sd_price *= factor;
+        self.offset_dist = Normal::new(0.0, self.sd_price).expect("error creating a normal dist for price offsets");
+      },
+      RegimeChange::OrderProbs(probs) => self.actions = ActionSampler::new(probs)
+    }
+  }
+}
+
+// random walk mid with prices placed relative to the current spread
+pub struct RandomWalkModel {
+  actions: ActionSampler,
+  mid: f64,
+  volatility: f64,
+  noise: Normal<f64>,
+  depth_dist: Exp<f64>,
+  side_dist: Bernoulli,
+  qty_dist: QuantitySampler
+}
+
+impl RandomWalkModel {
+  fn new(params: RandomWalkParams, mean_price: f64, order_probs: &[f32], qty_dist: QuantitySampler) -> Result<Self, String> {
+    if !(params.spread_factor > 0.0 && params.spread_factor.is_finite()) {
+      return Err(format!("spread_factor should be a finite number > 0, got {}", params.spread_factor));
+    }
+    Ok(RandomWalkModel {
+      actions: ActionSampler::new(order_probs),
+      mid: mean_price,
+      volatility: params.volatility,
+      noise: noise_dist(params.volatility, "volatility")?,
+      depth_dist: Exp::new(1.0 / params.spread_factor).expect("spread factor should be positive!"),
+      side_dist: Bernoulli::new(0.5).expect("error creating bernoulii distr"),
+      qty_dist
+    })
+  }
+}
+
+impl OrderFlowModel for RandomWalkModel {
+  fn next_action(&mut self, rng: &mut StdRng) -> OrderAction {
+    self.mid += self.noise.sample(rng);
+    self.actions.sample(rng)
+  }
+
+  fn side(&mut self, rng: &mut StdRng) -> BidOrAsk {
+    if self.side_dist.sample(rng) { BidOrAsk::Bid } else { BidOrAsk::Ask }
+  }
+
+  fn quantity(&mut self, rng: &mut StdRng) -> u64 {
+    self.qty_dist.sample(rng)
+  }
+
+  fn add_price(&mut self, rng: &mut StdRng, side: &BidOrAsk, book: &Arena) -> f64 {
+    let spread = match (best_price(book.highest_buy), best_price(book.lowest_sell)) {
+      (Some(bid), Some(ask)) => (ask - bid).max(TICK),
+      _ => 2.0 * TICK
+    };
+    let offset = spread / 2.0 * (1.0 + self.depth_dist.sample(rng));
+    match side {
+      BidOrAsk::Bid => self.mid - offset,
+      BidOrAsk::Ask => self.mid + offset
+    }
+  }
+
+  // the mid jumps by the shift and sd scales the step volatility
+  fn apply_regime(&mut self, change: &RegimeChange) {
+    match change {
+      RegimeChange::ShiftMean(delta) => self.mid += delta,
+      RegimeChange::ScaleSd(factor) => {
+        self.volatility *= factor;
+        self.noise = Normal::new(0.0, self.volatility).expect("error creating a normal dist for mid price noise");
+      },
+      RegimeChange::OrderProbs(probs) => self.actions = ActionSampler::new(probs)
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use rand::SeedableRng;
+
+  use super::*;
+  use crate::order_generator::distributions::QuantityDistConfig;
+
+  fn hawkes(baseline: Vec<f64>, order_probs: &[f32]) -> Result<Box<dyn OrderFlowModel>, String> {
+    let config = FlowModelConfig::Hawkes(HawkesParams { baseline, ..HawkesParams::default() });
+    config.build(100.0, 5.0, order_probs, QuantitySampler::new(&QuantityDistConfig::default()).expect("default quantities should be valid!"))
+  }
+
+  #[test]
+  fn hawkes_draws_aggressive_orders_from_their_own_intensities() {
+    assert!(hawkes(vec![0.4, 0.2, 0.4], &[0.4, 0.2, 0.2, 0.1, 0.05, 0.05]).is_err());
+    assert!(hawkes(vec![0.4, 0.2, 0.4, 0.1], &[0.4, 0.2, 0.4]).is_err());
+
+    let mut model = hawkes(vec![0.0, 0.0, 0.0, 0.2, 0.2, 0.2], &[0.4, 0.2, 0.2, 0.1, 0.05, 0.05]).expect("aggressive dimensions should build!");
+    let mut rng = StdRng::seed_from_u64(7);
+    for _ in 0..100 {
+      assert!(matches!(model.next_action(&mut rng), OrderAction::MarketableLimit | OrderAction::Market | OrderAction::Ioc));
+    }
+  }
+
+  #[test]
+  fn order_probs_are_validated_before_sampling() {
+    let qty_dist = || QuantitySampler::new(&QuantityDistConfig::default()).expect("default quantities should be valid!");
+    for order_probs in [&[][..], &[0.5, 0.4], &[0.2, 0.2, 0.2, 0.2, 0.1, 0.05, 0.05], &[1.5, -0.5], &[f32::NAN, 1.0]] {
+      assert!(FlowModelConfig::Normal.build(100.0, 5.0, order_probs, qty_dist()).is_err(), "{:?} should be refused", order_probs);
+    }
+
+    // floats a little off 1 still sample every draw
+    let mut model = FlowModelConfig::Normal.build(100.0, 5.0, &[0.3333, 0.3333, 0.3333], qty_dist()).expect("rounded probabilities should build!");
+    let mut rng = StdRng::seed_from_u64(7);
+    for _ in 0..10_000 {
+      model.next_action(&mut rng);
+    }
+  }
+
+  #[test]
+  fn reversion_outside_its_stable_range_is_refused() {
+    let qty_dist = || QuantitySampler::new(&QuantityDistConfig::default()).expect("default quantities should be valid!");
+    for reversion in [0.0, -0.5, 2.0, 3.5, f64::NAN] {
+      let config = FlowModelConfig::OrnsteinUhlenbeck(OrnsteinUhlenbeckParams { reversion, ..OrnsteinUhlenbeckParams::default() });
+      assert!(config.build(100.0, 5.0, &[0.4, 0.3, 0.3], qty_dist()).is_err(), "reversion {} should be refused", reversion);
+    }
+    let config = FlowModelConfig::OrnsteinUhlenbeck(OrnsteinUhlenbeckParams { reversion: 1.5, ..OrnsteinUhlenbeckParams::default() });
+    assert!(config.build(100.0, 5.0, &[0.4, 0.3, 0.3], qty_dist()).is_ok());
+  }
+}
@@ -0,0 +1,148 @@
+use serde::Deserialize;
+
+use crate::engine::orderbook::BidOrAsk;
+use super::{distributions::MAX_LIMIT_PRICE, models::validate_order_probs};
+
+// A scripted sequence of market regimes, e.g.
+// {"name": "flash crash", "phases": [
+//   {"name": "calm", "at_order": 0},
+//   {"name": "crash", "at_order": 20000, "actions": [{"action": "PullLiquidity", "side": "Bid"}, {"action": "Sweep", "side": "Ask", "shares": 50000}, {"action": "ShiftMean", "delta": -30.0}]},
+//   {"name": "recovery", "at_time_ms": 40000, "actions": [{"action": "ShiftMean", "delta": 25.0}, {"action": "ScaleSd", "factor": 0.5}]}
+// ]}
+#[derive(Debug, Clone, Deserialize)]
+pub struct Scenario {
+  pub name: String,
+  pub phases: Vec<ScenarioPhase>
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ScenarioPhase {
+  pub name: String,
+  #[serde(flatten)]
+  pub trigger: Trigger,
+  #[serde(default)]
+  pub actions: Vec<ScenarioAction>
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Trigger {
+  // 0-based index of the simulated order the phase starts at
+  AtOrder(usize),
+  // simulated time since the start of the run
+  AtTimeMs(u64)
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "action")]
+pub enum ScenarioAction {
+  // moves the price level the order-flow model centers on
+  ShiftMean { delta: f64 },
+  // multiplies the price dispersion (or volatility) of the order-flow model
+  ScaleSd { factor: f64 },
+  // replaces `order_probs`, e.g. to spike cancellations
+  SetOrderProbs { order_probs: Vec<f32> },
+  // a single market order that takes `shares` from the opposite side
+  Sweep { side: BidOrAsk, shares: u64 },
+  // cancels every resting order on one side of the book
+  PullLiquidity { side: BidOrAsk }
+}
+
+impl Scenario {
+  // Actions are checked up front, a bad one would otherwise panic the simulation when its phase fires.
+  // A negative scale would flip the sign of a dispersion, shifts may not take the mean past `MAX_LIMIT_PRICE`,
+  // and sweeps stay within `max_order_qty` so a replay of the exported commands through the pre-trade checks matches the run
+  pub fn validate(&self, mean_price: f64, max_order_qty: u64) -> Result<(), String> {
+    let mut shifted_mean = mean_price;
+    for phase in &self.phases {
+      for action in &phase.actions {
+        match action {
+          ScenarioAction::ScaleSd { factor } if !(factor.is_finite() && *factor >= 0.0) => {
+            return Err(format!("ScaleSd factor of phase {:?} should be a finite number >= 0, got {}", phase.name, factor));
+          },
+          ScenarioAction::ShiftMean { delta } => {
+            shifted_mean += delta;
+            if !(delta.is_finite() && shifted_mean <= MAX_LIMIT_PRICE) {
+              return Err(format!("ShiftMean of phase {:?} takes the mean to {}, past {}", phase.name, shifted_mean, MAX_LIMIT_PRICE));
+            }
+          },
+          ScenarioAction::SetOrderProbs { order_probs } => {
+            validate_order_probs(order_probs).map_err(|e| format!("SetOrderProbs of phase {:?}: {}", phase.name, e))?;
+          },
+          ScenarioAction::Sweep { shares, .. } if *shares == 0 || *shares > max_order_qty => {
+            return Err(format!("Sweep of phase {:?} should take between 1 and {} shares, got {}", phase.name, max_order_qty, shares));
+          },
+          _ => {}
+        }
+      }
+    }
+    Ok(())
+  }
+}
+
+// Walks through the phases in order. A phase fires once its trigger is reached and stays active until the next one fires
+pub struct ScenarioRunner {
+  scenario: Scenario,
+  next_phase: usize
+}
+
+impl ScenarioRunner {
+  pub fn new(scenario: Scenario) -> Self {
+    ScenarioRunner { scenario, next_phase: 0 }
+  }
+
+  pub fn name(&self) -> &str {
+    &self.scenario.name
+  }
+
+  // phases due at this order index / simulated time, usually zero or one
+  pub fn due_phases(&mut self, order_idx: usize, sim_time_ns: u64) -> Vec<ScenarioPhase> {
+    let mut due = Vec::new();
+    while let Some(phase) = self.scenario.phases.get(self.next_phase) {
+      let reached = match phase.trigger {
+        Trigger::AtOrder(idx) => order_idx >= idx,
+        Trigger::AtTimeMs(ms) => sim_time_ns >= ms.saturating_mul(1_000_000)
+      };
+      if !reached {
+        break;
+      }
+      due.push(phase.clone());
+      self.next_phase += 1;
+    }
+    due
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn scenario(actions: serde_json::Value) -> Scenario {
+    serde_json::from_value(serde_json::json!({ "name": "test", "phases": [{ "name": "shock", "at_order": 10, "actions": actions }] })).expect("scenario should parse!")
+  }
+
+  #[test]
+  fn actions_that_would_panic_or_skip_risk_checks_are_refused() {
+    let valid = scenario(serde_json::json!([
+      { "action": "ShiftMean", "delta": -30.0 },
+      { "action": "ScaleSd", "factor": 0.5 },
+      { "action": "SetOrderProbs", "order_probs": [0.1, 0.6, 0.3] },
+      { "action": "Sweep", "side": "Ask", "shares": 1000 }
+    ]));
+    assert!(valid.validate(100.0, 1000).is_ok());
+
+    let invalid = [
+      serde_json::json!([{ "action": "ScaleSd", "factor": -1.0 }]),
+      serde_json::json!([{ "action": "SetOrderProbs", "order_probs": [] }]),
+      serde_json::json!([{ "action": "SetOrderProbs", "order_probs": [0.2, 0.2] }]),
+      serde_json::json!([{ "action": "ShiftMean", "delta": 1e300 }]),
+      // shifts add up
+      serde_json::json!([{ "action": "ShiftMean", "delta": 6e8 }, { "action": "ShiftMean", "delta": 6e8 }]),
+      serde_json::json!([{ "action": "Sweep", "side": "Bid", "shares": 1001 }]),
+      serde_json::json!([{ "action": "Sweep", "side": "Bid", "shares": 0 }])
+    ];
+    for actions in invalid {
+      assert!(scenario(actions.clone()).validate(100.0, 1000).is_err(), "{} should be refused", actions);
+    }
+  }
+}
@@ -0,0 +1,175 @@
+use rand::{rngs::StdRng, Rng};
+use rand_distr::{Distribution, Normal};
+use rust_decimal::{prelude::ToPrimitive, Decimal};
+use serde::Deserialize;
+
+use crate::engine::orderbook::BidOrAsk;
+
+use super::{distributions::{to_limit_price, QuantitySampler}, models::TICK};
+
+pub fn default_seed_orders() -> u64 { 10_000 }
+
+// most orders a seeded book may start with, they are generated in one go before the first simulated order
+pub const MAX_SEED_ORDERS: u64 = 100_000;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct FlatSeedParams {
+  // orders are spread evenly over this many ticks on each side
+  pub levels: u32,
+  // distance of the best bid/ask from `mean_price`, at least one tick so the seed book never crosses
+  pub half_spread_ticks: u32
+}
+
+impl Default for FlatSeedParams {
+  fn default() -> Self {
+    FlatSeedParams { levels: 100, half_spread_ticks: 1 }
+  }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ExpDecaySeedParams {
+  pub levels: u32,
+  pub half_spread_ticks: u32,
+  // mean distance (in ticks) of a seed order from the touch, volume decays exponentially beyond it
+  pub decay_ticks: f64
+}
+
+impl Default for ExpDecaySeedParams {
+  fn default() -> Self {
+    ExpDecaySeedParams { levels: 500, half_spread_ticks: 1, decay_ticks: 50.0 }
+  }
+}
+
+// same layout as the `/depth/{sim_id}` response, each level is seeded as a single order
+#[derive(Debug, Clone, Deserialize)]
+pub struct SnapshotSeed {
+  pub bids: Vec<(Decimal, u64)>,
+  pub asks: Vec<(Decimal, u64)>
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(tag = "shape")]
+pub enum SeedShape {
+  // normal prices around `mean_price`, bids below and asks above it
+  #[default]
+  Normal,
+  Flat(FlatSeedParams),
+  ExponentialDecay(ExpDecaySeedParams),
+  Snapshot(SnapshotSeed)
+}
+
+pub struct SeedOrder {
+  pub bid_or_ask: BidOrAsk,
+  pub shares: u64,
+  pub limit_price: Decimal
+}
+
+impl SeedShape {
+  // orders the book is seeded with, `n` unless the levels come from a snapshot
+  pub fn order_count(&self, n: u64) -> u64 {
+    match self {
+      Self::Snapshot(snapshot) => (snapshot.bids.len() + snapshot.asks.len()) as u64,
+      _ => n
+    }
+  }
+
+  // checked before the simulation starts, `sd_price` spreads the `Normal` seed
+  pub fn validate(&self, n: u64, sd_price: f64) -> Result<(), String> {
+    let count = self.order_count(n);
+    if count > MAX_SEED_ORDERS {
+      return Err(format!("the book can be seeded with at most {} orders or snapshot levels, got {}", MAX_SEED_ORDERS, count));
+    }
+    match self {
+      Self::Normal if !(sd_price.is_finite() && sd_price >= 0.0) => Err(format!("sd_price should be a finite number >= 0, got {}", sd_price)),
+      _ => Ok(())
+    }
+  }
+
+  // generates the resting orders the book starts from, snapshots ignore `n`
+  pub fn orders(&self, n: u64, mean_price: f64, sd_price: f64, qty_dist: &QuantitySampler, rng: &mut StdRng) -> Vec<SeedOrder> {
+    match self {
+      Self::Normal => {
+        let price_dist = Normal::new(mean_price, sd_price).expect("error creating a normal distribution");
+        (0..n).map(|_| {
+          let shares = qty_dist.sample(rng);
+          let limit_price = price_dist.sample(rng);
+          // Initially all bids < mean price and asks >= mean price
+          let bid_or_ask = if limit_price < mean_price {BidOrAsk::Bid} else {BidOrAsk::Ask};
+          SeedOrder { bid_or_ask, shares, limit_price: to_limit_price(limit_price) }
+        }).collect()
+      },
+      Self::Flat(params) => {
+        (0..n).map(|_| {
+          let level = rng.random_range(0..params.levels.max(1));
+          ladder_order(mean_price, params.half_spread_ticks, level, qty_dist, rng)
+        }).collect()
+      },
+      Self::ExponentialDecay(params) => {
+        let levels = params.levels.max(1) as f64;
+        let decay = params.decay_ticks.max(f64::MIN_POSITIVE);
+        // inverse CDF of an exponential truncated to the ladder
+        let mass = 1.0 - (-levels / decay).exp();
+        (0..n).map(|_| {
+          let u: f64 = rng.random();
+          let level = ((-decay * (1.0 - u * mass).ln()).floor() as u32).min(params.levels.max(1) - 1);
+          ladder_order(mean_price, params.half_spread_ticks, level, qty_dist, rng)
+        }).collect()
+      },
+      Self::Snapshot(snapshot) => {
+        let bids = snapshot.bids.iter().map(|level| (BidOrAsk::Bid, level));
+        let asks = snapshot.asks.iter().map(|level| (BidOrAsk::Ask, level));
+        // client prices get the same 2 decimal rounding as generated ones, levels without a positive price are dropped
+        bids.chain(asks).filter_map(|(bid_or_ask, (price, volume))| {
+          let price = price.to_f64().filter(|price| *price > 0.0 && *volume > 0)?;
+          Some(SeedOrder { bid_or_ask, shares: *volume, limit_price: to_limit_price(price) })
+        }).collect()
+      }
+    }
+  }
+}
+
+// random side, `level` ticks behind the touch of that side
+fn ladder_order(mean_price: f64, half_spread_ticks: u32, level: u32, qty_dist: &QuantitySampler, rng: &mut StdRng) -> SeedOrder {
+  let offset = (half_spread_ticks.max(1) + level) as f64 * TICK;
+  let (bid_or_ask, price) = if rng.random_bool(0.5) { (BidOrAsk::Bid, mean_price - offset) } else { (BidOrAsk::Ask, mean_price + offset) };
+  SeedOrder { bid_or_ask, shares: qty_dist.sample(rng), limit_price: to_limit_price(price) }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::str::FromStr;
+  use rand::SeedableRng;
+
+  use super::*;
+  use crate::order_generator::distributions::QuantityDistConfig;
+
+  #[test]
+  fn snapshot_levels_are_rounded_and_non_positive_prices_dropped() {
+    let price = |p: &str| Decimal::from_str(p).expect("test prices should parse!");
+    let shape = SeedShape::Snapshot(SnapshotSeed {
+      bids: vec![(price("99.987"), 10), (price("0"), 10), (price("-5"), 10), (price("98"), 0)],
+      asks: vec![(price("100.011"), 20)]
+    });
+    let qty_dist = QuantitySampler::new(&QuantityDistConfig::default()).expect("default quantities should be valid!");
+    let orders = shape.orders(0, 100.0, 5.0, &qty_dist, &mut StdRng::seed_from_u64(1));
+
+    let levels: Vec<(Decimal, u64)> = orders.iter().map(|order| (order.limit_price, order.shares)).collect();
+    assert_eq!(levels, vec![(price("99.99"), 10), (price("100.01"), 20)]);
+  }
+
+  #[test]
+  fn seeds_are_capped_by_order_count_or_snapshot_levels() {
+    assert!(SeedShape::Normal.validate(MAX_SEED_ORDERS, 5.0).is_ok());
+    assert!(SeedShape::Normal.validate(MAX_SEED_ORDERS + 1, 5.0).is_err());
+    assert!(SeedShape::Flat(FlatSeedParams::default()).validate(u64::MAX, 5.0).is_err());
+
+    let level = (Decimal::ONE_HUNDRED, 10);
+    let snapshot = |levels: usize| SeedShape::Snapshot(SnapshotSeed { bids: vec![level; levels], asks: vec![level; levels] });
+    // snapshots ignore `seed_orders`
+    assert!(snapshot(10).validate(u64::MAX, 5.0).is_ok());
+    assert_eq!(snapshot(10).order_count(u64::MAX), 20);
+    assert!(snapshot(MAX_SEED_ORDERS as usize / 2 + 1).validate(0, 5.0).is_err());
+  }
+}
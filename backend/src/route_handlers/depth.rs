@@ -0,0 +1,14 @@
+use std::sync::Arc;
+use axum::{extract::Path, Extension, Json};
+
+use crate::{engine::snapshot::DepthSnapshot, midwares::app_state::{AppError, DepthRegistry}};
+
+// latest published depth of a running simulation, served without touching the engine task
+pub async fn depth_handler(
+  Path(sim_id): Path<String>,
+  Extension(depth_registry): Extension<DepthRegistry>
+) -> Result<Json<Arc<DepthSnapshot>>, AppError> {
+
+  let reader = depth_registry.get(&sim_id).await.ok_or_else(|| AppError::NotFound(format!("No running simulation with id {}", sim_id)))?;
+  Ok(Json(reader.latest()))
+}
@@ -0,0 +1,78 @@
+use std::{convert::Infallible, sync::Arc};
+use axum::{body::Body, extract::{Path, Query}, http::header::{CONTENT_DISPOSITION, CONTENT_TYPE}, response::{IntoResponse, Response}, Extension, Json};
+use protocol::upload::JobStatus;
+use serde::Deserialize;
+
+use crate::{file_upload::{downloads::{BookDepth, DownloadFormat, UploadDownloads}, jobs::{UploadJob, UploadJobResult}}, midwares::app_state::{AppError, RequestContext}};
+use super::uploads::UploadServices;
+
+#[derive(Debug, Deserialize)]
+pub struct DownloadParams {
+  #[serde(default)]
+  format: DownloadFormat,
+  // only used by the book download
+  #[serde(default)]
+  depth: BookDepth
+}
+
+fn find_job(services: &UploadServices, job_id: &str, req_ctx: &RequestContext) -> Result<Arc<UploadJob>, AppError> {
+  services.jobs.get(job_id, &req_ctx.remote_ip).ok_or_else(|| AppError::NotFound(format!("No upload job with id {}", job_id)))
+}
+
+fn find_downloads(services: &UploadServices, job_id: &str, req_ctx: &RequestContext) -> Result<Arc<UploadDownloads>, AppError> {
+  find_job(services, job_id, req_ctx)?.downloads().ok_or_else(|| AppError::NotFound(format!("Upload job {} has no downloads, they are kept for completed uploads that asked for them", job_id)))
+}
+
+// streams the rendered pieces of a download, the whole file is never held at once
+fn attachment(pieces: impl Iterator<Item = String> + Send + 'static, format: DownloadFormat, file_name: String) -> Response {
+  let disposition = format!("attachment; filename=\"{}.{}\"", file_name, format.extension());
+  let body = Body::from_stream(futures::stream::iter(pieces.map(Ok::<_, Infallible>)));
+  ([(CONTENT_TYPE, format.content_type().to_string()), (CONTENT_DISPOSITION, disposition)], body).into_response()
+}
+
+// state of an upload job, with its results once completed
+pub async fn get_job_handler(
+  Path(job_id): Path<String>,
+  Extension(services): Extension<UploadServices>,
+  Extension(req_ctx): Extension<RequestContext>
+) -> Result<Json<JobStatus<UploadJobResult>>, AppError> {
+
+  let job = find_job(&services, &job_id, &req_ctx)?;
+  Ok(Json(job.status()))
+}
+
+// cancels an upload job, finished jobs are left as they are
+pub async fn cancel_job_handler(
+  Path(job_id): Path<String>,
+  Extension(services): Extension<UploadServices>,
+  Extension(req_ctx): Extension<RequestContext>
+) -> Result<Json<JobStatus<UploadJobResult>>, AppError> {
+
+  let job = find_job(&services, &job_id, &req_ctx)?;
+  job.cancel();
+  Ok(Json(job.status()))
+}
+
+// every trade of the upload in the order the engine made them
+pub async fn trades_download_handler(
+  Path(job_id): Path<String>,
+  Query(params): Query<DownloadParams>,
+  Extension(services): Extension<UploadServices>,
+  Extension(req_ctx): Extension<RequestContext>
+) -> Result<Response, AppError> {
+
+  let downloads = find_downloads(&services, &job_id, &req_ctx)?;
+  Ok(attachment(downloads.trades(params.format), params.format, format!("{}_trades", job_id)))
+}
+
+// the books left once the whole upload was processed, per order (l3) or per price level (l2)
+pub async fn book_download_handler(
+  Path(job_id): Path<String>,
+  Query(params): Query<DownloadParams>,
+  Extension(services): Extension<UploadServices>,
+  Extension(req_ctx): Extension<RequestContext>
+) -> Result<Response, AppError> {
+
+  let downloads = find_downloads(&services, &job_id, &req_ctx)?;
+  Ok(attachment(downloads.book(params.depth, params.format), params.format, format!("{}_book_{}", job_id, params.depth.as_str())))
+}
@@ -1,2 +1,4 @@
+pub mod depth;
+pub mod jobs;
 pub mod sockets;
 pub mod uploads;
\ No newline at end of file
@@ -1,52 +1,149 @@
-use std::{io::Write, time::Duration};
+use std::{io::Write, sync::Arc, time::Duration};
 use axum::{
   extract::{ws::{self, CloseFrame, Message, Utf8Bytes, WebSocket}, WebSocketUpgrade}, response::IntoResponse, Extension
 };
 use flate2::{write::DeflateEncoder, Compression};
 use futures::stream::SplitSink;
-use tokio::{sync::mpsc, time::sleep};
+use tokio::{sync::mpsc, time::{sleep, sleep_until, Instant}};
+use tokio_util::sync::CancellationToken;
 use futures_util::{SinkExt, StreamExt};
-use serde::Deserialize;
-
-use crate::{midwares::app_state::{AppError, PostgresDBPool, RateLimiter, RequestContext}, order_generator::gen::{Simulator, WsResponse}};
-
-#[derive(Debug, Deserialize)]
-#[serde(tag = "type")]
-enum WsRequest {
-  Start {
-    total_objects: usize,  //defaults to 50_000
-    mean_price: f64,  //defaults to 300.0
-    sd_price: f64,  // defaults to 50.0
-    order_probs: Vec<f32>, //probs for [ADD, CANCEL, MODIFY] defaults to [0.0, 0.4 ,0.6] 
-    best_price_levels: bool // whether to show best bids and asks, defaults to false
-  },
-  Stop,
-  Ack
+use protocol::ws::{WsRequest, WsResponse};
+
+use uuid::Uuid;
+
+use crate::{engine::{risk::RiskConfig, snapshot::DepthReader}, midwares::app_state::{AppError, CommandExportDir, DepthRegistry, PostgresDBPool, RateLimiter, RequestContext, SimulationSlots}, order_generator::{export::CommandWriter, gen::{Simulator, SimulatorConfig}}};
+
+// shared server state every simulation needs
+#[derive(Clone)]
+pub struct SimulationServices {
+  pub risk_config: Arc<RiskConfig>,
+  pub depth_registry: DepthRegistry,
+  pub export_dir: CommandExportDir,
+  pub slots: SimulationSlots
 }
 
 enum Simulation {
   Start(Vec<WsResponse>),
   Data(Vec<WsResponse>),
+  // waiting for a simulation slot at this queue position
+  Queued(usize),
+  // send out the partially filled batch right away
+  Flush,
+  // the config was rejected before the simulation took a slot
+  Rejected(String),
   Complete
 }
 
+#[derive(Debug)]
+enum SimControl {
+  Pause,
+  Resume,
+  Step(usize),
+  SetRate(f64)
+}
+
+// flush partial batches this often while throttled so the client sees orders as they happen
+const THROTTLED_FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+// slower rates than one order per minute are treated as one per minute
+const SLOWEST_ORDER_INTERVAL: Duration = Duration::from_secs(60);
+// A paused simulation keeps its slot, so one left paused this long is ended to let queued runs start
+const MAX_PAUSE: Duration = Duration::from_secs(5 * 60);
+
+// Gates the simulation loop on the client's playback controls
+struct Playback {
+  controls: mpsc::UnboundedReceiver<SimControl>,
+  cancel: CancellationToken,
+  paused: bool,
+  steps_left: usize,
+  interval: Option<Duration>,
+  next_due: Instant,
+  last_flush: Instant
+}
+
+impl Playback {
+  fn new(controls: mpsc::UnboundedReceiver<SimControl>, cancel: CancellationToken) -> Self {
+    Playback { controls, cancel, paused: false, steps_left: 0, interval: None, next_due: Instant::now(), last_flush: Instant::now() }
+  }
+
+  fn apply(&mut self, control: SimControl) {
+    match control {
+      SimControl::Pause => self.paused = true,
+      SimControl::Resume => {
+        self.paused = false;
+        self.steps_left = 0;
+      },
+      SimControl::Step(orders) => {
+        self.paused = true;
+        self.steps_left = self.steps_left.saturating_add(orders);
+      },
+      SimControl::SetRate(orders_per_sec) => {
+        self.interval = (orders_per_sec > 0.0).then(|| Duration::try_from_secs_f64(1.0 / orders_per_sec).map_or(SLOWEST_ORDER_INTERVAL, |interval| interval.min(SLOWEST_ORDER_INTERVAL)));
+        self.next_due = Instant::now();
+      }
+    }
+  }
+
+  // waits until the next order may be generated, buffered updates are flushed before any wait.
+  // Returns false once the simulation was cancelled or stayed paused for longer than MAX_PAUSE
+  async fn next_turn(&mut self, tx: &mpsc::Sender<Simulation>) -> bool {
+    while let Ok(control) = self.controls.try_recv() {
+      self.apply(control);
+    }
+
+    let pause_deadline = Instant::now() + MAX_PAUSE;
+    while self.paused && self.steps_left == 0 {
+      let _ = tx.send(Simulation::Flush).await;
+      tokio::select! {
+        _ = self.cancel.cancelled() => return false,
+        _ = sleep_until(pause_deadline) => {
+          println!("[INFO] simulation stayed paused for longer than {:?}, ending it to free its slot", MAX_PAUSE);
+          return false;
+        },
+        control = self.controls.recv() => match control {
+          Some(control) => self.apply(control),
+          // the socket is gone and cancels the simulation as well
+          None => self.paused = false
+        }
+      }
+    }
+    if self.paused {
+      self.steps_left -= 1;
+    }
+
+    if let Some(interval) = self.interval {
+      if self.last_flush.elapsed() >= THROTTLED_FLUSH_INTERVAL {
+        let _ = tx.send(Simulation::Flush).await;
+        self.last_flush = Instant::now();
+      }
+      tokio::select! {
+        _ = self.cancel.cancelled() => return false,
+        _ = sleep_until(self.next_due) => {}
+      }
+      self.next_due = self.next_due.max(Instant::now()) + interval;
+    }
+    !self.cancel.is_cancelled()
+  }
+}
+
 pub async fn ws_handler (
   ws: WebSocketUpgrade,
   Extension(rate_limiter): Extension<RateLimiter>,
   Extension(postgres): Extension<PostgresDBPool>,
+  Extension(services): Extension<SimulationServices>,
   Extension(ctx): Extension<RequestContext>,
 ) -> impl IntoResponse {
 
   let RequestContext { remote_ip, origin, user_agent, timestamp, signature } = ctx;
 
   ws.protocols([signature.to_owned(), timestamp.to_owned()])
-    .on_upgrade(move|socket| handle_socket(socket,  rate_limiter, postgres, remote_ip, origin, user_agent))
+    .on_upgrade(move|socket| handle_socket(socket,  rate_limiter, postgres, services, remote_ip, origin, user_agent))
 }
 
 async fn handle_socket(
   socket: WebSocket,
   rate_limiter: RateLimiter,
   postgres: PostgresDBPool,
+  services: SimulationServices,
   who: String,
   origin: String,
   user_agent: String
@@ -63,6 +160,11 @@ async fn handle_socket(
 
   // Add flag to track if client supports compression
   let mut use_compression = false;
+  // playback controls of the running simulation
+  let mut controls: Option<mpsc::UnboundedSender<SimControl>> = None;
+  // simulations started on this socket end with it, whether the client stopped, left or the socket failed
+  let cancel = CancellationToken::new();
+  let _cancel_on_exit = cancel.clone().drop_guard();
 
   loop { 
     tokio::select! {
@@ -72,16 +174,29 @@ async fn handle_socket(
             Message::Text(t) => {
               println!(">>> {} sent string: {:?}", &who, t);
 
-              let payload = serde_json::from_str::<WsRequest>(t.as_str()).expect("derserializng client message failed");      
-            
+              let payload = match serde_json::from_str::<WsRequest<Box<SimulatorConfig>>>(t.as_str()) {
+                Ok(payload) => payload,
+                Err(e) => {
+                  reject_request(sender, format!("malformed request: {}", e), &who).await;
+                  break;
+                }
+              };
+
               match payload {
-                WsRequest::Start {total_objects, mean_price, sd_price, order_probs, best_price_levels, } => {
-                  println!("client payload\ntotal orders: {:?} mean: {:?} sd: {:?} show best price levels: {:?} order probs: {:?}", total_objects, mean_price, sd_price, best_price_levels, order_probs);
+                // one simulation per socket, a second one would interleave with the first on the same stream
+                WsRequest::Start { .. } if controls.is_some() => {
+                  reject_request(sender, "a simulation is already running on this socket".to_string(), &who).await;
+                  break;
+                },
+                WsRequest::Start {total_objects, config } => {
+                  println!("client payload\ntotal orders: {:?} mean: {:?} sd: {:?} show best price levels: {:?} order probs: {:?} seed: {:?}", total_objects, config.params.mean_price, config.params.sd_price, config.params.best_price_levels, config.params.order_probs, config.params.seed);
                   
                   // for now enable compression for all clients
                   use_compression = true;
 
-                  if let Err(e) = rate_limiter.would_exceed_limit(&who, &total_objects).await {
+                  // the seed orders go through the engine like the simulated ones
+                  let charged_orders = total_objects.saturating_add(config.seed_order_count());
+                  if let Err(e) = rate_limiter.would_exceed_limit(&who, &charged_orders).await {
                     println!("{:?}", e);
                     
                     // send a msg to client and close the connection
@@ -99,15 +214,21 @@ async fn handle_socket(
                     break;
                   }
                   // log in db
-                  postgres.record_in_db(&who, &origin, &user_agent, total_objects, false);
+                  postgres.record_in_db(&who, &origin, &user_agent, charged_orders, false);
                   // log in redis
-                  if let Err(e) = rate_limiter.record_orders(&who, total_objects).await {
+                  if let Err(e) = rate_limiter.record_orders(&who, charged_orders).await {
                     println!("recording orders to redis db failed with: {:?}", e);
                     break;
                   }
                   // spawn a task to start the ob engine
-                  tokio::spawn(process_start_message(tx.clone(), total_objects, mean_price, sd_price, order_probs, best_price_levels));
+                  let (control_tx, control_rx) = mpsc::unbounded_channel();
+                  controls = Some(control_tx);
+                  tokio::spawn(process_start_message(tx.clone(), total_objects, *config, services.clone(), control_rx, cancel.child_token()));
                 },
+                WsRequest::Pause => send_control(&controls, SimControl::Pause, &who),
+                WsRequest::Resume => send_control(&controls, SimControl::Resume, &who),
+                WsRequest::Step { orders } => send_control(&controls, SimControl::Step(orders), &who),
+                WsRequest::SetRate { orders_per_sec } => send_control(&controls, SimControl::SetRate(orders_per_sec), &who),
                 WsRequest::Stop => {
                   println!(">>> {} requested STOP", who);
                   graceful_ws_closure(sender, ws::close_code::NORMAL, "client requested to stop simulation").await;
@@ -133,6 +254,8 @@ async fn handle_socket(
         
         match msg {
           Simulation::Complete => {
+            // the simulation task is done, its controls went with it
+            controls = None;
             if !batch.is_empty() {
               // Send final batch
               println!("sending rem updates: {:?}", &batch.len());
@@ -160,6 +283,34 @@ async fn handle_socket(
               println!("Successfully sent completion signal")
             }
           },
+          Simulation::Rejected(reason) => {
+            reject_request(sender, reason, &who).await;
+            break;
+          },
+          Simulation::Queued(position) => {
+            // no need to compress here, small message
+            let queued_msg = Message::text(serde_json::to_string(&vec![[WsResponse::Queued { position }]]).expect("serializing queue position failed!"));
+            if sender.send(queued_msg).await.is_err() {
+              break;
+            }
+          },
+          Simulation::Flush => {
+            // paused or throttled simulations push out partial batches
+            if !batch.is_empty() {
+              let json_data = serde_json::to_string(&batch).expect("serializing flushed server updates failed!");
+              let update_msg = if use_compression {
+                let compressed = compress_data(&json_data).map_err(|e| println!("failed to compress flushed batch with {:?}", e)).expect("Compression Failure!");
+                Message::binary(compressed)
+              } else {
+                Message::text(json_data)
+              };
+
+              if sender.send(update_msg).await.is_err() {
+                break;
+              }
+              batch.clear();
+            }
+          },
           Simulation::Start(snapshot) => {
             // Send intial snapshot to WebSocket Client immediately
             let json_data = serde_json::to_string(&vec![snapshot]).expect("serializing snapshot failed!");
@@ -210,32 +361,150 @@ async fn handle_socket(
   println!("Websocket context destroyed for: {}", who);
 }
 
-async fn process_start_message(tx: mpsc::Sender<Simulation>, num_orders: usize, mean_price: f64, sd_price: f64, order_probs: Vec<f32>, best_price_lvls: bool) {
+async fn process_start_message(
+  tx: mpsc::Sender<Simulation>,
+  num_orders: usize,
+  config: SimulatorConfig,
+  services: SimulationServices,
+  controls: mpsc::UnboundedReceiver<SimControl>,
+  cancel: CancellationToken
+) {
+
+  let sim_id = Uuid::new_v4().to_string();
+
+  // invalid configs are turned away before they hold up the queue
+  let export_commands = config.export_commands;
+  let mut simulator = match Simulator::new(config, services.risk_config) {
+    Ok(simulator) => simulator,
+    Err(reason) => {
+      let _ = tx.send(Simulation::Rejected(reason)).await;
+      return;
+    }
+  };
+
+  // wait for a free simulation slot, the client is told where it is in line
+  let mut ticket = services.slots.enqueue();
+  let mut reported_position = 0;
+  let _permit = loop {
+    match ticket.try_start() {
+      Ok(permit) => break permit,
+      Err(position) => {
+        if position != reported_position {
+          reported_position = position;
+          println!("[INFO] simulation {} queued at position {}", sim_id, position);
+          if tx.send(Simulation::Queued(position)).await.is_err() {
+            return;
+          }
+        }
+        tokio::select! {
+          _ = cancel.cancelled() => {
+            println!("[INFO] simulation {} cancelled while queued", sim_id);
+            return;
+          },
+          _ = ticket.changed() => {}
+        }
+      }
+    }
+  };
+  drop(ticket);
 
-  let mut simulator = Simulator::new(mean_price, sd_price, order_probs, best_price_lvls);
-  // seed the orderbook with 10k ADD limit orders
-  simulator.seed_orderbook(10_000);
-  let snapshot = simulator.get_snapshot();
+  if export_commands {
+    match services.export_dir.file_for(&sim_id) {
+      Some(path) => match CommandWriter::create(&path) {
+        Ok(writer) => {
+          println!("[INFO] exporting commands of simulation {} to {:?}", sim_id, path);
+          simulator.export_commands(writer);
+        },
+        Err(e) => println!("creating command export file {:?} failed with: {:?}", path, e)
+      },
+      None => println!("command export requested but SIM_EXPORT_DIR is not set")
+    }
+  }
+  // seed the orderbook with ADD limit orders (10k by default)
+  simulator.seed_orderbook();
+  let snapshot = simulator.get_snapshot(&sim_id);
+
+  // price levels are read from published snapshots so the engine task never serializes depth
+  let depth_reader = simulator.depth_reader();
+  services.depth_registry.register(&sim_id, depth_reader.clone()).await;
   
   if tx.send(Simulation::Start(snapshot)).await.is_err() {
-    panic!("receiver half of channel dropped when sending initial snapshot!");
+    println!("client of simulation {} left before the initial snapshot was sent", sim_id);
+    cancel.cancel();
   }
+  let depth_fan_out = tokio::spawn(fan_out_depth(depth_reader, tx.clone()));
 
-  println!("[INFO] Starting simulation");
+  println!("[INFO] Starting simulation {}", sim_id);
+  let mut playback = Playback::new(controls, cancel.clone());
   for idx in 0..num_orders {
+    if simulator.duration_reached() {
+      println!("[INFO] simulation {} reached its duration after {} orders", sim_id, idx);
+      break;
+    }
+    if !playback.next_turn(&tx).await {
+      break;
+    }
     // generate and process the orders
+    simulator.run_scenario(idx);
     simulator.generate_orders();
     let updates = simulator.generate_updates(idx);
 
     if tx.send(Simulation::Data(updates)).await.is_err() {
-      panic!("receiver half of channel dropped!");
+      cancel.cancel();
+      break;
     };
   }
   //println!("trades: {:?}", simulator.book.executed_orders);
-  println!("[INFO] Completed simulation (total trades: {:?})", simulator.book.executed_orders.len());
+  let cancelled = cancel.is_cancelled();
+  if cancelled {
+    println!("[INFO] Cancelled simulation {} (total trades: {:?})", sim_id, simulator.book.executed_orders.len());
+  } else {
+    println!("[INFO] Completed simulation (total trades: {:?})", simulator.book.executed_orders.len());
+    if let Some(report) = simulator.agent_report() {
+      if tx.send(Simulation::Data(vec![WsResponse::AgentReport(report)])).await.is_err() {
+        println!("sending agent report of simulation {} failed, client left", sim_id);
+      }
+    }
+  }
 
-  if tx.send(Simulation::Complete).await.is_err() { 
-    panic!("Could not send close signal to channel after simulation was complete!");
+  // a cancelled run still leaves a replayable export of what was processed
+  if let Err(e) = simulator.finish_export() {
+    println!("flushing command export of simulation {} failed with: {:?}", sim_id, e);
+  }
+
+  // dropping the simulator closes the depth publisher, let the fan-out drain before signalling completion
+  drop(simulator);
+  if let Err(e) = depth_fan_out.await {
+    println!("depth fan-out task failed with: {:?}", e);
+  }
+  services.depth_registry.remove(&sim_id).await;
+
+  if !cancelled && tx.send(Simulation::Complete).await.is_err() {
+    println!("sending completion of simulation {} failed, client left", sim_id);
+  }
+}
+
+fn send_control(controls: &Option<mpsc::UnboundedSender<SimControl>>, control: SimControl, who: &str) {
+  println!(">>> {} sent {:?}", who, control);
+  match controls {
+    Some(controls) => if controls.send(control).is_err() {
+      println!("simulation of {} already finished, ignoring control", who);
+    },
+    None => println!("no simulation running for {}, ignoring control", who)
+  }
+}
+
+// streams newly published depth snapshots to the client, skipping any it could not keep up with
+async fn fan_out_depth(reader: DepthReader, tx: mpsc::Sender<Simulation>) {
+  let mut seen_seq = reader.latest().seq;
+
+  while let Some(depth) = reader.changed(seen_seq).await {
+    seen_seq = depth.seq;
+    // NOTE: bids or asks may be empty vectors
+    let price_levels = WsResponse::PriceLevels { snapshot: false, sim_time_ns: depth.sim_time_ns, bids: depth.bids.clone(), asks: depth.asks.clone() };
+    if tx.send(Simulation::Data(vec![price_levels])).await.is_err() {
+      break;
+    }
   }
 }
 
@@ -248,6 +517,16 @@ fn compress_data(data: &str) -> Result<Vec<u8>, AppError> {
 }
 
 // helper to close the Websocket gracefully
+// tells the client why its request was turned down and closes the socket
+async fn reject_request(mut sender: SplitSink<WebSocket, Message>, reason: String, who: &str) {
+  println!("request of {} rejected: {}", who, reason);
+  let rejected_msg = Message::text(serde_json::to_string(&vec![[WsResponse::InvalidRequest { reason }]]).expect("serializing invalid request signal failed!"));
+  if let Err(e) = sender.send(rejected_msg).await {
+    println!("sending invalid request message to client failed with: {:?}", e);
+  }
+  graceful_ws_closure(sender, ws::close_code::INVALID, "invalid request").await;
+}
+
 async fn graceful_ws_closure(mut sender: SplitSink<WebSocket, Message>, code: u16, reason_str: & 'static str) {
   //send a closeframe
   if let Err(e) = sender.send(Message::Close(Some(CloseFrame {
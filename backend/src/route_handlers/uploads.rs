@@ -1,23 +1,44 @@
-use axum::{body::Bytes, extract::{Multipart, State}, http::HeaderMap, Extension, Json};
+use std::sync::Arc;
+use axum::{body::Bytes, extract::{Multipart, Path, State}, http::HeaderMap, Extension, Json};
+use protocol::{upload::{ChunkStatus, SmallUploadRequest}, validator::{validate_orders, ValidationPolicy}};
 use serde::Deserialize;
 
 use crate::{
-  file_upload::{parser::parse_file_orders, processor::{decompress_if_needed, process_uploaded_orders, LargeUploadResponse, LargeUploadSessionManager, SmallUploadRequest, SmallUploadResponse, SmallUploadSessionManager}},
+  engine::{book_stats::BookStatsConfig, risk::RiskConfig},
+  file_upload::{downloads::UploadDownloads, jobs::{UploadJobResult, UploadJobs}, processor::{capped_parse_report, decode_body, process_uploaded_orders, ChunkMeta, FinishedUpload, LargeUploadResponse, LargeUploadSessionManager, SmallUploadResponse, SmallUploadSessionManager, StreamingUpload, UploadResults}},
   midwares::app_state::{estimate_orders_from_1stchunk, AppError, PostgresDBPool, RateLimiter, RequestContext}
 };
 
+// shared server state every upload needs
+#[derive(Clone)]
+pub struct UploadServices {
+  pub risk_config: Arc<RiskConfig>,
+  pub jobs: UploadJobs,
+  // book stats sampling of uploads that do not pick their own interval
+  pub book_stats: BookStatsConfig
+}
+
+impl UploadServices {
+  // the server's book stats config, sampled every `interval` orders if the upload asked for it
+  fn book_stats(&self, interval: Option<usize>) -> BookStatsConfig {
+    BookStatsConfig { interval: interval.unwrap_or(self.book_stats.interval), ..self.book_stats.clone() }
+  }
+}
+
 pub async fn small_upload_handler(
   State(state): State<SmallUploadSessionManager>,
   Extension(rate_limiter): Extension<RateLimiter>,
   Extension(postgres): Extension<PostgresDBPool>,
+  Extension(services): Extension<UploadServices>,
   Extension(req_ctx): Extension<RequestContext>,
   headers: HeaderMap,
   body: Bytes
 ) -> Result<Json<SmallUploadResponse>, AppError> {
   // decompress if required
-  let content_encoding = headers.get("content-encoding").and_then(|v| v.to_str().ok());
+  let content_encoding = headers.get("content-encoding").and_then(|v| v.to_str().ok()).map(str::to_string);
   //println!("content encoding for sf handler: {:?}", &content_encoding);
-  let decompressed_data = decompress_if_needed(&body, content_encoding)?;
+  let checksum = headers.get("x-chunk-sha256").and_then(|v| v.to_str().ok()).map(str::to_string);
+  let (decompressed_data, checksum) = decode_body(body, content_encoding, checksum).await?;
   // deserialize the payload
   let payload = match <SmallUploadRequest>::deserialize(&mut rmp_serde::Deserializer::new(&decompressed_data[..])) {
     Ok(de_payload) => de_payload,
@@ -28,7 +49,7 @@ pub async fn small_upload_handler(
   };
 
   // destructure the payload
-  let SmallUploadRequest { session_id, total_chunks, total_orders, chunk_number, orders } = payload;
+  let SmallUploadRequest { session_id, total_chunks, total_orders, chunk_number, orders, parse_report, validation, downloads, book_stats_interval } = payload;
   
   let remote_ip = req_ctx.remote_ip;
   let origin = req_ctx.origin;
@@ -43,27 +64,82 @@ pub async fn small_upload_handler(
     };
   }
 
-  state.store_chunk(&session_id, chunk_number, orders, total_chunks).await;
+  let chunk = ChunkMeta { session_id: session_id.clone(), total_chunks, chunk_number, bytes: decompressed_data.len(), checksum };
+  state.store_chunk(&remote_ip, &chunk, orders).await?;
 
   let is_complete = state.is_upload_complete(&session_id).await;
 
   if is_complete {
+    // the job is reserved while the chunks are still kept, a client refused here can send its last chunk again later
+    let UploadServices { risk_config, jobs, .. } = services.clone();
+    let job = jobs.create(&remote_ip).map_err(AppError::RateLimitExceeded)?;
     // get complete order vector
-    let complete_orders = state.get_all_chunks(&session_id).await.map_err(|e| AppError::InternalError(e))?;
+    let complete_orders = match state.get_all_chunks(&session_id).await {
+      Ok(complete_orders) => complete_orders,
+      Err(e) => {
+        job.fail(&e);
+        return Err(AppError::InternalError(e));
+      }
+    };
+    // Clean up the chunks, the job owns the orders now
+    state.clear_chunks(&session_id).await.map_err(AppError::InternalError)?;
 
-    // log in db
-    postgres.record_in_db(&remote_ip, &origin, &user_agent, total_orders, false);
-    // log in redis
-    rate_limiter.record_orders(&remote_ip, total_orders).await?;
+    let book_stats = services.book_stats(book_stats_interval);
+    let job_id = job.id().to_string();
+    tokio::spawn(async move {
+      let account = remote_ip.clone();
+      let outcome = jobs.run(job.clone(), move |job| {
+        job.parsing();
+        let (complete_orders, validation_report) = validate_orders(complete_orders, validation);
+        // a rejected file never reaches the engine
+        if validation_report.rejected {
+          return Some((None, validation_report));
+        }
+        let results = process_uploaded_orders(complete_orders, risk_config, &account, downloads, book_stats, job)?;
+        Some((Some(results), validation_report))
+      }).await;
+      let Some((results, validation_report)) = outcome else {
+        return;
+      };
 
-    let ob_results = process_uploaded_orders(complete_orders);
+      // rejected files do not count towards the rate limit
+      if results.is_some() {
+        // log in db
+        postgres.record_in_db(&remote_ip, &origin, &user_agent, total_orders, false);
+        // log in redis
+        if let Err(e) = rate_limiter.record_orders(&remote_ip, total_orders).await {
+          job.fail(e.message());
+          return;
+        }
+      }
 
-    // Clean up the chunks after processing
-    state.clear_chunks(&session_id).await.map_err(|e| AppError::InternalError(e))?;
+      let (orderbook_results, overall_results, risk_rejects, book_stats, downloads) = match results {
+        Some(UploadResults { orderbook_results, overall_results, risk_rejects, book_stats, downloads }) => (Some(orderbook_results), overall_results, Some(risk_rejects), Some(book_stats), downloads),
+        None => (None, None, None, None, None)
+      };
+      job.complete(UploadJobResult::Small(SmallUploadResponse {
+        orderbook_results,
+        overall_results,
+        risk_rejects,
+        book_stats,
+        parse_report: parse_report.map(capped_parse_report),
+        validation_report: Some(validation_report),
+        downloads: downloads.as_ref().map(UploadDownloads::summary),
+        processed: true,
+        job_id: None
+      }), downloads);
+    });
 
     return Ok(Json(SmallUploadResponse {
-      orderbook_results: Some(ob_results),
-      processed: true
+      orderbook_results: None,
+      overall_results: None,
+      risk_rejects: None,
+      book_stats: None,
+      parse_report: None,
+      validation_report: None,
+      downloads: None,
+      processed: false,
+      job_id: Some(job_id)
     }));
   }
 
@@ -71,7 +147,14 @@ pub async fn small_upload_handler(
   Ok(Json(
     SmallUploadResponse {
       orderbook_results: None,
-      processed: false
+      overall_results: None,
+      risk_rejects: None,
+      book_stats: None,
+      parse_report: None,
+      validation_report: None,
+      downloads: None,
+      processed: false,
+      job_id: None
     }))
 }
 
@@ -79,12 +162,13 @@ pub async fn large_upload_handler (
   State(state): State<LargeUploadSessionManager>,
   Extension(rate_limiter): Extension<RateLimiter>,
   Extension(postgres): Extension<PostgresDBPool>,
+  Extension(services): Extension<UploadServices>,
   Extension(req_ctx): Extension<RequestContext>,
   headers: HeaderMap,
   mut multipart: Multipart
 ) -> Result<Json<LargeUploadResponse>, AppError> {
 
-  let content_encoding = headers.get("content-encoding").and_then(|v| v.to_str().ok());
+  let content_encoding = headers.get("content-encoding").and_then(|v| v.to_str().ok()).map(str::to_string);
   //println!("content encoding for lf handler: {:?}", &content_encoding);
 
   // Extract multipart fields
@@ -92,12 +176,18 @@ pub async fn large_upload_handler (
   let mut total_chunks = None;
   let mut chunk_number = None;
   let mut chunk_data = None;
+  let mut validation = ValidationPolicy::default();
+  let mut downloads = false;
+  let mut book_stats_interval = None;
 
   while let Some(field) = multipart.next_field().await.map_err(|e| AppError::BadRequest(e.to_string()))? {
     match field.name() {
       Some("session_id") => session_id = Some(field.text().await.map_err(|e| AppError::BadRequest(e.to_string()))?),
       Some("total_chunks") => total_chunks = Some(field.text().await.map_err(|e| AppError::BadRequest(e.to_string()))?.parse::<usize>().map_err(|_| AppError::BadRequest("Invalid total_chunks value".to_string()))?),
       Some("chunk_number") => chunk_number = Some(field.text().await.map_err(|e| AppError::BadRequest(e.to_string()))?.parse::<usize>().map_err(|_| AppError::BadRequest("Invalid chunk_number value".to_string()))?),
+      Some("validation") => validation = field.text().await.map_err(|e| AppError::BadRequest(e.to_string()))?.parse().map_err(AppError::BadRequest)?,
+      Some("downloads") => downloads = field.text().await.map_err(|e| AppError::BadRequest(e.to_string()))?.parse().map_err(|_| AppError::BadRequest("Invalid downloads value".to_string()))?,
+      Some("book_stats_interval") => book_stats_interval = Some(field.text().await.map_err(|e| AppError::BadRequest(e.to_string()))?.parse::<usize>().map_err(|_| AppError::BadRequest("Invalid book_stats_interval value".to_string()))?),
       Some("chunk") => chunk_data = Some(field.bytes().await.map_err(|e| AppError::BadRequest(e.to_string()))?),
       _ => {}
     }
@@ -110,7 +200,9 @@ pub async fn large_upload_handler (
   let chunk_data = chunk_data.ok_or(AppError::BadRequest("Missing chunk_data".to_string()))?;
 
   // decompress if needed
-  let decompressed_chunk: Bytes = decompress_if_needed(&chunk_data, content_encoding)?.into();
+  let checksum = headers.get("x-chunk-sha256").and_then(|v| v.to_str().ok()).map(str::to_string);
+  let (decompressed_chunk, checksum) = decode_body(chunk_data, content_encoding, checksum).await?;
+  let decompressed_chunk = Bytes::from(decompressed_chunk);
 
   let remote_ip = req_ctx.remote_ip;
   let origin = req_ctx.origin;
@@ -125,43 +217,90 @@ pub async fn large_upload_handler (
     };
   }
   
-  state.store_chunk(&session_id, chunk_number, decompressed_chunk, total_chunks).await;
-
-  let is_complete = state.is_upload_complete(&session_id).await;
+  // chunks are parsed and processed as they arrive, the session only keeps the books and a partial line
+  let chunk = ChunkMeta { session_id: session_id.clone(), total_chunks, chunk_number, bytes: decompressed_chunk.len(), checksum };
+  let (session, is_new) = state.accept_chunk(&remote_ip, &chunk, || StreamingUpload::new(total_chunks, validation, downloads, services.book_stats(book_stats_interval), services.risk_config.clone(), &remote_ip)).await?;
+  // feeding a chunk runs the engine, so it happens off the async executor
+  let (jobs, owner) = (services.jobs.clone(), remote_ip.clone());
+  let (fed, buffered, finished) = tokio::task::spawn_blocking(move || {
+    let mut session = session.lock().expect("upload session lock poisoned!");
+    // another request completed the upload already
+    let Some(upload) = session.as_mut() else {
+      return (Ok(None), 0, None);
+    };
+    // a resent chunk went through the engine already, it is only acknowledged again
+    let fed = if is_new { upload.accept(chunk_number, decompressed_chunk).map(|_| Some(upload.orders_seen())) } else { Ok(None) };
+    let buffered = upload.buffered_bytes();
+    // The request that sees the upload complete reserves its job and takes it out, any other one still holding the session finds it empty.
+    // Without a job the upload stays in the session, so resending the last chunk tries again
+    let finished = (fed.is_ok() && upload.is_complete()).then(|| jobs.create(&owner).map(|job| (job, session.take().expect("the upload was just seen!"))));
+    (fed, buffered, finished)
+  }).await.map_err(|e| AppError::InternalError(e.to_string()))?;
+  state.set_buffered(&session_id, buffered).await;
+  let orders_seen = fed.map_err(AppError::BadRequest)?;
+  let finished = finished.transpose().map_err(AppError::RateLimitExceeded)?;
 
-  if is_complete {
-    // get complete file data
-    let complete_data = state.get_all_chunks(&session_id).await.map_err(|e| AppError::InternalError(e))?;
-
-    let (parsed_orders, duration, raw_cnt, invalid_cnt) = parse_file_orders(&complete_data);
-    let total_orders = parsed_orders.len();
-    // return early if no valid orders were found
-    if total_orders == 0 {
-      return Ok(Json(
-        LargeUploadResponse {
-          orderbook_results: None,
-          parse_results: Some((duration, raw_cnt, invalid_cnt)),
-          processed: true
-        }
-      ));
+  // now we check for ratelimits with actual orders
+  if let Some(orders_seen) = orders_seen {
+    if let Err(e) = rate_limiter.would_exceed_limit(&remote_ip, &orders_seen).await {
+      state.remove(&session_id).await;
+      if let Some((job, _)) = &finished {
+        job.fail(e.message());
+      }
+      return Err(e);
     }
-    // now we check for ratelimits with actual orders
-    rate_limiter.would_exceed_limit(&remote_ip, &total_orders).await?;
-    // log in db
-    postgres.record_in_db(&remote_ip, &origin, &user_agent, total_orders, false);
-    // log in redis
-    rate_limiter.record_orders(&remote_ip, total_orders).await?;
+  }
 
-    let ob_results = process_uploaded_orders(parsed_orders);
+  if let Some((job, upload)) = finished {
+    state.remove(&session_id).await;
+    let jobs = services.jobs;
+    let job_id = job.id().to_string();
+    tokio::spawn(async move {
+      let Some(FinishedUpload { results, orders, parse_results, parse_report, validation_report }) = jobs.run(job.clone(), move |job| upload.finish(job)).await else {
+        return;
+      };
 
-    // Clean up the chunks after processing
-    state.clear_chunks(&session_id).await.map_err(|e| AppError::InternalError(e))?;
+      // no valid orders were found or the file was rejected
+      if results.is_some() {
+        // log in db
+        postgres.record_in_db(&remote_ip, &origin, &user_agent, orders, false);
+        // log in redis
+        if let Err(e) = rate_limiter.record_orders(&remote_ip, orders).await {
+          job.fail(e.message());
+          return;
+        }
+      }
+
+      let (orderbook_results, overall_results, risk_rejects, book_stats, downloads) = match results {
+        Some(UploadResults { orderbook_results, overall_results, risk_rejects, book_stats, downloads }) => (Some(orderbook_results), overall_results, Some(risk_rejects), Some(book_stats), downloads),
+        None => (None, None, None, None, None)
+      };
+      job.complete(UploadJobResult::Large(LargeUploadResponse {
+        orderbook_results,
+        overall_results,
+        risk_rejects,
+        book_stats,
+        parse_results: Some(parse_results),
+        parse_report: Some(parse_report),
+        validation_report: Some(validation_report),
+        downloads: downloads.as_ref().map(UploadDownloads::summary),
+        processed: true,
+        job_id: None
+      }), downloads);
+    });
 
     return Ok(Json(
       LargeUploadResponse {
-        orderbook_results: Some(ob_results),
-        parse_results: Some((duration, raw_cnt, invalid_cnt)),
-        processed: true
+        orderbook_results: None,
+        overall_results: None,
+        risk_rejects: None,
+        book_stats: None,
+        parse_results: None,
+        parse_report: None,
+        validation_report: None,
+        downloads: None,
+        processed: false,
+        job_id: Some(job_id)
       }
     ));
   }
@@ -170,7 +309,28 @@ pub async fn large_upload_handler (
   Ok(Json(
     LargeUploadResponse {
       orderbook_results: None,
+      overall_results: None,
+      risk_rejects: None,
+      book_stats: None,
       parse_results: None,
-      processed: false
+      parse_report: None,
+      validation_report: None,
+      downloads: None,
+      processed: false,
+      job_id: None
     }))
+}
+
+// received and missing chunks of an upload in transit, so an interrupted client can send only what is missing
+pub async fn chunk_status_handler(
+  Path(session_id): Path<String>,
+  State((small_sessions, large_sessions)): State<(SmallUploadSessionManager, LargeUploadSessionManager)>,
+  Extension(req_ctx): Extension<RequestContext>
+) -> Result<Json<ChunkStatus>, AppError> {
+
+  let status = match small_sessions.status(&session_id, &req_ctx.remote_ip).await {
+    Some(status) => Some(status),
+    None => large_sessions.status(&session_id, &req_ctx.remote_ip).await
+  };
+  status.map(Json).ok_or_else(|| AppError::NotFound(format!("No upload in progress with session id {}", session_id)))
 }
\ No newline at end of file
@@ -27,6 +27,16 @@ fn main() {
   } else {
     panic!("LARGE_UPLOAD_URL must be set at compile time!");
   }
+  if let Ok(key) = std::env::var("JOBS_URL") {
+    println!("cargo::rustc-env=JOBS_URL={}", key);
+  } else {
+    panic!("JOBS_URL must be set at compile time!");
+  }
+  if let Ok(key) = std::env::var("UPLOADS_URL") {
+    println!("cargo::rustc-env=UPLOADS_URL={}", key);
+  } else {
+    panic!("UPLOADS_URL must be set at compile time!");
+  }
   if let Ok(key) = std::env::var("WSS_URL") {
     println!("cargo::rustc-env=WSS_URL={}", key);
   } else {
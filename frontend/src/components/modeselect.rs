@@ -8,9 +8,12 @@ use dioxus::web::WebEventExt;
 use web_sys::{HtmlInputElement, wasm_bindgen::JsCast};
 use crate::components::formDialog::Dialog;
 use crate::components::toast::{ErrorToast, SuccessToast};
-use crate::pages::simulator::{Mode, HEALTH_CHECK_URL, LARGE_UPLOAD_URL, SMALL_UPLOAD_URL};
+use crate::pages::simulator::{Mode, HEALTH_CHECK_URL, JOBS_URL, LARGE_UPLOAD_URL, SMALL_UPLOAD_URL, UPLOADS_URL};
 use crate::utils::auth::AuthSignature;
-use crate::utils::file_handler::{format_duration, FileUploadOrder, FileUploadOrderType, FinalStats, PreviewRow, UnifiedUploader};
+use crate::utils::file_handler::{format_duration, FinalStats, PreviewRow, UnifiedUploader, UPLOAD_DOWNLOADS};
+use crate::utils::server::{DownloadSummary, JobState};
+use crate::utils::grammar::{FileUploadOrderType, OrderFileParser, OrderLine, ParseReport};
+use crate::utils::validator::{ValidationPolicy, ValidationReport};
 
 static SMALL_FILE: Asset = asset!("assets/sample_small_file.txt");
 static LARGE_FILE: Asset = asset!("assets/sample_large_file.txt");
@@ -18,7 +21,7 @@ static LARGE_FILE: Asset = asset!("assets/sample_large_file.txt");
 #[component]
 pub fn ModeSelector(mut mode: Signal<Mode>, mut form_data: Signal<HashMap<String, FormValue>>, mut is_valid_sim_settings: Signal<bool>) -> Element {
   
-  let mut parsed_orders: Signal<Vec<FileUploadOrderType>> = use_signal(|| vec![]);
+  let mut parsed_orders: Signal<Vec<OrderLine>> = use_signal(|| vec![]);
   let mut large_file_contents: Signal<Vec<u8>> = use_signal(||vec![]);
   let mut total_raw_orders: Signal<i32> = use_signal(|| 0);
   let mut selected_file: Signal<Option<String>> = use_signal(||None);
@@ -27,10 +30,20 @@ pub fn ModeSelector(mut mode: Signal<Mode>, mut form_data: Signal<HashMap<String
   let mut is_large_file: Signal<bool> = use_signal(||false);
   let mut ob_results: Signal<Option<HashMap<String, FinalStats>>> = use_signal(||None);
   let mut parse_results: Signal<Option<(Duration, i32, i32)>> = use_signal(||None);
+  // errors found while parsing small files here, sent along with the upload
+  let mut client_report: Signal<ParseReport> = use_signal(ParseReport::default);
+  let mut parse_report: Signal<Option<ParseReport>> = use_signal(||None);
+  let mut validation_policy: Signal<ValidationPolicy> = use_signal(ValidationPolicy::default);
+  let mut validation_report: Signal<Option<ValidationReport>> = use_signal(||None);
+  // id and state of the server job processing the uploaded file
+  let mut upload_job: Signal<Option<(String, JobState)>> = use_signal(||None);
+  // keep the trade tape and final book of the upload for download
+  let mut keep_downloads: Signal<bool> = use_signal(||false);
+  let mut upload_downloads: Signal<Option<(String, DownloadSummary)>> = use_signal(||None);
 
   // set max size to 5MB for which we show preview and do UI side order parsing 
   const MAX_PREVIEWABLE_FILESIZE: u64 = 1024 * 1024 * 5;
-  let uploader = use_signal(||UnifiedUploader::new(reqwest::Client::new(), SMALL_UPLOAD_URL, LARGE_UPLOAD_URL, HEALTH_CHECK_URL).with_compression(true));
+  let uploader = use_signal(||UnifiedUploader::new(reqwest::Client::new(), SMALL_UPLOAD_URL, LARGE_UPLOAD_URL, JOBS_URL, UPLOADS_URL, HEALTH_CHECK_URL).with_compression(true));
 
   let read_files = move |file_engine: std::sync::Arc<dyn FileEngine>| async move {
     let current_file_names = file_engine.files();
@@ -39,25 +52,31 @@ pub fn ModeSelector(mut mode: Signal<Mode>, mut form_data: Signal<HashMap<String
       let file_name = &current_file_names[0];
       selected_file.set(Some(file_name.clone()));
 
-      if !file_name.to_lowercase().ends_with(".txt") {
+      let is_binary_file = file_name.to_lowercase().ends_with(".bin");
+      if !file_name.to_lowercase().ends_with(".txt") && !is_binary_file {
         invalid_file.set(true);
-        //error!("file name doesnt end with .txt!");
+        //error!("file name doesnt end with .txt or .bin!");
         return;
       }
 
       let file_size = file_engine.file_size(&file_name).await.expect("error getting uploaded file size!");
 
-      // parse small files client side
-      if file_size < MAX_PREVIEWABLE_FILESIZE {
+      // parse small text files client side, binary files are always parsed by the server
+      if file_size < MAX_PREVIEWABLE_FILESIZE && !is_binary_file {
 
         if let Some(contents) = file_engine.read_file_to_string(&file_name).await {
-          for order in contents.lines() {
-            *total_raw_orders.write() += 1;
-            match FileUploadOrder::parse(order) {
-              Ok(valid_order) => parsed_orders.write().push(valid_order.order),
-              Err(_e) => {
-                //warn!("parse error: {:?}", e);
-                //*invalid_orders.write() += 1;
+          // same grammar as the server, blank lines, comments and the header are not orders
+          let mut parser = OrderFileParser::new();
+          for (line_idx, line) in contents.lines().enumerate() {
+            match parser.parse_line(line) {
+              Ok(Some(valid_order)) => {
+                *total_raw_orders.write() += 1;
+                parsed_orders.write().push(valid_order);
+              },
+              Ok(None) => {},
+              Err(e) => {
+                *total_raw_orders.write() += 1;
+                client_report.write().record(line_idx + 1, &e, line);
               }
             }
           }
@@ -82,6 +101,9 @@ pub fn ModeSelector(mut mode: Signal<Mode>, mut form_data: Signal<HashMap<String
       selected_file.set(None);
       ob_results.set(None);
       parse_results.set(None);
+      parse_report.set(None);
+      validation_report.set(None);
+      client_report.set(ParseReport::default());
       read_files(file_engine).await;
       // clear file inputs to enable reupload of same file
       if let Some(web_evt) = evt.try_as_web_event() {
@@ -121,6 +143,7 @@ pub fn ModeSelector(mut mode: Signal<Mode>, mut form_data: Signal<HashMap<String
             //info!("[on submit] file name: {}", &f_name); 
             server_processing.set(Some(f_name.clone()));
             selected_file.set(None);
+            upload_downloads.set(None);
 
             let upload_handler = uploader.read();
             let auth_signer = AuthSignature::new().await.expect("failed to init the auth signature!");
@@ -139,8 +162,10 @@ pub fn ModeSelector(mut mode: Signal<Mode>, mut form_data: Signal<HashMap<String
             if !is_large_file() {
               let current_orders = parsed_orders();
               parsed_orders.write().clear();
+              let current_report = client_report();
+              client_report.set(ParseReport::default());
 
-              if let Err(_e) = upload_handler.upload_small_file(current_orders, 10_000, auth_signer, ob_results).await {
+              if let Err(_e) = upload_handler.upload_small_file(current_orders, current_report, validation_policy(), keep_downloads(), 10_000, auth_signer, ob_results, parse_report, validation_report, upload_job, upload_downloads).await {
                 //error!("[Small upload error] {}", e.to_string());
                 document::eval(r#"
                 var x = document.getElementById("upload-server-down-toast");
@@ -152,7 +177,7 @@ pub fn ModeSelector(mut mode: Signal<Mode>, mut form_data: Signal<HashMap<String
               let current_lf_bytes = large_file_contents();
               large_file_contents.write().clear();
 
-              if let Err(_e) = upload_handler.upload_large_file(current_lf_bytes, &f_name, auth_signer, ob_results, parse_results).await {
+              if let Err(_e) = upload_handler.upload_large_file(current_lf_bytes, &f_name, validation_policy(), keep_downloads(), auth_signer, ob_results, parse_results, parse_report, validation_report, upload_job, upload_downloads).await {
                 //error!("[Large upload error] {}", e.to_string());
                 document::eval(r#"
                 var x = document.getElementById("upload-server-down-toast");
@@ -161,12 +186,13 @@ pub fn ModeSelector(mut mode: Signal<Mode>, mut form_data: Signal<HashMap<String
                 "#);
               };
             }
+            upload_job.set(None);
             server_processing.set(None);
           },
           div {
             class: "upload-container",
             h3 { "Upload Text Files" }
-            p { class: "upload-subtitle", "Upload your .txt files for processing" }
+            p { class: "upload-subtitle", "Upload your .txt or .bin order files for processing" }
             div {
               class: "upload-area",
               id: "dropzone",
@@ -198,6 +224,9 @@ pub fn ModeSelector(mut mode: Signal<Mode>, mut form_data: Signal<HashMap<String
                   selected_file.set(None);
                   ob_results.set(None);
                   parse_results.set(None);
+                  parse_report.set(None);
+                  validation_report.set(None);
+                  client_report.set(ParseReport::default());
                   read_files(file_engine).await;
                   // clear file inputs to enable reupload of same file
                   if let Some(web_evt) = evt.try_as_web_event() {
@@ -230,7 +259,7 @@ pub fn ModeSelector(mut mode: Signal<Mode>, mut form_data: Signal<HashMap<String
                 r#type: "file",
                 id: "file-upload",
                 class: "file-input",
-                accept: ".txt",
+                accept: ".txt,.bin",
                 onchange: upload_files
               }
             }
@@ -250,6 +279,32 @@ pub fn ModeSelector(mut mode: Signal<Mode>, mut form_data: Signal<HashMap<String
                 }
               }
               if !invalid_file() {
+                div {
+                  class: "file-validation",
+                  label { r#for: "validation-policy", "Invalid orders: " }
+                  select {
+                    id: "validation-policy",
+                    value: "{validation_policy().as_str()}",
+                    onchange: move |evt| {
+                      if let Ok(policy) = evt.value().parse::<ValidationPolicy>() {
+                        validation_policy.set(policy);
+                      }
+                    },
+                    option { value: "skip", "Skip them" }
+                    option { value: "reject", "Reject the file" }
+                    option { value: "pass_through", "Send them anyway" }
+                  }
+                }
+                div {
+                  class: "file-validation",
+                  label { r#for: "keep-downloads", "Keep trades and final book for download: " }
+                  input {
+                    id: "keep-downloads",
+                    r#type: "checkbox",
+                    checked: keep_downloads(),
+                    onchange: move |evt| keep_downloads.set(evt.checked())
+                  }
+                }
                 if !is_large_file() {
                   if parsed_orders().len() > 0 {
                     div { 
@@ -270,17 +325,31 @@ pub fn ModeSelector(mut mode: Signal<Mode>, mut form_data: Signal<HashMap<String
                       "Submit"
                     }
                   }
-                  span {"No preview available for files > 5MB"}
+                  span {"No preview available for binary files or files > 5MB"}
                 }
               } else {
-                div { class: "upload-err-msg fileformat", "Only .txt files are allowed!" }
+                div { class: "upload-err-msg fileformat", "Only .txt and .bin files are allowed!" }
               }
             }
           }
           if selected_file().is_some() && !is_large_file() && parsed_orders().len() > 0 {
             PreviewTable { orders: parsed_orders() }
           }
-          if let Some(f_name) = server_processing() { ProgressBar { f_name } }
+          if let Some(f_name) = server_processing() {
+            ProgressBar {
+              f_name,
+              job_state: upload_job().map(|(_, state)| state),
+              on_cancel: move |_| async move {
+                let Some((job_id, _)) = upload_job() else {
+                  return;
+                };
+                let auth_signer = AuthSignature::new().await.expect("failed to init the auth signature!");
+                if let Err(_e) = uploader.read().cancel_job(&job_id, &auth_signer).await {
+                  //error!("[Cancel upload error] {}", e.to_string());
+                }
+              }
+            }
+          }
         }
 
         if server_processing().is_none() && selected_file().is_none() && ob_results().is_none() {
@@ -292,6 +361,46 @@ pub fn ModeSelector(mut mode: Signal<Mode>, mut form_data: Signal<HashMap<String
         if parse_results().is_some() {
           ParseTable { parse_results: parse_results().expect("parse results should exist here!") }
         }
+        if let Some(report) = parse_report().filter(|r| r.total_errors > 0) {
+          ParseErrorTable { report }
+        }
+        if let Some(report) = validation_report().filter(|r| r.total_issues > 0) {
+          ValidationTable { report }
+        }
+        if let Some((_, summary)) = upload_downloads() {
+          DownloadsPanel {
+            summary,
+            on_download: move |(path, file_suffix): (String, String)| async move {
+              let Some((job_id, _)) = upload_downloads() else {
+                return;
+              };
+              let auth_signer = AuthSignature::new().await.expect("failed to init the auth signature!");
+              match uploader.read().download(&job_id, &path, &auth_signer).await {
+                Ok(body) => {
+                  // hand the body to the browser as a file
+                  let eval = document::eval(r#"
+                    const [name, body] = await dioxus.recv();
+                    const url = URL.createObjectURL(new Blob([body], { type: "text/plain" }));
+                    const link = document.createElement("a");
+                    link.href = url;
+                    link.download = name;
+                    link.click();
+                    URL.revokeObjectURL(url);
+                  "#);
+                  let _ = eval.send((format!("{}_{}", job_id, file_suffix), body));
+                },
+                Err(_e) => {
+                  //error!("[Download error] {}", e.to_string());
+                  document::eval(r#"
+                  var x = document.getElementById("upload-server-down-toast");
+                  x.classList.add("show");
+                  setTimeout(function(){{x.classList.remove("show");}}, 2000);
+                  "#);
+                }
+              }
+            }
+          }
+        }
         ErrorToast { id: "upload-server-down-toast", content: "SERVER IS DOWN! Try again later." }
         ErrorToast { id: "upload-server-rl-toast", content: "Max order limit reached! Please try again in some time." }
       },
@@ -442,7 +551,7 @@ fn SettingsPanel(name: String, mut form_data: Signal<HashMap<String, FormValue>>
 }
 
 #[component]
-fn PreviewTable(orders: Vec<FileUploadOrderType>) -> Element {
+fn PreviewTable(orders: Vec<OrderLine>) -> Element {
 
   let len: usize = orders.len();
   let max: usize = 5;
@@ -498,13 +607,19 @@ fn PreviewTable(orders: Vec<FileUploadOrderType>) -> Element {
   }
 }
 
-fn get_preview_row(idx:usize, order: &FileUploadOrderType) -> PreviewRow {
+fn get_preview_row(idx:usize, line: &OrderLine) -> PreviewRow {
   let row_id = format!("preview-row-{idx}");
-  match order {
+  match &line.order {
     FileUploadOrderType::Add { id, side, shares, price } => PreviewRow { row_id, ordertype: "ADD".to_string(), order_id: id.to_string(), side: side.to_string() , shares: shares.to_string(), price: price.to_string() 
     },
     FileUploadOrderType::Modify { id, shares, price } => PreviewRow { row_id, ordertype: "MODIFY".to_string(), order_id: id.to_string(), side: "-".to_string() , shares: shares.to_string(), price: price.to_string() },
-    FileUploadOrderType::Cancel { id } => PreviewRow { row_id, ordertype: "CANCEL".to_string(), order_id: id.to_string(), side: "-".to_string() , shares: "-".to_string(), price: "-".to_string() }
+    FileUploadOrderType::Cancel { id } => PreviewRow { row_id, ordertype: "CANCEL".to_string(), order_id: id.to_string(), side: "-".to_string() , shares: "-".to_string(), price: "-".to_string() },
+    FileUploadOrderType::Market { id, side, shares } => PreviewRow { row_id, ordertype: "MARKET".to_string(), order_id: id.to_string(), side: side.to_string() , shares: shares.to_string(), price: "-".to_string() },
+    FileUploadOrderType::Ioc { id, side, shares, price } => PreviewRow { row_id, ordertype: "IOC".to_string(), order_id: id.to_string(), side: side.to_string() , shares: shares.to_string(), price: price.to_string() },
+    FileUploadOrderType::Fok { id, side, shares, price } => PreviewRow { row_id, ordertype: "FOK".to_string(), order_id: id.to_string(), side: side.to_string() , shares: shares.to_string(), price: price.to_string() },
+    // stop price, followed by the limit price of stop limits
+    FileUploadOrderType::Stop { id, side, shares, stop_price, limit_price } => PreviewRow { row_id, ordertype: "STOP".to_string(), order_id: id.to_string(), side: side.to_string() , shares: shares.to_string(), price: limit_price.map_or(stop_price.to_string(), |limit| format!("{} / {}", stop_price, limit)) },
+    FileUploadOrderType::MassCancel { side } => PreviewRow { row_id, ordertype: "MASSCANCEL".to_string(), order_id: "-".to_string(), side: side.map_or("-".to_string(), |side| side.to_string()) , shares: "-".to_string(), price: "-".to_string() }
   }
 }
 
@@ -645,7 +760,152 @@ fn ParseTable(parse_results: (Duration, i32, i32)) -> Element {
 }
 
 #[component]
-fn ProgressBar(f_name: String) -> Element {
+fn ParseErrorTable(report: ParseReport) -> Element {
+  let shown = report.errors.len();
+
+  rsx! {
+    table {
+      class: "upload-results-table parse",
+      caption { "Parse errors by type" }
+      thead {
+        tr {
+          th { scope: "col", "Error" },
+          th { scope: "col", "Lines" }
+        }
+      }
+      tbody {
+        for (kind, count) in report.error_counts.iter() {
+          tr {
+            td { "{kind}" },
+            td { "{count}" }
+          }
+        }
+      }
+    }
+    table {
+      class: "upload-results-table parse",
+      caption { "First {shown} of {report.total_errors} invalid lines" }
+      thead {
+        tr {
+          th { scope: "col", "Line" },
+          th { scope: "col", "Error" },
+          th { scope: "col", "Text" }
+        }
+      }
+      tbody {
+        for err in report.errors.iter() {
+          tr {
+            td { "{err.line}" },
+            td { title: "{err.message}", "{err.kind}" },
+            td { "{err.text}" }
+          }
+        }
+      }
+    }
+  }
+}
+
+#[component]
+fn ValidationTable(report: ValidationReport) -> Element {
+  let shown = report.issues.len();
+  let outcome = if report.rejected {
+    "File rejected".to_string()
+  } else if report.skipped_orders > 0 {
+    format!("{} orders skipped", report.skipped_orders)
+  } else {
+    "All orders sent to the engine".to_string()
+  };
+
+  rsx! {
+    table {
+      class: "upload-results-table parse",
+      caption { "Order validation: {outcome}" }
+      thead {
+        tr {
+          th { scope: "col", "Issue" },
+          th { scope: "col", "Orders" }
+        }
+      }
+      tbody {
+        for (kind, count) in report.issue_counts.iter() {
+          tr {
+            td { "{kind}" },
+            td { "{count}" }
+          }
+        }
+      }
+    }
+    table {
+      class: "upload-results-table parse",
+      caption { "First {shown} of {report.total_issues} invalid orders" }
+      thead {
+        tr {
+          th { scope: "col", "Line" },
+          th { scope: "col", "Issue" }
+        }
+      }
+      tbody {
+        for issue in report.issues.iter() {
+          tr {
+            td { "{issue.line}" },
+            td { title: "{issue.kind}", "{issue.message}" }
+          }
+        }
+      }
+    }
+  }
+}
+
+#[component]
+fn DownloadsPanel(summary: DownloadSummary, on_download: EventHandler<(String, String)>) -> Element {
+  rsx! {
+    div {
+      class: "upload-file-proc-container",
+      div {
+        class: "upload-file-proc-label",
+        "DOWNLOADS"
+      }
+      div {
+        class: "upload-file-details",
+        "{summary.trades} trades, {summary.resting_orders} orders left resting"
+      }
+      if summary.truncated {
+        div {
+          class: "upload-file-details",
+          "Only the first rows were kept, the upload made more than the server records"
+        }
+      }
+      div {
+        class: "upload-file-proc-status upload-downloads",
+        for (label, path, file_suffix) in UPLOAD_DOWNLOADS {
+          button {
+            r#type: "button",
+            class: "button",
+            onclick: move |_evt| on_download.call((path.to_string(), file_suffix.to_string())),
+            "{label}"
+          }
+        }
+      }
+    }
+  }
+}
+
+#[component]
+fn ProgressBar(f_name: String, job_state: Option<JobState>, on_cancel: EventHandler<()>) -> Element {
+  let status = match &job_state {
+    None => "Uploading...".to_string(),
+    Some(JobState::Queued) => "Waiting for a free worker...".to_string(),
+    Some(JobState::Parsing) => "Parsing...".to_string(),
+    Some(JobState::Processing { percent_done }) => format!("Processing... {:.0}%", percent_done),
+    Some(JobState::Completed) => "Done".to_string(),
+    Some(JobState::Failed { error }) => format!("Failed: {}", error),
+    Some(JobState::Cancelled) => "Cancelled".to_string()
+  };
+  let percent_done = match &job_state {
+    Some(JobState::Processing { percent_done }) => Some(*percent_done),
+    _ => None
+  };
+
   rsx! {
     div {
       class: "upload-file-proc-container",
@@ -655,13 +915,28 @@ fn ProgressBar(f_name: String) -> Element {
       }
       div {
         class: "progress-container",
-        div {
-          class: "indeterminate-progress-bar"
+        if let Some(percent_done) = percent_done {
+          div {
+            class: "determinate-progress-bar",
+            style: "width: {percent_done}%"
+          }
+        } else {
+          div {
+            class: "indeterminate-progress-bar"
+          }
         }
       }
       div {
         class: "upload-file-proc-status",
-        span { "Processing..." }
+        span { {status} }
+        if job_state.is_some() {
+          button {
+            r#type: "button",
+            class: "button button-danger",
+            onclick: move |_evt| on_cancel.call(()),
+            "Cancel"
+          }
+        }
       }
       div {
         class: "upload-file-details",
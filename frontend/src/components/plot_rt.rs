@@ -157,7 +157,7 @@ pub fn BarPlotCharming(latency_by_ordertype: ReadOnlySignal<HashMap<String, Vec<
   let y_max = (use_context::<PlotPropsState>().avg_latency_cutoff)();
 
   use_effect(move || {
-    let x_labels = vec!["ADD", "MODIFY", "CANCEL"];
+    let x_labels = vec!["ADD", "MODIFY", "CANCEL", "MARKET", "IOC"];
     let mut avg_lat: Vec<i64> = vec![];
 
     //info!("avg lat for bar plot: {:?}", latency_by_ordertype());
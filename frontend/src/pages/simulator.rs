@@ -5,7 +5,7 @@ use rust_decimal::Decimal;
 use futures::{stream::SplitSink, SinkExt};
 use futures_util::StreamExt;
 use gloo_net::websocket::{futures::WebSocket, Message};
-use serde::Deserialize;
+use protocol::ws::SimulationParams;
 use tokio::sync::mpsc;
 use web_sys::{window, Performance};
 
@@ -16,27 +16,15 @@ use crate::{
         ws_handler::handle_websocket
     }
 };
+
+// engine stats and trades come with the websocket messages shared with the backend
+pub use protocol::ws::{EngineStats, ExecutedOrders};
+
 enum Action {
   Start,
   Stop
 }
 
-#[derive(Debug, Deserialize, Clone)]
-pub struct EngineStats {
-  pub order_type: String,
-  pub latency: i64,
-  pub avl_rebalances: i64,
-  pub executed_orders_cnt: usize
-}
-
-#[derive(Debug, Deserialize, Clone, PartialEq)]
-pub struct ExecutedOrders {
-  pub price: Decimal,
-  pub volume: u64,
-  pub aggresive_order_id: u64,
-  pub passive_order_id: u64,
-}
-
 pub const ORDERBOOK_LEVELS: usize = 20;
 
 #[derive(Clone)]
@@ -142,6 +130,8 @@ impl DataCollector {
 pub const HEALTH_CHECK_URL: &str = env!("HEALTH_CHECK_URL");
 pub const SMALL_UPLOAD_URL: &str = env!("SMALL_UPLOAD_URL");
 pub const LARGE_UPLOAD_URL: &str = env!("LARGE_UPLOAD_URL");
+pub const JOBS_URL: &str = env!("JOBS_URL");
+pub const UPLOADS_URL: &str = env!("UPLOADS_URL");
 pub const WSS_URL: &str = env!("WSS_URL"); 
 
 #[component]
@@ -252,7 +242,7 @@ pub fn Simulator() -> Element {
                             use_context::<PlotPropsState>().avg_latency_cutoff.set(35_000);
                         }
                         
-                        let client_msg = WsRequest::Start { total_objects: orders, mean_price, sd_price, order_probs, best_price_levels: price_lvls_display};
+                        let client_msg = WsRequest::Start { total_objects: orders, config: SimulationParams { mean_price, sd_price, order_probs, best_price_levels: price_lvls_display, seed: None } };
                         //info!("prepped formdata: {:?}", &client_msg);
 
                         let start_payload =  Message::Text(serde_json::to_string(&client_msg).expect("error deserializing START message!"));
@@ -60,15 +60,26 @@ impl AuthSignature {
     let sign_promise = self.subtle.sign_with_object_and_buffer_source(&self.algo, &self.crypto_key, &message_array).map_err(|e| AppError::WasmError(format!("{:?}", e)))?;
 
     let signature_buffer = JsFuture::from(sign_promise).await.map_err(|e| AppError::WasmError(format!("{:?}", e)))?;
-    let signature_array = Uint8Array::new(&signature_buffer);
 
-    // Convert to hex string
-    let mut result = String::with_capacity(signature_array.length() as usize * 2);
-    for i in 0..signature_array.length() {
-      let byte = signature_array.get_index(i);
-      result.push_str(&format!("{:02x}", byte));
-    }
+    Ok(to_hex(&Uint8Array::new(&signature_buffer)))
+  }
+
+  // hex sha256 of an upload chunk, sent along so the server can detect corrupted chunks
+  pub async fn sha256(&self, data: &[u8]) -> Result<String, AppError> {
+    let data_array = Uint8Array::new_with_length(data.len() as u32);
+    data_array.copy_from(data);
+
+    let digest_promise = self.subtle.digest_with_str_and_buffer_source("SHA-256", &data_array).map_err(|e| AppError::WasmError(format!("{:?}", e)))?;
+    let digest_buffer = JsFuture::from(digest_promise).await.map_err(|e| AppError::WasmError(format!("{:?}", e)))?;
+
+    Ok(to_hex(&Uint8Array::new(&digest_buffer)))
+  }
+}
 
-    Ok(result)
+fn to_hex(bytes: &Uint8Array) -> String {
+  let mut result = String::with_capacity(bytes.length() as usize * 2);
+  for i in 0..bytes.length() {
+    result.push_str(&format!("{:02x}", bytes.get_index(i)));
   }
+  result
 }
\ No newline at end of file
@@ -3,7 +3,7 @@ use charming::datatype::{CompositeValue, DataPoint, NumericValue};
 use crate::pages::simulator::{EngineStats, ExecutedOrders};
 
 // Response format
-// {order_type:"ADD"/"MODIFY"/"CANCEL", latency, avl_rebalances, executed_orders_cnt}
+// {order_type:"ADD"/"MODIFY"/"CANCEL"/"MARKET"/"IOC", latency, avl_rebalances, executed_orders_cnt}
 
 fn mean(vec: &Vec<i64>) -> f64 {
   let n = vec.len();
@@ -58,6 +58,12 @@ pub fn get_latency_by_ordertype<'a>(stats: &'a Vec<EngineStats>, qvals: &'a Vec<
       "CANCEL" => {
         map_state.entry("CANCEL").or_insert(Vec::<i64>::new()).push(e.latency)
       },
+      "MARKET" => {
+        map_state.entry("MARKET").or_insert(Vec::<i64>::new()).push(e.latency)
+      },
+      "IOC" => {
+        map_state.entry("IOC").or_insert(Vec::<i64>::new()).push(e.latency)
+      },
       _ => unreachable!("unsupported order type in engine stat!")
     }
     map_state
@@ -1,13 +1,27 @@
 use dioxus::prelude::*;
 use rmp_serde::Serializer;
-use std::{collections::HashMap, fmt, io::Write, str::FromStr, time::Duration};
+use std::{collections::HashMap, io::Write, time::Duration};
 use flate2::{write::DeflateEncoder, Compression};
 use reqwest::multipart::{Form, Part};
-use rust_decimal::Decimal;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use uuid::Uuid;
 
-use super::{auth::AuthSignature, server::{AppError, HealthCheckResponse, LargeUploadResponse, SmallUploadRequest, SmallUploadResponse}};
+use super::{auth::AuthSignature, grammar::{OrderLine, ParseReport}, validator::{ValidationPolicy, ValidationReport}, server::{AppError, ChunkStatus, DownloadSummary, HealthCheckResponse, JobState, JobStatus, LargeUploadResponse, SmallUploadRequest, SmallUploadResponse}};
+
+// how often the job of a completed upload is polled
+const JOB_POLL_INTERVAL: Duration = Duration::from_millis(500);
+// resends of a chunk whose request failed before the upload is given up
+const MAX_CHUNK_RETRIES: usize = 3;
+const CHUNK_RETRY_DELAY: Duration = Duration::from_secs(1);
+// downloads of a completed upload as (label, path under its job, file name suffix)
+pub const UPLOAD_DOWNLOADS: [(&str, &str, &str); 6] = [
+  ("Trades (CSV)", "trades?format=csv", "trades.csv"),
+  ("Trades (JSONL)", "trades?format=jsonl", "trades.jsonl"),
+  ("L2 book (CSV)", "book?depth=l2&format=csv", "book_l2.csv"),
+  ("L2 book (JSONL)", "book?depth=l2&format=jsonl", "book_l2.jsonl"),
+  ("L3 book (CSV)", "book?depth=l3&format=csv", "book_l3.csv"),
+  ("L3 book (JSONL)", "book?depth=l3&format=jsonl", "book_l3.jsonl")
+];
 
 #[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct FinalStats {
@@ -58,178 +72,26 @@ pub struct PreviewRow {
   pub price: String,
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize)]
-pub enum BidOrAsk {
-  Bid,
-  Ask,
-}
-
-impl fmt::Display for BidOrAsk {
-  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-    match self {
-        Self::Bid => write!(f, "BID"),
-        Self::Ask => write!(f, "ASK"),
-    }
-  }
-}
-
-impl FromStr for BidOrAsk {
-  type Err = ParseError;
-  fn from_str(s: &str) -> Result<Self, Self::Err> {
-      match s.to_lowercase().as_str() {
-        "bid" => Ok(BidOrAsk::Bid),
-        "ask" => Ok(BidOrAsk::Ask),
-        _ => Err(ParseError::InvalidBidorAsk(s.to_string())) 
-      }
-  }
-}
-
-#[derive(Debug, Clone, PartialEq, Serialize)]
-pub enum FileUploadOrderType {
-  Add {
-    id: u64,
-    side: BidOrAsk,
-    shares: u64,
-    price: Decimal
-  },
-  Modify {
-    id: u64,
-    shares: u64,
-    price: Decimal
-  },
-  Cancel {
-    id: u64,
-  },
-}
-
-#[derive(Debug)]
-pub enum ParseError {
-  InvalidBidorAsk(String),
-  InvalidOrderType(String),
-  InvalidOrderFormat(String),
-  InvalidOrderId(std::num::ParseIntError),
-  InvalidShares(std::num::ParseIntError),
-  InvalidPrice(rust_decimal::Error),
-  Empty
-}
-
-impl fmt::Display for ParseError {
-  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-    match self {
-      Self::InvalidBidorAsk(bid_or_ask) => {
-        write!(f, "Invalid bid/ask string: {}", bid_or_ask)
-      },
-      Self::InvalidOrderType(order_type) => {
-        write!(f, "Invalid order type string: {}", order_type)
-      },
-      Self::InvalidOrderFormat(order) => {
-        write!(f, "Invalid {} order format", order)
-      },
-      Self::InvalidOrderId(err) => {
-        write!(f, "Faled to parse Order ID: {:?}", err)
-      },
-      Self::InvalidShares(err) => {
-        write!(f, "Faled to parse shares: {:?}", err)
-      },
-      Self::InvalidPrice(err) => {
-        write!(f, "Faled to parse price: {:?}", err)
-      }
-      Self::Empty => {
-        write!(f, "Empty order line in file")
-      }
-    }
-  }
-}
-
-impl std::error::Error for ParseError {}
-
-impl From<std::num::ParseIntError> for ParseError {
-  fn from(value: std::num::ParseIntError) -> Self {
-      ParseError::InvalidOrderId(value)
-  }
-}
-
-impl From<rust_decimal::Error> for ParseError {
-  fn from(value: rust_decimal::Error) -> Self {
-      ParseError::InvalidPrice(value)
-  }
-}
-
-pub struct FileUploadOrder {
-  pub order: FileUploadOrderType
-}
-
-impl FileUploadOrder {
-  pub fn parse(line: &str) -> Result<Self, ParseError> {
-    let parts: Vec<&str> = line.split(|c| c == ',').map(|s| s.trim()).collect();
-
-    let order_type = match parts.get(0).map(|s| s.to_uppercase()) {
-        Some(s) => s,
-        None => return Err(ParseError::Empty)
-    };
-
-    let order = match order_type.as_str() {
-      "ADD" => {
-        if parts.len() != 5 {
-          return Err(ParseError::InvalidOrderFormat("ADD".to_string()));
-        }
-        let id = parts[1].parse().map_err(|err| ParseError::InvalidOrderId(err))?;
-        let side = BidOrAsk::from_str(parts[2])?;
-        let shares = parts[3].parse().map_err(|err| ParseError::InvalidShares(err))?;
-        let mut price =  Decimal::from_str(parts[4])?; 
-        price.rescale(2);
-        
-        FileUploadOrderType::Add { 
-          id,
-          side,
-          shares,
-          price
-        }
-      },
-      "MODIFY" => {
-        if parts.len() != 4 {
-          return Err(ParseError::InvalidOrderFormat("MODIFY".to_string()));
-        }
-        let id = parts[1].parse().map_err(|err| ParseError::InvalidOrderId(err))?;
-        let shares = parts[2].parse().map_err(|err| ParseError::InvalidShares(err))?;
-        let mut price =  Decimal::from_str(parts[3])?;
-        price.rescale(2); 
-
-        FileUploadOrderType::Modify { 
-          id,
-          shares,
-          price
-        }
-      },
-      "CANCEL" => {
-        if parts.len() != 2 {
-          return Err(ParseError::InvalidOrderFormat("CANCEL".to_string()));
-        }
-        let id = parts[1].parse().map_err(|err| ParseError::InvalidOrderId(err))?;
-        FileUploadOrderType::Cancel { id }
-      },
-      _ => return Err(ParseError::InvalidOrderType(order_type)),
-    };
-    Ok(FileUploadOrder {order})
-  }
-}
-
 // Upload Handler
 pub struct UnifiedUploader {
   client: reqwest::Client,
   small_upload_url: String,
   large_upload_url: String,
+  jobs_url: String,
+  uploads_url: String,
   health_check_url: String,
   chunk_size: usize,
   compression_enabled: bool
 }
 
 impl UnifiedUploader {
-  pub fn new(client: reqwest::Client, small_url: &str, large_url: &str, health_url: &str) -> Self {
+  pub fn new(client: reqwest::Client, small_url: &str, large_url: &str, jobs_url: &str, uploads_url: &str, health_url: &str) -> Self {
     Self {
       client,
       small_upload_url: small_url.to_string(),
       large_upload_url: large_url.to_string(),
+      jobs_url: jobs_url.to_string(),
+      uploads_url: uploads_url.to_string(),
       health_check_url: health_url.to_string(),
       chunk_size: 8 * 1024 * 1024, // default chunk size of 8MB
       compression_enabled: false // compression in not enabled by default
@@ -253,12 +115,111 @@ impl UnifiedUploader {
     Ok(())
   }
 
+  // signed request to `job_path` (the job id, optionally followed by a resource and query) under the jobs url
+  async fn job_request(&self, method: reqwest::Method, job_path: &str, auth_signer: &AuthSignature) -> Result<reqwest::Response, AppError> {
+    let timestamp = (js_sys::Date::now() / 1000.0) as u64;
+    // the server signs the path without the query
+    let signed_path = job_path.split('?').next().unwrap_or(job_path);
+    let signature = auth_signer.sign_with_key(&format!("/jobs/{}", signed_path), timestamp).await?;
+
+    let resp = self.client.request(method, format!("{}/{}", self.jobs_url, job_path))
+      .header("x-timestamp", timestamp.to_string())
+      .header("x-signature", signature)
+      .send().await.map_err(|e| AppError::UploadConnectionError(e.to_string()))?;
+    if !resp.status().is_success() {
+      return Err(AppError::UploadConnectionError(format!("job request {} answered with status {}", job_path, resp.status())));
+    }
+    Ok(resp)
+  }
+
+  // received and missing chunks of the upload session
+  async fn chunk_status(&self, session_id: &str, auth_signer: &AuthSignature) -> Result<ChunkStatus, AppError> {
+    let timestamp = (js_sys::Date::now() / 1000.0) as u64;
+    let signature = auth_signer.sign_with_key(&format!("/uploads/{}/chunks", session_id), timestamp).await?;
+
+    let resp = self.client.get(format!("{}/{}/chunks", self.uploads_url, session_id))
+      .header("x-timestamp", timestamp.to_string())
+      .header("x-signature", signature)
+      .send().await.map_err(|e| AppError::UploadConnectionError(e.to_string()))?;
+    if !resp.status().is_success() {
+      return Err(AppError::UploadConnectionError(format!("no upload session {}, status {}", session_id, resp.status())));
+    }
+    resp.json::<ChunkStatus>().await.map_err(|e| AppError::DeserializeError(e.to_string()))
+  }
+
+  // Signs and sends a chunk request built by `build`, resending it when the connection fails. Before a resend the session is
+  // asked whether the chunk got through anyway, None then as there is no response to read
+  async fn send_chunk<F>(&self, build: F, signed_path: &str, session_id: &str, chunk_number: usize, auth_signer: &AuthSignature) -> Result<Option<reqwest::Response>, AppError>
+  where
+    F: Fn() -> Result<reqwest::RequestBuilder, AppError>
+  {
+    let mut retries = 0;
+    loop {
+      let timestamp = (js_sys::Date::now() / 1000.0) as u64;
+      let signature = auth_signer.sign_with_key(signed_path, timestamp).await?;
+      let req = build()?
+        .header("x-timestamp", timestamp.to_string())
+        .header("x-signature", signature);
+
+      let err = match req.send().await {
+        Ok(resp) => return Ok(Some(resp)),
+        Err(e) => AppError::UploadConnectionError(e.to_string())
+      };
+      if retries == MAX_CHUNK_RETRIES {
+        return Err(err);
+      }
+      retries += 1;
+      async_std::task::sleep(CHUNK_RETRY_DELAY).await;
+
+      match self.chunk_status(session_id, auth_signer).await {
+        Ok(status) if !status.missing.contains(&chunk_number) => return Ok(None),
+        Ok(_) => {},
+        // the session only exists once its first chunk arrived
+        Err(_) if chunk_number == 0 => {},
+        // the session completed or expired, resending cannot help
+        Err(_) => return Err(err)
+      }
+    }
+  }
+
+  // polls the job until it finishes, None if it got cancelled
+  async fn wait_for_job<T: DeserializeOwned>(&self, job_id: &str, auth_signer: &AuthSignature, mut upload_job: Signal<Option<(String, JobState)>>) -> Result<Option<T>, AppError> {
+    loop {
+      let resp = self.job_request(reqwest::Method::GET, job_id, auth_signer).await?;
+      let status = resp.json::<JobStatus<T>>().await.map_err(|e| AppError::DeserializeError(e.to_string()))?;
+      upload_job.set(Some((status.job_id, status.state.clone())));
+      match status.state {
+        JobState::Completed => return Ok(status.result),
+        JobState::Failed { error } => return Err(AppError::UploadConnectionError(error)),
+        JobState::Cancelled => return Ok(None),
+        _ => async_std::task::sleep(JOB_POLL_INTERVAL).await
+      }
+    }
+  }
+
+  pub async fn cancel_job(&self, job_id: &str, auth_signer: &AuthSignature) -> Result<(), AppError> {
+    self.job_request(reqwest::Method::DELETE, job_id, auth_signer).await?;
+    Ok(())
+  }
+
+  // one of the `UPLOAD_DOWNLOADS` of a completed upload
+  pub async fn download(&self, job_id: &str, path: &str, auth_signer: &AuthSignature) -> Result<String, AppError> {
+    let resp = self.job_request(reqwest::Method::GET, &format!("{}/{}", job_id, path), auth_signer).await?;
+    resp.text().await.map_err(|e| AppError::ReqwestError(e.to_string()))
+  }
+
   pub async fn upload_large_file(&self,
     file_bytes: Vec<u8>,
     f_name: &str,
+    validation: ValidationPolicy,
+    downloads: bool,
     auth_signer: AuthSignature,
     mut ob_results: Signal<Option<HashMap<String, FinalStats>>>,
-    mut parse_results: Signal<Option<(Duration, i32, i32)>>
+    mut parse_results: Signal<Option<(Duration, i32, i32)>>,
+    mut parse_report: Signal<Option<ParseReport>>,
+    mut validation_report: Signal<Option<ValidationReport>>,
+    upload_job: Signal<Option<(String, JobState)>>,
+    mut upload_downloads: Signal<Option<(String, DownloadSummary)>>
   ) -> Result<(), AppError> {
     let total_bytes = file_bytes.len();
     //info!("**large file total bytes: {}", &total_bytes);
@@ -273,6 +234,8 @@ impl UnifiedUploader {
       let end = std::cmp::min(start + self.chunk_size, total_bytes);
       let chunk = &file_bytes[start..end];
 
+      let checksum = auth_signer.sha256(chunk).await?;
+
       let (final_chunk, content_encoding) = if self.compression_enabled {
         let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
         encoder.write_all(chunk).map_err(|e| AppError::CompressionError(e.to_string()))?;
@@ -282,30 +245,31 @@ impl UnifiedUploader {
       };
 
       //info!("size of lf chunk:{}", std::mem::size_of_val(&*final_chunk));
-      let part = Part::bytes(final_chunk)
-        .file_name(format!("{}_chunk_{}", &f_name, &chunk_number))
-        //.mime_str(&f_type)
-        .mime_str("application/octet-stream")
-        .map_err(|e| AppError::ReqwestError(e.to_string()))?;
-      
-      let form = Form::new().
-        text("session_id", session_id.clone()).
-        text("total_chunks", total_chunks.to_string()).
-        text("chunk_number", chunk_number.to_string()).  
-        part("chunk", part);
-
-      let mut req = self.client.post(&self.large_upload_url).multipart(form);
-      if let Some(encoding) = content_encoding {
-        req = req.header("content-encoding", encoding);
-      }
-
-      let timestamp = (js_sys::Date::now() / 1000.0) as u64;
-      let signature = auth_signer.sign_with_key("/largeupload", timestamp).await?;
-
-      req = req.header("x-timestamp", timestamp.to_string())
-        .header("x-signature", signature);
+      let build = || {
+        let part = Part::bytes(final_chunk.clone())
+          .file_name(format!("{}_chunk_{}", &f_name, &chunk_number))
+          //.mime_str(&f_type)
+          .mime_str("application/octet-stream")
+          .map_err(|e| AppError::ReqwestError(e.to_string()))?;
+        
+        let form = Form::new().
+          text("session_id", session_id.clone()).
+          text("total_chunks", total_chunks.to_string()).
+          text("chunk_number", chunk_number.to_string()).  
+          text("validation", validation.as_str()).
+          text("downloads", downloads.to_string()).
+          part("chunk", part);
+
+        let mut req = self.client.post(&self.large_upload_url).multipart(form).header("x-chunk-sha256", checksum.as_str());
+        if let Some(encoding) = content_encoding {
+          req = req.header("content-encoding", encoding);
+        }
+        Ok(req)
+      };
 
-      let resp = req.send().await.map_err(|e| AppError::UploadConnectionError(e.to_string()))?;
+      let Some(resp) = self.send_chunk(build, "/largeupload", &session_id, chunk_number, &auth_signer).await? else {
+        continue;
+      };
 
       if !resp.status().is_success() {
         // let status = resp.status();
@@ -320,11 +284,18 @@ impl UnifiedUploader {
         break;
       } else {
         if chunk_number == total_chunks - 1 {
-          let result = resp.json::<LargeUploadResponse>().await.map_err(|e| AppError::DeserializeError(e.to_string()))?;
+          let ack = resp.json::<LargeUploadResponse>().await.map_err(|e| AppError::DeserializeError(e.to_string()))?;
+          let job_id = ack.job_id.expect("the last chunk should start a job!");
+          let Some(result) = self.wait_for_job::<LargeUploadResponse>(&job_id, &auth_signer, upload_job).await? else {
+            break;
+          };
           //info!("Processing complete for large file:\n{:?}", &result);
           assert_eq!(true, result.processed, "processing should be complete here!!");
           ob_results.set(result.orderbook_results);
           parse_results.set(result.parse_results);
+          parse_report.set(result.parse_report);
+          validation_report.set(result.validation_report);
+          upload_downloads.set(result.downloads.map(|summary| (job_id.clone(), summary)));
         }
       }
     }
@@ -332,10 +303,17 @@ impl UnifiedUploader {
   }
 
   pub async fn upload_small_file(&self,
-    orders: Vec<FileUploadOrderType>,
+    orders: Vec<OrderLine>,
+    client_report: ParseReport,
+    validation: ValidationPolicy,
+    downloads: bool,
     chunk_size: usize,
     auth_signer: AuthSignature,
-    mut ob_results: Signal<Option<HashMap<String, FinalStats>>>
+    mut ob_results: Signal<Option<HashMap<String, FinalStats>>>,
+    mut parse_report: Signal<Option<ParseReport>>,
+    mut validation_report: Signal<Option<ValidationReport>>,
+    upload_job: Signal<Option<(String, JobState)>>,
+    mut upload_downloads: Signal<Option<(String, DownloadSummary)>>
   ) -> Result<(), AppError> {
     let total_orders = orders.len();
     let total_chunks = if total_orders % chunk_size == 0 {total_orders/chunk_size} else { (total_orders / chunk_size) + 1 };
@@ -349,12 +327,19 @@ impl UnifiedUploader {
         total_chunks,
         total_orders,
         chunk_number,
-        orders: chunk.to_vec()
+        orders: chunk.to_vec(),
+        parse_report: if chunk_number == total_chunks - 1 { Some(client_report.clone()) } else { None },
+        validation,
+        downloads,
+        // the server's sampling interval applies
+        book_stats_interval: None
       };
 
       let mut buf = Vec::new();
       upload_request.serialize(&mut Serializer::new(&mut buf)).map_err(|e| AppError::SerializeError(e.to_string()))?;
 
+      let checksum = auth_signer.sha256(&buf).await?;
+
       let (final_data, content_encoding) = if self.compression_enabled {
         let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
         encoder.write_all(&buf).map_err(|e| AppError::CompressionError(e.to_string()))?;
@@ -364,18 +349,17 @@ impl UnifiedUploader {
       };
       //info!("size of sf chunk:{}", std::mem::size_of_val(&*final_data));
 
-      let mut req = self.client.post(&self.small_upload_url).body(final_data);
-      if let Some(encoding) = content_encoding {
-        req = req.header("content-encoding", encoding);
-      }
-
-      let timestamp = (js_sys::Date::now() / 1000.0) as u64;
-      let signature = auth_signer.sign_with_key("/smallupload", timestamp).await?;
-
-      req = req.header("x-timestamp", timestamp.to_string())
-        .header("x-signature", signature);
+      let build = || {
+        let mut req = self.client.post(&self.small_upload_url).body(final_data.clone()).header("x-chunk-sha256", checksum.as_str());
+        if let Some(encoding) = content_encoding {
+          req = req.header("content-encoding", encoding);
+        }
+        Ok(req)
+      };
 
-      let resp = req.send().await.map_err(|e| AppError::UploadConnectionError(e.to_string()))?;
+      let Some(resp) = self.send_chunk(build, "/smallupload", &session_id, chunk_number, &auth_signer).await? else {
+        continue;
+      };
 
       if !resp.status().is_success() {
         // let status = resp.status();
@@ -390,10 +374,17 @@ impl UnifiedUploader {
         break;
       } else {
         if chunk_number == total_chunks - 1 {
-          let result = resp.json::<SmallUploadResponse>().await.map_err(|e| AppError::DeserializeError(e.to_string()))?;
+          let ack = resp.json::<SmallUploadResponse>().await.map_err(|e| AppError::DeserializeError(e.to_string()))?;
+          let job_id = ack.job_id.expect("the last chunk should start a job!");
+          let Some(result) = self.wait_for_job::<SmallUploadResponse>(&job_id, &auth_signer, upload_job).await? else {
+            break;
+          };
           //info!("Processing complete for small file:\n{:?}", &result);
           assert_eq!(true, result.processed, "processing should be complete here!!");
           ob_results.set(result.orderbook_results);
+          parse_report.set(result.parse_report);
+          validation_report.set(result.validation_report);
+          upload_downloads.set(result.downloads.map(|summary| (job_id.clone(), summary)));
         }
       }
     }
@@ -1,6 +1,8 @@
 pub mod enginestats;
 pub mod priceupdate;
 pub mod file_handler;
+// order file grammar and upload validation, shared with the backend
+pub use protocol::{grammar, validator};
 pub mod ws_handler;
 pub mod server;
 pub mod auth;
\ No newline at end of file
@@ -1,33 +1,14 @@
 use std::{collections::HashMap, fmt, time::Duration};
-use rust_decimal::Decimal;
-use serde::{Deserialize, Serialize};
+use protocol::ws::SimulationParams;
+use serde::Deserialize;
 
-use crate::pages::simulator::{EngineStats, ExecutedOrders};
-use super::file_handler::{FileUploadOrderType, FinalStats};
+use super::{file_handler::FinalStats, grammar::ParseReport, validator::ValidationReport};
 
-/* Server Requests */
-#[derive(Debug, Serialize)]
-#[serde(tag = "type")]
-pub enum WsRequest {
-  Start { 
-    total_objects: usize,  //defaults to 50_000
-    mean_price: f64,  //defaults to 250.0
-    sd_price: f64,  // defaults to 20.0
-    order_probs: Vec<f32>, //probs for [ADD, CANCEL, MODIFY] defaults to [0.0, 0.4 ,0.6] 
-    best_price_levels: bool // defaults to false
-  },
-  Stop,
-  Ack
-}
+// messages shared with the backend
+pub use protocol::{upload::{ChunkStatus, DownloadSummary, JobState, JobStatus, SmallUploadRequest}, ws::WsResponse};
 
-#[derive(Debug, Serialize)]
-pub struct SmallUploadRequest {
-  pub session_id: String,
-  pub total_chunks: usize,
-  pub total_orders: usize,
-  pub chunk_number: usize,
-  pub orders: Vec<FileUploadOrderType>
-}
+/* Server Requests */
+pub type WsRequest = protocol::ws::WsRequest<SimulationParams>;
 
 /* Server Responses */
 #[derive(Debug, Deserialize)]
@@ -36,27 +17,27 @@ pub struct HealthCheckResponse {
   pub status: String
 }
 
-#[derive(Debug, Deserialize)]
-pub enum WsResponse {
-    PriceLevels { snapshot: bool, bids: Vec<(Decimal, u64)>, asks: Vec<(Decimal, u64)> },
-    Trades (Vec<ExecutedOrders>),
-    ExecutionStats (EngineStats),
-    BestLevels {best_buy: Option<Decimal>, best_sell: Option<Decimal>},
-    Completed,
-    RateLimitExceeded
-}
-
 #[derive(Debug, Deserialize)]
 pub struct SmallUploadResponse {
   pub orderbook_results: Option<HashMap<String, FinalStats>>,
-  pub processed: bool
+  pub parse_report: Option<ParseReport>,
+  pub validation_report: Option<ValidationReport>,
+  pub downloads: Option<DownloadSummary>,
+  pub processed: bool,
+  // set on the response to the last chunk, the results then come from the job
+  pub job_id: Option<String>
 }
 
 #[derive(Debug, Deserialize)]
 pub struct LargeUploadResponse {
   pub orderbook_results: Option<HashMap<String, FinalStats>>,
   pub parse_results: Option<(Duration, i32, i32)>,
-  pub processed: bool
+  pub parse_report: Option<ParseReport>,
+  pub validation_report: Option<ValidationReport>,
+  pub downloads: Option<DownloadSummary>,
+  pub processed: bool,
+  // set on the response to the last chunk, the results then come from the job
+  pub job_id: Option<String>
 }
 
 // App Errors
@@ -66,6 +47,7 @@ pub enum AppError {
   UploadConnectionError(String),
   ServerUnhealthy(String),
   RateLimitExceeded(String),
+  InvalidRequest(String),
   CompressionError(String),
   DecompressionError(String),
   WsChannelError(String),
@@ -85,6 +67,7 @@ impl fmt::Display for AppError {
       AppError::UploadConnectionError(msg) => write!(f, "Upload connection error: {}", msg),
       AppError::ServerUnhealthy(msg) => write!(f, "Server unhealthy: {}", msg),
       AppError::RateLimitExceeded(msg) => write!(f, "Rate limit exceeded: {}", msg),
+      AppError::InvalidRequest(msg) => write!(f, "Invalid request: {}", msg),
       AppError::CompressionError(msg) => write!(f, "Compression error: {}", msg),
       AppError::DecompressionError(msg) => write!(f, "Decompression error: {}", msg),
       AppError::WsChannelError(msg) => write!(f, "Websocket update channel error: {}", msg),
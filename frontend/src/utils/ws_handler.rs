@@ -113,6 +113,16 @@ async fn process_updates(batch: Vec<Vec<WsResponse>>,
         if let WsResponse::RateLimitExceeded = update {
           return Err(AppError::RateLimitExceeded("order limit exceeded".to_string()))
         }
+
+        if let WsResponse::InvalidRequest { reason } = update {
+          return Err(AppError::InvalidRequest(reason))
+        }
+
+        // the simulation has not started yet, stay on the current view
+        if let WsResponse::Queued { position } = update {
+          info!("simulation queued at position {}", position);
+          continue;
+        }
         
         if !*first_valid_message_received {
           feed_killed.set(false);
@@ -121,18 +131,28 @@ async fn process_updates(batch: Vec<Vec<WsResponse>>,
         }
 
         match update {
-          WsResponse::PriceLevels { snapshot, bids, asks } => {
+          WsResponse::SimulationInfo { sim_id, seed } => {
+            info!("simulation started with id: {} (seed: {})", sim_id, seed);
+          },
+          WsResponse::PriceLevels { snapshot, bids, asks, .. } => {
             update_tx.send(DataUpdate::PriceLevels { snapshot, bids, asks }).await.map_err(|e| AppError::WsChannelError(e.to_string()))?;
           },
           WsResponse::ExecutionStats(stats) => {
             update_tx.send(DataUpdate::PlotData(stats)).await.map_err(|e| AppError::WsChannelError(e.to_string()))?;
           },
-          WsResponse::Trades(trades) => {
+          WsResponse::Trades { trades, .. } => {
             update_tx.send(DataUpdate::Transactions(trades)).await.map_err(|e| AppError::WsChannelError(e.to_string()))?;
           },
           WsResponse::BestLevels { best_buy, best_sell } => {
             update_tx.send(DataUpdate::BestPrices { best_buy, best_sell }).await.map_err(|e| AppError::WsChannelError(e.to_string()))?;
           },
+          WsResponse::RiskRejected { order_id, reason } => {
+            info!("order {} rejected by risk checks: {}", order_id, reason);
+          },
+          WsResponse::BookStats(_) => {},
+          WsResponse::AgentReport(_) => {},
+          WsResponse::ScenarioPhase(_) => {},
+          WsResponse::Queued { .. } => {},
           WsResponse::Completed => {
             //info!("setting sim completed to true!");
             let ack_msg = serde_json::to_string(&WsRequest::Ack).expect("error serializing acknowledgement message!");
@@ -152,6 +172,12 @@ async fn process_updates(batch: Vec<Vec<WsResponse>>,
             view.set(View::Selector);
             feed_killed.set(true);
             return Err(AppError::RateLimitExceeded("order limit exceeded".to_string()));
+          },
+          WsResponse::InvalidRequest { reason } => {
+            // handled above, kept as a fallback
+            view.set(View::Selector);
+            feed_killed.set(true);
+            return Err(AppError::InvalidRequest(reason));
           }
         }
       }
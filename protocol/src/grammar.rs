@@ -0,0 +1,473 @@
+// Order file grammar, shared by the backend parser and the frontend previewer.
+//
+// Every order line is `[timestamp,][symbol,]VERB,fields...` with the verbs
+//   ADD,id,side,shares,price          MARKET,id,side,shares
+//   MODIFY,id,shares,price            IOC,id,side,shares,price
+//   CANCEL,id                         FOK,id,side,shares,price
+//   STOP,id,side,shares,stop_price[,limit_price]
+//   MASSCANCEL[,side]
+// `#` starts a comment. An optional `VERSION,<n>[,TIMESTAMP][,SYMBOL]` header before the first order fixes the
+// version and the leading columns of every line, without it leading columns are recognised line by line.
+// Version 1 files (ADD/MODIFY/CANCEL without leading columns) parse unchanged.
+use std::{collections::BTreeMap, fmt, str::FromStr};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+pub const LATEST_VERSION: u32 = 2;
+// parse reports list this many failing lines, the histogram still counts all of them
+pub const MAX_REPORTED_ERRORS: usize = 100;
+// offending lines are cut to this many characters in parse reports
+const MAX_REPORTED_LINE_CHARS: usize = 120;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Side {
+  Bid,
+  Ask
+}
+
+impl fmt::Display for Side {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Bid => write!(f, "BID"),
+      Self::Ask => write!(f, "ASK"),
+    }
+  }
+}
+
+impl FromStr for Side {
+  type Err = ParseError;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.to_lowercase().as_str() {
+      "bid" => Ok(Side::Bid),
+      "ask" => Ok(Side::Ask),
+      _ => Err(ParseError::InvalidBidorAsk(s.to_string()))
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum FileUploadOrderType {
+  Add {
+    id: u64,
+    side: Side,
+    shares: u64,
+    price: Decimal
+  },
+  Modify {
+    id: u64,
+    shares: u64,
+    price: Decimal
+  },
+  Cancel {
+    id: u64,
+  },
+  Market {
+    id: u64,
+    side: Side,
+    shares: u64
+  },
+  Ioc {
+    id: u64,
+    side: Side,
+    shares: u64,
+    price: Decimal
+  },
+  // fill-or-kill: executes in full up to `price` or not at all
+  Fok {
+    id: u64,
+    side: Side,
+    shares: u64,
+    price: Decimal
+  },
+  // waits off-book for a trade at or through `stop_price`, then enters as a market order, or as a limit order with `limit_price`
+  Stop {
+    id: u64,
+    side: Side,
+    shares: u64,
+    stop_price: Decimal,
+    limit_price: Option<Decimal>
+  },
+  // cancels every resting order of the book, or of one side of it
+  MassCancel {
+    side: Option<Side>
+  },
+}
+
+// one parsed order line with its optional leading columns
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OrderLine {
+  // 1-based line of the order in its file, 0 when unknown
+  #[serde(default)]
+  pub line: usize,
+  // integer time of the order, the unit is up to the file (nanoseconds are recommended)
+  pub timestamp: Option<u64>,
+  pub symbol: Option<String>,
+  pub order: FileUploadOrderType
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+  InvalidBidorAsk(String),
+  InvalidOrderType(String),
+  InvalidOrderFormat(String),
+  InvalidOrderId(std::num::ParseIntError),
+  InvalidShares(std::num::ParseIntError),
+  InvalidPrice(rust_decimal::Error),
+  InvalidTimestamp(std::num::ParseIntError),
+  InvalidSymbol(String),
+  InvalidHeader(String),
+  MisplacedHeader,
+  InvalidEncoding,
+  Empty
+}
+
+impl ParseError {
+  // stable name of the error type for parse report histograms
+  pub fn kind(&self) -> &'static str {
+    match self {
+      Self::InvalidBidorAsk(_) => "invalid_side",
+      Self::InvalidOrderType(_) => "invalid_order_type",
+      Self::InvalidOrderFormat(_) => "invalid_order_format",
+      Self::InvalidOrderId(_) => "invalid_order_id",
+      Self::InvalidShares(_) => "invalid_shares",
+      Self::InvalidPrice(_) => "invalid_price",
+      Self::InvalidTimestamp(_) => "invalid_timestamp",
+      Self::InvalidSymbol(_) => "invalid_symbol",
+      Self::InvalidHeader(_) => "invalid_header",
+      Self::MisplacedHeader => "misplaced_header",
+      Self::InvalidEncoding => "invalid_encoding",
+      Self::Empty => "empty"
+    }
+  }
+}
+
+impl fmt::Display for ParseError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::InvalidBidorAsk(bid_or_ask) => {
+        write!(f, "Invalid bid/ask string: {}", bid_or_ask)
+      },
+      Self::InvalidOrderType(order_type) => {
+        write!(f, "Invalid order type string: {}", order_type)
+      },
+      Self::InvalidOrderFormat(order) => {
+        write!(f, "Invalid {} order format", order)
+      },
+      Self::InvalidOrderId(err) => {
+        write!(f, "Faled to parse Order ID: {:?}", err)
+      },
+      Self::InvalidShares(err) => {
+        write!(f, "Faled to parse shares: {:?}", err)
+      },
+      Self::InvalidPrice(err) => {
+        write!(f, "Faled to parse price: {:?}", err)
+      },
+      Self::InvalidTimestamp(err) => {
+        write!(f, "Failed to parse timestamp: {:?}", err)
+      },
+      Self::InvalidSymbol(symbol) => {
+        write!(f, "Invalid symbol: {:?}", symbol)
+      },
+      Self::InvalidHeader(reason) => {
+        write!(f, "Invalid file header: {}", reason)
+      },
+      Self::MisplacedHeader => {
+        write!(f, "File header must come before the first order")
+      },
+      Self::InvalidEncoding => {
+        write!(f, "Order line is not valid UTF-8")
+      },
+      Self::Empty => {
+        write!(f, "Empty order line in file")
+      }
+    }
+  }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<rust_decimal::Error> for ParseError {
+  fn from(value: rust_decimal::Error) -> Self {
+    ParseError::InvalidPrice(value)
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LineError {
+  // 1-based line number in the file, blank and comment lines included
+  pub line: usize,
+  pub kind: String,
+  pub message: String,
+  pub text: String
+}
+
+// what went wrong while parsing a file, so users can fix it without guessing
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ParseReport {
+  // the first `MAX_REPORTED_ERRORS` failing lines
+  pub errors: Vec<LineError>,
+  // failing lines per error kind
+  pub error_counts: BTreeMap<String, usize>,
+  pub total_errors: usize
+}
+
+impl ParseReport {
+  pub fn record(&mut self, line: usize, error: &ParseError, text: &str) {
+    self.total_errors += 1;
+    *self.error_counts.entry(error.kind().to_string()).or_insert(0) += 1;
+    if self.errors.len() < MAX_REPORTED_ERRORS {
+      self.errors.push(LineError { line, kind: error.kind().to_string(), message: error.to_string(), text: text.chars().take(MAX_REPORTED_LINE_CHARS).collect() });
+    }
+  }
+}
+
+// leading columns declared by the header, in this order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileHeader {
+  pub version: u32,
+  pub timestamp: bool,
+  pub symbol: bool
+}
+
+impl FileHeader {
+  // fields after `VERSION`
+  fn parse(fields: &[&str]) -> Result<Self, ParseError> {
+    let version = fields.first().and_then(|v| v.parse::<u32>().ok()).ok_or_else(|| ParseError::InvalidHeader("missing version".to_string()))?;
+    if version == 0 || version > LATEST_VERSION {
+      return Err(ParseError::InvalidHeader(format!("unsupported version {}", version)));
+    }
+
+    let mut header = FileHeader { version, timestamp: false, symbol: false };
+    for column in &fields[1..] {
+      match column.to_uppercase().as_str() {
+        "TIMESTAMP" if !header.timestamp && !header.symbol => header.timestamp = true,
+        "SYMBOL" if !header.symbol => header.symbol = true,
+        _ => return Err(ParseError::InvalidHeader(format!("unexpected column {}", column)))
+      }
+    }
+    if version == 1 && (header.timestamp || header.symbol) {
+      return Err(ParseError::InvalidHeader("version 1 files have no leading columns".to_string()));
+    }
+    Ok(header)
+  }
+}
+
+fn is_verb(field: &str) -> bool {
+  matches!(field.to_uppercase().as_str(), "ADD" | "MODIFY" | "CANCEL" | "MARKET" | "IOC" | "FOK" | "STOP" | "MASSCANCEL")
+}
+
+fn parse_price(field: &str) -> Result<Decimal, ParseError> {
+  let mut price = Decimal::from_str(field)?;
+  price.rescale(2);
+  Ok(price)
+}
+
+fn parse_id(field: &str) -> Result<u64, ParseError> {
+  field.parse().map_err(ParseError::InvalidOrderId)
+}
+
+fn parse_shares(field: &str) -> Result<u64, ParseError> {
+  field.parse().map_err(ParseError::InvalidShares)
+}
+
+fn parse_order(verb: &str, fields: &[&str]) -> Result<FileUploadOrderType, ParseError> {
+  let order = match (verb, fields) {
+    ("ADD", [id, side, shares, price]) => FileUploadOrderType::Add { id: parse_id(id)?, side: side.parse()?, shares: parse_shares(shares)?, price: parse_price(price)? },
+    ("MODIFY", [id, shares, price]) => FileUploadOrderType::Modify { id: parse_id(id)?, shares: parse_shares(shares)?, price: parse_price(price)? },
+    ("CANCEL", [id]) => FileUploadOrderType::Cancel { id: parse_id(id)? },
+    ("MARKET", [id, side, shares]) => FileUploadOrderType::Market { id: parse_id(id)?, side: side.parse()?, shares: parse_shares(shares)? },
+    ("IOC", [id, side, shares, price]) => FileUploadOrderType::Ioc { id: parse_id(id)?, side: side.parse()?, shares: parse_shares(shares)?, price: parse_price(price)? },
+    ("FOK", [id, side, shares, price]) => FileUploadOrderType::Fok { id: parse_id(id)?, side: side.parse()?, shares: parse_shares(shares)?, price: parse_price(price)? },
+    ("STOP", [id, side, shares, stop_price, rest @ ..]) if rest.len() <= 1 => FileUploadOrderType::Stop {
+      id: parse_id(id)?,
+      side: side.parse()?,
+      shares: parse_shares(shares)?,
+      stop_price: parse_price(stop_price)?,
+      limit_price: rest.first().map(|price| parse_price(price)).transpose()?
+    },
+    ("MASSCANCEL", []) => FileUploadOrderType::MassCancel { side: None },
+    ("MASSCANCEL", [side]) => FileUploadOrderType::MassCancel { side: Some(side.parse()?) },
+    (verb, _) if is_verb(verb) => return Err(ParseError::InvalidOrderFormat(verb.to_string())),
+    (verb, _) => return Err(ParseError::InvalidOrderType(verb.to_string()))
+  };
+  Ok(order)
+}
+
+// Parses a file line by line, the header (if any) decides how the lines after it are read
+#[derive(Debug, Default)]
+pub struct OrderFileParser {
+  header: Option<FileHeader>,
+  seen_order_line: bool,
+  lines_read: usize
+}
+
+impl OrderFileParser {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn version(&self) -> u32 {
+    self.header.map_or(LATEST_VERSION, |header| header.version)
+  }
+
+  // lines handed to `parse_line` or skipped so far
+  pub fn lines_read(&self) -> usize {
+    self.lines_read
+  }
+
+  // counts a line that could not be handed to `parse_line`, e.g. because it is not valid UTF-8
+  pub fn skip_line(&mut self) {
+    self.lines_read += 1;
+  }
+
+  // returns None for blank lines, comments and the header. Expects every line of the file, so it can number them
+  pub fn parse_line(&mut self, line: &str) -> Result<Option<OrderLine>, ParseError> {
+    self.lines_read += 1;
+    let line = line.split('#').next().unwrap_or_default().trim();
+    if line.is_empty() {
+      return Ok(None);
+    }
+    let parts: Vec<&str> = line.split(',').map(|s| s.trim()).collect();
+
+    if parts[0].eq_ignore_ascii_case("VERSION") {
+      if self.seen_order_line || self.header.is_some() {
+        return Err(ParseError::MisplacedHeader);
+      }
+      self.header = Some(FileHeader::parse(&parts[1..])?);
+      return Ok(None);
+    }
+    self.seen_order_line = true;
+
+    // number of leading columns and whether the first one is a timestamp
+    let (leading, has_timestamp) = match self.header {
+      Some(header) => (header.timestamp as usize + header.symbol as usize, header.timestamp),
+      None => match parts.iter().take(3).position(|field| is_verb(field)) {
+        Some(0) => (0, false),
+        Some(1) => (1, parts[0].bytes().all(|b| b.is_ascii_digit())),
+        Some(_) => (2, true),
+        None => return Err(ParseError::InvalidOrderType(parts[0].to_string()))
+      }
+    };
+    if parts.len() <= leading {
+      return Err(ParseError::Empty);
+    }
+
+    let timestamp = if has_timestamp { Some(parts[0].parse().map_err(ParseError::InvalidTimestamp)?) } else { None };
+    let symbol = if leading > has_timestamp as usize {
+      let symbol = parts[leading - 1];
+      if symbol.is_empty() || symbol.contains(char::is_whitespace) {
+        return Err(ParseError::InvalidSymbol(symbol.to_string()));
+      }
+      Some(symbol.to_string())
+    } else {
+      None
+    };
+
+    let verb = parts[leading].to_uppercase();
+    if self.version() == 1 && !matches!(verb.as_str(), "ADD" | "MODIFY" | "CANCEL") {
+      return Err(ParseError::InvalidOrderType(verb));
+    }
+    let order = parse_order(&verb, &parts[leading + 1..])?;
+    Ok(Some(OrderLine { line: self.lines_read, timestamp, symbol, order }))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn price(p: &str) -> Decimal {
+    parse_price(p).expect("test prices should parse!")
+  }
+
+  // parses the lines as one file, errors are reduced to their kind
+  fn parse(lines: &[&str]) -> Vec<Result<Option<OrderLine>, &'static str>> {
+    let mut parser = OrderFileParser::new();
+    lines.iter().map(|line| parser.parse_line(line).map_err(|e| e.kind())).collect()
+  }
+
+  fn order(line: usize, timestamp: Option<u64>, symbol: Option<&str>, order: FileUploadOrderType) -> Result<Option<OrderLine>, &'static str> {
+    Ok(Some(OrderLine { line, timestamp, symbol: symbol.map(str::to_string), order }))
+  }
+
+  #[test]
+  fn version_1_lines_parse_in_any_case_and_spacing() {
+    let parsed = parse(&["  add , 1 , Bid , 100 , 10.5 ", "Modify,1,50,10.40", "cAnCeL,\t1", "ADD,2,ask,7,11"]);
+    assert_eq!(parsed, vec![
+      order(1, None, None, FileUploadOrderType::Add { id: 1, side: Side::Bid, shares: 100, price: price("10.50") }),
+      order(2, None, None, FileUploadOrderType::Modify { id: 1, shares: 50, price: price("10.40") }),
+      order(3, None, None, FileUploadOrderType::Cancel { id: 1 }),
+      order(4, None, None, FileUploadOrderType::Add { id: 2, side: Side::Ask, shares: 7, price: price("11.00") })
+    ]);
+
+    // an explicit version 1 header keeps the v2 verbs out
+    let parsed = parse(&["VERSION,1", "ADD,1,BID,100,10.5", "MARKET,2,ASK,10"]);
+    assert_eq!(parsed[1], order(2, None, None, FileUploadOrderType::Add { id: 1, side: Side::Bid, shares: 100, price: price("10.50") }));
+    assert_eq!(parsed[2], Err("invalid_order_type"));
+  }
+
+  #[test]
+  fn header_fixes_the_leading_columns() {
+    let parsed = parse(&["version,2,timestamp,symbol", "1000, AAPL, ADD,1,BID,10,1.00", "1001,7203,CANCEL,1", "AAPL,ADD,2,BID,10,1.00"]);
+    assert_eq!(parsed[0], Ok(None));
+    assert_eq!(parsed[1], order(2, Some(1000), Some("AAPL"), FileUploadOrderType::Add { id: 1, side: Side::Bid, shares: 10, price: price("1.00") }));
+    // all digit symbols are fine once the header declares the column
+    assert_eq!(parsed[2], order(3, Some(1001), Some("7203"), FileUploadOrderType::Cancel { id: 1 }));
+    assert_eq!(parsed[3], Err("invalid_timestamp"));
+
+    let parsed = parse(&["VERSION,2,SYMBOL", "7203,CANCEL,1"]);
+    assert_eq!(parsed[1], order(2, None, Some("7203"), FileUploadOrderType::Cancel { id: 1 }));
+
+    for header in ["VERSION", "VERSION,3", "VERSION,0", "VERSION,1,TIMESTAMP", "VERSION,2,SYMBOL,TIMESTAMP", "VERSION,2,TIMESTAMP,TIMESTAMP", "VERSION,2,PRICE"] {
+      assert_eq!(parse(&[header]), vec![Err("invalid_header")], "{}", header);
+    }
+  }
+
+  #[test]
+  fn headerless_leading_columns_are_recognised_per_line() {
+    let parsed = parse(&["1000,ADD,1,BID,10,1.00", "AAPL,CANCEL,1", "1002,MSFT,MASSCANCEL,ask", "MARKET,3,BID,5", "AAPL,1003,CANCEL,1", "FOO,1", "1000,AAPL,MSFT,CANCEL,1"]);
+    assert_eq!(parsed, vec![
+      order(1, Some(1000), None, FileUploadOrderType::Add { id: 1, side: Side::Bid, shares: 10, price: price("1.00") }),
+      order(2, None, Some("AAPL"), FileUploadOrderType::Cancel { id: 1 }),
+      order(3, Some(1002), Some("MSFT"), FileUploadOrderType::MassCancel { side: Some(Side::Ask) }),
+      order(4, None, None, FileUploadOrderType::Market { id: 3, side: Side::Bid, shares: 5 }),
+      Err("invalid_timestamp"),
+      Err("invalid_order_type"),
+      Err("invalid_order_type")
+    ]);
+  }
+
+  #[test]
+  fn comments_blank_lines_and_misplaced_headers() {
+    let parsed = parse(&["# generated file", "", "   ", "VERSION,2 # latest", "ADD,1,BID,10,1.00 # opening bid", "VERSION,2", "#VERSION,1"]);
+    assert_eq!(parsed, vec![
+      Ok(None),
+      Ok(None),
+      Ok(None),
+      Ok(None),
+      order(5, None, None, FileUploadOrderType::Add { id: 1, side: Side::Bid, shares: 10, price: price("1.00") }),
+      Err("misplaced_header"),
+      Ok(None)
+    ]);
+    assert_eq!(parse(&["VERSION,2", "VERSION,2"])[1], Err("misplaced_header"));
+  }
+
+  #[test]
+  fn every_verb_rejects_a_wrong_number_of_fields() {
+    let lines = [
+      "ADD,1,BID,10", "ADD,1,BID,10,1.00,2",
+      "MODIFY,1,10", "MODIFY,1,10,1.00,2",
+      "CANCEL", "CANCEL,1,2",
+      "MARKET,1,BID", "MARKET,1,BID,10,1.00",
+      "IOC,1,BID,10", "IOC,1,BID,10,1.00,2",
+      "FOK,1,BID,10", "FOK,1,BID,10,1.00,2",
+      "STOP,1,BID,10", "STOP,1,BID,10,1.00,2.00,3.00",
+      "MASSCANCEL,BID,ASK"
+    ];
+    for line in lines {
+      assert_eq!(parse(&[line]), vec![Err("invalid_order_format")], "{}", line);
+    }
+    assert_eq!(parse(&["STOP,1,BID,10,1.00"]), vec![order(1, None, None, FileUploadOrderType::Stop { id: 1, side: Side::Bid, shares: 10, stop_price: price("1.00"), limit_price: None })]);
+    assert_eq!(parse(&["1000,AAPL"]), vec![Err("invalid_order_type")]);
+  }
+}
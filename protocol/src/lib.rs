@@ -0,0 +1,6 @@
+// Wire protocol and order file grammar shared by the backend and the frontend, so a change to either side
+// breaks the build of the other instead of its users at runtime. Only depends on std, serde and rust_decimal.
+pub mod grammar;
+pub mod validator;
+pub mod ws;
+pub mod upload;
\ No newline at end of file
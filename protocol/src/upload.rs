@@ -0,0 +1,76 @@
+// Requests and answers of the upload routes that both sides build or read as is. The result payloads of
+// completed uploads carry engine stats and stay with the backend.
+use serde::{Deserialize, Serialize};
+
+use crate::{grammar::{OrderLine, ParseReport}, validator::ValidationPolicy};
+
+// one chunk of a client parsed file sent to `/smallupload`, msgpack encoded
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SmallUploadRequest {
+  pub session_id: String,
+  pub total_chunks: usize,
+  pub total_orders: usize,
+  pub chunk_number: usize,
+  pub orders: Vec<OrderLine>,
+  // small files are parsed by the client, which sends its parse report with the last chunk
+  #[serde(default)]
+  pub parse_report: Option<ParseReport>,
+  #[serde(default)]
+  pub validation: ValidationPolicy,
+  // keep the trade tape and final book for download
+  #[serde(default)]
+  pub downloads: bool,
+  // sample book stats every this many orders instead of the server's default, 0 turns sampling off
+  #[serde(default)]
+  pub book_stats_interval: Option<usize>
+}
+
+// received and missing chunks of an upload in transit, answer of `GET /uploads/{session_id}/chunks`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChunkStatus {
+  pub session_id: String,
+  pub total_chunks: usize,
+  pub received: usize,
+  // chunks still to be sent, in order
+  pub missing: Vec<usize>,
+  pub bytes: usize,
+  pub expires_in_secs: u64
+}
+
+// what a completed upload that asked for downloads keeps with its job
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct DownloadSummary {
+  pub trades: usize,
+  pub resting_orders: usize,
+  // the server stopped recording rows past its limit, the downloads hold only the first ones
+  #[serde(default)]
+  pub truncated: bool
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum JobState {
+  // waiting for a free worker
+  Queued,
+  Parsing,
+  Processing { percent_done: f64 },
+  Completed,
+  Failed { error: String },
+  Cancelled
+}
+
+impl JobState {
+  pub fn is_finished(&self) -> bool {
+    matches!(self, Self::Completed | Self::Failed { .. } | Self::Cancelled)
+  }
+}
+
+// answer of `GET /jobs/{job_id}`, `T` is the response of the upload route that started the job
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JobStatus<T> {
+  pub job_id: String,
+  #[serde(flatten)]
+  pub state: JobState,
+  // set once the job completed
+  pub result: Option<T>
+}
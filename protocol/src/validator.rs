@@ -0,0 +1,405 @@
+use std::{collections::{BTreeMap, HashMap}, fmt, str::FromStr};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::grammar::{FileUploadOrderType, OrderLine, Side, MAX_REPORTED_ERRORS};
+
+// what happens to orders that parse fine but make no sense for the book they are sent to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ValidationPolicy {
+  // drop the offending orders and process the rest
+  #[default]
+  Skip,
+  // process nothing if any order is invalid
+  Reject,
+  // report the issues but send every order to the engine, except orders reusing an id. The engine keys
+  // resting orders by id, so a second order under the same id would corrupt the first one's price level
+  PassThrough
+}
+
+impl ValidationPolicy {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Self::Skip => "skip",
+      Self::Reject => "reject",
+      Self::PassThrough => "pass_through"
+    }
+  }
+}
+
+impl FromStr for ValidationPolicy {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let s = s.trim().to_lowercase();
+    [Self::Skip, Self::Reject, Self::PassThrough].into_iter()
+      .find(|policy| policy.as_str() == s)
+      .ok_or_else(|| format!("unknown validation policy {}", s))
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidationIssue {
+  UnknownOrderId(u64),
+  DuplicateOrderId(u64),
+  NotResting(u64),
+  ModifyAfterCancel(u64),
+  DuplicateCancel(u64),
+  ZeroShares(u64),
+  NonPositivePrice(u64, Decimal)
+}
+
+impl ValidationIssue {
+  // stable name of the issue for validation report histograms
+  pub fn kind(&self) -> &'static str {
+    match self {
+      Self::UnknownOrderId(_) => "unknown_order_id",
+      Self::DuplicateOrderId(_) => "duplicate_order_id",
+      Self::NotResting(_) => "not_resting",
+      Self::ModifyAfterCancel(_) => "modify_after_cancel",
+      Self::DuplicateCancel(_) => "duplicate_cancel",
+      Self::ZeroShares(_) => "zero_shares",
+      Self::NonPositivePrice(..) => "non_positive_price"
+    }
+  }
+}
+
+impl fmt::Display for ValidationIssue {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::UnknownOrderId(id) => write!(f, "Order {} was never added", id),
+      Self::DuplicateOrderId(id) => write!(f, "Order id {} is already in use", id),
+      Self::NotResting(id) => write!(f, "Order {} never rests on the book", id),
+      Self::ModifyAfterCancel(id) => write!(f, "Order {} is modified after being cancelled", id),
+      Self::DuplicateCancel(id) => write!(f, "Order {} is already cancelled", id),
+      Self::ZeroShares(id) => write!(f, "Order {} has zero shares", id),
+      Self::NonPositivePrice(id, price) => write!(f, "Order {} has a non-positive price {}", id, price)
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LineIssue {
+  // line of the order in the uploaded file, 0 if the client did not send it
+  pub line: usize,
+  pub kind: String,
+  pub message: String
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ValidationReport {
+  pub policy: ValidationPolicy,
+  // the first `MAX_REPORTED_ERRORS` issues
+  pub issues: Vec<LineIssue>,
+  // issues per kind
+  pub issue_counts: BTreeMap<String, usize>,
+  pub total_issues: usize,
+  // orders dropped by the skip policy, and orders reusing an id under the pass through policy
+  pub skipped_orders: usize,
+  // set when the reject policy refused the whole file
+  pub rejected: bool
+}
+
+impl ValidationReport {
+  fn record(&mut self, line: usize, issue: &ValidationIssue) {
+    self.total_issues += 1;
+    *self.issue_counts.entry(issue.kind().to_string()).or_insert(0) += 1;
+    if self.issues.len() < MAX_REPORTED_ERRORS {
+      self.issues.push(LineIssue { line, kind: issue.kind().to_string(), message: issue.to_string() });
+    }
+  }
+}
+
+// what the validator knows about an order id within one symbol
+#[derive(Debug, Clone, Copy)]
+enum IdState {
+  // may rest on the book, unless it filled on arrival. Stop-limits too, they rest once triggered
+  Resting(Side),
+  // a stop-market order, which can be cancelled until it triggers but never modified
+  Stop(Side),
+  // immediate orders, which modify and cancel cannot reach
+  Transient,
+  Cancelled
+}
+
+fn check_shares(id: u64, shares: u64) -> Result<(), ValidationIssue> {
+  if shares == 0 { Err(ValidationIssue::ZeroShares(id)) } else { Ok(()) }
+}
+
+fn check_price(id: u64, price: Decimal) -> Result<(), ValidationIssue> {
+  if price <= Decimal::ZERO { Err(ValidationIssue::NonPositivePrice(id, price)) } else { Ok(()) }
+}
+
+fn check_new_id(ids: &HashMap<u64, IdState>, id: u64) -> Result<(), ValidationIssue> {
+  if ids.contains_key(&id) { Err(ValidationIssue::DuplicateOrderId(id)) } else { Ok(()) }
+}
+
+// finds the first problem of an order given the ids seen so far on its symbol
+fn check_order(ids: &HashMap<u64, IdState>, order: &FileUploadOrderType) -> Result<(), ValidationIssue> {
+  match order {
+    FileUploadOrderType::Add { id, shares, price, .. }
+    | FileUploadOrderType::Ioc { id, shares, price, .. }
+    | FileUploadOrderType::Fok { id, shares, price, .. } => {
+      check_new_id(ids, *id)?;
+      check_shares(*id, *shares)?;
+      check_price(*id, *price)
+    },
+    FileUploadOrderType::Market { id, shares, .. } => {
+      check_new_id(ids, *id)?;
+      check_shares(*id, *shares)
+    },
+    FileUploadOrderType::Stop { id, shares, stop_price, limit_price, .. } => {
+      check_new_id(ids, *id)?;
+      check_shares(*id, *shares)?;
+      check_price(*id, *stop_price)?;
+      limit_price.map_or(Ok(()), |limit_price| check_price(*id, limit_price))
+    },
+    FileUploadOrderType::Modify { id, shares, price } => {
+      match ids.get(id) {
+        None => return Err(ValidationIssue::UnknownOrderId(*id)),
+        Some(IdState::Transient | IdState::Stop(_)) => return Err(ValidationIssue::NotResting(*id)),
+        Some(IdState::Cancelled) => return Err(ValidationIssue::ModifyAfterCancel(*id)),
+        Some(IdState::Resting(_)) => {}
+      }
+      check_shares(*id, *shares)?;
+      check_price(*id, *price)
+    },
+    FileUploadOrderType::Cancel { id } => match ids.get(id) {
+      None => Err(ValidationIssue::UnknownOrderId(*id)),
+      Some(IdState::Transient) => Err(ValidationIssue::NotResting(*id)),
+      Some(IdState::Cancelled) => Err(ValidationIssue::DuplicateCancel(*id)),
+      Some(IdState::Resting(_) | IdState::Stop(_)) => Ok(())
+    },
+    FileUploadOrderType::MassCancel { .. } => Ok(())
+  }
+}
+
+fn apply_order(ids: &mut HashMap<u64, IdState>, order: &FileUploadOrderType) {
+  match order {
+    FileUploadOrderType::Add { id, side, .. } => { ids.insert(*id, IdState::Resting(*side)); },
+    FileUploadOrderType::Stop { id, side, limit_price, .. } => {
+      ids.entry(*id).or_insert(if limit_price.is_some() { IdState::Resting(*side) } else { IdState::Stop(*side) });
+    },
+    FileUploadOrderType::Market { id, .. }
+    | FileUploadOrderType::Ioc { id, .. }
+    | FileUploadOrderType::Fok { id, .. } => { ids.entry(*id).or_insert(IdState::Transient); },
+    FileUploadOrderType::Modify { .. } => {},
+    FileUploadOrderType::Cancel { id } => {
+      if let Some(state @ (IdState::Resting(_) | IdState::Stop(_))) = ids.get_mut(id) {
+        *state = IdState::Cancelled;
+      }
+    },
+    FileUploadOrderType::MassCancel { side } => {
+      for state in ids.values_mut() {
+        if let IdState::Resting(resting_side) | IdState::Stop(resting_side) = state {
+          if side.is_none_or(|side| side == *resting_side) {
+            *state = IdState::Cancelled;
+          }
+        }
+      }
+    }
+  }
+}
+
+// Checks every order against the ones before it on the same symbol and applies the policy.
+// Under the skip policy dropped orders do not count as seen, so a duplicate ADD leaves the first one in place
+pub struct OrderValidator {
+  report: ValidationReport,
+  ids_by_symbol: HashMap<Option<String>, HashMap<u64, IdState>>
+}
+
+impl OrderValidator {
+  pub fn new(policy: ValidationPolicy) -> Self {
+    OrderValidator { report: ValidationReport { policy, ..Default::default() }, ids_by_symbol: HashMap::new() }
+  }
+
+  pub fn policy(&self) -> ValidationPolicy {
+    self.report.policy
+  }
+
+  // the order if it should reach the engine. Under the reject policy the caller has to hold
+  // the returned orders back until `finish` tells whether the file was rejected
+  pub fn check(&mut self, order_line: OrderLine) -> Option<OrderLine> {
+    let ids = self.ids_by_symbol.entry(order_line.symbol.clone()).or_default();
+    match check_order(ids, &order_line.order) {
+      Ok(()) => {
+        apply_order(ids, &order_line.order);
+        Some(order_line)
+      },
+      Err(issue) => {
+        self.report.record(order_line.line, &issue);
+        match self.report.policy {
+          ValidationPolicy::Skip | ValidationPolicy::PassThrough if matches!(issue, ValidationIssue::DuplicateOrderId(_)) => {
+            self.report.skipped_orders += 1;
+            None
+          },
+          ValidationPolicy::Skip => {
+            self.report.skipped_orders += 1;
+            None
+          },
+          ValidationPolicy::Reject => None,
+          ValidationPolicy::PassThrough => {
+            apply_order(ids, &order_line.order);
+            Some(order_line)
+          }
+        }
+      }
+    }
+  }
+
+  pub fn finish(mut self) -> ValidationReport {
+    self.report.rejected = self.report.policy == ValidationPolicy::Reject && self.report.total_issues > 0;
+    self.report
+  }
+}
+
+pub fn validate_orders(orders: Vec<OrderLine>, policy: ValidationPolicy) -> (Vec<OrderLine>, ValidationReport) {
+  let mut validator = OrderValidator::new(policy);
+  let mut valid_orders: Vec<OrderLine> = orders.into_iter().filter_map(|order_line| validator.check(order_line)).collect();
+  let report = validator.finish();
+  if report.rejected {
+    valid_orders.clear();
+  }
+  (valid_orders, report)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn price(p: &str) -> Decimal {
+    Decimal::from_str(p).expect("test prices should parse!")
+  }
+
+  fn add(id: u64, side: Side) -> FileUploadOrderType {
+    FileUploadOrderType::Add { id, side, shares: 10, price: price("100.00") }
+  }
+
+  fn lines(orders: Vec<(Option<&str>, FileUploadOrderType)>) -> Vec<OrderLine> {
+    orders.into_iter().enumerate().map(|(idx, (symbol, order))| OrderLine { line: idx + 1, timestamp: None, symbol: symbol.map(str::to_string), order }).collect()
+  }
+
+  fn kinds(report: &ValidationReport) -> Vec<(usize, &str)> {
+    report.issues.iter().map(|issue| (issue.line, issue.kind.as_str())).collect()
+  }
+
+  #[test]
+  fn check_order_finds_the_first_problem() {
+    let mut ids = HashMap::new();
+    assert_eq!(check_order(&ids, &add(1, Side::Bid)), Ok(()));
+    apply_order(&mut ids, &add(1, Side::Bid));
+    apply_order(&mut ids, &FileUploadOrderType::Ioc { id: 2, side: Side::Ask, shares: 5, price: price("99.00") });
+
+    assert_eq!(check_order(&ids, &add(1, Side::Ask)), Err(ValidationIssue::DuplicateOrderId(1)));
+    assert_eq!(check_order(&ids, &FileUploadOrderType::Add { id: 3, side: Side::Bid, shares: 0, price: price("-1") }), Err(ValidationIssue::ZeroShares(3)));
+    assert_eq!(check_order(&ids, &FileUploadOrderType::Fok { id: 3, side: Side::Bid, shares: 1, price: price("0") }), Err(ValidationIssue::NonPositivePrice(3, price("0"))));
+    assert_eq!(check_order(&ids, &FileUploadOrderType::Stop { id: 3, side: Side::Bid, shares: 1, stop_price: price("1"), limit_price: Some(price("-2")) }), Err(ValidationIssue::NonPositivePrice(3, price("-2"))));
+    assert_eq!(check_order(&ids, &FileUploadOrderType::Modify { id: 9, shares: 1, price: price("1") }), Err(ValidationIssue::UnknownOrderId(9)));
+    assert_eq!(check_order(&ids, &FileUploadOrderType::Modify { id: 2, shares: 1, price: price("1") }), Err(ValidationIssue::NotResting(2)));
+    assert_eq!(check_order(&ids, &FileUploadOrderType::Cancel { id: 2 }), Err(ValidationIssue::NotResting(2)));
+
+    apply_order(&mut ids, &FileUploadOrderType::Cancel { id: 1 });
+    assert_eq!(check_order(&ids, &FileUploadOrderType::Modify { id: 1, shares: 1, price: price("1") }), Err(ValidationIssue::ModifyAfterCancel(1)));
+    assert_eq!(check_order(&ids, &FileUploadOrderType::Cancel { id: 1 }), Err(ValidationIssue::DuplicateCancel(1)));
+    // cancelled ids stay taken
+    assert_eq!(check_order(&ids, &add(1, Side::Bid)), Err(ValidationIssue::DuplicateOrderId(1)));
+  }
+
+  #[test]
+  fn mass_cancel_only_cancels_its_side() {
+    let mut ids = HashMap::new();
+    apply_order(&mut ids, &add(1, Side::Bid));
+    apply_order(&mut ids, &add(2, Side::Ask));
+    apply_order(&mut ids, &FileUploadOrderType::MassCancel { side: Some(Side::Bid) });
+    assert_eq!(check_order(&ids, &FileUploadOrderType::Cancel { id: 1 }), Err(ValidationIssue::DuplicateCancel(1)));
+    assert_eq!(check_order(&ids, &FileUploadOrderType::Cancel { id: 2 }), Ok(()));
+
+    apply_order(&mut ids, &FileUploadOrderType::MassCancel { side: None });
+    assert_eq!(check_order(&ids, &FileUploadOrderType::Cancel { id: 2 }), Err(ValidationIssue::DuplicateCancel(2)));
+  }
+
+  #[test]
+  fn stops_can_be_cancelled_and_stop_limits_modified() {
+    let stop = |id: u64, side: Side, limit_price: Option<Decimal>| FileUploadOrderType::Stop { id, side, shares: 5, stop_price: price("99.00"), limit_price };
+    let mut ids = HashMap::new();
+    apply_order(&mut ids, &stop(1, Side::Ask, None));
+    apply_order(&mut ids, &stop(2, Side::Ask, Some(price("98.00"))));
+    apply_order(&mut ids, &stop(3, Side::Bid, None));
+
+    // a stop-market never rests, a stop-limit may once it triggers
+    assert_eq!(check_order(&ids, &FileUploadOrderType::Modify { id: 1, shares: 1, price: price("1") }), Err(ValidationIssue::NotResting(1)));
+    assert_eq!(check_order(&ids, &FileUploadOrderType::Modify { id: 2, shares: 1, price: price("1") }), Ok(()));
+    assert_eq!(check_order(&ids, &FileUploadOrderType::Cancel { id: 1 }), Ok(()));
+    assert_eq!(check_order(&ids, &FileUploadOrderType::Cancel { id: 2 }), Ok(()));
+
+    apply_order(&mut ids, &FileUploadOrderType::Cancel { id: 1 });
+    assert_eq!(check_order(&ids, &FileUploadOrderType::Cancel { id: 1 }), Err(ValidationIssue::DuplicateCancel(1)));
+    apply_order(&mut ids, &FileUploadOrderType::MassCancel { side: Some(Side::Bid) });
+    assert_eq!(check_order(&ids, &FileUploadOrderType::Cancel { id: 3 }), Err(ValidationIssue::DuplicateCancel(3)));
+    assert_eq!(check_order(&ids, &FileUploadOrderType::Cancel { id: 2 }), Ok(()));
+  }
+
+  #[test]
+  fn ids_are_scoped_per_symbol() {
+    let orders = lines(vec![
+      (Some("AAPL"), add(1, Side::Bid)),
+      (Some("MSFT"), add(1, Side::Ask)),
+      (None, add(1, Side::Bid)),
+      (Some("MSFT"), FileUploadOrderType::Cancel { id: 1 }),
+      (Some("AAPL"), FileUploadOrderType::Modify { id: 1, shares: 5, price: price("101.00") }),
+      (Some("TSLA"), FileUploadOrderType::Cancel { id: 1 })
+    ]);
+    let (valid, report) = validate_orders(orders, ValidationPolicy::Skip);
+    assert_eq!(valid.len(), 5);
+    assert_eq!(kinds(&report), vec![(6, "unknown_order_id")]);
+  }
+
+  #[test]
+  fn skipped_orders_do_not_count_as_seen() {
+    let orders = lines(vec![
+      (None, add(1, Side::Bid)),
+      // dropped, so the resting order 1 is still the bid
+      (None, add(1, Side::Ask)),
+      (None, FileUploadOrderType::Cancel { id: 1 }),
+      // never added because its ADD was dropped
+      (None, FileUploadOrderType::Add { id: 2, side: Side::Bid, shares: 0, price: price("1") }),
+      (None, FileUploadOrderType::Cancel { id: 2 })
+    ]);
+    let (valid, report) = validate_orders(orders, ValidationPolicy::Skip);
+    assert_eq!(valid.iter().map(|order| order.line).collect::<Vec<_>>(), vec![1, 3]);
+    assert_eq!(kinds(&report), vec![(2, "duplicate_order_id"), (4, "zero_shares"), (5, "unknown_order_id")]);
+    assert_eq!(report.skipped_orders, 3);
+    assert_eq!(report.issue_counts.get("unknown_order_id"), Some(&1));
+    assert!(!report.rejected);
+  }
+
+  #[test]
+  fn reject_clears_every_order() {
+    let orders = lines(vec![(None, add(1, Side::Bid)), (None, FileUploadOrderType::Cancel { id: 7 }), (None, add(2, Side::Ask))]);
+    let (valid, report) = validate_orders(orders.clone(), ValidationPolicy::Reject);
+    assert!(valid.is_empty());
+    assert!(report.rejected);
+    assert_eq!(report.skipped_orders, 0);
+    assert_eq!(kinds(&report), vec![(2, "unknown_order_id")]);
+
+    let (valid, report) = validate_orders(orders[..1].to_vec(), ValidationPolicy::Reject);
+    assert_eq!(valid.len(), 1);
+    assert!(!report.rejected);
+  }
+
+  #[test]
+  fn pass_through_keeps_invalid_orders_but_never_reuses_an_id() {
+    let orders = lines(vec![
+      (None, add(1, Side::Bid)),
+      (None, add(1, Side::Ask)),
+      (None, FileUploadOrderType::Stop { id: 1, side: Side::Ask, shares: 5, stop_price: price("99.00"), limit_price: Some(price("98.00")) }),
+      (None, FileUploadOrderType::Cancel { id: 7 }),
+      (None, FileUploadOrderType::Add { id: 2, side: Side::Bid, shares: 0, price: price("1") })
+    ]);
+    let (valid, report) = validate_orders(orders, ValidationPolicy::PassThrough);
+    assert_eq!(valid.iter().map(|order| order.line).collect::<Vec<_>>(), vec![1, 4, 5]);
+    assert_eq!(kinds(&report), vec![(2, "duplicate_order_id"), (3, "duplicate_order_id"), (4, "unknown_order_id"), (5, "zero_shares")]);
+    assert_eq!(report.skipped_orders, 2);
+  }
+}
@@ -0,0 +1,131 @@
+// Messages of the `/wslob` simulation websocket. Both directions are JSON, the server sends its responses
+// in batches (`Vec<Vec<WsResponse>>`), deflated in binary frames except for single signals.
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum WsRequest<C> {
+  // `config` holds the simulation parameters, `SimulationParams` at least
+  Start {
+    total_objects: usize,  //defaults to 50_000
+    #[serde(flatten)]
+    config: C
+  },
+  Stop,
+  Ack,
+  // playback controls for the running simulation
+  Pause,
+  Resume,
+  // process `orders` more orders and pause again
+  Step { orders: usize },
+  // throttle the simulation, 0 removes the limit
+  SetRate { orders_per_sec: f64 }
+}
+
+// simulation parameters every START message carries, the server's simulator config adds optional ones
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SimulationParams {
+  pub mean_price: f64,  //defaults to 300.0
+  pub sd_price: f64,  // defaults to 50.0
+  pub order_probs: Vec<f32>, //probs for [ADD, CANCEL, MODIFY] defaults to [0.0, 0.4 ,0.6], optionally followed by [MARKETABLE_LIMIT, MARKET, IOC], adding up to 1
+  pub best_price_levels: bool, // whether to show best bids and asks, defaults to false
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub seed: Option<u64> // rng seed for reproducible runs, a random one is picked (and echoed back) if missing
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum WsResponse {
+  SimulationInfo { sim_id: String, seed: u64 },
+  PriceLevels { snapshot: bool, sim_time_ns: u64, bids: Vec<(Decimal, u64)>, asks: Vec<(Decimal, u64)> },
+  Trades { sim_time_ns: u64, trades: Vec<ExecutedOrders> },
+  ExecutionStats (EngineStats),
+  BestLevels {best_buy: Option<Decimal>, best_sell: Option<Decimal>},
+  RiskRejected { order_id: u64, reason: String },
+  BookStats (BookSample),
+  AgentReport (Vec<AgentStats>),
+  // every update after this belongs to the new phase until the next one
+  ScenarioPhase (PhaseChange),
+  // the simulation waits for a free slot, `position` 1 is next in line
+  Queued { position: usize },
+  Completed,
+  RateLimitExceeded,
+  // the request could not be used (malformed or invalid simulation parameters), the server closes the socket after it
+  InvalidRequest { reason: String }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ExecutedOrders {
+  pub price: Decimal,
+  pub volume: u64,
+  pub aggresive_order_id: u64,
+  pub passive_order_id: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EngineStats {
+  pub order_type: String,
+  pub latency: i64,
+  pub avl_rebalances: i64,
+  pub executed_orders_cnt: usize,
+  // simulated arrival time of the order
+  pub sim_time_ns: u64,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub phases: Option<PhaseBreakdown>
+}
+
+// Time (in ns) spent in each phase of the last processed order.
+// NOTE: phases nest, `matching` includes the `delete_limit` calls for emptied levels
+// and both `tree_insert` and `delete_limit` include their `rebalance` time
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct PhaseBreakdown {
+  pub matching: u64,
+  pub tree_insert: u64,
+  pub rebalance: u64,
+  pub delete_limit: u64
+}
+
+impl PhaseBreakdown {
+  pub fn reset(&mut self) {
+    *self = PhaseBreakdown::default();
+  }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BookSample {
+  pub order_idx: usize,
+  pub spread: Option<Decimal>,
+  pub best_bid_size: u64,
+  pub best_ask_size: u64,
+  pub bid_depth: u64,
+  pub ask_depth: u64,
+  pub bid_levels: usize,
+  pub ask_levels: usize,
+  pub resting_orders: usize,
+  // trades since the previous sample
+  pub trade_count: usize,
+  pub trade_volume: u64
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AgentStats {
+  pub account: String,
+  pub kind: String,
+  pub orders_sent: u64,
+  pub risk_rejects: u64,
+  pub fills: u64,
+  pub bought: u64,
+  pub sold: u64,
+  pub position: i64,
+  pub cash: Decimal,
+  // cash plus the position marked at the last trade price (or mid if nothing traded)
+  pub pnl: Decimal
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PhaseChange {
+  pub scenario: String,
+  pub phase: String,
+  pub order_idx: usize,
+  pub sim_time_ns: u64
+}
@@ -0,0 +1,188 @@
+use std::fmt::Debug;
+use protocol::{
+  grammar::{OrderFileParser, OrderLine, ParseReport},
+  upload::{ChunkStatus, DownloadSummary, JobState, JobStatus, SmallUploadRequest},
+  validator::{validate_orders, ValidationPolicy, ValidationReport},
+  ws::{AgentStats, BookSample, EngineStats, ExecutedOrders, PhaseBreakdown, PhaseChange, SimulationParams, WsRequest, WsResponse}
+};
+use rust_decimal::Decimal;
+use serde::{de::DeserializeOwned, Serialize};
+
+const ORDER_FILE: &str = "\
+VERSION,2,TIMESTAMP,SYMBOL
+# a comment
+1000,AAPL,ADD,1,BID,100,150.25
+1001,AAPL,ADD,2,ASK,50,150.30
+1002,MSFT,MODIFY,1,80,150.20
+1003,AAPL,MARKET,3,BID,20
+1004,AAPL,IOC,4,ASK,10,150.00
+1005,AAPL,FOK,5,BID,10,151.00
+1006,AAPL,STOP,6,ASK,10,149.00,148.50
+1007,AAPL,STOP,7,BID,10,152.00
+1008,AAPL,CANCEL,2
+1009,AAPL,MASSCANCEL,BID
+1010,AAPL,MASSCANCEL
+1011,AAPL,ADD,8,BID,0,-1.00
+1012,AAPL,ADD,9,SIDEWAYS,1,1.00
+";
+
+fn json_round_trip<T: Serialize + DeserializeOwned + PartialEq + Debug>(value: &T) {
+  let json = serde_json::to_string(value).expect("value should serialize to json!");
+  let decoded: T = serde_json::from_str(&json).expect("json should deserialize back!");
+  assert_eq!(value, &decoded, "json: {}", json);
+}
+
+// encoded the way the frontend sends small uploads
+fn msgpack_round_trip<T: Serialize + DeserializeOwned + PartialEq + Debug>(value: &T) {
+  let bytes = rmp_serde::to_vec(value).expect("value should serialize to msgpack!");
+  let decoded: T = rmp_serde::from_slice(&bytes).expect("msgpack should deserialize back!");
+  assert_eq!(value, &decoded);
+}
+
+fn parse_order_file() -> (Vec<OrderLine>, ParseReport) {
+  let mut parser = OrderFileParser::new();
+  let mut report = ParseReport::default();
+  let mut orders = Vec::new();
+  for (idx, line) in ORDER_FILE.lines().enumerate() {
+    match parser.parse_line(line) {
+      Ok(Some(order)) => orders.push(order),
+      Ok(None) => {},
+      Err(e) => report.record(idx + 1, &e, line)
+    }
+  }
+  (orders, report)
+}
+
+fn params() -> SimulationParams {
+  SimulationParams { mean_price: 300.0, sd_price: 50.0, order_probs: vec![0.0, 0.5, 0.5], best_price_levels: true, seed: Some(42) }
+}
+
+fn ws_responses() -> Vec<WsResponse> {
+  let price = |p: &str| p.parse::<Decimal>().expect("test prices should parse!");
+  vec![
+    WsResponse::SimulationInfo { sim_id: "sim".to_string(), seed: 42 },
+    WsResponse::PriceLevels { snapshot: true, sim_time_ns: 10, bids: vec![(price("99.50"), 300)], asks: vec![(price("100.25"), 120), (price("100.50"), 5)] },
+    WsResponse::Trades { sim_time_ns: 11, trades: vec![ExecutedOrders { price: price("100.25"), volume: 20, aggresive_order_id: 7, passive_order_id: 3 }] },
+    WsResponse::ExecutionStats(EngineStats { order_type: "ADD".to_string(), latency: 850, avl_rebalances: 1, executed_orders_cnt: 2, sim_time_ns: 12, phases: None }),
+    WsResponse::ExecutionStats(EngineStats { order_type: "MODIFY".to_string(), latency: 900, avl_rebalances: 0, executed_orders_cnt: 0, sim_time_ns: 13, phases: Some(PhaseBreakdown { matching: 100, tree_insert: 200, rebalance: 50, delete_limit: 0 }) }),
+    WsResponse::BestLevels { best_buy: Some(price("99.50")), best_sell: None },
+    WsResponse::RiskRejected { order_id: 9, reason: "max order size exceeded".to_string() },
+    WsResponse::BookStats(BookSample { order_idx: 100, spread: Some(price("0.75")), best_bid_size: 300, best_ask_size: 120, bid_depth: 900, ask_depth: 400, bid_levels: 4, ask_levels: 2, resting_orders: 20, trade_count: 3, trade_volume: 60 }),
+    WsResponse::AgentReport(vec![AgentStats { account: "market_maker_0".to_string(), kind: "market_maker".to_string(), orders_sent: 10, risk_rejects: 1, fills: 4, bought: 30, sold: 10, position: 20, cash: price("-2000.50"), pnl: price("5.25") }]),
+    WsResponse::ScenarioPhase(PhaseChange { scenario: "flash crash".to_string(), phase: "crash".to_string(), order_idx: 20_000, sim_time_ns: 14 }),
+    WsResponse::Queued { position: 2 },
+    WsResponse::Completed,
+    WsResponse::RateLimitExceeded,
+    WsResponse::InvalidRequest { reason: "spread_factor should be positive".to_string() }
+  ]
+}
+
+#[test]
+fn ws_requests_round_trip() {
+  let requests = vec![
+    WsRequest::Start { total_objects: 50_000, config: params() },
+    WsRequest::Start { total_objects: 10, config: SimulationParams { seed: None, ..params() } },
+    WsRequest::Stop,
+    WsRequest::Ack,
+    WsRequest::Pause,
+    WsRequest::Resume,
+    WsRequest::Step { orders: 5 },
+    WsRequest::SetRate { orders_per_sec: 250.5 }
+  ];
+  for request in &requests {
+    json_round_trip(request);
+  }
+}
+
+#[test]
+fn ws_start_flattens_its_params() {
+  let start = serde_json::to_value(WsRequest::Start { total_objects: 10, config: SimulationParams { seed: None, ..params() } }).expect("start should serialize!");
+  assert_eq!(start, serde_json::json!({
+    "type": "Start",
+    "total_objects": 10,
+    "mean_price": 300.0,
+    "sd_price": 50.0,
+    "order_probs": [0.0, 0.5, 0.5],
+    "best_price_levels": true
+  }));
+}
+
+#[test]
+fn ws_responses_round_trip_in_batches() {
+  let batch: Vec<Vec<WsResponse>> = ws_responses().into_iter().map(|response| vec![response]).collect();
+  json_round_trip(&batch);
+}
+
+#[test]
+fn engine_stats_without_phases_deserialize() {
+  let json = r#"{"ExecutionStats":{"order_type":"CANCEL","latency":400,"avl_rebalances":0,"executed_orders_cnt":0,"sim_time_ns":5}}"#;
+  let response: WsResponse = serde_json::from_str(json).expect("stats without phases should deserialize!");
+  assert!(matches!(response, WsResponse::ExecutionStats(EngineStats { phases: None, .. })));
+}
+
+#[test]
+fn parsed_orders_round_trip() {
+  let (orders, report) = parse_order_file();
+  assert_eq!(orders.len(), 12);
+  assert_eq!(report.total_errors, 1);
+  for order in &orders {
+    json_round_trip(order);
+    msgpack_round_trip(order);
+  }
+  json_round_trip(&report);
+  msgpack_round_trip(&report);
+}
+
+#[test]
+fn validation_reports_round_trip() {
+  let (orders, _) = parse_order_file();
+  for policy in [ValidationPolicy::Skip, ValidationPolicy::Reject, ValidationPolicy::PassThrough] {
+    let (_, report): (_, ValidationReport) = validate_orders(orders.clone(), policy);
+    assert!(report.total_issues > 0);
+    json_round_trip(&report);
+    msgpack_round_trip(&report);
+    assert_eq!(policy.as_str().parse::<ValidationPolicy>(), Ok(policy));
+  }
+}
+
+#[test]
+fn small_upload_requests_round_trip() {
+  let (orders, report) = parse_order_file();
+  let request = SmallUploadRequest {
+    session_id: "session".to_string(),
+    total_chunks: 2,
+    total_orders: orders.len(),
+    chunk_number: 1,
+    orders,
+    parse_report: Some(report),
+    validation: ValidationPolicy::Reject,
+    downloads: true,
+    book_stats_interval: Some(50)
+  };
+  msgpack_round_trip(&request);
+  msgpack_round_trip(&SmallUploadRequest { parse_report: None, book_stats_interval: None, ..request });
+}
+
+#[test]
+fn job_statuses_round_trip() {
+  let states = vec![
+    JobState::Queued,
+    JobState::Parsing,
+    JobState::Processing { percent_done: 42.5 },
+    JobState::Completed,
+    JobState::Failed { error: "upload processing failed".to_string() },
+    JobState::Cancelled
+  ];
+  for state in states {
+    let result = (state == JobState::Completed).then_some(DownloadSummary { trades: 12, resting_orders: 3, truncated: false });
+    json_round_trip(&JobStatus { job_id: "job".to_string(), state, result });
+  }
+
+  let processing = serde_json::to_value(JobStatus::<DownloadSummary> { job_id: "job".to_string(), state: JobState::Processing { percent_done: 10.0 }, result: None }).expect("status should serialize!");
+  assert_eq!(processing, serde_json::json!({ "job_id": "job", "state": "processing", "percent_done": 10.0, "result": null }));
+}
+
+#[test]
+fn chunk_statuses_round_trip() {
+  json_round_trip(&ChunkStatus { session_id: "session".to_string(), total_chunks: 4, received: 2, missing: vec![1, 3], bytes: 1024, expires_in_secs: 300 });
+}